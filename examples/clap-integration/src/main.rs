@@ -1,14 +1,16 @@
+use openai_func_enums::async_trait::async_trait;
 use clap::{Parser, Subcommand, ValueEnum};
 use openai_func_enums::{
-    logger_task, rank_functions, single_embedding, CommandError, EnumDescriptor, FuncEmbedding,
-    Logger, RunCommand, ToolCallExecutionStrategy, ToolSet, VariantDescriptors,
+    get_ranked_function_names, logger_task, single_embedding, CommandError, EnumDescriptor, Logger,
+    PlanExecutor, PlanStep, PlanTool, RunCommand, RunConfig, ToolCallExecutionStrategy,
+    ToolOutput, ToolSet, VariantDescriptors,
 };
-use std::io::Read;
+use serde::Deserialize;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Instant;
-use std::{fs::File, path::Path};
 use tokio::spawn;
-use tokio::sync::Mutex;
+use tokio::sync::mpsc;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -48,11 +50,6 @@ pub enum Commands {
         rounding_mode: RoundingMode,
     },
 
-    /// CallMultiStep is designed to efficiently process complex, multi-step user requests. It takes an array of text prompts, each detailing a specific step in a sequential task. This function is crucial for handling requests where the output of one step forms the input of the next. When constructing the prompt list, consider the dependency and order of tasks. Independent tasks within the same step should be consolidated into a single prompt to leverage parallel processing capabilities. This function ensures that multi-step tasks are executed in the correct sequence and that all dependencies are respected, thus faithfully representing and fulfilling the user's request."
-    CallMultiStep {
-        prompt_list: Vec<String>,
-    },
-
     GPT {
         prompt: String,
     },
@@ -66,10 +63,8 @@ impl RunCommand for Commands {
         _arguments: Option<Vec<String>>,
         logger: Arc<Logger>,
         system_message: Option<(String, usize)>,
-    ) -> Result<
-        (Option<String>, Option<Vec<String>>),
-        Box<dyn std::error::Error + Send + Sync + 'static>,
-    > {
+        _state: &(),
+    ) -> Result<ToolOutput, Box<dyn std::error::Error + Send + Sync + 'static>> {
         let max_response_tokens = 1000_u16;
         let request_token_limit = 4191;
         let model_name = "gpt-4-1106-preview";
@@ -88,7 +83,7 @@ impl RunCommand for Commands {
                     rounding_mode.variant_name_with_token_count().0,
                     result
                 );
-                return Ok((Some(result.to_string()), None));
+                return Ok(ToolOutput::json(&result)?);
             }
             Commands::Subtract {
                 a,
@@ -103,7 +98,7 @@ impl RunCommand for Commands {
                     rounding_mode.variant_name_with_token_count().0,
                     result
                 );
-                return Ok((Some(result.to_string()), None));
+                return Ok(ToolOutput::json(&result)?);
             }
             Commands::Multiply {
                 a,
@@ -118,7 +113,7 @@ impl RunCommand for Commands {
                     rounding_mode.variant_name_with_token_count().0,
                     result
                 );
-                return Ok((Some(result.to_string()), None));
+                return Ok(ToolOutput::json(&result)?);
             }
             Commands::Divide {
                 a,
@@ -134,133 +129,73 @@ impl RunCommand for Commands {
                         rounding_mode.variant_name_with_token_count().0,
                         result
                     );
-                    return Ok((Some(result.to_string()), None));
+                    return Ok(ToolOutput::json(&result)?);
                 } else {
                     return Err(Box::new(CommandError::new("Cannot divide by zero")));
                 }
             }
-            Commands::CallMultiStep { prompt_list } => {
-                let _ = logger
-                    .sender
-                    .send(String::from("this is the prompt list"))
-                    .await;
-                let message = format!("{:#?}", prompt_list);
-                let _ = logger.sender.send(message).await;
-
-                let prior_result = Arc::new(Mutex::new(None));
-
-                let command_args_list: Vec<String> = Vec::new();
-                let command_args = Arc::new(Mutex::new(Some(command_args_list)));
+            Commands::GPT { prompt } => {
+                let prompt_embedding = single_embedding(prompt, FUNC_ENUMS_EMBED_MODEL).await?;
                 let embed_path = Path::new(FUNC_ENUMS_EMBED_PATH);
-
-                for (i, prompt) in prompt_list.iter().enumerate() {
-                    let prompt_embedding = single_embedding(prompt, FUNC_ENUMS_EMBED_MODEL).await?;
-                    let prior_result_clone = prior_result.clone();
-                    let command_args_clone = command_args.clone();
-                    let logger_clone = logger.clone();
-
-                    // If you are attempting to do something that branches more than once you will
-                    // want to force the inclusion of this variant. It is unlikely to rank high.
-                    let required_funcs = vec![String::from("CallMultiStep")];
-
-                    let mut ranked_func_names = vec![];
-                    if embed_path.exists() {
-                        let mut file = File::open(embed_path).unwrap();
-                        let mut bytes = Vec::new();
-                        file.read_to_end(&mut bytes).unwrap();
-
-                        let archived_funcs =
-                            rkyv::check_archived_root::<Vec<FuncEmbedding>>(&bytes).unwrap();
-                        ranked_func_names = rank_functions(archived_funcs, prompt_embedding).await;
-                    }
-
-                    match i {
-                        0 => {
-                            CommandsGPT::run(
-                                &prompt.to_string(),
+                let ranked_func_names =
+                    get_ranked_function_names(prompt_embedding, embed_path).await?;
+
+                // Requests that take several steps are broken into a plan by the model. Steps
+                // that don't depend on each other run at the same time, and each step is handed
+                // the results of the steps it depends on.
+                let step_logger = logger.clone();
+                let step_execution_strategy = execution_strategy.clone();
+                let plan_tool = PlanTool::new(
+                    PlanExecutor::new(4),
+                    move |_step: &PlanStep, prompt: String| {
+                        let logger = step_logger.clone();
+                        let execution_strategy = step_execution_strategy.clone();
+
+                        async move {
+                            let output = CommandsGPT::run_with_config(
+                                &prompt,
                                 model_name,
                                 Some(request_token_limit),
                                 Some(max_response_tokens),
-                                system_message.clone(),
-                                prior_result_clone,
-                                execution_strategy.clone(),
-                                command_args_clone,
-                                Some(ranked_func_names),
-                                Some(required_funcs),
-                                logger_clone,
+                                None,
+                                execution_strategy,
+                                None,
+                                None,
+                                logger,
+                                &RunConfig::current(),
                             )
-                            .await?
-                        }
+                            .await?;
 
-                        _ => {
-                            let prior_result_guard = prior_result.lock().await;
-                            if let Some(prior) = &*prior_result_guard {
-                                let new_prompt =
-                                    format!("The prior result was: {}. {}", prior.clone(), prompt);
-                                drop(prior_result_guard);
-
-                                CommandsGPT::run(
-                                    &new_prompt,
-                                    model_name,
-                                    Some(request_token_limit),
-                                    Some(max_response_tokens),
-                                    system_message.clone(),
-                                    prior_result_clone,
-                                    execution_strategy.clone(),
-                                    command_args_clone,
-                                    None,
-                                    None,
-                                    logger_clone,
-                                )
-                                .await?
-                            } else {
-                                *prior_result.lock().await = None;
-                            }
+                            Ok::<_, Box<dyn std::error::Error + Send + Sync + 'static>>(
+                                output.tool_calls.last().and_then(|call| call.output.clone()),
+                            )
                         }
-                    }
-                }
-                let result = String::from("Ok.");
-                return Ok((Some(result), None));
-            }
-            Commands::GPT { prompt } => {
-                let prompt_embedding = single_embedding(prompt, FUNC_ENUMS_EMBED_MODEL).await?;
-
-                let prior_result = Arc::new(Mutex::new(None));
-                let command_args = Arc::new(Mutex::new(None));
-                let embed_path = Path::new(FUNC_ENUMS_EMBED_PATH);
-                let mut ranked_func_names = vec![];
-                let logger_clone = logger.clone();
-
-                if embed_path.exists() {
-                    let mut file = File::open(embed_path).unwrap();
-                    let mut bytes = Vec::new();
-                    file.read_to_end(&mut bytes).unwrap();
+                    },
+                );
 
-                    let archived_funcs =
-                        rkyv::check_archived_root::<Vec<FuncEmbedding>>(&bytes).unwrap();
-                    ranked_func_names = rank_functions(archived_funcs, prompt_embedding).await;
-                }
+                let mut config = RunConfig::default();
+                config.tools.register(plan_tool);
 
-                let required_funcs = vec![String::from("CallMultiStep")];
+                // The plan tool is unlikely to rank high, so it is always presented.
+                let required_funcs = vec![String::from("Plan")];
 
-                CommandsGPT::run(
+                CommandsGPT::run_with_config(
                     prompt,
                     model_name,
                     Some(request_token_limit),
                     Some(max_response_tokens),
                     system_message,
-                    prior_result,
                     execution_strategy.clone(),
-                    command_args,
                     Some(ranked_func_names),
                     Some(required_funcs),
-                    logger_clone,
+                    logger.clone(),
+                    &config,
                 )
                 .await?;
             }
         };
 
-        Ok((None, None))
+        Ok(ToolOutput::none())
     }
 }
 
@@ -295,23 +230,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let system_instructions = Some((
         String::from(
             "You are an advanced function-calling bot, adept at handling complex, \
-                      multi-step user requests. Your role is to discern and articulate \
-                      each step of a user's request, especially when it involves sequential \
-                      operations. Use the CallMultiStep function for requests that require \
-                      sequential processing. Each step should be described in a separate \
-                      prompt, with attention to whether the steps are independent or \
-                      interdependent. For interdependent steps, ensure each prompt \
-                      accurately represents the sequence and dependencies of the tasks. \
-                      Remember, a single step may encompass multiple tasks that can be \
-                      executed in parallel. Your goal is to capture the entire scope of the \
-                      user's request, structuring it into an appropriate sequence of function \
-                      calls without omitting any steps. For example, if a user asks to add 8 \
-                      and 2 in the first step, and then requests the result to be multiplied \
-                      by 7 and 5 in separate tasks of the second step, use CallMultiStep with \
-                      two prompts: the first for addition, and the second combining both \
-                      multiplication tasks, recognizing their parallel nature.",
+                      multi-step user requests. Use the Plan function for requests that \
+                      take more than one function call. Give every step its own prompt, and \
+                      list the steps it needs the results of in depends_on. Steps that don't \
+                      depend on each other can share a parallel_group so they run at the \
+                      same time. For example, if a user asks to add 8 and 2, and then to \
+                      multiply the result by 7 and by 5, plan one step for the addition and \
+                      two multiplication steps that both depend on it.",
         ),
-        207_usize,
+        119_usize,
     ));
 
     let cli = Cli::parse();
@@ -324,6 +251,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             None,
             logger_clone,
             system_instructions,
+            &(),
         )
         .await
         .map_err(|e| {
@@ -32,7 +32,7 @@ fn main() {
         max_func_tokens
     );
 
-    // This currently doesn't do anything but it will soon. If you don't 
+    // This currently doesn't do anything but it will soon. If you don't
     // ever want this to come into play just set it high.
     let max_single_arg_tokens = 20u16;
     println!(
@@ -1,10 +1,11 @@
+use openai_func_enums::async_trait::async_trait;
 use openai_func_enums::{
-    arg_description, logger_task, CommandError, EnumDescriptor, Logger, RunCommand,
-    ToolCallExecutionStrategy, ToolSet, VariantDescriptors,
+    logger_task, CommandError, Logger, RunCommand, ToolArg, ToolCallExecutionStrategy, ToolOutput,
+    ToolSet,
 };
 use std::sync::Arc;
 use tokio::spawn;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -24,6 +25,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         None,
         logger_clone,
         system_message,
+        &(),
     )
     .await
     .map_err(|e| {
@@ -57,10 +59,8 @@ impl RunCommand for FunctionDef {
         _arguments: Option<Vec<String>>,
         logger: Arc<Logger>,
         system_message: Option<(String, usize)>,
-    ) -> Result<
-        (Option<String>, Option<Vec<String>>),
-        Box<dyn std::error::Error + Send + Sync + 'static>,
-    > {
+        _state: &(),
+    ) -> Result<ToolOutput, Box<dyn std::error::Error + Send + Sync + 'static>> {
         // These two arguments are optional. If None is passed, the
         // values are taken from environment variables. Look at
         // the build.rs file for this example.
@@ -102,11 +102,11 @@ impl RunCommand for FunctionDef {
             }
         }
 
-        Ok((None, None))
+        Ok(ToolOutput::none())
     }
 }
 
-#[derive(Clone, Debug, Deserialize, EnumDescriptor, VariantDescriptors)]
+#[derive(ToolArg)]
 #[arg_description(description = "The only valid locations that can be passed.")]
 pub enum Location {
     Atlanta,
@@ -124,7 +124,7 @@ pub enum Location {
     Washington,
 }
 
-#[derive(Clone, Debug, Deserialize, EnumDescriptor, VariantDescriptors)]
+#[derive(ToolArg)]
 #[arg_description(description = "A temperature unit chosen from the enum.")]
 pub enum TemperatureUnits {
     Celcius,
@@ -0,0 +1,146 @@
+/// Shortens `description` to at most `max_tokens`, as counted by `count_tokens`, without a
+/// model.
+///
+/// Whitespace is collapsed and parenthesized asides are dropped first. Then whole sentences
+/// are kept from the start for as long as they fit, since the first sentence of a description
+/// usually says what the function does. If not even the first sentence fits, it is cut off
+/// after the last word that fits. Descriptions that already fit are returned unchanged, and so
+/// are descriptions whose first word doesn't fit, since an empty description says even less.
+/// A description that is all aside keeps its asides.
+pub fn compress_description<F>(description: &str, max_tokens: usize, count_tokens: F) -> String
+where
+    F: Fn(&str) -> usize,
+{
+    if count_tokens(description) <= max_tokens {
+        return description.to_string();
+    }
+
+    let collapsed = description.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut without_asides = remove_asides(&collapsed);
+    if without_asides.is_empty() {
+        without_asides = collapsed;
+    } else if count_tokens(&without_asides) <= max_tokens {
+        return without_asides;
+    }
+
+    let mut kept = String::new();
+    for sentence in sentences(&without_asides) {
+        let candidate = if kept.is_empty() {
+            sentence.to_string()
+        } else {
+            format!("{} {}", kept, sentence)
+        };
+        if count_tokens(&candidate) > max_tokens {
+            break;
+        }
+        kept = candidate;
+    }
+    if !kept.is_empty() {
+        return kept;
+    }
+
+    let mut kept = String::new();
+    for word in without_asides.split(' ') {
+        let candidate = if kept.is_empty() {
+            word.to_string()
+        } else {
+            format!("{} {}", kept, word)
+        };
+        if count_tokens(&candidate) > max_tokens {
+            break;
+        }
+        kept = candidate;
+    }
+    if kept.is_empty() {
+        return description.to_string();
+    }
+    kept
+}
+
+/// Drops text in parentheses, along with the space in front of it.
+fn remove_asides(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut depth = 0_usize;
+    for ch in text.chars() {
+        match ch {
+            '(' => {
+                depth += 1;
+                if depth == 1 && result.ends_with(' ') {
+                    result.pop();
+                }
+            }
+            ')' if depth > 0 => depth -= 1,
+            _ if depth == 0 => result.push(ch),
+            _ => {}
+        }
+    }
+    result.trim().to_string()
+}
+
+/// Splits text after every `.`, `!` or `?` that is followed by a space.
+fn sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let bytes = text.as_bytes();
+    for (index, byte) in bytes.iter().enumerate() {
+        if matches!(byte, b'.' | b'!' | b'?') && bytes.get(index + 1) == Some(&b' ') {
+            sentences.push(text[start..=index].trim());
+            start = index + 1;
+        }
+    }
+    if start < text.len() {
+        sentences.push(text[start..].trim());
+    }
+    sentences.retain(|sentence| !sentence.is_empty());
+    sentences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> usize {
+        text.split_whitespace().count()
+    }
+
+    #[test]
+    fn descriptions_that_fit_are_unchanged() {
+        assert_eq!(
+            compress_description("Gets the  weather.", 3, words),
+            "Gets the  weather."
+        );
+    }
+
+    #[test]
+    fn asides_go_before_sentences() {
+        assert_eq!(
+            compress_description("Gets the weather (in Celsius). Uses a cache.", 6, words),
+            "Gets the weather. Uses a cache."
+        );
+        assert_eq!(
+            compress_description("Gets the weather (in Celsius). Uses a cache.", 4, words),
+            "Gets the weather."
+        );
+    }
+
+    #[test]
+    fn a_first_sentence_that_doesnt_fit_is_cut_after_a_word() {
+        assert_eq!(
+            compress_description("Gets the current weather in a location.", 3, words),
+            "Gets the current"
+        );
+    }
+
+    #[test]
+    fn descriptions_are_never_compressed_to_nothing() {
+        assert_eq!(
+            compress_description("Gets the weather", 0, words),
+            "Gets the weather"
+        );
+        assert_eq!(
+            compress_description("(Internal only, do not use)", 2, words),
+            "(Internal only,"
+        );
+        assert!(!compress_description("(Internal) (only)", 0, words).is_empty());
+    }
+}
@@ -1,8 +1,22 @@
-use async_openai::{types::CreateEmbeddingRequestArgs, Client};
-use rkyv::{vec::ArchivedVec, Archive, Deserialize, Serialize};
+use async_openai::{
+    config::{Config, OpenAIConfig},
+    types::CreateEmbeddingRequestArgs,
+    Client,
+};
+use futures::{StreamExt, TryStreamExt};
+use rkyv::{vec::ArchivedVec, AlignedVec, Archive, Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+
+mod compress;
+mod manifest;
+
+pub use compress::*;
+pub use manifest::*;
 
 #[derive(Debug, Archive, Deserialize, Serialize)]
 #[archive(check_bytes)]
@@ -10,7 +24,47 @@ use std::path::Path;
 pub struct FuncEmbedding {
     pub name: String,
     pub description: String,
+    /// The embedding of `name:description`.
     pub embedding: Vec<f32>,
+    /// The embedding of the name alone, see `FunctionDescription::name_embedding_input`. Empty
+    /// if there is none, in which case only `embedding` is scored.
+    pub name_embedding: Vec<f32>,
+    /// The model both embeddings were made with. Empty for embeddings read from an archive
+    /// that didn't record it.
+    pub embed_model: String,
+}
+
+/// How much the name and the full description of a function count towards its similarity to
+/// a prompt.
+///
+/// Short prompts like "weather in Boston" tend to match names well, while long ones match
+/// descriptions better. The similarity is the weighted mean of both, so only the ratio of the
+/// weights matters.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ScoreWeights {
+    pub name: f32,
+    pub description: f32,
+}
+
+impl Default for ScoreWeights {
+    fn default() -> Self {
+        ScoreWeights {
+            name: 0.3,
+            description: 0.7,
+        }
+    }
+}
+
+impl ScoreWeights {
+    pub fn new(name: f32, description: f32) -> Self {
+        ScoreWeights { name, description }
+    }
+
+    /// Scores the description embedding alone, the way archives without name embeddings are
+    /// scored.
+    pub fn description_only() -> Self {
+        ScoreWeights::new(0.0, 1.0)
+    }
 }
 
 /// Asynchronously generates a single embedding vector for the given text using a specified model.
@@ -26,7 +80,7 @@ pub struct FuncEmbedding {
 /// A `Result<Vec<f32>, Box<dyn std::error::Error + Send + Sync>>`:
 /// - `Ok(Vec<f32>)` containing the embedding vector if the operation is successful.
 /// - `Err(Box<dyn std::error::Error + Send + Sync>)` if there is an error during the operation,
-///     including issues with creating the request, network errors, or if the response does not contain an embedding.
+///   including issues with creating the request, network errors, or if the response does not contain an embedding.
 ///
 /// # Errors
 /// This function can return an error in several cases, including:
@@ -35,15 +89,13 @@ pub struct FuncEmbedding {
 /// - The response from the external service does not include an embedding vector.
 ///
 /// # Example
-/// ```rust
-/// use std::path::Path;
-/// use your_module::{single_embedding, FuncEnumsError, Client, CreateEmbeddingRequestArgs};
+/// ```no_run
+/// use openai_func_embeddings::single_embedding;
 ///
-/// #[tokio::main]
-/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 ///     let text = String::from("Your sample text here");
 ///     let model = "your-model-name";
-///     
+///
 ///     let embedding = single_embedding(&text, model).await?;
 ///     println!("Embedding vector: {:?}", embedding);
 ///
@@ -54,7 +106,45 @@ pub async fn single_embedding(
     text: &String,
     model: &str,
 ) -> Result<Vec<f32>, Box<dyn std::error::Error + Send + Sync>> {
-    let client = Client::new();
+    single_embedding_with_client(text, model, &Client::with_config(default_openai_config())).await
+}
+
+/// The API settings requests are sent with unless told otherwise: the key from
+/// `OPENAI_API_KEY`, and the base URL from `OPENAI_BASE_URL` if it is set, to use an OpenAI
+/// compatible server like vLLM, the llama.cpp server or LM Studio.
+pub fn default_openai_config() -> OpenAIConfig {
+    match std::env::var("OPENAI_BASE_URL") {
+        Ok(api_base) if !api_base.is_empty() => OpenAIConfig::new().with_api_base(api_base),
+        _ => OpenAIConfig::new(),
+    }
+}
+
+/// Parses headers written as `name=value` pairs separated by commas, the format of
+/// `FUNC_ENUMS_HTTP_HEADERS` at compile time and `FUNC_ENUMS_RUNTIME_HTTP_HEADERS` at runtime.
+/// Names and values are trimmed.
+///
+/// # Errors
+/// Returns an error for a pair without a `=`.
+pub fn parse_headers(
+    headers: &str,
+) -> Result<HashMap<String, String>, Box<dyn std::error::Error + Send + Sync>> {
+    headers
+        .split(',')
+        .filter(|header| !header.trim().is_empty())
+        .map(|header| match header.split_once('=') {
+            Some((name, value)) => Ok((name.trim().to_string(), value.trim().to_string())),
+            None => Err(format!("Invalid header {}, expected name=value", header).into()),
+        })
+        .collect()
+}
+
+/// Same as `single_embedding`, sending the request with `client`. This is how proxies, custom
+/// headers and other client settings apply to embedding requests.
+pub async fn single_embedding_with_client<C: Config>(
+    text: &String,
+    model: &str,
+    client: &Client<C>,
+) -> Result<Vec<f32>, Box<dyn std::error::Error + Send + Sync>> {
     let request = CreateEmbeddingRequestArgs::default()
         .model(model)
         .input([text])
@@ -73,6 +163,34 @@ pub async fn single_embedding(
     }
 }
 
+/// How many embedding requests `embeddings_with_client` has in flight at once when an archive
+/// is built, read from `FUNC_ENUMS_EMBED_CONCURRENCY`. Defaults to 8.
+pub fn embed_concurrency() -> usize {
+    std::env::var("FUNC_ENUMS_EMBED_CONCURRENCY")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|concurrency| *concurrency > 0)
+        .unwrap_or(8)
+}
+
+/// Embeds every text in `texts` with `client`, with up to `concurrency` requests in flight at
+/// a time. The embeddings come back in the order of `texts`.
+///
+/// # Errors
+/// Returns the first error any of the requests returns.
+pub async fn embeddings_with_client<C: Config>(
+    texts: &[String],
+    model: &str,
+    client: &Client<C>,
+    concurrency: usize,
+) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error + Send + Sync>> {
+    futures::stream::iter(texts)
+        .map(|text| single_embedding_with_client(text, model, client))
+        .buffered(concurrency.max(1))
+        .try_collect()
+        .await
+}
+
 pub fn cosine_similarity(vec1: &[f32], vec2: &[f32]) -> f32 {
     let dot_product: f32 = vec1.iter().zip(vec2.iter()).map(|(&x1, &x2)| x1 * x2).sum();
     let magnitude1: f32 = vec1.iter().map(|&x| x.powf(2.0)).sum::<f32>().sqrt();
@@ -85,15 +203,92 @@ pub fn cosine_similarity(vec1: &[f32], vec2: &[f32]) -> f32 {
     dot_product / (magnitude1 * magnitude2)
 }
 
+/// The similarity of an archived function to `input_vector`, see `ScoreWeights`.
+pub fn function_similarity(
+    archived_embedding: &ArchivedFuncEmbedding,
+    input_vector: &[f32],
+    weights: &ScoreWeights,
+) -> f32 {
+    let description_similarity = cosine_similarity(&archived_embedding.embedding, input_vector);
+    let total_weight = weights.name + weights.description;
+    if archived_embedding.name_embedding.is_empty() || total_weight <= 0.0 {
+        return description_similarity;
+    }
+
+    let name_similarity = cosine_similarity(&archived_embedding.name_embedding, input_vector);
+    (weights.name * name_similarity + weights.description * description_similarity) / total_weight
+}
+
 pub async fn rank_functions(
     archived_embeddings: &ArchivedVec<ArchivedFuncEmbedding>,
     input_vector: Vec<f32>,
 ) -> Vec<String> {
+    rank_functions_with_prior(archived_embeddings, input_vector, |_| 0.0).await
+}
+
+/// Same as `rank_functions`, with `prior(name)` added to the similarity of each function
+/// before sorting.
+///
+/// Pure cosine similarity is noisy for short prompts like "again but for Denver", so a small
+/// bonus for the functions that get used the most keeps them from being culled by a tight
+/// budget. Cosine similarities of related texts tend to sit close together, so a prior of a
+/// few hundredths is already enough to reorder them.
+pub async fn rank_functions_with_prior<F>(
+    archived_embeddings: &ArchivedVec<ArchivedFuncEmbedding>,
+    input_vector: Vec<f32>,
+    prior: F,
+) -> Vec<String>
+where
+    F: Fn(&str) -> f32,
+{
+    score_functions_with_prior(archived_embeddings, &input_vector, prior)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// The names of the archived functions with their similarity to `input_vector`, most similar
+/// first.
+pub fn score_functions(
+    archived_embeddings: &ArchivedVec<ArchivedFuncEmbedding>,
+    input_vector: &[f32],
+) -> Vec<(String, f32)> {
+    score_functions_with_prior(archived_embeddings, input_vector, |_| 0.0)
+}
+
+/// Same as `score_functions`, with `prior(name)` added to each similarity.
+pub fn score_functions_with_prior<F>(
+    archived_embeddings: &ArchivedVec<ArchivedFuncEmbedding>,
+    input_vector: &[f32],
+    prior: F,
+) -> Vec<(String, f32)>
+where
+    F: Fn(&str) -> f32,
+{
+    score_functions_weighted(
+        archived_embeddings,
+        input_vector,
+        &ScoreWeights::default(),
+        prior,
+    )
+}
+
+/// Same as `score_functions_with_prior`, with the name and description similarities combined
+/// by `weights`.
+pub fn score_functions_weighted<F>(
+    archived_embeddings: &ArchivedVec<ArchivedFuncEmbedding>,
+    input_vector: &[f32],
+    weights: &ScoreWeights,
+    prior: F,
+) -> Vec<(String, f32)>
+where
+    F: Fn(&str) -> f32,
+{
     let mut name_similarity_pairs: Vec<(String, f32)> = archived_embeddings
         .iter()
         .map(|archived_embedding| {
-            let archived_embedding_vec: &ArchivedVec<f32> = &archived_embedding.embedding;
-            let similarity = cosine_similarity(archived_embedding_vec, &input_vector);
+            let similarity = function_similarity(archived_embedding, input_vector, weights)
+                + prior(archived_embedding.name.as_str());
             (archived_embedding.name.to_string(), similarity)
         })
         .collect();
@@ -102,15 +297,14 @@ pub async fn rank_functions(
         .sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
 
     name_similarity_pairs
-        .into_iter()
-        .map(|(name, _)| name)
-        .collect()
 }
 
 /// Asynchronously retrieves and ranks function names based on their similarity to a given prompt embedding.
 ///
 /// This function searches a specified file for function embeddings, compares them to the provided prompt embedding, and returns a ranked list of function names based on their similarity to the prompt.
 ///
+/// The archive is read and validated the first time a path is used and cached after that, see `load_embedding_archive`.
+///
 /// # Parameters
 /// - `prompt_embedding`: A `Vec<f32>` representing the embedding of the prompt. This embedding is used to compare against the function embeddings stored in the file located at `embed_path`.
 /// - `embed_path`: A reference to a `Path` where the function embeddings are stored. This file should contain a serialized `Vec<FuncEmbedding>` where `FuncEmbedding` is a structure representing the function name and its embedding.
@@ -125,8 +319,11 @@ pub async fn rank_functions(
 /// - Archive processing failure if deserialization of the stored embeddings encounters errors.
 ///
 /// # Examples
-/// ```
-/// async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// ```no_run
+/// use openai_func_embeddings::get_ranked_function_names;
+/// use std::path::Path;
+///
+/// async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 ///     let prompt_embedding = vec![0.1, 0.2, 0.3];
 ///     let embed_path = Path::new("function_embeddings.bin");
 ///     let ranked_function_names = get_ranked_function_names(prompt_embedding, embed_path).await?;
@@ -138,41 +335,446 @@ pub async fn get_ranked_function_names(
     prompt_embedding: Vec<f32>,
     embed_path: &Path,
 ) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
-    if embed_path.exists() {
-        let mut file = match File::open(embed_path) {
-            Ok(f) => f,
-            Err(e) => return Err(Box::new(e)),
+    if tokio::fs::try_exists(embed_path).await? {
+        let archive = load_embedding_archive_async(embed_path).await?;
+        Ok(rank_functions(archive.embeddings(), prompt_embedding).await)
+    } else {
+        Ok(vec![])
+    }
+}
+
+/// Same as `get_ranked_function_names`, blending `prior(name)` into the similarity of each
+/// function, see `rank_functions_with_prior`.
+pub async fn get_ranked_function_names_with_prior<F>(
+    prompt_embedding: Vec<f32>,
+    embed_path: &Path,
+    prior: F,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>>
+where
+    F: Fn(&str) -> f32,
+{
+    if tokio::fs::try_exists(embed_path).await? {
+        let archive = load_embedding_archive_async(embed_path).await?;
+        Ok(rank_functions_with_prior(archive.embeddings(), prompt_embedding, prior).await)
+    } else {
+        Ok(vec![])
+    }
+}
+
+/// Same as `get_ranked_function_names`, with the name and description similarities combined
+/// by `weights`.
+pub async fn get_ranked_function_names_weighted(
+    prompt_embedding: Vec<f32>,
+    embed_path: &Path,
+    weights: &ScoreWeights,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(
+        RankedFunctions::weighted(prompt_embedding, embed_path, weights, |_| 0.0)
+            .await?
+            .names(),
+    )
+}
+
+/// A prompt ranked against an embedding archive, kept so that the ranking can be narrowed down
+/// again later without embedding the prompt a second time.
+///
+/// Similarities don't depend on which other functions are in the running, so excluding a
+/// function, for example one that already failed this session or one the user disabled, only
+/// drops it from the order.
+#[derive(Clone, Debug, Default)]
+pub struct RankedFunctions {
+    prompt_embedding: Vec<f32>,
+    scores: Vec<(String, f32)>,
+}
+
+impl RankedFunctions {
+    /// Ranks the functions in the archive at `embed_path` against `prompt_embedding`. A
+    /// missing archive ranks nothing, like `get_ranked_function_names`.
+    ///
+    /// # Errors
+    /// Returns an error if the archive can't be read or fails validation.
+    pub async fn new(
+        prompt_embedding: Vec<f32>,
+        embed_path: &Path,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        RankedFunctions::with_prior(prompt_embedding, embed_path, |_| 0.0).await
+    }
+
+    /// Same as `new`, with `prior(name)` added to each similarity, see
+    /// `rank_functions_with_prior`.
+    pub async fn with_prior<F>(
+        prompt_embedding: Vec<f32>,
+        embed_path: &Path,
+        prior: F,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        F: Fn(&str) -> f32,
+    {
+        RankedFunctions::weighted(
+            prompt_embedding,
+            embed_path,
+            &ScoreWeights::default(),
+            prior,
+        )
+        .await
+    }
+
+    /// Same as `with_prior`, with the name and description similarities combined by
+    /// `weights`.
+    pub async fn weighted<F>(
+        prompt_embedding: Vec<f32>,
+        embed_path: &Path,
+        weights: &ScoreWeights,
+        prior: F,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        F: Fn(&str) -> f32,
+    {
+        let scores = if tokio::fs::try_exists(embed_path).await? {
+            let archive = load_embedding_archive_async(embed_path).await?;
+            score_functions_weighted(archive.embeddings(), &prompt_embedding, weights, prior)
+        } else {
+            Vec::new()
+        };
+
+        Ok(RankedFunctions {
+            prompt_embedding,
+            scores,
+        })
+    }
+
+    pub fn prompt_embedding(&self) -> &[f32] {
+        &self.prompt_embedding
+    }
+
+    /// Every ranked function with its similarity, most similar first.
+    pub fn scores(&self) -> &[(String, f32)] {
+        &self.scores
+    }
+
+    /// The function names, most similar first.
+    pub fn names(&self) -> Vec<String> {
+        self.scores.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    /// The function names, most similar first, leaving out every name in `excluded`.
+    pub fn excluding<S: AsRef<str>>(&self, excluded: &[S]) -> Vec<String> {
+        self.scores
+            .iter()
+            .map(|(name, _)| name)
+            .filter(|name| !excluded.iter().any(|excluded| excluded.as_ref() == *name))
+            .cloned()
+            .collect()
+    }
+}
+
+static EMBEDDING_ARCHIVES: OnceLock<Mutex<HashMap<PathBuf, Arc<EmbeddingArchive>>>> =
+    OnceLock::new();
+
+/// The first bytes of every embedding archive, followed by its format version.
+const ARCHIVE_MAGIC: &[u8; 8] = b"FUNCEMBD";
+
+/// The layout of the archives `write_embedding_archive` writes. Older layouts are still read:
+/// version 1 archives have no header and no name embeddings, and version 2 archives don't
+/// record the embedding model.
+pub const ARCHIVE_FORMAT_VERSION: u32 = 3;
+
+/// The magic, the version as a little endian `u32` and padding, so the archive after it keeps
+/// the alignment of the buffer.
+const ARCHIVE_HEADER_LEN: usize = 16;
+
+/// A validated embedding archive held in memory.
+///
+/// The archive is checked once when it is loaded, so looking at the embeddings afterwards is
+/// free.
+pub struct EmbeddingArchive {
+    bytes: AlignedVec,
+}
+
+impl EmbeddingArchive {
+    /// Validates `bytes` as an embedding archive of any format version this crate knows.
+    ///
+    /// Archives in an older format are converted. Fields they lack are left empty, so ranking
+    /// falls back to the description embeddings for them, and `build_embedding_archive`
+    /// embeds them again.
+    ///
+    /// # Errors
+    /// Returns `FuncEnumsError::RkyvError` if the bytes are not a valid archive, or
+    /// `FuncEnumsError::ArchiveVersion` if the archive was written by a newer version of this
+    /// crate.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FuncEnumsError> {
+        let Some(header) = bytes
+            .get(..ARCHIVE_HEADER_LEN)
+            .filter(|header| header.starts_with(ARCHIVE_MAGIC))
+        else {
+            return EmbeddingArchive::from_unversioned(bytes);
         };
 
-        let mut bytes = Vec::new();
-        if let Err(e) = file.read_to_end(&mut bytes) {
-            return Err(Box::new(e));
+        let mut version = [0_u8; 4];
+        version.copy_from_slice(&header[ARCHIVE_MAGIC.len()..ARCHIVE_MAGIC.len() + 4]);
+        let mut aligned = AlignedVec::with_capacity(bytes.len() - ARCHIVE_HEADER_LEN);
+        aligned.extend_from_slice(&bytes[ARCHIVE_HEADER_LEN..]);
+
+        match u32::from_le_bytes(version) {
+            ARCHIVE_FORMAT_VERSION => EmbeddingArchive::from_aligned(aligned),
+            2 => {
+                let archived = rkyv::check_archived_root::<Vec<FuncEmbeddingV2>>(&aligned)
+                    .map_err(|e| archive_error(&e))?;
+                EmbeddingArchive::converted(archived.iter().map(FuncEmbeddingV2::upgrade))
+            }
+            version => Err(FuncEnumsError::ArchiveVersion(version)),
         }
+    }
 
-        // TODO: Would be nice to check how much faster unsafe version of this is.
-        let archived_funcs =
-            rkyv::check_archived_root::<Vec<FuncEmbedding>>(&bytes).map_err(|e| {
-                Box::new(FuncEnumsError::RkyvError(format!(
-                    "Archive processing failed: {}",
-                    e
-                ))) as Box<dyn std::error::Error + Send + Sync>
-            })?;
+    fn from_aligned(bytes: AlignedVec) -> Result<Self, FuncEnumsError> {
+        rkyv::check_archived_root::<Vec<FuncEmbedding>>(&bytes)
+            .map_err(|e| FuncEnumsError::RkyvError(format!("Archive processing failed: {}", e)))?;
 
-        Ok(rank_functions(archived_funcs, prompt_embedding).await)
-    } else {
-        Ok(vec![])
+        Ok(EmbeddingArchive { bytes })
+    }
+
+    /// Reads an archive without a header, which is a version 1 archive or a version 2 one
+    /// written before the header was added.
+    fn from_unversioned(bytes: &[u8]) -> Result<Self, FuncEnumsError> {
+        let mut aligned = AlignedVec::with_capacity(bytes.len());
+        aligned.extend_from_slice(bytes);
+        if let Ok(archived) = rkyv::check_archived_root::<Vec<FuncEmbeddingV2>>(&aligned) {
+            return EmbeddingArchive::converted(archived.iter().map(FuncEmbeddingV2::upgrade));
+        }
+
+        let archived = rkyv::check_archived_root::<Vec<FuncEmbeddingV1>>(&aligned)
+            .map_err(|e| archive_error(&e))?;
+        EmbeddingArchive::converted(archived.iter().map(FuncEmbeddingV1::upgrade))
+    }
+
+    fn converted(embeddings: impl Iterator<Item = FuncEmbedding>) -> Result<Self, FuncEnumsError> {
+        let embeddings: Vec<FuncEmbedding> = embeddings.collect();
+        let bytes = rkyv::to_bytes::<_, 256>(&embeddings)
+            .map_err(|e| FuncEnumsError::RkyvError(format!("Failed to convert archive: {}", e)))?;
+
+        Ok(EmbeddingArchive { bytes })
+    }
+
+    /// Copies the embeddings out of the archive.
+    pub fn to_vec(&self) -> Vec<FuncEmbedding> {
+        self.embeddings()
+            .deserialize(&mut rkyv::Infallible)
+            .unwrap_or_else(|never| match never {})
+    }
+
+    pub fn embeddings(&self) -> &ArchivedVec<ArchivedFuncEmbedding> {
+        // Safety: the bytes were validated in `from_aligned` and are never modified afterwards.
+        unsafe { rkyv::archived_root::<Vec<FuncEmbedding>>(&self.bytes) }
+    }
+}
+
+/// Returns the validated embedding archive at `embed_path`, reading it from disk only the
+/// first time it is asked for.
+///
+/// Later calls with the same path hand back the cached archive, so ranking a prompt only costs
+/// the similarity scan. Call `invalidate_embedding_archive` after rewriting the file to have
+/// the next call read it again.
+///
+/// This reads the file with blocking IO. Use `load_embedding_archive_async` from async code.
+///
+/// # Errors
+/// Returns an error if the file cannot be read or is not a valid archive.
+pub fn load_embedding_archive(
+    embed_path: &Path,
+) -> Result<Arc<EmbeddingArchive>, Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(archive) = embedding_archives().get(embed_path) {
+        return Ok(archive.clone());
+    }
+
+    let mut file = File::open(embed_path)?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+
+    let archive = Arc::new(EmbeddingArchive::from_bytes(&bytes)?);
+    embedding_archives().insert(embed_path.to_path_buf(), archive.clone());
+
+    Ok(archive)
+}
+
+/// Same as `load_embedding_archive`, but reads the file without blocking the executor.
+///
+/// # Errors
+/// Returns an error if the file cannot be read or is not a valid archive.
+pub async fn load_embedding_archive_async(
+    embed_path: &Path,
+) -> Result<Arc<EmbeddingArchive>, Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(archive) = embedding_archives().get(embed_path) {
+        return Ok(archive.clone());
+    }
+
+    let bytes = tokio::fs::read(embed_path).await?;
+
+    let archive = Arc::new(EmbeddingArchive::from_bytes(&bytes)?);
+    embedding_archives().insert(embed_path.to_path_buf(), archive.clone());
+
+    Ok(archive)
+}
+
+/// Serializes `embeddings` to the archive at `embed_path` and drops the cached copy of it.
+///
+/// The archive is written to a temporary file next to it that is then renamed over it, so an
+/// interrupted write never leaves a torn archive behind.
+///
+/// # Errors
+/// Returns an error if the embeddings can't be serialized or the file can't be written.
+pub fn write_embedding_archive(
+    embed_path: &Path,
+    embeddings: &Vec<FuncEmbedding>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let bytes = archive_bytes(embeddings)?;
+    let temp_path = archive_temp_path(embed_path);
+
+    std::fs::write(&temp_path, &bytes)?;
+    if let Err(e) = std::fs::rename(&temp_path, embed_path) {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e.into());
+    }
+
+    invalidate_embedding_archive(embed_path);
+    Ok(())
+}
+
+/// Same as `write_embedding_archive`, but writes the file without blocking the executor.
+///
+/// # Errors
+/// Returns an error if the embeddings can't be serialized or the file can't be written.
+pub async fn write_embedding_archive_async(
+    embed_path: &Path,
+    embeddings: &Vec<FuncEmbedding>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let bytes = archive_bytes(embeddings)?;
+    let temp_path = archive_temp_path(embed_path);
+
+    tokio::fs::write(&temp_path, &bytes).await?;
+    if let Err(e) = tokio::fs::rename(&temp_path, embed_path).await {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(e.into());
+    }
+
+    invalidate_embedding_archive(embed_path);
+    Ok(())
+}
+
+/// The bytes of an archive of `embeddings`: the header, then the rkyv archive.
+fn archive_bytes(
+    embeddings: &Vec<FuncEmbedding>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let archive = rkyv::to_bytes::<_, 256>(embeddings)
+        .map_err(|e| format!("Failed to serialize embeddings: {}", e))?;
+
+    let mut bytes = Vec::with_capacity(ARCHIVE_HEADER_LEN + archive.len());
+    bytes.extend_from_slice(ARCHIVE_MAGIC);
+    bytes.extend_from_slice(&ARCHIVE_FORMAT_VERSION.to_le_bytes());
+    bytes.resize(ARCHIVE_HEADER_LEN, 0);
+    bytes.extend_from_slice(&archive);
+    Ok(bytes)
+}
+
+/// A temporary file next to `embed_path` that no other write uses. Builds running at the same
+/// time write the same archive and manifest, so the name has the process id, and a counter for
+/// the writes of one process.
+fn archive_temp_path(embed_path: &Path) -> PathBuf {
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+
+    let mut temp_path = embed_path.as_os_str().to_owned();
+    temp_path.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+    PathBuf::from(temp_path)
+}
+
+/// Drops the cached archive for `embed_path`, if there is one.
+pub fn invalidate_embedding_archive(embed_path: &Path) {
+    embedding_archives().remove(embed_path);
+}
+
+/// Drops every cached archive.
+pub fn clear_embedding_archives() {
+    embedding_archives().clear();
+}
+
+fn embedding_archives() -> MutexGuard<'static, HashMap<PathBuf, Arc<EmbeddingArchive>>> {
+    EMBEDDING_ARCHIVES
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// The layout of `FuncEmbedding` in version 1 archives, before it had a name embedding.
+#[derive(Archive, Deserialize, Serialize)]
+#[archive(check_bytes)]
+struct FuncEmbeddingV1 {
+    name: String,
+    description: String,
+    embedding: Vec<f32>,
+}
+
+impl FuncEmbeddingV1 {
+    fn upgrade(embedding: &ArchivedFuncEmbeddingV1) -> FuncEmbedding {
+        FuncEmbedding {
+            name: embedding.name.to_string(),
+            description: embedding.description.to_string(),
+            embedding: embedding.embedding.to_vec(),
+            name_embedding: Vec::new(),
+            embed_model: String::new(),
+        }
+    }
+}
+
+/// The layout of `FuncEmbedding` in version 2 archives, before it recorded its model.
+#[derive(Archive, Deserialize, Serialize)]
+#[archive(check_bytes)]
+struct FuncEmbeddingV2 {
+    name: String,
+    description: String,
+    embedding: Vec<f32>,
+    name_embedding: Vec<f32>,
+}
+
+impl FuncEmbeddingV2 {
+    fn upgrade(embedding: &ArchivedFuncEmbeddingV2) -> FuncEmbedding {
+        FuncEmbedding {
+            name: embedding.name.to_string(),
+            description: embedding.description.to_string(),
+            embedding: embedding.embedding.to_vec(),
+            name_embedding: embedding.name_embedding.to_vec(),
+            embed_model: String::new(),
+        }
     }
 }
 
+fn archive_error(error: &dyn std::fmt::Display) -> FuncEnumsError {
+    FuncEnumsError::RkyvError(format!(
+        "Archive processing failed: {}. Rebuild it with `cargo func-enums build-embeddings`",
+        error
+    ))
+}
+
 #[derive(Debug)]
 pub enum FuncEnumsError {
     OpenAIError(String),
     RkyvError(String),
+    /// The archive has a format version this crate doesn't know, from a newer version of it.
+    ArchiveVersion(u32),
 }
 
 impl std::fmt::Display for FuncEnumsError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            FuncEnumsError::ArchiveVersion(version) => write!(
+                f,
+                "The embedding archive has format version {}, but this version of openai-func-embeddings reads version {}. Rebuild it with `cargo func-enums build-embeddings`",
+                version, ARCHIVE_FORMAT_VERSION
+            ),
+            _ => write!(f, "{:?}", self),
+        }
     }
 }
 
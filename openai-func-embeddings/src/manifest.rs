@@ -0,0 +1,368 @@
+use crate::{
+    archive_temp_path, default_openai_config, embed_concurrency, embeddings_with_client,
+    write_embedding_archive_async, EmbeddingArchive, FuncEmbedding,
+};
+use async_openai::{config::Config, Client};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// A function as the `ToolSet` derive saw it: the text its embedding is made from, without the
+/// embedding itself.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FunctionDescription {
+    pub name: String,
+    pub description: String,
+    /// `description_hash` of the name and description. An embedding is stale when the hash it
+    /// was made for no longer matches.
+    pub hash: String,
+}
+
+impl FunctionDescription {
+    pub fn new(name: impl Into<String>, description: impl Into<String>) -> Self {
+        let name = name.into();
+        let description = description.into();
+        FunctionDescription {
+            hash: description_hash(&name, &description),
+            name,
+            description,
+        }
+    }
+
+    /// The text that gets embedded, `name:description`.
+    pub fn embedding_input(&self) -> String {
+        format!("{}:{}", self.name, self.description)
+    }
+
+    /// The text the name embedding is made from, the name split into words: `GetCurrentWeather`
+    /// becomes `Get Current Weather`.
+    pub fn name_embedding_input(&self) -> String {
+        name_embedding_input(&self.name)
+    }
+}
+
+/// Every function of a `ToolSet`, written by the derive at compile time without touching the
+/// network. `build_embedding_archive` turns it into the archive that ranking reads.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DescriptionManifest {
+    /// The model the embeddings are made with, from `FUNC_ENUMS_EMBED_MODEL`.
+    pub embed_model: Option<String>,
+    pub functions: Vec<FunctionDescription>,
+}
+
+impl DescriptionManifest {
+    /// # Errors
+    /// Returns an error if the file can't be read or isn't a manifest.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// Same as `load`, but reads the file without blocking the executor.
+    ///
+    /// # Errors
+    /// Returns an error if the file can't be read or isn't a manifest.
+    pub async fn load_async(path: &Path) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let contents = tokio::fs::read_to_string(path).await?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// Writes the manifest as JSON. The file is left alone when it already has these contents,
+    /// so that builds which watch it are not triggered for nothing.
+    ///
+    /// Like the archive, the manifest is written to a temporary file next to it that is then
+    /// renamed over it, so a build reading it never sees half of it.
+    ///
+    /// # Errors
+    /// Returns an error if the file can't be written.
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let contents = serde_json::to_string_pretty(self)?;
+        if std::fs::read_to_string(path).is_ok_and(|existing| existing == contents) {
+            return Ok(());
+        }
+
+        let temp_path = archive_temp_path(path);
+        std::fs::write(&temp_path, contents)?;
+        if let Err(e) = std::fs::rename(&temp_path, path) {
+            let _ = std::fs::remove_file(&temp_path);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Adds `functions`, replacing the entries of the same name. Every `ToolSet` writes to the
+    /// manifest of the archive they share, so the functions of the others are kept.
+    pub fn merge(&mut self, functions: Vec<FunctionDescription>) {
+        for function in functions {
+            match self
+                .functions
+                .iter_mut()
+                .find(|existing| existing.name == function.name)
+            {
+                Some(existing) => *existing = function,
+                None => self.functions.push(function),
+            }
+        }
+    }
+
+    pub fn function(&self, name: &str) -> Option<&FunctionDescription> {
+        self.functions.iter().find(|function| function.name == name)
+    }
+}
+
+/// Where the manifest for the archive at `embed_path` goes: next to it, with the extension
+/// `manifest.json`.
+pub fn manifest_path(embed_path: &Path) -> PathBuf {
+    embed_path.with_extension("manifest.json")
+}
+
+/// A stable hash of the text a function's embedding is made from, as 16 hex digits.
+pub fn description_hash(name: &str, description: &str) -> String {
+    format!(
+        "{:016x}",
+        stable_hash(format!("{}:{}", name, description).as_bytes())
+    )
+}
+
+/// FNV-1a. Std's hasher is not guaranteed to be stable between Rust releases, and hashes are
+/// kept on disk for much longer than that.
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// What `build_embedding_archive` did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EmbeddingBuild {
+    /// Functions whose embedding was requested from the API.
+    pub embedded: Vec<String>,
+    /// Functions whose embedding was carried over from the existing archive.
+    pub reused: Vec<String>,
+    /// Functions in the existing archive that are no longer in the manifest.
+    pub removed: Vec<String>,
+}
+
+/// Reads the manifest at `manifest_path` and writes the archive at `embed_path`. Every
+/// function gets an embedding of its name and description and one of its name alone, see
+/// `ScoreWeights`.
+///
+/// Embeddings in an existing archive are reused for functions whose name and description are
+/// unchanged, so only new and edited functions cost a request. Meant to run outside of
+/// compilation, from a small binary or a release script, so the derive never needs network
+/// access.
+///
+/// # Errors
+/// Returns an error if the manifest can't be read or names no model, an embedding request
+/// fails, or the archive can't be written.
+pub async fn build_embedding_archive(
+    manifest_path: &Path,
+    embed_path: &Path,
+) -> Result<EmbeddingBuild, Box<dyn std::error::Error + Send + Sync>> {
+    build_embedding_archive_with_client(
+        manifest_path,
+        embed_path,
+        &Client::with_config(default_openai_config()),
+    )
+    .await
+}
+
+/// Same as `build_embedding_archive`, sending the embedding requests with `client`.
+pub async fn build_embedding_archive_with_client<C: Config>(
+    manifest_path: &Path,
+    embed_path: &Path,
+    client: &Client<C>,
+) -> Result<EmbeddingBuild, Box<dyn std::error::Error + Send + Sync>> {
+    let manifest = DescriptionManifest::load_async(manifest_path).await?;
+    if manifest.embed_model.is_none() {
+        return Err(format!(
+            "The manifest at {} names no embedding model",
+            manifest_path.display()
+        )
+        .into());
+    }
+
+    build_embedding_archive_from(&manifest, embed_path, client).await
+}
+
+/// Same as `build_embedding_archive_with_client`, for a manifest that is already in memory,
+/// for example one with translated descriptions.
+pub async fn build_embedding_archive_from<C: Config>(
+    manifest: &DescriptionManifest,
+    embed_path: &Path,
+    client: &Client<C>,
+) -> Result<EmbeddingBuild, Box<dyn std::error::Error + Send + Sync>> {
+    let embed_model = manifest
+        .embed_model
+        .as_deref()
+        .ok_or("The manifest names no embedding model")?;
+
+    let mut existing = read_embeddings_async(embed_path).await?;
+    let mut build = EmbeddingBuild::default();
+    let mut reused = Vec::with_capacity(manifest.functions.len());
+    let mut texts = Vec::new();
+
+    for function in &manifest.functions {
+        let reusable = existing
+            .iter()
+            .position(|embedding| is_current(embedding, function, Some(embed_model)));
+
+        match reusable {
+            Some(index) => {
+                reused.push(Some(existing.swap_remove(index)));
+                build.reused.push(function.name.clone());
+            }
+            None => {
+                reused.push(None);
+                texts.push(function.embedding_input());
+                texts.push(function.name_embedding_input());
+                build.embedded.push(function.name.clone());
+            }
+        }
+    }
+
+    // Every function that isn't reused has its description and its name embedded, in that
+    // order.
+    let fetched = embeddings_with_client(&texts, embed_model, client, embed_concurrency()).await?;
+    let mut fetched = fetched.into_iter();
+
+    let mut embeddings = Vec::with_capacity(manifest.functions.len());
+    for (function, reused) in manifest.functions.iter().zip(reused) {
+        let embedding = match reused {
+            Some(embedding) => embedding,
+            None => FuncEmbedding {
+                name: function.name.clone(),
+                description: function.description.clone(),
+                embedding: fetched.next().unwrap_or_default(),
+                name_embedding: fetched.next().unwrap_or_default(),
+                embed_model: embed_model.to_string(),
+            },
+        };
+        embeddings.push(embedding);
+    }
+
+    build.removed = existing
+        .into_iter()
+        .map(|embedding| embedding.name)
+        .filter(|name| manifest.function(name).is_none())
+        .collect();
+
+    write_embedding_archive_async(embed_path, &embeddings).await?;
+
+    Ok(build)
+}
+
+fn read_embeddings(
+    embed_path: &Path,
+) -> Result<Vec<FuncEmbedding>, Box<dyn std::error::Error + Send + Sync>> {
+    if !embed_path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let bytes = std::fs::read(embed_path)?;
+    Ok(EmbeddingArchive::from_bytes(&bytes)?.to_vec())
+}
+
+async fn read_embeddings_async(
+    embed_path: &Path,
+) -> Result<Vec<FuncEmbedding>, Box<dyn std::error::Error + Send + Sync>> {
+    let bytes = match tokio::fs::read(embed_path).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(EmbeddingArchive::from_bytes(&bytes)?.to_vec())
+}
+
+/// An embedding can be kept when it was made for the function's current name and
+/// description with `embed_model`, if one is given, and has a name embedding. Embeddings of
+/// different models can't be compared, so switching models makes every embedding stale.
+fn is_current(
+    embedding: &FuncEmbedding,
+    function: &FunctionDescription,
+    embed_model: Option<&str>,
+) -> bool {
+    embedding.name == function.name
+        && description_hash(&embedding.name, &embedding.description) == function.hash
+        && !embedding.name_embedding.is_empty()
+        && (embed_model.is_none() || embed_model == Some(embedding.embed_model.as_str()))
+}
+
+/// Splits a `CamelCase` function name into words.
+pub fn name_embedding_input(name: &str) -> String {
+    let mut words = String::with_capacity(name.len() + 4);
+    for (i, ch) in name.char_indices() {
+        if i > 0 && ch.is_uppercase() {
+            words.push(' ');
+        }
+        words.push(ch);
+    }
+    words
+}
+
+/// How an archive differs from the manifest it should have been built from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ArchiveDiff {
+    /// Functions in the manifest that have no embedding yet.
+    pub added: Vec<String>,
+    /// Functions whose name or description changed since they were embedded, that were
+    /// embedded with a different model than the manifest names, or that have no name
+    /// embedding yet.
+    pub changed: Vec<String>,
+    /// Functions in the archive that are no longer in the manifest.
+    pub removed: Vec<String>,
+    pub unchanged: Vec<String>,
+}
+
+impl ArchiveDiff {
+    /// `true` when the archive matches the manifest.
+    pub fn is_up_to_date(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Compares the archive at `embed_path` with the manifest at `manifest_path`, without making
+/// any requests. A missing archive counts as empty.
+///
+/// # Errors
+/// Returns an error if either file can't be read.
+pub fn diff_embedding_archive(
+    manifest_path: &Path,
+    embed_path: &Path,
+) -> Result<ArchiveDiff, Box<dyn std::error::Error + Send + Sync>> {
+    let manifest = DescriptionManifest::load(manifest_path)?;
+    diff_manifest_with_archive(&manifest, embed_path)
+}
+
+/// Same as `diff_embedding_archive`, for a manifest that is already in memory.
+///
+/// # Errors
+/// Returns an error if the archive can't be read.
+pub fn diff_manifest_with_archive(
+    manifest: &DescriptionManifest,
+    embed_path: &Path,
+) -> Result<ArchiveDiff, Box<dyn std::error::Error + Send + Sync>> {
+    let embeddings = read_embeddings(embed_path)?;
+    let mut diff = ArchiveDiff::default();
+
+    for function in &manifest.functions {
+        let name = function.name.clone();
+        match embeddings
+            .iter()
+            .find(|embedding| embedding.name == function.name)
+        {
+            None => diff.added.push(name),
+            Some(embedding) if is_current(embedding, function, manifest.embed_model.as_deref()) => {
+                diff.unchanged.push(name)
+            }
+            Some(_) => diff.changed.push(name),
+        }
+    }
+
+    diff.removed = embeddings
+        .into_iter()
+        .map(|embedding| embedding.name)
+        .filter(|name| manifest.function(name).is_none())
+        .collect();
+
+    Ok(diff)
+}
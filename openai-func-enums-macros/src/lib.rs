@@ -6,13 +6,13 @@ use syn::{parse_macro_input, Attribute, Data, DeriveInput, Expr, Ident, Lit, Met
     feature = "compile_embeddings_all",
     feature = "compile_embeddings_update"
 ))]
-use async_openai::{types::CreateEmbeddingRequestArgs, Client};
+use async_openai::{config::OpenAIConfig, Client};
 
 #[cfg(any(
     feature = "compile_embeddings_all",
     feature = "compile_embeddings_update"
 ))]
-use std::io::Write;
+use std::path::Path;
 
 /// The `arg_description` attribute is a procedural macro used to provide additional description for an enum.
 ///
@@ -22,9 +22,10 @@ use std::io::Write;
 ///
 /// # Usage
 ///
-/// ```rust
-/// #[arg_description(description = "This is a sample enum.", tokens = 5)]
+/// ```
+/// # use openai_func_enums::EnumDescriptor;
 /// #[derive(EnumDescriptor)]
+/// #[arg_description(description = "This is a sample enum.", tokens = 5)]
 /// pub enum SampleEnum {
 ///     Variant1,
 ///     Variant2,
@@ -56,6 +57,7 @@ pub fn arg_description(_args: TokenStream, input: TokenStream) -> TokenStream {
 /// `EnumDescriptor` trait for it.
 ///
 /// ```
+/// # use openai_func_enums::EnumDescriptor;
 /// #[derive(EnumDescriptor)]
 /// enum MyEnum {
 ///     Variant1,
@@ -65,7 +67,7 @@ pub fn arg_description(_args: TokenStream, input: TokenStream) -> TokenStream {
 ///
 /// This will generate:
 ///
-/// ```
+/// ```text
 /// impl EnumDescriptor for MyEnum {
 ///     fn name_with_token_count() -> (String, usize) {
 ///         (String::from("MyEnum"), /* token count of "MyEnum" */)
@@ -77,7 +79,17 @@ pub fn arg_description(_args: TokenStream, input: TokenStream) -> TokenStream {
 /// `calculate_token_count` function.
 #[proc_macro_derive(EnumDescriptor, attributes(arg_description))]
 pub fn enum_descriptor_derive(input: TokenStream) -> TokenStream {
-    let DeriveInput { ident, attrs, .. } = parse_macro_input!(input as DeriveInput);
+    let ast = parse_macro_input!(input as DeriveInput);
+    TokenStream::from(enum_descriptor_tokens(&ast, None))
+}
+
+/// `arg_schema` is the body of `arg_schema_with_token_count`, for enums whose schema isn't a
+/// plain list of variant names.
+fn enum_descriptor_tokens(
+    ast: &DeriveInput,
+    arg_schema: Option<proc_macro2::TokenStream>,
+) -> proc_macro2::TokenStream {
+    let DeriveInput { ident, attrs, .. } = ast;
 
     let name_str = ident.to_string();
     let name_token_count = calculate_token_count(&name_str);
@@ -85,7 +97,7 @@ pub fn enum_descriptor_derive(input: TokenStream) -> TokenStream {
     let mut description: &'static str = "";
     let mut desc_tokens = 0_usize;
 
-    for attr in &attrs {
+    for attr in attrs {
         if attr.path().is_ident("arg_description") {
             let _result = attr.parse_nested_meta(|meta| {
                 let content = meta.input;
@@ -110,8 +122,8 @@ pub fn enum_descriptor_derive(input: TokenStream) -> TokenStream {
         }
     }
 
-    let expanded = quote! {
-        impl openai_func_enums::EnumDescriptor for #ident {
+    quote! {
+        impl ::openai_func_enums::EnumDescriptor for #ident {
             fn name_with_token_count() -> &'static (&'static str, usize) {
                 static NAME_DATA: (&'static str, usize) = (stringify!(#ident), #name_token_count);
                 &NAME_DATA
@@ -121,10 +133,10 @@ pub fn enum_descriptor_derive(input: TokenStream) -> TokenStream {
                 static DESC_DATA: (&'static str, usize) = (#description, #desc_tokens);
                 &DESC_DATA
             }
-        }
-    };
 
-    TokenStream::from(expanded)
+            #arg_schema
+        }
+    }
 }
 
 /// A derive procedural macro for the `VariantDescriptors` trait.
@@ -133,16 +145,17 @@ pub fn enum_descriptor_derive(input: TokenStream) -> TokenStream {
 /// an enum. The trait provides two methods:
 ///
 /// 1. `variant_names_with_token_counts`: Returns a `Vec` containing tuples,
-/// each with a string representation of a variant's name and its token count.
+///    each with a string representation of a variant's name and its token count.
 ///
 /// 2. `variant_name_with_token_count`: Takes an enum variant as input and
-/// returns a tuple with the variant's name as a string and its token count.
+///    returns a tuple with the variant's name as a string and its token count.
 ///
 /// Note: This macro will panic if it is used on anything other than an enum.
 ///
 /// # Usage
 ///
 /// ```
+/// # use openai_func_enums::VariantDescriptors;
 /// #[derive(VariantDescriptors)]
 /// enum MyEnum {
 ///     Variant1,
@@ -152,7 +165,7 @@ pub fn enum_descriptor_derive(input: TokenStream) -> TokenStream {
 ///
 /// This will generate the following:
 ///
-/// ```
+/// ```text
 /// impl VariantDescriptors for MyEnum {
 ///     fn variant_names_with_token_counts() -> Vec<(String, usize)> {
 ///         vec![
@@ -175,7 +188,10 @@ pub fn enum_descriptor_derive(input: TokenStream) -> TokenStream {
 #[proc_macro_derive(VariantDescriptors)]
 pub fn variant_descriptors_derive(input: TokenStream) -> TokenStream {
     let ast = parse_macro_input!(input as DeriveInput);
+    TokenStream::from(variant_descriptors_tokens(&ast))
+}
 
+fn variant_descriptors_tokens(ast: &DeriveInput) -> proc_macro2::TokenStream {
     let enum_name = &ast.ident;
 
     let variants = if let syn::Data::Enum(ref e) = ast.data {
@@ -195,7 +211,7 @@ pub fn variant_descriptors_derive(input: TokenStream) -> TokenStream {
     let variant_name_with_token_count: Vec<_> = variants
         .iter()
         .map(|(variant_name, token_count)| {
-            quote! { Self::#variant_name => (stringify!(#variant_name), #token_count) }
+            quote! { Self::#variant_name { .. } => (stringify!(#variant_name), #token_count) }
         })
         .collect();
 
@@ -216,8 +232,8 @@ pub fn variant_descriptors_derive(input: TokenStream) -> TokenStream {
         .map(|(_, token_count)| *token_count)
         .sum::<usize>();
 
-    let expanded = quote! {
-        impl VariantDescriptors for #enum_name {
+    quote! {
+        impl ::openai_func_enums::VariantDescriptors for #enum_name {
             fn variant_names_with_token_counts() -> &'static (&'static [&'static str], &'static [usize], usize, usize) {
                 static VARIANT_DATA: (&'static [&'static str], &'static [usize], usize, usize) = (
                     &[#(#variant_names),*],
@@ -234,11 +250,623 @@ pub fn variant_descriptors_derive(input: TokenStream) -> TokenStream {
                 }
             }
         }
+    }
+}
+
+/// A derive procedural macro that does everything an argument enum needs in one go.
+///
+/// Deriving `ToolArg` is the same as deriving `Clone`, `Debug`, `Deserialize`,
+/// `EnumDescriptor` and `VariantDescriptors`, so the enum can't end up missing one of them.
+/// The `arg_description` attribute works the same way it does with `EnumDescriptor`. Enums
+/// with only unit variants deserialize from their variant names, ignoring case, and from the
+/// names given with `#[func_enums(alias = "...")]` on a variant.
+///
+/// Variants can also have named fields. The schema is then an `anyOf` with an object per
+/// variant, tagged by a `kind` property that holds the variant name, and that is also how the
+/// arguments are deserialized. Unit variants of such an enum accept the bare name as well.
+///
+/// # Usage
+///
+/// ```
+/// # use openai_func_enums::{arg_description, ToolArg};
+/// #[derive(ToolArg)]
+/// #[arg_description(description = "The only valid locations that can be passed.")]
+/// pub enum Location {
+///     Atlanta,
+///     #[func_enums(alias = "Beantown")]
+///     Boston,
+/// }
+///
+/// #[derive(ToolArg)]
+/// pub enum Shape {
+///     Circle { radius: f64 },
+///     Rect { w: f64, h: f64 },
+/// }
+/// ```
+#[proc_macro_derive(ToolArg, attributes(arg_description, func_enums))]
+pub fn tool_arg_derive(input: TokenStream) -> TokenStream {
+    let ast = parse_macro_input!(input as DeriveInput);
+    let ident = &ast.ident;
+
+    let variants = match &ast.data {
+        Data::Enum(data_enum) => &data_enum.variants,
+        _ => {
+            return syn::Error::new_spanned(ident, "ToolArg can only be used with enums")
+                .to_compile_error()
+                .into();
+        }
+    };
+
+    if let Some(variant) = variants
+        .iter()
+        .find(|variant| matches!(variant.fields, syn::Fields::Unnamed(_)))
+    {
+        return syn::Error::new_spanned(
+            variant,
+            "ToolArg variants with fields need named fields, which become the properties of the variant's schema",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    if let Some(field) = variants
+        .iter()
+        .flat_map(|variant| variant.fields.iter())
+        .find(|field| {
+            field
+                .ident
+                .as_ref()
+                .is_some_and(|ident| ident == DATA_ENUM_TAG)
+        })
+    {
+        return syn::Error::new_spanned(
+            field,
+            "`kind` is the tag of the variant and can't be used as a field name",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    let variant_idents: Vec<&Ident> = variants.iter().map(|variant| &variant.ident).collect();
+    let variant_names: Vec<String> = variant_idents
+        .iter()
+        .map(|variant| variant.to_string())
+        .collect();
+
+    let mut aliases = Vec::new();
+    for (variant, name) in variants.iter().zip(&variant_names) {
+        match parse_variant_aliases(variant) {
+            Ok(variant_aliases) => aliases.extend(
+                variant_aliases
+                    .into_iter()
+                    .map(|alias| quote! { (#alias, #name) }),
+            ),
+            Err(e) => return e.to_compile_error().into(),
+        }
+    }
+    let has_data = variants
+        .iter()
+        .any(|variant| !matches!(variant.fields, syn::Fields::Unit));
+
+    let enum_descriptor =
+        enum_descriptor_tokens(&ast, has_data.then(|| data_enum_schema(variants)));
+    let variant_descriptors = variant_descriptors_tokens(&ast);
+
+    let field_idents: Vec<Vec<&Ident>> = variants
+        .iter()
+        .map(|variant| {
+            variant
+                .fields
+                .iter()
+                .filter_map(|field| field.ident.as_ref())
+                .collect()
+        })
+        .collect();
+
+    let clone_arms = variant_idents.iter().zip(&field_idents).map(|(variant, fields)| {
+        if fields.is_empty() {
+            quote! { Self::#variant => Self::#variant }
+        } else {
+            quote! { Self::#variant { #(#fields),* } => Self::#variant { #(#fields: #fields.clone()),* } }
+        }
+    });
+
+    let debug_arms = variant_idents
+        .iter()
+        .zip(&variant_names)
+        .zip(&field_idents)
+        .map(|((variant, name), fields)| {
+            if fields.is_empty() {
+                quote! { Self::#variant => f.write_str(#name) }
+            } else {
+                let field_names = fields.iter().map(|field| field.to_string());
+                quote! {
+                    Self::#variant { #(#fields),* } => f.debug_struct(#name)
+                        #(.field(#field_names, #fields))*
+                        .finish()
+                }
+            }
+        });
+
+    let deserialize = if has_data {
+        let deserialize_arms = variants.iter().zip(&variant_names).map(|(variant, name)| {
+            let variant_ident = &variant.ident;
+            if matches!(variant.fields, syn::Fields::Unit) {
+                return quote! { Some(#name) => Ok(Self::#variant_ident), };
+            }
+
+            let fields: Vec<_> = variant.fields.iter().map(deserialized_field_tokens).collect();
+            let field_names: Vec<_> = variant.fields.iter().filter_map(|field| field.ident.as_ref()).collect();
+            quote! {
+                Some(#name) => {
+                    #[derive(::openai_func_enums::serde::Deserialize)]
+                    #[serde(crate = "::openai_func_enums::serde")]
+                    struct Fields {
+                        #(#fields)*
+                    }
+
+                    let fields: Fields = ::openai_func_enums::serde_json::from_value(value).map_err(::openai_func_enums::serde::de::Error::custom)?;
+                    Ok(Self::#variant_ident { #(#field_names: fields.#field_names),* })
+                }
+            }
+        });
+
+        quote! {
+            let value = <::openai_func_enums::serde_json::Value as ::openai_func_enums::serde::Deserialize>::deserialize(deserializer)?;
+            let kind = match &value {
+                ::openai_func_enums::serde_json::Value::String(kind) => kind.clone(),
+                ::openai_func_enums::serde_json::Value::Object(object) => match object.get(#DATA_ENUM_TAG) {
+                    Some(::openai_func_enums::serde_json::Value::String(kind)) => kind.clone(),
+                    _ => return Err(::openai_func_enums::serde::de::Error::missing_field(#DATA_ENUM_TAG)),
+                },
+                _ => {
+                    return Err(::openai_func_enums::serde::de::Error::custom(
+                        "expected a variant name or an object with a kind",
+                    ))
+                }
+            };
+
+            match ::openai_func_enums::resolve_variant_name(&kind, VARIANTS, ALIASES) {
+                #(#deserialize_arms)*
+                _ => Err(::openai_func_enums::serde::de::Error::unknown_variant(&kind, VARIANTS)),
+            }
+        }
+    } else {
+        quote! {
+            let value = <String as ::openai_func_enums::serde::Deserialize>::deserialize(deserializer)?;
+            match ::openai_func_enums::resolve_variant_name(&value, VARIANTS, ALIASES) {
+                #(Some(#variant_names) => Ok(Self::#variant_idents),)*
+                _ => Err(::openai_func_enums::serde::de::Error::unknown_variant(&value, VARIANTS)),
+            }
+        }
+    };
+
+    let expanded = quote! {
+        #enum_descriptor
+
+        #variant_descriptors
+
+        impl Clone for #ident {
+            fn clone(&self) -> Self {
+                match self {
+                    #(#clone_arms,)*
+                }
+            }
+        }
+
+        impl ::std::fmt::Debug for #ident {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                match self {
+                    #(#debug_arms,)*
+                }
+            }
+        }
+
+        impl<'de> ::openai_func_enums::serde::Deserialize<'de> for #ident {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: ::openai_func_enums::serde::Deserializer<'de>,
+            {
+                const VARIANTS: &[&str] = &[#(#variant_names),*];
+                const ALIASES: &[(&str, &str)] = &[#(#aliases),*];
+
+                #deserialize
+            }
+        }
+    };
+
+    TokenStream::from(expanded)
+}
+
+/// The names given to a variant with `#[func_enums(alias = "...")]`.
+fn parse_variant_aliases(variant: &syn::Variant) -> syn::Result<Vec<syn::LitStr>> {
+    let mut aliases = Vec::new();
+    for attr in variant
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("func_enums"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("alias") {
+                aliases.push(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("unsupported func_enums attribute on a ToolArg variant"))
+            }
+        })?;
+    }
+    Ok(aliases)
+}
+
+/// Derives `openai_func_enums::ToolArgs` for a struct with named fields, the arguments of a
+/// tool written as a function with `#[tool]`.
+///
+/// The schema is built from the fields the same way it is for the fields of a `ToolSet`
+/// variant, and they take the same `#[arg(...)]` attributes. The struct has to derive
+/// `Deserialize` itself, so unlike with `ToolSet` numbers are only accepted as strings when a
+/// field asks for it with `openai_func_enums::deserialize_lenient_number`.
+///
+/// # Usage
+///
+/// ```
+/// # use openai_func_enums::{ToolArg, ToolArgs};
+/// # use serde::Deserialize;
+/// # #[derive(ToolArg)]
+/// # pub enum TemperatureUnits { Celsius, Fahrenheit }
+/// #[derive(Deserialize, ToolArgs)]
+/// pub struct GetWeatherArgs {
+///     #[arg(description = "The city to get the weather for.")]
+///     location: String,
+///     units: Option<TemperatureUnits>,
+/// }
+/// ```
+#[proc_macro_derive(ToolArgs, attributes(arg, arg_description, func_enums))]
+pub fn tool_args_derive(input: TokenStream) -> TokenStream {
+    let ast = parse_macro_input!(input as DeriveInput);
+    let ident = &ast.ident;
+
+    let fields = match &ast.data {
+        Data::Struct(data) if matches!(data.fields, syn::Fields::Named(_)) => &data.fields,
+        _ => {
+            return syn::Error::new_spanned(
+                &ast.ident,
+                "ToolArgs can only be derived for structs with named fields",
+            )
+            .to_compile_error()
+            .into();
+        }
+    };
+
+    let mut field_attributes = match fields
+        .iter()
+        .map(parse_field_attributes)
+        .collect::<syn::Result<Vec<_>>>()
+    {
+        Ok(field_attributes) => field_attributes,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    // Enum arguments are named after their type in a `ToolSet`, which parses either name,
+    // but here the struct's own `Deserialize` only knows the field name.
+    for (field, attributes) in fields.iter().zip(field_attributes.iter_mut()) {
+        if attributes.own_rename {
+            return syn::Error::new_spanned(
+                field,
+                "the struct's own `Deserialize` doesn't know about this name, use `#[serde(rename = \"...\")]` instead",
+            )
+            .to_compile_error()
+            .into();
+        }
+        if attributes.rename.is_none() {
+            attributes.rename = field.ident.as_ref().map(|ident| ident.to_string());
+        }
+    }
+
+    let field_info = fields_arg_info(fields, &field_attributes);
+    let field_required = fields_required(fields, &field_attributes);
+
+    // The struct can also be the type of an argument, as a nested object. Arguments get their
+    // schema from `EnumDescriptor`, which also needs `VariantDescriptors`.
+    let enum_descriptor = enum_descriptor_tokens(
+        &ast,
+        Some(quote! {
+            fn arg_schema_with_token_count() -> Option<&'static (::openai_func_enums::serde_json::Value, usize)> {
+                static ARG_SCHEMA: ::std::sync::OnceLock<(::openai_func_enums::serde_json::Value, usize)> = ::std::sync::OnceLock::new();
+                Some(ARG_SCHEMA.get_or_init(|| {
+                    let (parameters, tokens) = <Self as ::openai_func_enums::ToolArgs>::parameters_with_token_count();
+                    (parameters.clone(), tokens + #NESTED_OBJECT_STRUCTURE_TOKENS)
+                }))
+            }
+
+            fn named_after_field() -> bool {
+                true
+            }
+        }),
+    );
+    let name_tokens = calculate_token_count(&ident.to_string());
+
+    let expanded = quote! {
+        #enum_descriptor
+
+        // A struct has no variants.
+        impl ::openai_func_enums::VariantDescriptors for #ident {
+            fn variant_names_with_token_counts(
+            ) -> &'static (&'static [&'static str], &'static [usize], usize, usize) {
+                static VARIANTS: (&[&str], &[usize], usize, usize) = (&[], &[], 0, 0);
+                &VARIANTS
+            }
+
+            fn variant_name_with_token_count(&self) -> (&'static str, usize) {
+                (stringify!(#ident), #name_tokens)
+            }
+        }
+
+        impl ::openai_func_enums::ToolArgs for #ident {
+            fn parameters_with_token_count() -> &'static (::openai_func_enums::serde_json::Value, usize) {
+                static PARAMETERS: ::std::sync::OnceLock<(::openai_func_enums::serde_json::Value, usize)> = ::std::sync::OnceLock::new();
+                PARAMETERS.get_or_init(|| {
+                    let mut properties = ::openai_func_enums::serde_json::Map::new();
+                    let mut required = Vec::new();
+                    let mut total_tokens = 0;
+
+                    let field_info: Vec<(::openai_func_enums::serde_json::Value, usize)> = vec![#(#field_info),*];
+                    for ((arg_json, arg_tokens), is_required) in field_info.into_iter().zip([#(#field_required),*]) {
+                        total_tokens += arg_tokens + 3;
+                        if let Some((name, schema)) = arg_json.as_object().and_then(|arg| arg.iter().next()) {
+                            properties.insert(name.clone(), schema.clone());
+                            if is_required {
+                                required.push(name.clone());
+                            }
+                        }
+                    }
+
+                    let parameters = ::openai_func_enums::serde_json::json!({
+                        "type": "object",
+                        "properties": properties,
+                        "required": required
+                    });
+
+                    (parameters, total_tokens)
+                })
+            }
+        }
+    };
+
+    TokenStream::from(expanded)
+}
+
+/// Turns an async function that takes a `ToolArgs` struct into a tool that can be registered
+/// with a `ToolRegistry`.
+///
+/// The function is kept as it is, and next to it comes a unit struct named after it, with
+/// `Tool` appended, that implements `openai_func_enums::Tool`. The tool is called by the
+/// function's name in CamelCase, or by `name` if one is given, and its description is the
+/// doc comment, or the contents of `description_file`, a path relative to the crate root. The
+/// function has to return a `Result` whose value serializes to JSON. `cpu_bound` has its calls
+/// run on the runtime's blocking threads under the `Parallel` strategy.
+///
+/// # Usage
+///
+/// ```
+/// # use openai_func_enums::{tool, CommandError, ToolArgs, ToolRegistry};
+/// # use serde::Deserialize;
+/// # #[derive(Deserialize, ToolArgs)]
+/// # pub struct GetWeatherArgs { location: String }
+/// # let mut registry = ToolRegistry::new();
+/// /// Gets the current weather for a city.
+/// #[tool]
+/// async fn get_weather(args: GetWeatherArgs) -> Result<String, CommandError> {
+///     // ...
+/// #   Ok(args.location)
+/// }
+///
+/// registry.register(GetWeatherTool);
+/// ```
+#[proc_macro_attribute]
+pub fn tool(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let function = parse_macro_input!(item as syn::ItemFn);
+
+    let mut name = None;
+    let mut description_file = None;
+    let mut cpu_bound = false;
+    let attribute_parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("name") {
+            name = Some(meta.value()?.parse::<syn::LitStr>()?.value());
+            Ok(())
+        } else if meta.path.is_ident("cpu_bound") {
+            cpu_bound = true;
+            Ok(())
+        } else if meta.path.is_ident("description_file") {
+            let path: syn::LitStr = meta.value()?.parse()?;
+            let contents = read_description_file(&path)?;
+            description_file = Some((path, contents));
+            Ok(())
+        } else {
+            Err(meta.error("unrecognized tool attribute"))
+        }
+    });
+    parse_macro_input!(attr with attribute_parser);
+
+    if function.sig.asyncness.is_none() {
+        return syn::Error::new_spanned(&function.sig, "#[tool] functions must be async")
+            .to_compile_error()
+            .into();
+    }
+
+    let args_type = match function.sig.inputs.iter().collect::<Vec<_>>().as_slice() {
+        [syn::FnArg::Typed(argument)] => argument.ty.clone(),
+        _ => {
+            return syn::Error::new_spanned(
+                &function.sig.inputs,
+                "#[tool] functions take a single argument, a struct that derives ToolArgs",
+            )
+            .to_compile_error()
+            .into();
+        }
+    };
+
+    let function_name = &function.sig.ident;
+    let camel_case_name = to_camel_case(&function_name.to_string());
+    let tool_name = name.unwrap_or_else(|| camel_case_name.clone());
+    let struct_name = format_ident!("{}Tool", camel_case_name);
+    let visibility = &function.vis;
+
+    let description = match &description_file {
+        Some((_, contents)) => contents.clone(),
+        None => function
+            .attrs
+            .iter()
+            .filter_map(get_comment_from_attr)
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join(" "),
+    };
+    let track_description_file = description_file
+        .as_ref()
+        .map(|(path, _)| track_description_file(path));
+
+    let expanded = quote! {
+        #function
+
+        #track_description_file
+
+        #[doc = concat!("The `", #tool_name, "` tool, which runs `", stringify!(#function_name), "`.")]
+        #[derive(Clone, Copy, Debug, Default)]
+        #visibility struct #struct_name;
+
+        impl #struct_name {
+            pub const NAME: &'static str = #tool_name;
+
+            pub const DESCRIPTION: &'static str = #description;
+
+            /// The tool's description as it goes into a `DescriptionManifest`, so that it can be
+            /// ranked with embeddings like a derived function.
+            pub fn function_description() -> ::openai_func_enums::FunctionDescription {
+                ::openai_func_enums::FunctionDescription::new(#tool_name, #description)
+            }
+        }
+
+        #[::openai_func_enums::async_trait::async_trait]
+        impl ::openai_func_enums::Tool for #struct_name {
+            fn name(&self) -> &str {
+                #tool_name
+            }
+
+            fn description(&self) -> &str {
+                #description
+            }
+
+            fn parameters(&self) -> ::openai_func_enums::serde_json::Value {
+                <#args_type as ::openai_func_enums::ToolArgs>::parameters_with_token_count().0.clone()
+            }
+
+            fn cpu_bound(&self) -> bool {
+                #cpu_bound
+            }
+
+            async fn call(
+                &self,
+                mut arguments: ::openai_func_enums::serde_json::Value,
+            ) -> Result<::openai_func_enums::serde_json::Value, Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+                let parameters = self.parameters();
+                ::openai_func_enums::apply_argument_defaults(&parameters, &mut arguments);
+                ::openai_func_enums::validate_arguments(#tool_name, &parameters, &arguments)?;
+                let arguments: #args_type = ::openai_func_enums::serde_json::from_value(arguments)?;
+                let output = #function_name(arguments)
+                    .await
+                    .map_err(Into::<Box<dyn ::std::error::Error + Send + Sync + 'static>>::into)?;
+                Ok(::openai_func_enums::serde_json::to_value(output)?)
+            }
+        }
     };
 
     TokenStream::from(expanded)
 }
 
+/// The property that says which variant of a `ToolArg` enum with fields an argument is.
+const DATA_ENUM_TAG: &str = "kind";
+
+/// The body of `arg_schema_with_token_count` for a `ToolArg` enum whose variants have fields:
+/// an `anyOf` with an object per variant, tagged with `DATA_ENUM_TAG`.
+fn data_enum_schema(
+    variants: &syn::punctuated::Punctuated<syn::Variant, syn::token::Comma>,
+) -> proc_macro2::TokenStream {
+    let variant_schemas = variants.iter().map(|variant| {
+        let name = variant.ident.to_string();
+        let field_info = variant.fields.iter().filter_map(|field| {
+            field
+                .ident
+                .as_ref()
+                .map(|ident| arg_info_tokens(ident, &field.ty))
+        });
+        let field_required = fields_required(&variant.fields, &[]);
+
+        quote! {
+            {
+                let mut properties = ::openai_func_enums::serde_json::Map::new();
+                properties.insert(
+                    #DATA_ENUM_TAG.to_string(),
+                    ::openai_func_enums::serde_json::json!({ "type": "string", "enum": [#name] }),
+                );
+                let mut required = vec![#DATA_ENUM_TAG.to_string()];
+                let field_info: Vec<(::openai_func_enums::serde_json::Value, usize)> = vec![#(#field_info),*];
+                for ((arg_json, _), is_required) in field_info.into_iter().zip([#(#field_required),*]) {
+                    if let Some((name, schema)) = arg_json.as_object().and_then(|arg| arg.iter().next()) {
+                        properties.insert(name.clone(), schema.clone());
+                        if is_required {
+                            required.push(name.clone());
+                        }
+                    }
+                }
+
+                ::openai_func_enums::serde_json::json!({
+                    "type": "object",
+                    "properties": properties,
+                    "required": required,
+                    "additionalProperties": false
+                })
+            }
+        }
+    });
+
+    quote! {
+        fn arg_schema_with_token_count() -> Option<&'static (::openai_func_enums::serde_json::Value, usize)> {
+            static ARG_SCHEMA: ::std::sync::OnceLock<(::openai_func_enums::serde_json::Value, usize)> = ::std::sync::OnceLock::new();
+            Some(ARG_SCHEMA.get_or_init(|| {
+                let schema = ::openai_func_enums::serde_json::json!({ "anyOf": vec![#(#variant_schemas),*] });
+                let tokens = ::openai_func_enums::count_tokens(&schema.to_string());
+                (schema, tokens)
+            }))
+        }
+    }
+}
+
+/// A field of a generated struct that arguments are deserialized into. Numbers are accepted
+/// as strings too.
+fn deserialized_field_tokens(field: &syn::Field) -> proc_macro2::TokenStream {
+    let field_name = &field.ident;
+    let field_type = &field.ty;
+
+    if is_numeric_type(field_type) {
+        return quote! {
+            #[serde(deserialize_with = "::openai_func_enums::deserialize_lenient_number")]
+            #field_name: #field_type,
+        };
+    }
+
+    if option_inner_type(field_type).is_some_and(is_numeric_type) {
+        return quote! {
+            #[serde(default, deserialize_with = "::openai_func_enums::deserialize_lenient_optional_number")]
+            #field_name: #field_type,
+        };
+    }
+
+    quote! {
+        #field_name: #field_type,
+    }
+}
+
 /// A procedural macro to generate JSON information about an enum, including its name,
 /// variant names, and descriptions, along with a total token count.
 ///
@@ -255,7 +883,7 @@ pub fn variant_descriptors_derive(input: TokenStream) -> TokenStream {
 ///
 /// When applied to an enum, the macro generates code similar to the following example:
 ///
-/// ```rust
+/// ```text
 /// {
 ///     use serde_json::Value;
 ///     let mut token_count = 0;
@@ -307,14 +935,25 @@ pub fn generate_enum_info(input: TokenStream) -> TokenStream {
     let enum_ident = parse_macro_input!(input as Ident);
 
     let output = quote! {
-        {
-            let ARG_DESC_AND_TOKENS: &'static (&'static str, usize) = <#enum_ident as openai_func_enums::EnumDescriptor>::arg_description_with_token_count();
-            let ENUM_NAME_AND_TOKENS: &'static (&'static str, usize) = <#enum_ident as openai_func_enums::EnumDescriptor>::name_with_token_count();
-            let ENUM_VARIANTS_INFO: &'static (&'static [&'static str], &'static [usize], usize, usize) = <#enum_ident as openai_func_enums::VariantDescriptors>::variant_names_with_token_counts();
+        if let Some((schema, schema_tokens)) = <#enum_ident as ::openai_func_enums::EnumDescriptor>::arg_schema_with_token_count() {
+            let (description, description_tokens) = <#enum_ident as ::openai_func_enums::EnumDescriptor>::arg_description_with_token_count();
+            let (enum_name, enum_name_tokens) = <#enum_ident as ::openai_func_enums::EnumDescriptor>::name_with_token_count();
+
+            let mut schema = schema.clone();
+            if !description.is_empty() {
+                schema["description"] = ::openai_func_enums::serde_json::Value::from(*description);
+            }
+
+            let json_enum: ::openai_func_enums::serde_json::Value = ::openai_func_enums::serde_json::json!({ *enum_name: schema });
+            (json_enum, #ARG_DESCRIPTION_STRUCTURE_TOKENS + description_tokens + #ARG_NAME_STRUCTURE_TOKENS + enum_name_tokens + schema_tokens)
+        } else {
+            let ARG_DESC_AND_TOKENS: &'static (&'static str, usize) = <#enum_ident as ::openai_func_enums::EnumDescriptor>::arg_description_with_token_count();
+            let ENUM_NAME_AND_TOKENS: &'static (&'static str, usize) = <#enum_ident as ::openai_func_enums::EnumDescriptor>::name_with_token_count();
+            let ENUM_VARIANTS_INFO: &'static (&'static [&'static str], &'static [usize], usize, usize) = <#enum_ident as ::openai_func_enums::VariantDescriptors>::variant_names_with_token_counts();
 
-            let token_count = 6 + ARG_DESC_AND_TOKENS.1 + 6 + ENUM_NAME_AND_TOKENS.1 + 7 + 7 + ENUM_VARIANTS_INFO.2 + ENUM_VARIANTS_INFO.3;
+            let token_count = #ARG_DESCRIPTION_STRUCTURE_TOKENS + ARG_DESC_AND_TOKENS.1 + #ARG_NAME_STRUCTURE_TOKENS + ENUM_NAME_AND_TOKENS.1 + #STRING_TYPE_TOKENS + #ENUM_LIST_STRUCTURE_TOKENS + ENUM_VARIANTS_INFO.2 + ENUM_VARIANTS_INFO.3;
 
-            let json_enum: Value = serde_json::json!({
+            let json_enum: ::openai_func_enums::serde_json::Value = ::openai_func_enums::serde_json::json!({
                 ENUM_NAME_AND_TOKENS.0: {
                     "type": "string",
                     "enum": ENUM_VARIANTS_INFO.0.iter().map(|name| *name).collect::<Vec<_>>(),
@@ -362,7 +1001,7 @@ pub fn generate_value_arg_info(input: TokenStream) -> TokenStream {
         quote! {
             {
                 static JSON_STR: &str = #json_string;
-                let json_enum: serde_json::Value = serde_json::from_str(JSON_STR).unwrap();
+                let json_enum: ::openai_func_enums::serde_json::Value = ::openai_func_enums::serde_json::from_str(JSON_STR).unwrap();
                 (json_enum, #total_tokens)
             }
         }
@@ -380,7 +1019,7 @@ pub fn generate_value_arg_info(input: TokenStream) -> TokenStream {
 ///
 /// # Usage
 ///
-/// ```rust
+/// ```text
 /// enum MyEnum {
 ///     #[func_description(description="This function does a thing.")]
 ///     DoAThing,
@@ -407,7 +1046,8 @@ pub fn func_description(_args: TokenStream, input: TokenStream) -> TokenStream {
 /// methods for executing the commands and dealing with the responses.
 ///
 /// Each variant of the original enum will be converted into a corresponding structure,
-/// and each field in the variant will become a field in the generated structure.
+/// and each field in the variant will become a field in the generated structure. Variants
+/// marked `#[func_enums(skip)]` are left out, and are never shown to the model.
 /// The generated structures will derive `serde::Deserialize` and `Debug` automatically.
 ///
 /// This macro also generates methods for calculating the token count of a string and
@@ -417,7 +1057,8 @@ pub fn func_description(_args: TokenStream, input: TokenStream) -> TokenStream {
 /// generated structures are treated. For example, fields of type `String` or `&str` are
 /// converted to JSON value arguments with type `"string"`, while fields of type `u8`, `u16`,
 /// `u32`, `u64`, `usize`, `i8`, `i16`, `i32`, `i64`, `isize`, `f32` or `f64` are converted
-/// to JSON value arguments with type `"integer"` or `"number"` respectively.
+/// to JSON value arguments with type `"integer"` or `"number"` respectively, and fields of
+/// type `bool` to JSON value arguments with type `"boolean"`.
 /// For fields with a tuple type, currently this macro simply prints that the field is of a tuple type.
 /// For fields with an array type, they are converted to JSON value arguments with type `"array"`.
 ///
@@ -427,33 +1068,100 @@ pub fn func_description(_args: TokenStream, input: TokenStream) -> TokenStream {
 ///
 /// If the total token count of the request exceeds a specified limit, an error will be returned.
 ///
+/// The variant that takes a prompt is the one called `GPT`, or the one named with
+/// `#[func_enums(entry = "Ask")]` on the enum, and `CommandsGPT::ENTRY_VARIANT` says which it
+/// is. Enums that are only used for their schemas and for parsing calls can go without one.
+///
+/// The generated `CommandsGPT`, `FunctionResponse` and `FUNC_ENUMS_*` constants are named
+/// after the enum instead with `#[func_enums(namespaced)]`, like `WeatherToolsGPT`,
+/// `WeatherToolsFunctionResponse` and `WEATHER_TOOLS_MAX_FUNC_TOKENS` for `WeatherTools`, so
+/// that several enums can derive `ToolSet` in the same module. Their argument structs are
+/// prefixed the same way, `WeatherToolsGetForecast` for a `GetForecast` variant.
+///
 /// The `derive_subcommand_gpt` function consumes a `TokenStream` representing the enum
 /// to which the macro is applied and produces a `TokenStream` representing the generated code.
 ///
 /// # Panics
 /// This macro will panic (only at compile time) if it is applied to a non-enum item.
-#[proc_macro_derive(ToolSet)]
+#[proc_macro_derive(ToolSet, attributes(arg, func_enums, serde))]
 pub fn derive_subcommand_gpt(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
 
     let name = input.ident;
 
+    let ToolSetAttributes {
+        entry,
+        namespaced,
+        state,
+    } = match parse_tool_set_attributes(&input.attrs) {
+        Ok(attributes) => attributes,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    // A namespaced enum's generated items are named after it, `WeatherToolsGPT` and
+    // `WEATHER_TOOLS_MAX_FUNC_TOKENS` for `WeatherTools`, instead of `CommandsGPT` and
+    // `FUNC_ENUMS_MAX_FUNC_TOKENS`.
+    let (commands_gpt, function_response, const_prefix) = if namespaced {
+        (
+            format_ident!("{}GPT", name),
+            format_ident!("{}FunctionResponse", name),
+            to_snake_case(&name.to_string()).to_uppercase(),
+        )
+    } else {
+        (
+            format_ident!("CommandsGPT"),
+            format_ident!("FunctionResponse"),
+            "FUNC_ENUMS".to_string(),
+        )
+    };
+    let max_response_tokens_const = format_ident!("{}_MAX_RESPONSE_TOKENS", const_prefix);
+    let max_request_tokens_const = format_ident!("{}_MAX_REQUEST_TOKENS", const_prefix);
+    let max_func_tokens_const = format_ident!("{}_MAX_FUNC_TOKENS", const_prefix);
+    let max_single_arg_tokens_const = format_ident!("{}_MAX_SINGLE_ARG_TOKENS", const_prefix);
+    let default_system_message_tokens = calculate_token_count(DEFAULT_SYSTEM_MESSAGE);
+    let embed_path_const = format_ident!("{}_EMBED_PATH", const_prefix);
+    let embed_model_const = format_ident!("{}_EMBED_MODEL", const_prefix);
+
     let data = match input.data {
         Data::Enum(data) => data,
         _ => panic!("ToolSet can only be implemented for enums"),
     };
 
+    // The variant that takes a prompt, `GPT` unless the enum names another.
+    let entry_variant = match &entry {
+        Some(entry) => match data
+            .variants
+            .iter()
+            .find(|variant| variant.ident == entry.value())
+        {
+            Some(variant) => Some(variant.ident.to_string()),
+            None => {
+                return syn::Error::new_spanned(
+                    entry,
+                    format!("{} has no variant called {}", name, entry.value()),
+                )
+                .to_compile_error()
+                .into();
+            }
+        },
+        None => data
+            .variants
+            .iter()
+            .find(|variant| variant.ident == "GPT")
+            .map(|variant| variant.ident.to_string()),
+    };
+    let entry_variant_name = entry_variant.clone();
+    let entry_variant = match entry_variant {
+        Some(entry_variant) => quote! { Some(#entry_variant) },
+        None => quote! { None },
+    };
+
     let mut generated_structs = Vec::new();
     let mut json_generator_functions = Vec::new();
 
-    let mut generated_clap_gpt_enum: Vec<proc_macro2::TokenStream> = Vec::new();
     let mut generated_struct_names = Vec::new();
-
-    #[cfg(any(
-        feature = "compile_embeddings_all",
-        feature = "compile_embeddings_update"
-    ))]
-    let rt = tokio::runtime::Runtime::new().unwrap();
+    let mut response_variant_names = Vec::new();
+    let mut function_names = Vec::new();
 
     #[cfg(any(
         feature = "compile_embeddings_all",
@@ -508,117 +1216,112 @@ pub fn derive_subcommand_gpt(input: TokenStream) -> TokenStream {
         .parse()
         .expect("Failed to parse u16 value from FUNC_ENUMS_MAX_SINGLE_ARG_TOKENS");
 
+    // Opt in: descriptions longer than this are sent shortened.
+    let max_description_tokens: Option<usize> = std::env::var("FUNC_ENUMS_MAX_DESCRIPTION_TOKENS")
+        .ok()
+        .map(|value| {
+            value
+                .parse()
+                .expect("Failed to parse usize value from FUNC_ENUMS_MAX_DESCRIPTION_TOKENS")
+        });
+
+    let mut function_descriptions = Vec::new();
+
+    // The name, description and embedding inputs of every variant, see `embed_functions`.
     #[cfg(any(
         feature = "compile_embeddings_all",
         feature = "compile_embeddings_update"
     ))]
-    let mut embeddings: Vec<openai_func_embeddings::FuncEmbedding> = Vec::new();
+    let mut pending_embeddings: Vec<(String, String, String, String)> = Vec::new();
 
-    #[cfg(feature = "compile_embeddings_update")]
-    {
-        if Path::new(&embed_path).exists() {
-            let mut file = std::fs::File::open(&embed_path).unwrap();
-            let mut bytes = Vec::new();
-            file.read_to_end(&mut bytes).unwrap();
-            let archived_data = rkyv::check_archived_root::<Vec<FuncEmbedding>>(&bytes).unwrap();
-            embeddings = archived_data.deserialize(&mut rkyv::Infallible).unwrap();
-        }
-    }
-
-    let mut has_gpt_variant = false;
-    // TODO: make this setable:
-    let gpt_variant_name = "GPT";
     for variant in data.variants.iter() {
         let variant_name = &variant.ident;
-        if variant_name.to_string() == gpt_variant_name {
-            has_gpt_variant = true;
-        }
-
-        let struct_name = format_ident!("{}", variant_name);
-        let struct_name_tokens = calculate_token_count(struct_name.to_string().as_str());
-        generated_struct_names.push(struct_name.clone());
-        let mut variant_desc = String::new();
-        let mut variant_desc_tokens = 0_usize;
-
-        for variant_attrs in &variant.attrs {
-            let description = get_comment_from_attr(variant_attrs);
-            if let Some(description) = description {
-                variant_desc = description;
-                variant_desc_tokens = calculate_token_count(variant_desc.as_str());
-
-                // TODO: Do a default, show a helpful error message, do something, you will forget
-                #[cfg(feature = "compile_embeddings_all")]
-                {
-                    println!("Writing embeddings");
-                    let mut name_and_desc = variant_name.to_string();
-                    name_and_desc.push(':');
-                    name_and_desc.push_str(&variant_desc);
-
-                    rt.block_on(async {
-                        let embedding = get_single_embedding(&name_and_desc, &embed_model).await;
-                        if let Ok(embedding) = embedding {
-                            let data = openai_func_embeddings::FuncEmbedding {
-                                name: variant_name.to_string(),
-                                description: variant_desc.clone(),
-                                embedding,
-                            };
-
-                            embeddings.push(data);
-                        }
-                    });
-                }
 
-                #[cfg(feature = "compile_embeddings_update")]
-                {
-                    let mut name_and_desc = variant_name.to_string();
-                    name_and_desc.push(':');
-                    name_and_desc.push_str(&variant_desc);
+        let variant_attributes = match parse_variant_attributes(variant) {
+            Ok(variant_attributes) => variant_attributes,
+            Err(e) => return e.to_compile_error().into(),
+        };
 
-                    rt.block_on(async {
-                        let mut existing = embeddings.iter().find(|x| x.name == name);
+        // Skipped variants stay part of the enum, but the model never hears of them. Neither
+        // does it of the entry variant, which is how a prompt comes in rather than a tool.
+        if variant_attributes.skip
+            || entry_variant_name.as_deref() == Some(&*variant_name.to_string())
+        {
+            continue;
+        }
 
-                        if let Some(existing) = existing {
-                            if existing.description != variant_desc {
-                                let embedding =
-                                    get_single_embedding(&name_and_desc, &embed_model).await;
+        // The name the model knows the function by, which `rename` can set apart from the
+        // variant's.
+        let function_name = match &variant_attributes.rename {
+            Some(rename) => rename.value(),
+            None => variant_name.to_string(),
+        };
+        function_names.push(function_name.clone());
 
-                                if let Ok(embedding) = embedding {
-                                    existing.description = variant_desc.clone();
-                                    existing.embedding = embedding;
-                                }
-                            }
-                        } else {
-                            let embedding =
-                                get_single_embedding(&name_and_desc, &embed_model).await;
-                            if let Ok(embedding) = embedding {
-                                let data = FuncEmbedding {
-                                    name: variant_name.to_string(),
-                                    description: variant_desc.clone(),
-                                    embedding,
-                                };
-
-                                embeddings.push(data);
-                            }
-                        }
-                    });
-                }
-            }
-        }
+        // The arguments struct goes next to the enum, so a namespaced enum's are prefixed with
+        // its name like the rest of its items, and `FunctionResponse` wraps them by variant.
+        let struct_name = if namespaced {
+            format_ident!("{}{}", name, variant_name)
+        } else {
+            format_ident!("{}", variant_name)
+        };
+        let struct_name_tokens = calculate_token_count(&function_name);
+        generated_struct_names.push(struct_name.clone());
+        response_variant_names.push(variant_name.clone());
+        // A doc comment of several lines is one description, like a `#[tool]` function's.
+        let mut variant_desc = match &variant_attributes.description_file {
+            Some((_, contents)) => contents.clone(),
+            None => variant
+                .attrs
+                .iter()
+                .filter_map(get_comment_from_attr)
+                .map(|line| line.trim().to_string())
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>()
+                .join(" "),
+        };
+        let mut variant_desc_tokens = calculate_token_count(variant_desc.as_str());
+        function_descriptions.push(openai_func_embeddings::FunctionDescription::new(
+            function_name.clone(),
+            variant_desc.clone(),
+        ));
 
+        // TODO: Do a default, show a helpful error message, do something, you will forget
         #[cfg(any(
             feature = "compile_embeddings_all",
             feature = "compile_embeddings_update"
         ))]
         {
-            let serialized_data = rkyv::to_bytes::<_, 256>(&embeddings).unwrap();
-            let mut file = std::fs::File::create(&embed_path).unwrap();
-            file.write_all(&serialized_data).unwrap();
+            let mut name_and_desc = function_name.clone();
+            name_and_desc.push(':');
+            name_and_desc.push_str(&variant_desc);
+
+            let name_words = openai_func_embeddings::name_embedding_input(&function_name);
+
+            // Fetched together once every variant has been seen.
+            pending_embeddings.push((
+                function_name.clone(),
+                variant_desc.clone(),
+                name_and_desc,
+                name_words,
+            ));
         }
 
+        let field_attributes = match variant
+            .fields
+            .iter()
+            .map(parse_field_attributes)
+            .collect::<syn::Result<Vec<_>>>()
+        {
+            Ok(field_attributes) => field_attributes,
+            Err(e) => return e.to_compile_error().into(),
+        };
+
         let fields: Vec<_> = variant
             .fields
             .iter()
-            .map(|f| {
+            .zip(&field_attributes)
+            .map(|(f, attributes)| {
                 // If the field has an identifier (i.e., it is a named field),
                 // use it. Otherwise, use the type as the name.
                 let field_name = if let Some(ident) = &f.ident {
@@ -627,12 +1330,94 @@ pub fn derive_subcommand_gpt(input: TokenStream) -> TokenStream {
                     format_ident!("{}", to_snake_case(&f.ty.to_token_stream().to_string()))
                 };
                 let field_type = &f.ty;
-                quote! {
-                    pub #field_name: #field_type,
+
+                let mut serde_arguments = Vec::new();
+                if attributes.own_rename {
+                    let rename = &attributes.rename;
+                    serde_arguments.push(quote! { rename = #rename, });
+                }
+
+                // A declared default fills the field in when the model leaves it out.
+                let has_default = attributes.default.is_some();
+                if has_default {
+                    let default_function = format!("{}::default_{}", struct_name, field_name);
+                    serde_arguments.push(quote! { default = #default_function, });
+                }
+
+                // The field's own serde attributes take over its deserialization.
+                if !attributes.serde.is_empty() {
+                    let serde = &attributes.serde;
+                    let serde_arguments = (!serde_arguments.is_empty())
+                        .then(|| quote! { #[serde(#(#serde_arguments)*)] });
+                    return quote! {
+                        #(#serde)*
+                        #serde_arguments
+                        pub #field_name: #field_type,
+                    };
+                }
+
+                // Models often send numbers as strings, so numeric fields accept both.
+                if is_numeric_type(field_type) {
+                    return quote! {
+                        #[serde(#(#serde_arguments)* deserialize_with = "::openai_func_enums::deserialize_lenient_number")]
+                        pub #field_name: #field_type,
+                    };
+                }
+
+                if option_inner_type(field_type).is_some_and(is_numeric_type) {
+                    if !has_default {
+                        serde_arguments.push(quote! { default, });
+                    }
+                    return quote! {
+                        #[serde(#(#serde_arguments)* deserialize_with = "::openai_func_enums::deserialize_lenient_optional_number")]
+                        pub #field_name: #field_type,
+                    };
+                }
+
+                if serde_arguments.is_empty() {
+                    quote! {
+                        pub #field_name: #field_type,
+                    }
+                } else {
+                    quote! {
+                        #[serde(#(#serde_arguments)*)]
+                        pub #field_name: #field_type,
+                    }
                 }
             })
             .collect();
 
+        let mut default_functions = Vec::new();
+        let mut default_checks = Vec::new();
+        for (f, attributes) in variant.fields.iter().zip(&field_attributes) {
+            let (Some((default, _)), Some(ident)) = (&attributes.default, &f.ident) else {
+                continue;
+            };
+            let function_name = format_ident!("default_{}", ident);
+            let field_type = &f.ty;
+
+            let body = match typed_default(field_type, default) {
+                Some(body) => body,
+                None => {
+                    default_checks.push(quote! {
+                        ::openai_func_enums::serde_json::from_value::<#field_type>(::openai_func_enums::serde_json::json!(#default))?;
+                    });
+                    // `check_defaults` runs before any call is deserialized, so this never
+                    // fails once a call got that far.
+                    quote! {
+                        ::openai_func_enums::serde_json::from_value(::openai_func_enums::serde_json::json!(#default)).unwrap_or_else(|_| {
+                            unreachable!("check_defaults rejects defaults that don't fit")
+                        })
+                    }
+                }
+            };
+            default_functions.push(quote! {
+                fn #function_name() -> #field_type {
+                    #body
+                }
+            });
+        }
+
         let execute_command_parameters: Vec<_> = variant
             .fields
             .iter()
@@ -642,92 +1427,76 @@ pub fn derive_subcommand_gpt(input: TokenStream) -> TokenStream {
             })
             .collect();
 
-        let number_type = "number";
-        let number_ident = format_ident!("{}", number_type);
-        let integer_type = "integer";
-        let integer_ident = format_ident!("{}", integer_type);
-        let string_type = "string";
-        let string_ident = format_ident!("{}", string_type);
-        let array_type = "array";
-        let array_ident = format_ident!("{}", array_type);
+        // The manifest and embeddings above get the full description, the request gets the
+        // shortened one.
+        let full_description = variant_desc.clone();
+        let full_description_tokens = variant_desc_tokens;
+        if let Some(max_description_tokens) = max_description_tokens {
+            if variant_desc_tokens > max_description_tokens {
+                let compressed = openai_func_embeddings::compress_description(
+                    &variant_desc,
+                    max_description_tokens,
+                    calculate_token_count,
+                );
+                variant_desc_tokens = calculate_token_count(&compressed);
+                variant_desc = compressed;
+            }
+        }
 
-        let field_info: Vec<_> = variant
-            .fields
-            .iter()
-            .map(|f| {
-                let field_name = if let Some(ident) = &f.ident {
-                    format_ident!("{}", ident)
-                } else {
-                    format_ident!("{}", to_snake_case(&f.ty.to_token_stream().to_string()))
-                };
-                let field_type = &f.ty;
+        // A declared return schema is appended to the description, which makes the
+        // description's token count a runtime value.
+        let describe_function = match &variant_attributes.returns {
+            Some(return_type) => quote! {
+                {
+                    let returns = ::openai_func_enums::serde_json::to_string(
+                        &<#return_type as ::openai_func_enums::ReturnSchema>::return_schema(),
+                    ).unwrap();
+                    let description = format!("{} Returns: {}", #variant_desc, returns);
+                    let description_tokens = #variant_desc_tokens
+                        + ::openai_func_enums::count_tokens(" Returns: ")
+                        + ::openai_func_enums::count_tokens(&returns);
+                    (description, description_tokens)
+                }
+            },
+            None => quote! { (#variant_desc.to_string(), #variant_desc_tokens) },
+        };
 
-                match field_type {
-                    syn::Type::Path(typepath) if typepath.qself.is_none() => {
-                        let type_ident = &typepath.path.segments.last().unwrap().ident;
+        let return_schema = match &variant_attributes.returns {
+            Some(return_type) => quote! {
+                Some(<#return_type as ::openai_func_enums::ReturnSchema>::return_schema())
+            },
+            None => quote! { None },
+        };
 
-                        match type_ident.to_string().as_str() {
-                            "f32" | "f64" => {
-                                return quote! {
-                                    generate_value_arg_info!(#number_ident, #field_name)
-                                };
-                            }
-                            "u8" | "u16" | "u32" | "u64" | "u128" | "usize" | "i8" | "i16"
-                            | "i32" | "i64" | "i128" | "isize" => {
-                                return quote! {
-                                    generate_value_arg_info!(#integer_ident, #field_name)
-                                };
-                            }
-                            "String" | "&str" => {
-                                return quote! {
-                                    generate_value_arg_info!(#string_ident, #field_name)
-                                };
-                            }
-                            "Vec" => {
-                                return quote! {
-                                    generate_value_arg_info!(#array_ident, #field_name)
-                                };
-                            }
-                            _ => {
-                                return quote! {
-                                    openai_func_enums::generate_enum_info!(#field_type)
-                                };
-                            }
-                        }
-                    }
-                    syn::Type::Tuple(_) => {
-                        println!("Field {} is of tuple type", field_name);
-                    }
-                    syn::Type::Array(_) => {
-                        println!("Field {} is of array type", field_name);
-                        return quote! {
-                            generate_value_arg_info!(#array_ident, #field_name)
-                        };
-                    }
-                    _ => {
-                        println!("Field {} is of another type.", field_name);
-                    }
-                }
-                quote! {}
-            })
-            .collect();
+        let risky = variant_attributes.risky;
+        let scopes = &variant_attributes.scopes;
+        let sandboxed = variant_attributes.sandboxed;
+        let cpu_bound = variant_attributes.cpu_bound;
+        let strict = variant_attributes.strict;
+        let timeout = match variant_attributes.timeout_ms {
+            Some(timeout_ms) => quote! { Some(::std::time::Duration::from_millis(#timeout_ms)) },
+            None => quote! { None },
+        };
+
+        let field_info = fields_arg_info(&variant.fields, &field_attributes);
+        let field_required = fields_required(&variant.fields, &field_attributes);
 
         json_generator_functions.push(quote! {
             impl #struct_name {
                 pub fn name() -> String {
-                    stringify!(#struct_name).to_string()
+                    #function_name.to_string()
                 }
 
-                pub fn to_function_call() -> ChatCompletionFunctionCall {
-                    ChatCompletionFunctionCall::Function {
-                        name: stringify!(#struct_name).to_string(),
+                pub fn to_function_call() -> ::openai_func_enums::async_openai::types::ChatCompletionFunctionCall {
+                    ::openai_func_enums::async_openai::types::ChatCompletionFunctionCall::Function {
+                        name: #function_name.to_string(),
                     }
                 }
 
-                pub fn to_tool_choice() -> ChatCompletionToolChoiceOption {
-                    ChatCompletionToolChoiceOption::Named(ChatCompletionNamedToolChoice {
-                        r#type: ChatCompletionToolType::Function,
-                        function: FunctionName { name: stringify!(#struct_name).to_string() }
+                pub fn to_tool_choice() -> ::openai_func_enums::async_openai::types::ChatCompletionToolChoiceOption {
+                    ::openai_func_enums::async_openai::types::ChatCompletionToolChoiceOption::Named(::openai_func_enums::async_openai::types::ChatCompletionNamedToolChoice {
+                        r#type: ::openai_func_enums::async_openai::types::ChatCompletionToolType::Function,
+                        function: ::openai_func_enums::async_openai::types::FunctionName { name: #function_name.to_string() }
                     })
                 }
 
@@ -737,189 +1506,459 @@ pub fn derive_subcommand_gpt(input: TokenStream) -> TokenStream {
                     }
                 }
 
-                // Bake this in. Can be much faster.
-                pub fn get_function_json() -> (serde_json::Value, usize) {
-                    let mut parameters = serde_json::Map::new();
-                    let mut total_tokens = 0;
+                /// The function definition sent to the model, along with its token count.
+                ///
+                /// It is built the first time it is needed and cached after that, so building
+                /// a request doesn't rebuild the definitions of the tools it presents.
+                pub fn function_json() -> &'static (::openai_func_enums::serde_json::Value, usize) {
+                    static FUNCTION_JSON: ::std::sync::OnceLock<(::openai_func_enums::serde_json::Value, usize)> = ::std::sync::OnceLock::new();
+                    FUNCTION_JSON.get_or_init(|| {
+                        let mut parameters = ::openai_func_enums::serde_json::Map::new();
+                        let mut required = Vec::new();
+                        let mut total_tokens = 0;
+
+                        let field_info: Vec<(::openai_func_enums::serde_json::Value, usize)> = vec![#(#field_info),*];
+                        for ((arg_json, arg_tokens), is_required) in field_info.into_iter().zip([#(#field_required),*]) {
+                            total_tokens += arg_tokens;
+                            total_tokens += 3;
+
+                            let name = arg_json.as_object().unwrap().keys().next().unwrap().clone();
+                            if is_required {
+                                required.push(name.clone());
+                            }
+                            parameters.insert(
+                                name,
+                                arg_json
+                                    .as_object()
+                                    .unwrap()
+                                    .values()
+                                    .next()
+                                    .unwrap()
+                                    .clone(),
+                            );
+                        }
 
-                    for (arg_json, arg_tokens) in vec![#(#field_info),*] {
-                        total_tokens += arg_tokens;
-                        total_tokens += 3;
-
-                        parameters.insert(
-                            arg_json.as_object().unwrap().keys().next().unwrap().clone(),
-                            arg_json
-                                .as_object()
-                                .unwrap()
-                                .values()
-                                .next()
-                                .unwrap()
-                                .clone(),
-                        );
-                    }
+                        let (description, description_tokens) = #describe_function;
 
-                    let function_json = serde_json::json!({
-                        "name": stringify!(#struct_name),
-                        "description": #variant_desc,
-                        "parameters": {
-                            "type": "object",
-                            "properties": parameters,
-                            "required": parameters.keys().collect::<Vec<_>>()
+                        let mut function_json = ::openai_func_enums::serde_json::json!({
+                            "name": #function_name,
+                            "description": description,
+                            "parameters": {
+                                "type": "object",
+                                "properties": parameters,
+                                "required": required
+                            }
+                        });
+
+                        // Counts what strict mode adds to the schema, and the flag itself.
+                        if Self::STRICT {
+                            let parameters_tokens = ::openai_func_enums::count_tokens(&function_json["parameters"].to_string());
+                            ::openai_func_enums::make_schema_strict(&mut function_json["parameters"]);
+                            function_json["strict"] = ::openai_func_enums::serde_json::Value::Bool(true);
+                            total_tokens += ::openai_func_enums::count_tokens(&function_json["parameters"].to_string())
+                                .saturating_sub(parameters_tokens);
+                            total_tokens += #STRICT_FLAG_TOKENS;
                         }
-                    });
 
-                    total_tokens += 43;
-                    total_tokens += #struct_name_tokens;
-                    total_tokens += #variant_desc_tokens;
+                        total_tokens += #FUNCTION_STRUCTURE_TOKENS;
+                        total_tokens += #struct_name_tokens;
+                        total_tokens += description_tokens;
+
+                        (function_json, total_tokens)
+                    })
+                }
+
+                /// Checks the arguments the model sent against the constraints declared on the
+                /// fields, like `min` or `pattern`, see `openai_func_enums::validate_arguments`.
+                pub fn validate_arguments(arguments: &str) -> Result<(), Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+                    let arguments: ::openai_func_enums::serde_json::Value = ::openai_func_enums::serde_json::from_str(arguments)?;
+                    ::openai_func_enums::validate_arguments(
+                        #function_name,
+                        &Self::function_json().0["parameters"],
+                        &arguments,
+                    )?;
+                    Ok(())
+                }
+
+                /// The schema declared with `#[func_enums(returns = ...)]`, if there is one.
+                pub fn return_schema() -> Option<::openai_func_enums::serde_json::Value> {
+                    #return_schema
+                }
+
+                #(#default_functions)*
+
+                /// Checks the defaults that go through `serde_json` fit their fields, the ones
+                /// the compiler can't check, like the default of an enum argument. A call is
+                /// only deserialized once they do, so a bad default fails the call instead of
+                /// panicking.
+                pub fn check_defaults() -> Result<(), ::openai_func_enums::serde_json::Error> {
+                    #(#default_checks)*
+                    Ok(())
+                }
+
+                /// The description the function is presented with, without a return schema. It is
+                /// shorter than `FULL_DESCRIPTION` when `FUNC_ENUMS_MAX_DESCRIPTION_TOKENS` made it
+                /// so.
+                pub const DESCRIPTION: &'static str = #variant_desc;
+
+                /// The doc comment of the variant.
+                pub const FULL_DESCRIPTION: &'static str = #full_description;
+
+                /// The tokens of `FULL_DESCRIPTION`, next to the `DESCRIPTION_TOKENS` the
+                /// function is sent with.
+                pub const FULL_DESCRIPTION_TOKENS: usize = #full_description_tokens;
+
+                /// The tokens of `DESCRIPTION`.
+                pub const DESCRIPTION_TOKENS: usize = #variant_desc_tokens;
+
+                /// Whether the function is marked `#[func_enums(risky)]`.
+                pub const RISKY: bool = #risky;
+
+                /// The scopes declared with `#[func_enums(scope = "...")]`.
+                pub const SCOPES: &'static [&'static str] = &[#(#scopes),*];
+
+                /// Whether the function is marked `#[func_enums(sandboxed)]`.
+                pub const SANDBOXED: bool = #sandboxed;
+
+                /// Whether the function is marked `#[func_enums(cpu_bound)]`, which has its calls
+                /// run on the runtime's blocking threads under the `Parallel` strategy.
+                pub const CPU_BOUND: bool = #cpu_bound;
+
+                /// Whether the function is marked `#[func_enums(strict)]`, which has the model
+                /// follow its schema exactly.
+                pub const STRICT: bool = #strict;
+
+                /// The timeout declared with `#[func_enums(timeout_ms = ...)]`.
+                pub const TIMEOUT: Option<::std::time::Duration> = #timeout;
+
+                /// An owned copy of `function_json`.
+                pub fn get_function_json() -> (::openai_func_enums::serde_json::Value, usize) {
+                    Self::function_json().clone()
+                }
+
+                /// The function definition for this struct, ready to use with your own
+                /// async-openai request builders.
+                pub fn to_function_object() -> ::openai_func_enums::async_openai::types::FunctionObject {
+                    let (function_json, _) = Self::function_json();
+
+                    ::openai_func_enums::async_openai::types::FunctionObject {
+                        name: #function_name.to_string(),
+                        description: function_json["description"].as_str().map(str::to_string),
+                        parameters: Some(function_json["parameters"].clone()),
+                        strict: Self::STRICT.then_some(true),
+                    }
+                }
+
+                /// The function definition for this struct wrapped in a function tool.
+                pub fn to_chat_completion_tool() -> ::openai_func_enums::async_openai::types::ChatCompletionTool {
+                    ::openai_func_enums::async_openai::types::ChatCompletionTool {
+                        r#type: ::openai_func_enums::async_openai::types::ChatCompletionToolType::Function,
+                        function: Self::to_function_object(),
+                    }
+                }
+            }
+
+            impl ::openai_func_enums::ToolSetFunction for #struct_name {
+                const NAME: &'static str = #function_name;
+            }
+
+            impl From<&#struct_name> for ::openai_func_enums::async_openai::types::FunctionObject {
+                fn from(_: &#struct_name) -> Self {
+                    #struct_name::to_function_object()
+                }
+            }
 
-                    (function_json, total_tokens)
+            impl From<&#struct_name> for ::openai_func_enums::async_openai::types::ChatCompletionTool {
+                fn from(_: &#struct_name) -> Self {
+                    #struct_name::to_chat_completion_tool()
                 }
             }
         });
 
         generated_structs.push(quote! {
-            #[derive(Clone, serde::Deserialize, Debug)]
+            #[derive(Clone, ::openai_func_enums::serde::Deserialize, Debug)]
+            #[serde(crate = "::openai_func_enums::serde")]
             pub struct #struct_name {
                 #(#fields)*
             }
         });
+
+        if let Some((path, _)) = &variant_attributes.description_file {
+            generated_structs.push(track_description_file(path));
+        }
+    }
+
+    #[cfg(any(
+        feature = "compile_embeddings_all",
+        feature = "compile_embeddings_update"
+    ))]
+    {
+        println!("Writing embeddings");
+        if let Err(e) = embed_functions(Path::new(&embed_path), pending_embeddings, &embed_model) {
+            return syn::Error::new_spanned(
+                &name,
+                format!("Failed to build the embedding archive: {}", e),
+            )
+            .to_compile_error()
+            .into();
+        }
     }
 
-    if !has_gpt_variant {
-        panic!("Enums that derive ToolSet must define a variant called 'GPT'.")
+    // Writing the descriptions manifest needs no network, so it happens whenever an archive
+    // path is set. `build_embedding_archive` turns it into the archive outside of compilation.
+    if let Ok(embed_path) = std::env::var("FUNC_ENUMS_EMBED_PATH") {
+        let manifest_path = std::env::var("FUNC_ENUMS_MANIFEST_PATH")
+            .map(std::path::PathBuf::from)
+            .unwrap_or_else(|_| {
+                openai_func_embeddings::manifest_path(std::path::Path::new(&embed_path))
+            });
+
+        // Other `ToolSet`s write to the same manifest, so their functions are kept.
+        let mut manifest =
+            openai_func_embeddings::DescriptionManifest::load(&manifest_path).unwrap_or_default();
+        manifest.embed_model = std::env::var("FUNC_ENUMS_EMBED_MODEL").ok();
+        manifest.merge(function_descriptions);
+
+        if let Err(e) = manifest.write(&manifest_path) {
+            panic!(
+                "Failed to write the descriptions manifest to {}: {}",
+                manifest_path.display(),
+                e
+            );
+        }
     }
 
     let all_function_calls = quote! {
-        pub fn all_function_jsons() -> (serde_json::Value, usize) {
-            let results = vec![#(#generated_struct_names::get_function_json(),)*];
-            let combined_json = serde_json::Value::Array(results.iter().map(|(json, _)| json.clone()).collect());
-            let total_tokens = results.iter().map(|(_, tokens)| tokens).sum();
-            (combined_json, total_tokens)
-        }
-
-        pub fn function_jsons_under_limit(ranked_func_names: Vec<String>) -> (serde_json::Value, usize) {
-            let results = vec![#(#generated_struct_names::get_function_json(),)*];
-
-            let limit = #max_func_tokens as usize;
-            let (functions_to_present, total_tokens) = results.into_iter().fold(
-                (vec![], 0_usize),
-                |(mut acc, token_count), (json, tokens)| {
-                    if token_count + tokens <= limit {
-                        acc.push((json.clone(), tokens));
-                        (acc, token_count + tokens)
-                    } else {
-                        (acc, token_count)
-                    }
-                },
-            );
+        /// The cached definitions of every function, see `function_json` on the generated
+        /// structs.
+        pub fn function_jsons() -> &'static [&'static (::openai_func_enums::serde_json::Value, usize)] {
+            static FUNCTION_JSONS: ::std::sync::OnceLock<Vec<&'static (::openai_func_enums::serde_json::Value, usize)>> = ::std::sync::OnceLock::new();
+            FUNCTION_JSONS.get_or_init(|| vec![#(#generated_struct_names::function_json(),)*])
+        }
+
+        /// Copies the chosen definitions into one JSON array, the only copy made of them while
+        /// building a request.
+        fn combine_function_jsons<'a>(
+            function_jsons: impl Iterator<Item = &'a (::openai_func_enums::serde_json::Value, usize)>,
+        ) -> (::openai_func_enums::serde_json::Value, usize) {
+            let mut total_tokens = 0;
+            let combined_json = function_jsons
+                .map(|(json, tokens)| {
+                    total_tokens += tokens;
+                    json.clone()
+                })
+                .collect();
+            (::openai_func_enums::serde_json::Value::Array(combined_json), total_tokens)
+        }
+
+        /// The definitions of `names`, in that order, skipping names that aren't functions of
+        /// this enum.
+        fn named_function_jsons<'a>(
+            names: impl Iterator<Item = &'a String> + 'a,
+        ) -> impl Iterator<Item = &'static (::openai_func_enums::serde_json::Value, usize)> + 'a {
+            names.filter_map(|name| {
+                Self::function_jsons()
+                    .iter()
+                    .copied()
+                    .find(|(json, _)| json["name"] == *name)
+            })
+        }
 
-            let combined_json = serde_json::Value::Array(functions_to_present.iter().map(|(json, _)| json.clone()).collect());
-            (combined_json, total_tokens)
+        /// Keeps functions, in order, for as long as they fit in `limit` tokens. With
+        /// `descriptions` they are counted as their translations.
+        fn under_function_token_limit<'a>(
+            function_jsons: impl Iterator<Item = &'static (::openai_func_enums::serde_json::Value, usize)> + 'a,
+            limit: usize,
+            descriptions: Option<&'a ::openai_func_enums::DescriptionCatalog>,
+        ) -> impl Iterator<Item = &'static (::openai_func_enums::serde_json::Value, usize)> + 'a {
+            let mut token_count = 0_usize;
+            function_jsons.filter(move |(json, tokens)| {
+                let tokens = Self::localized_tokens(json, *tokens, descriptions);
+                if token_count + tokens <= limit {
+                    token_count += tokens;
+                    true
+                } else {
+                    false
+                }
+            })
+        }
+
+        pub fn all_function_jsons() -> (::openai_func_enums::serde_json::Value, usize) {
+            Self::combine_function_jsons(Self::function_jsons().iter().copied())
+        }
+
+        fn localized_tokens(
+            json: &::openai_func_enums::serde_json::Value,
+            tokens: usize,
+            descriptions: Option<&::openai_func_enums::DescriptionCatalog>,
+        ) -> usize {
+            descriptions.map_or(tokens, |descriptions| descriptions.localized_tokens(json, tokens))
+        }
+
+        pub fn function_jsons_under_limit(_ranked_func_names: Vec<String>) -> (::openai_func_enums::serde_json::Value, usize) {
+            Self::combine_function_jsons(Self::under_function_token_limit(
+                Self::function_jsons().iter().copied(),
+                #max_func_tokens as usize,
+                None,
+            ))
         }
 
         pub fn function_jsons_allowed_with_required(
             allowed_func_names: Vec<String>,
             required_func_names: Option<Vec<String>>
-        ) -> (serde_json::Value, usize) {
-            let results = vec![#(#generated_struct_names::get_function_json(),)*];
+        ) -> (::openai_func_enums::serde_json::Value, usize) {
             let required_func_names = required_func_names.unwrap_or_default();
 
             // Take the vector of what has to be there just for it to function and add the ranked
             // functions to it, skipping ranked ones if it is already in the required list.
             let updated_func_names = required_func_names.iter()
-                .chain(allowed_func_names.iter().filter(|name| !required_func_names.contains(name)))
-                .cloned()
-                .collect::<Vec<String>>();
-
-            let (functions_to_present, total_tokens) = updated_func_names.iter()
-                .filter_map(|name| results.iter().find(|(json, _)| json["name"] == *name))
-                .fold((vec![], 0_usize), |(mut acc, token_count), (json, tokens)| {
-                    acc.push((json.clone(), tokens));
-                    (acc, token_count + tokens)
-                });
+                .chain(allowed_func_names.iter().filter(|name| !required_func_names.contains(name)));
 
-            let combined_json = serde_json::Value::Array(functions_to_present.iter().map(|(json, _)| json.clone()).collect());
-            (combined_json, total_tokens)
+            Self::combine_function_jsons(Self::named_function_jsons(updated_func_names))
         }
 
         pub fn function_jsons_with_required_under_limit(
             ranked_func_names: Vec<String>,
             required_func_names: Option<Vec<String>>
-        ) -> (serde_json::Value, usize) {
-            let results = vec![#(#generated_struct_names::get_function_json(),)*];
-            let required_func_names = required_func_names.unwrap_or_default();
-
-            // Take the vector of what has to be there just for it to function and add the ranked
-            // functions to it, skipping ranked ones if it is already in the required list.
-            let updated_func_names = required_func_names.iter()
-                .chain(ranked_func_names.iter().filter(|name| !required_func_names.contains(name)))
-                .cloned()
-                .collect::<Vec<String>>();
+        ) -> (::openai_func_enums::serde_json::Value, usize) {
+            Self::localized_function_jsons_with_required_under_limit(ranked_func_names, required_func_names, None)
+        }
 
-            let limit = #max_func_tokens as usize;
+        /// `function_jsons_with_required_under_limit` with the functions counted as the
+        /// translations of `descriptions`, which `build_request` swaps in afterwards.
+        pub fn localized_function_jsons_with_required_under_limit(
+            ranked_func_names: Vec<String>,
+            required_func_names: Option<Vec<String>>,
+            descriptions: Option<&::openai_func_enums::DescriptionCatalog>,
+        ) -> (::openai_func_enums::serde_json::Value, usize) {
+            let required_func_names = required_func_names.unwrap_or_default();
 
-            let (functions_to_present, total_tokens) = updated_func_names.iter()
-                .filter_map(|name| results.iter().find(|(json, _)| json["name"] == *name))
-                .fold((vec![], 0_usize), |(mut acc, token_count), (json, tokens)| {
-                    if token_count + tokens <= limit {
-                        acc.push((json.clone(), tokens));
-                        (acc, token_count + tokens)
-                    } else {
-                        (acc, token_count)
-                    }
-                });
+            // Required functions are always sent, and the ranked ones fill what is left of the
+            // limit, skipping ranked ones if they are already in the required list.
+            let required_jsons: Vec<_> = Self::named_function_jsons(required_func_names.iter()).collect();
+            let required_tokens: usize = required_jsons
+                .iter()
+                .map(|(json, tokens)| Self::localized_tokens(json, *tokens, descriptions))
+                .sum();
+            let ranked_jsons = Self::under_function_token_limit(
+                Self::named_function_jsons(
+                    ranked_func_names.iter().filter(|name| !required_func_names.contains(name)),
+                ),
+                (#max_func_tokens as usize).saturating_sub(required_tokens),
+                descriptions,
+            );
 
-            let combined_json = serde_json::Value::Array(functions_to_present.iter().map(|(json, _)| json.clone()).collect());
-            (combined_json, total_tokens)
+            Self::combine_function_jsons(required_jsons.into_iter().chain(ranked_jsons))
         }
     };
 
-    {
-        generated_clap_gpt_enum.push(quote! {
-            pub enum CommandsGPT {
-                GPT { a: String },
-            }
-        });
-    }
+    // The functions generated for the enum are associated functions of this type.
+    let commands_gpt_struct = quote! {
+        pub struct #commands_gpt;
+    };
 
-    let struct_names: Vec<String> = generated_struct_names
-        .iter()
-        .map(|name| format!("{}", name))
-        .collect();
+    // Commands are handed the run's state as the declared type, or `()` when there isn't one.
+    // `run` takes the state as its last parameter when there is one.
+    // A run without the state fails before it sends anything, rather than once for every call.
+    let (run_state_param, run_state_field, state_check) = match &state {
+        Some(state) => (
+            quote! { state: ::std::sync::Arc<#state>, },
+            quote! { state: Some(::openai_func_enums::RunState::from_arc(state)), },
+            quote! { ::openai_func_enums::state_of::<#state>(config)?; },
+        ),
+        None => (quote! {}, quote! {}, quote! {}),
+    };
+    let (state_type, state_fn, run_with_state) = match &state {
+        Some(state) => (
+            quote! { #state },
+            quote! {
+                /// The state of the current run, which every command is handed.
+                fn state() -> Result<::std::sync::Arc<#state>, Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+                    Ok(::openai_func_enums::current_state::<#state>()?)
+                }
+            },
+            quote! {
+                /// `run_with_config` with `state` as the `RunConfig::state` every command is
+                /// handed.
+                #[allow(clippy::too_many_arguments)]
+                pub async fn run_with_state(
+                    prompt: &str,
+                    model_name: &str,
+                    request_token_limit: Option<usize>,
+                    max_response_tokens: Option<u16>,
+                    custom_system_message: Option<(String, usize)>,
+                    execution_strategy: ::openai_func_enums::ToolCallExecutionStrategy,
+                    allowed_functions: Option<Vec<String>>,
+                    required_functions: Option<Vec<String>>,
+                    logger: ::std::sync::Arc<::openai_func_enums::Logger>,
+                    state: ::std::sync::Arc<#state>,
+                    config: &::openai_func_enums::RunConfig,
+                ) -> Result<::openai_func_enums::RunOutput, Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+                    let config = ::openai_func_enums::RunConfig {
+                        state: Some(::openai_func_enums::RunState::from_arc(state)),
+                        ..config.clone()
+                    };
+
+                    Self::run_with_config(
+                        prompt,
+                        model_name,
+                        request_token_limit,
+                        max_response_tokens,
+                        custom_system_message,
+                        execution_strategy,
+                        allowed_functions,
+                        required_functions,
+                        logger,
+                        &config,
+                    ).await
+                }
+            },
+        ),
+        None => (
+            quote! { () },
+            quote! {
+                fn state() -> Result<::std::sync::Arc<()>, Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+                    Ok(::std::sync::Arc::new(()))
+                }
+            },
+            quote! {},
+        ),
+    };
 
     let match_arms: Vec<_> = generated_struct_names
         .iter()
-        .map(|struct_name| {
-            let response_name = format_ident!("{}", struct_name);
-
+        .zip(&response_variant_names)
+        .map(|(response_name, variant_name)| {
             quote! {
-                Ok(FunctionResponse::#response_name(response)) => {
+                Ok(#function_response::#variant_name(response)) => {
                     let result = response.execute_command();
-                    let command_clone = command.clone();
+                    let state = Self::state();
                     let custom_system_message_clone = custom_system_message.clone();
                     let logger_clone = logger.clone();
-                    let command_lock = command_clone.lock().await;
-                    let command_inner_value = command_lock.as_ref().cloned();
-                    drop(command_lock);
 
-                    let run_result = result.run(execution_strategy_clone, command_inner_value, logger_clone, custom_system_message_clone).await;
+                    let run_result = ::openai_func_enums::run_tool_future(
+                        &mut tool_call_result,
+                        #response_name::SANDBOXED,
+                        #response_name::TIMEOUT,
+                        async move {
+                            let state = state?;
+                            ::openai_func_enums::RunCommand::<#state_type>::run(&result, execution_strategy_clone, arguments, logger_clone, custom_system_message_clone, &state).await
+                        },
+                    ).await;
                     match run_result {
                         Ok(run_result) => {
-                            {
-                                let prior_result_clone = prior_result.clone();
-                                let mut prior_result_lock = prior_result_clone.lock().await;
-                                *prior_result_lock = run_result.0;
-
-                                let command_clone = command.clone();
-                                let mut command_lock = command_clone.lock().await;
-                                *command_lock = run_result.1;
-
-                                let custom_system_message_clone = custom_system_message.clone();
+                            let output = ::openai_func_enums::sanitize_tool_output(&mut tool_call_result, run_result.text_output());
+                            let output = ::openai_func_enums::validate_tool_output(&mut tool_call_result, #response_name::return_schema(), output);
+                            tool_call_result.next_arguments = run_result.next_arguments;
+                            if output.is_some() {
+                                tool_call_result.value = ::openai_func_enums::sanitize_tool_value(&mut tool_call_result, run_result.value);
                             }
-                            return Ok(());
+                            tool_call_result.output = output;
+                            return Ok(tool_call_result);
                         }
                         Err(e) => {
                             println!("{:#?}", e);
+                            tool_call_result.error = Some(e.to_string());
                         }
                     }
                 }
@@ -930,27 +1969,34 @@ pub fn derive_subcommand_gpt(input: TokenStream) -> TokenStream {
     // TODO: reload this shit into your head.
     let match_arms_no_return: Vec<_> = generated_struct_names
         .iter()
-        .map(|struct_name| {
-            let response_name = format_ident!("{}", struct_name);
-
+        .zip(&response_variant_names)
+        .map(|(response_name, variant_name)| {
             quote! {
-                Ok(FunctionResponse::#response_name(response)) => {
+                Ok(#function_response::#variant_name(response)) => {
                     let result = response.execute_command();
-                    let run_result = result.run(execution_strategy_clone, None, logger_clone, custom_system_message_clone).await;
+                    let state = Self::state();
+                    let run_result = ::openai_func_enums::run_tool_future(
+                        &mut tool_call_result,
+                        #response_name::SANDBOXED,
+                        #response_name::TIMEOUT,
+                        async move {
+                            let state = state?;
+                            ::openai_func_enums::RunCommand::<#state_type>::run(&result, execution_strategy_clone, None, logger_clone, custom_system_message_clone, &state).await
+                        },
+                    ).await;
                     match run_result {
                         Ok(run_result) => {
-                            {
-                                // Feels like this is a dead lock.
-                                // Update: isn't.
-                                let mut prior_result_lock = prior_result_clone.lock().await;
-                                *prior_result_lock = run_result.0;
-
-                                let mut command_lock = command_clone.lock().await;
-                                *command_lock = run_result.1;
+                            let output = ::openai_func_enums::sanitize_tool_output(&mut tool_call_result, run_result.text_output());
+                            let output = ::openai_func_enums::validate_tool_output(&mut tool_call_result, #response_name::return_schema(), output);
+                            tool_call_result.next_arguments = run_result.next_arguments;
+                            if output.is_some() {
+                                tool_call_result.value = ::openai_func_enums::sanitize_tool_value(&mut tool_call_result, run_result.value);
                             }
+                            tool_call_result.output = output;
                         }
                         Err(e) => {
                             println!("{:#?}", e);
+                            tool_call_result.error = Some(e.to_string());
                         }
                     }
                 }
@@ -960,25 +2006,250 @@ pub fn derive_subcommand_gpt(input: TokenStream) -> TokenStream {
 
     #[cfg(feature = "function_filtering")]
     let filtering_delegate = quote! {
-        openai_func_enums::get_tools_limited(CommandsGPT::function_jsons_with_required_under_limit, allowed_functions, required_functions)?
+        ::openai_func_enums::get_tools_limited(
+            |ranked, required| #commands_gpt::localized_function_jsons_with_required_under_limit(ranked, required, config.descriptions.as_deref()),
+            allowed_functions,
+            required_functions,
+        )?
     };
 
     #[cfg(not(feature = "function_filtering"))]
     let filtering_delegate = quote! {
-        openai_func_enums::get_tools_limited(CommandsGPT::function_jsons_allowed_with_required, allowed_functions, required_functions)?
+        ::openai_func_enums::get_tools_limited(#commands_gpt::function_jsons_allowed_with_required, allowed_functions, required_functions)?
+    };
+
+    #[cfg(feature = "genai")]
+    let run_with_genai = quote! {
+        /// `run_with_config` with the requests sent through the genai crate, to use the same
+        /// tools with Claude, Gemini, Ollama and the other backends genai supports. The backend
+        /// is picked from `model_name`.
+        #[allow(clippy::too_many_arguments)]
+        pub async fn run_with_genai(
+            prompt: &str,
+            model_name: &str,
+            request_token_limit: Option<usize>,
+            max_response_tokens: Option<u16>,
+            custom_system_message: Option<(String, usize)>,
+            execution_strategy: ::openai_func_enums::ToolCallExecutionStrategy,
+            allowed_functions: Option<Vec<String>>,
+            required_functions: Option<Vec<String>>,
+            logger: ::std::sync::Arc<::openai_func_enums::Logger>,
+            client: &::openai_func_enums::genai::Client,
+            config: &::openai_func_enums::RunConfig,
+        ) -> Result<::openai_func_enums::RunOutput, Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+            let config = ::openai_func_enums::RunConfig {
+                provider: Some(::std::sync::Arc::new(::openai_func_enums::GenaiProvider::new(client.clone()))),
+                ..config.clone()
+            };
+
+            Self::run_with_config(
+                prompt,
+                model_name,
+                request_token_limit,
+                max_response_tokens,
+                custom_system_message,
+                execution_strategy,
+                allowed_functions,
+                required_functions,
+                logger,
+                &config,
+            ).await
+        }
+    };
+
+    #[cfg(not(feature = "genai"))]
+    let run_with_genai = quote! {};
+
+    let run_with_client = quote! {
+        /// `run_with_config` with the OpenAI requests sent through `client`, so that a client
+        /// configured once with its retries, proxy and timeouts is shared by every run along
+        /// with its connection pool. See `RunConfig::openai_client`.
+        #[allow(clippy::too_many_arguments)]
+        pub async fn run_with_client(
+            prompt: &str,
+            model_name: &str,
+            request_token_limit: Option<usize>,
+            max_response_tokens: Option<u16>,
+            custom_system_message: Option<(String, usize)>,
+            execution_strategy: ::openai_func_enums::ToolCallExecutionStrategy,
+            allowed_functions: Option<Vec<String>>,
+            required_functions: Option<Vec<String>>,
+            logger: ::std::sync::Arc<::openai_func_enums::Logger>,
+            client: ::std::sync::Arc<::openai_func_enums::async_openai::Client<::openai_func_enums::async_openai::config::OpenAIConfig>>,
+            config: &::openai_func_enums::RunConfig,
+        ) -> Result<::openai_func_enums::RunOutput, Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+            let config = ::openai_func_enums::RunConfig {
+                openai_client: Some(client),
+                ..config.clone()
+            };
+
+            Self::run_with_config(
+                prompt,
+                model_name,
+                request_token_limit,
+                max_response_tokens,
+                custom_system_message,
+                execution_strategy,
+                allowed_functions,
+                required_functions,
+                logger,
+                &config,
+            ).await
+        }
+    };
+
+    let run_forcing = quote! {
+        /// `run_with_config` with the model made to call the function `F`, like
+        /// `run_forcing::<GetCurrentWeather>(...)`, for pipelines that always extract the same
+        /// arguments. See `ToolChoiceMode::Named`.
+        #[allow(clippy::too_many_arguments)]
+        pub async fn run_forcing<F: ::openai_func_enums::ToolSetFunction>(
+            prompt: &str,
+            model_name: &str,
+            request_token_limit: Option<usize>,
+            max_response_tokens: Option<u16>,
+            custom_system_message: Option<(String, usize)>,
+            execution_strategy: ::openai_func_enums::ToolCallExecutionStrategy,
+            allowed_functions: Option<Vec<String>>,
+            required_functions: Option<Vec<String>>,
+            logger: ::std::sync::Arc<::openai_func_enums::Logger>,
+            config: &::openai_func_enums::RunConfig,
+        ) -> Result<::openai_func_enums::RunOutput, Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+            let config = ::openai_func_enums::RunConfig {
+                tool_choice: ::openai_func_enums::ToolChoiceMode::forcing::<F>(),
+                ..config.clone()
+            };
+
+            Self::run_with_config(
+                prompt,
+                model_name,
+                request_token_limit,
+                max_response_tokens,
+                custom_system_message,
+                execution_strategy,
+                allowed_functions,
+                required_functions,
+                logger,
+                &config,
+            ).await
+        }
+    };
+
+    let run_cancellable = quote! {
+        /// `run_with_config` stopped when `cancellation_token` is cancelled, by a Ctrl-C
+        /// handler or a server's request timeout. The requests in flight and the tool calls
+        /// still running are dropped and the run returns `RunCancelled`. See
+        /// `RunConfig::cancellation_token`.
+        #[allow(clippy::too_many_arguments)]
+        pub async fn run_cancellable(
+            prompt: &str,
+            model_name: &str,
+            request_token_limit: Option<usize>,
+            max_response_tokens: Option<u16>,
+            custom_system_message: Option<(String, usize)>,
+            execution_strategy: ::openai_func_enums::ToolCallExecutionStrategy,
+            allowed_functions: Option<Vec<String>>,
+            required_functions: Option<Vec<String>>,
+            logger: ::std::sync::Arc<::openai_func_enums::Logger>,
+            cancellation_token: ::openai_func_enums::CancellationToken,
+            config: &::openai_func_enums::RunConfig,
+        ) -> Result<::openai_func_enums::RunOutput, Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+            let config = ::openai_func_enums::RunConfig {
+                cancellation_token: Some(cancellation_token),
+                ..config.clone()
+            };
+
+            Self::run_with_config(
+                prompt,
+                model_name,
+                request_token_limit,
+                max_response_tokens,
+                custom_system_message,
+                execution_strategy,
+                allowed_functions,
+                required_functions,
+                logger,
+                &config,
+            ).await
+        }
+    };
+
+    let tool_set_impl = quote! {
+        #[::openai_func_enums::async_trait::async_trait]
+        impl ::openai_func_enums::ToolSet for #name {
+            fn build_request(
+                prompt: &str,
+                model_name: &str,
+                allowed_functions: Option<Vec<String>>,
+                required_functions: Option<Vec<String>>,
+                config: &::openai_func_enums::RunConfig,
+            ) -> Result<::openai_func_enums::async_openai::types::CreateChatCompletionRequest, Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+                #commands_gpt::build_request(
+                    prompt,
+                    model_name,
+                    None,
+                    None,
+                    &None,
+                    allowed_functions,
+                    required_functions,
+                    config,
+                )
+            }
+
+            fn request_token_limit(model_name: &str) -> usize {
+                ::openai_func_enums::model_request_token_limit(model_name, #max_response_tokens_const as usize)
+                    .unwrap_or(#max_request_tokens_const)
+            }
+
+            async fn run_request(
+                prompt: &str,
+                request: ::openai_func_enums::async_openai::types::CreateChatCompletionRequest,
+                execution_strategy: ::openai_func_enums::ToolCallExecutionStrategy,
+                logger: ::std::sync::Arc<::openai_func_enums::Logger>,
+                config: &::openai_func_enums::RunConfig,
+            ) -> Result<::openai_func_enums::RunOutput, Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+                let config = &config.start_run(prompt).await;
+                #commands_gpt::run_request(
+                    prompt,
+                    request,
+                    None,
+                    None,
+                    execution_strategy,
+                    logger,
+                    config,
+                ).await
+            }
+        }
     };
 
     let commands_gpt_impl = quote! {
-        #[derive(Clone, Debug, serde::Deserialize)]
-        pub enum FunctionResponse {
+        #[derive(Clone, Debug, ::openai_func_enums::serde::Deserialize)]
+        #[serde(crate = "::openai_func_enums::serde")]
+        pub enum #function_response {
             #(
-                #generated_struct_names(#generated_struct_names),
+                #response_variant_names(#generated_struct_names),
             )*
         }
 
-        impl CommandsGPT {
+        impl #commands_gpt {
+            /// The variant of the enum that takes a prompt, named with
+            /// `#[func_enums(entry = "...")]` or else `GPT`, if there is one.
+            pub const ENTRY_VARIANT: Option<&'static str> = #entry_variant;
+
             #all_function_calls
 
+            #run_with_client
+
+            #run_forcing
+
+            #run_cancellable
+
+            #run_with_state
+
+            #run_with_genai
+
+            #state_fn
+
             fn to_snake_case(camel_case: &str) -> String {
                 let mut snake_case = String::new();
                 for (i, ch) in camel_case.char_indices() {
@@ -990,38 +2261,93 @@ pub fn derive_subcommand_gpt(input: TokenStream) -> TokenStream {
                 snake_case
             }
 
-            pub fn parse_gpt_function_call(function_call: &FunctionCall) -> Result<FunctionResponse, Box<dyn std::error::Error + Send + Sync + 'static>> {
-                match function_call.name.as_str() {
+            /// The return schema declared for the function called `name`, see
+            /// `openai_func_enums::ReturnSchema`.
+            pub fn return_schema(name: &str) -> Option<::openai_func_enums::serde_json::Value> {
+                match name {
                     #(
-                    #struct_names => {
-                        match serde_json::from_str::<#generated_struct_names>(&function_call.arguments) {
-                            Ok(arguments) => Ok(FunctionResponse::#generated_struct_names(arguments)),
-                            Err(_) => {
-                                let snake_case_args = function_call.arguments
-                                    .as_str()
-                                    .split(',')
-                                    .map(|s| {
-                                        let mut parts = s.split(':');
-                                        match (parts.next(), parts.next()) {
-                                            (Some(key), Some(value)) => {
-                                                let key_trimmed = key.trim_matches(|c: char| !c.is_alphanumeric()).trim();
-                                                let key_snake_case = Self::to_snake_case(key_trimmed);
-                                                format!("\"{}\":{}", key_snake_case, value)
-                                            },
-                                            _ => s.to_string()
-                                        }
-                                    })
-                                    .collect::<Vec<String>>()
-                                    .join(",");
-
+                    #function_names => #generated_struct_names::return_schema(),
+                    )*
+                    _ => None,
+                }
+            }
+
+            /// Whether the function called `name` is marked `#[func_enums(risky)]`, see
+            /// `openai_func_enums::ToolCallVerifier`.
+            pub fn is_risky(name: &str) -> bool {
+                match name {
+                    #(
+                    #function_names => #generated_struct_names::RISKY,
+                    )*
+                    _ => false,
+                }
+            }
+
+            /// Whether the function called `name` is marked `#[func_enums(cpu_bound)]`.
+            pub fn is_cpu_bound(name: &str) -> bool {
+                match name {
+                    #(
+                    #function_names => #generated_struct_names::CPU_BOUND,
+                    )*
+                    _ => false,
+                }
+            }
+
+            /// The scopes the function called `name` needs, see
+            /// `openai_func_enums::RunConfig::granted_scopes`.
+            pub fn scopes(name: &str) -> &'static [&'static str] {
+                match name {
+                    #(
+                    #function_names => #generated_struct_names::SCOPES,
+                    )*
+                    _ => &[],
+                }
+            }
+
+            pub fn parse_gpt_function_call(function_call: &::openai_func_enums::async_openai::types::FunctionCall) -> Result<#function_response, Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+                Self::parse_gpt_function_call_with_repair(function_call).map(|(response, _)| response)
+            }
+
+            /// Parses a function call the same way `parse_gpt_function_call` does, and also
+            /// returns the repaired arguments when the ones the model sent only deserialized
+            /// after being converted to snake_case keys.
+            pub fn parse_gpt_function_call_with_repair(function_call: &::openai_func_enums::async_openai::types::FunctionCall) -> Result<(#function_response, Option<String>), Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+                match function_call.name.as_str() {
+                    #(
+                    #function_names => {
+                        #generated_struct_names::check_defaults()?;
+                        match ::openai_func_enums::serde_json::from_str::<#generated_struct_names>(&function_call.arguments) {
+                            Ok(arguments) => {
+                                #generated_struct_names::validate_arguments(&function_call.arguments)?;
+                                Ok((#function_response::#response_variant_names(arguments), None))
+                            }
+                            Err(_) => {
+                                let snake_case_args = function_call.arguments
+                                    .as_str()
+                                    .split(',')
+                                    .map(|s| {
+                                        let mut parts = s.split(':');
+                                        match (parts.next(), parts.next()) {
+                                            (Some(key), Some(value)) => {
+                                                let key_trimmed = key.trim_matches(|c: char| !c.is_alphanumeric()).trim();
+                                                let key_snake_case = Self::to_snake_case(key_trimmed);
+                                                format!("\"{}\":{}", key_snake_case, value)
+                                            },
+                                            _ => s.to_string()
+                                        }
+                                    })
+                                    .collect::<Vec<String>>()
+                                    .join(",");
+
                                 let snake_case_args = format!("{{{}", snake_case_args);
 
-                                match serde_json::from_str::<#generated_struct_names>(&snake_case_args) {
+                                match ::openai_func_enums::serde_json::from_str::<#generated_struct_names>(&snake_case_args) {
                                     Ok(arguments) => {
-                                        Ok(FunctionResponse::#generated_struct_names(arguments))
+                                        #generated_struct_names::validate_arguments(&snake_case_args)?;
+                                        Ok((#function_response::#response_variant_names(arguments), Some(snake_case_args)))
                                     }
                                     Err(e) => {
-                                        Err(Box::new(openai_func_enums::CommandError::new("There was an issue deserializing function arguments.")))
+                                        Err(Box::new(::openai_func_enums::CommandError::new("There was an issue deserializing function arguments.")))
                                     }
                                 }
                             }
@@ -1030,261 +2356,769 @@ pub fn derive_subcommand_gpt(input: TokenStream) -> TokenStream {
                     )*
                     _ => {
                         println!("{:#?}", function_call);
-                        Err(Box::new(openai_func_enums::CommandError::new("Unknown function name")))
+                        Err(Box::new(::openai_func_enums::CommandError::new("Unknown function name")))
                     }
                 }
             }
 
-            fn calculate_token_count(text: &str) -> usize {
-                let bpe = tiktoken_rs::cl100k_base().unwrap();
-                bpe.encode_ordinary(&text).len()
+            /// Parses a tool call and starts the `ToolCallResult` that records what happened to it.
+            fn parse_tool_call(
+                tool_call: &::openai_func_enums::async_openai::types::ChatCompletionMessageToolCall,
+            ) -> (Result<#function_response, Box<dyn ::std::error::Error + Send + Sync + 'static>>, ::openai_func_enums::ToolCallResult) {
+                let mut tool_call_result = ::openai_func_enums::ToolCallResult::new(tool_call);
+
+                let parsed = match Self::parse_gpt_function_call_with_repair(&tool_call.function) {
+                    Ok((response, repaired_arguments)) => {
+                        tool_call_result.attempts = if repaired_arguments.is_some() { 2 } else { 1 };
+                        tool_call_result.repaired_arguments = repaired_arguments;
+                        Ok(response)
+                    }
+                    Err(e) => {
+                        tool_call_result.error = Some(e.to_string());
+                        Err(e)
+                    }
+                };
+
+                (parsed, tool_call_result)
             }
 
+            /// Sends the prompt and runs the tool calls the model makes. `command` holds the
+            /// arguments the first command is handed, and afterwards the arguments the last one
+            /// returned for the next. `prior_result` gets the output of the last call, and is
+            /// left alone when the model answers in text instead. `run_with_config` returns the
+            /// tool outputs and the text separately, along with the finish reason and the
+            /// tokens and cost of the run's requests.
             #[allow(clippy::too_many_arguments)]
             pub async fn run(
-                prompt: &String,
+                prompt: &str,
                 model_name: &str,
                 request_token_limit: Option<usize>,
                 max_response_tokens: Option<u16>,
                 custom_system_message: Option<(String, usize)>,
-                prior_result: std::sync::Arc<tokio::sync::Mutex<Option<String>>>,
-                execution_strategy: ToolCallExecutionStrategy,
-                command: std::sync::Arc<tokio::sync::Mutex<Option<Vec<String>>>>,
+                prior_result: ::std::sync::Arc<::tokio::sync::Mutex<Option<String>>>,
+                execution_strategy: ::openai_func_enums::ToolCallExecutionStrategy,
+                command: ::std::sync::Arc<::tokio::sync::Mutex<Option<Vec<String>>>>,
                 allowed_functions: Option<Vec<String>>,
                 required_functions: Option<Vec<String>>,
-                logger: std::sync::Arc<openai_func_enums::Logger>,
-            ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+                logger: ::std::sync::Arc<::openai_func_enums::Logger>,
+                #run_state_param
+            ) -> Result<(), Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+                let current = ::openai_func_enums::RunConfig::current();
+                let config = ::openai_func_enums::RunConfig {
+                    tool_arguments: command.lock().await.clone(),
+                    #run_state_field
+                    ..current
+                };
+                let output = Self::run_with_config(
+                    prompt,
+                    model_name,
+                    request_token_limit,
+                    max_response_tokens,
+                    custom_system_message,
+                    execution_strategy,
+                    allowed_functions,
+                    required_functions,
+                    logger,
+                    &config,
+                ).await?;
+
+                if let Some(tool_call) = output.tool_calls.last() {
+                    *prior_result.lock().await = tool_call.output.clone();
+                    *command.lock().await = tool_call.next_arguments.clone();
+                }
+
+                Ok(())
+            }
 
-                let tool_args: (Vec<async_openai::types::ChatCompletionTool>, usize) = if let Some(allowed_functions) = allowed_functions {
+            /// Builds the request `run_with_config` sends, presenting the tools that pass the
+            /// allowed/required filtering along with the runtime tools of `config`.
+            #[allow(clippy::too_many_arguments)]
+            pub fn build_request(
+                prompt: &str,
+                model_name: &str,
+                request_token_limit: Option<usize>,
+                max_response_tokens: Option<u16>,
+                custom_system_message: &Option<(String, usize)>,
+                allowed_functions: Option<Vec<String>>,
+                required_functions: Option<Vec<String>>,
+                config: &::openai_func_enums::RunConfig,
+            ) -> Result<::openai_func_enums::async_openai::types::CreateChatCompletionRequest, Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+                let model_name = config.model.as_deref().unwrap_or(model_name);
+                let request_token_limit = config.request_token_limit.or(request_token_limit);
+                let max_response_tokens = config.max_response_tokens.or(max_response_tokens);
+                let allowed_functions = allowed_functions.map(|allowed| config.with_preferred_functions(allowed));
+                let required_functions = config.required_functions(required_functions);
+                let required_functions = config.tool_choice.required_functions(required_functions)
+                    .map(|required| config.without_banned_functions(required));
+                let registry_tool_args = config.tools.chat_completion_tools(
+                    allowed_functions.as_deref(),
+                    required_functions.as_deref(),
+                )?;
+
+                let mut tool_args: (Vec<::openai_func_enums::async_openai::types::ChatCompletionTool>, usize) = if let Some(allowed_functions) = allowed_functions {
                     if !allowed_functions.is_empty() {
+                        // Banned functions are left out before the token limit is applied, so
+                        // the next ranked functions take their place.
+                        let allowed_functions = config.without_banned_functions(allowed_functions);
                         #filtering_delegate
                     } else {
-                        get_tool_chat_completion_args(CommandsGPT::all_function_jsons)?
+                        ::openai_func_enums::get_tool_chat_completion_args(#commands_gpt::all_function_jsons)?
                     }
 
                 } else {
-                    get_tool_chat_completion_args(CommandsGPT::all_function_jsons)?
+                    ::openai_func_enums::get_tool_chat_completion_args(#commands_gpt::all_function_jsons)?
                 };
 
+                tool_args.0.extend(registry_tool_args.0);
+                tool_args.1 += registry_tool_args.1;
+                config.prefer_tools(&mut tool_args.0);
+                config.remove_banned_tools(&mut tool_args, #commands_gpt::function_jsons());
+                config.remove_ungranted_tools(&mut tool_args, #commands_gpt::function_jsons(), #commands_gpt::scopes);
+                if config.final_answer {
+                    let (final_answer_tool, final_answer_tokens) = ::openai_func_enums::FinalAnswer::chat_completion_tool();
+                    tool_args.0.push(final_answer_tool);
+                    tool_args.1 += final_answer_tokens;
+                }
+                // The functions were chosen by the size of their translations, which go in now
+                // that the list is complete.
+                if let Some(descriptions) = &config.descriptions {
+                    descriptions.localize_tools(&mut tool_args);
+                }
+
                 let custom_system_message_clone = custom_system_message.clone();
                 let (this_system_message, system_message_tokens) = match custom_system_message_clone {
                     Some((message, tokens)) => {
                         (message.clone(), tokens)
                     }
-                    None => (String::from("You are a helpful function calling bot."), 7)
+                    None => (String::from(#DEFAULT_SYSTEM_MESSAGE), #default_system_message_tokens)
                 };
 
-                let word_count = prompt.split_whitespace().count();
+                let request_token_total = tool_args.1 + system_message_tokens + ::openai_func_enums::estimate_prompt_tokens(prompt);
 
-                let request_token_total = tool_args.1 + system_message_tokens + if word_count < 200 {
-                    ((word_count as f64 / 0.75).round() as usize)
-                } else {
-                    Self::calculate_token_count(prompt.as_str())
-                };
+                // Without an explicit limit, the model's own context window is a better guide
+                // than the compile time limit, which may have been set for a different model.
+                let max_response_tokens = max_response_tokens.unwrap_or(#max_response_tokens_const);
+                let request_token_limit = request_token_limit
+                    .or_else(|| ::openai_func_enums::model_request_token_limit(model_name, max_response_tokens as usize))
+                    .unwrap_or(#max_request_tokens_const);
 
-                if request_token_total > request_token_limit.unwrap_or(FUNC_ENUMS_MAX_REQUEST_TOKENS)  {
-                    return Err(Box::new(openai_func_enums::CommandError::new("Request token count is too high")));
+                if request_token_total > request_token_limit {
+                    return Err(Box::new(::openai_func_enums::CommandError::new("Request token count is too high")));
                 }
 
                 let this_system_message_clone = this_system_message.clone();
 
-                let request = CreateChatCompletionRequestArgs::default()
-                    .max_tokens(max_response_tokens.unwrap_or(FUNC_ENUMS_MAX_RESPONSE_TOKENS))
+                let messages = config.few_shot_examples.request_messages(
+                    config.system_message_strategy,
+                    model_name,
+                    this_system_message_clone,
+                    prompt.to_string(),
+                    &tool_args.0,
+                    request_token_limit - request_token_total,
+                )?;
+
+                let mut request = ::openai_func_enums::async_openai::types::CreateChatCompletionRequestArgs::default();
+                config.max_tokens_field.apply(model_name, &mut request, max_response_tokens.into());
+                request
                     .model(model_name)
                     .temperature(0.0)
-                    .messages([ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessageArgs::default()
-                        .content(this_system_message_clone)
-                        .build()?),
-                    ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessageArgs::default()
-                        .content(prompt.to_string())
-                        .build()?)])
+                    .messages(messages)
                     .tools(tool_args.0)
-                    .tool_choice("auto")
-                    .build()?;
-
-                let client = Client::new();
-                let response_message = client
-                    .chat()
-                    .create(request)
-                    .await?
+                    .tool_choice(config.tool_choice.to_tool_choice());
+                if let Some(user) = &config.user {
+                    request.user(user);
+                }
+                if config.store {
+                    request.store(true);
+                }
+                if !config.metadata.is_empty() {
+                    request.metadata(::openai_func_enums::serde_json::json!(config.metadata));
+                }
+                if let Some(parallel_tool_calls) = config.parallel_tool_calls {
+                    request.parallel_tool_calls(parallel_tool_calls);
+                }
+
+                Ok(request.build()?)
+            }
+
+            /// Sends the same request as `run_with_config` and returns the tool calls the model
+            /// chose, without executing any of them. This is what evaluating tool selection
+            /// against a corpus needs, see `openai_func_enums::evaluate`.
+            #[allow(clippy::too_many_arguments)]
+            pub async fn select_tool_calls(
+                prompt: &str,
+                model_name: &str,
+                request_token_limit: Option<usize>,
+                max_response_tokens: Option<u16>,
+                custom_system_message: Option<(String, usize)>,
+                allowed_functions: Option<Vec<String>>,
+                required_functions: Option<Vec<String>>,
+                config: &::openai_func_enums::RunConfig,
+            ) -> Result<Vec<::openai_func_enums::async_openai::types::ChatCompletionMessageToolCall>, Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+                let allowed_functions = config.ranked_functions(prompt, allowed_functions).await?;
+                let request = Self::build_request(
+                    prompt,
+                    model_name,
+                    request_token_limit,
+                    max_response_tokens,
+                    &custom_system_message,
+                    allowed_functions,
+                    required_functions,
+                    config,
+                )?;
+
+                config.check_budget()?;
+                let response = config.cancellable(config.create_chat_completion(request)).await?;
+
+                Ok(response
                     .choices
-                    .get(0)
-                    .unwrap()
-                    .message
-                    .clone();
+                    .into_iter()
+                    .next()
+                    .and_then(|choice| choice.message.tool_calls)
+                    .unwrap_or_default())
+            }
 
-                if let Some(tool_calls) = response_message.tool_calls {
-                    if tool_calls.len() == 1 {
-                        let execution_strategy_clone = execution_strategy.clone();
-                        let custom_system_message_clone = custom_system_message.clone();
-
-                        match Self::parse_gpt_function_call(&tool_calls.first().unwrap().function) {
-                            #(#match_arms,)*
-                            Err(e) => {
-                                println!("{:#?}", e);
-                                return Err(Box::new(openai_func_enums::CommandError::new("Error running GPT command")));
+            /// Sends the prompt without any tools, asking for an answer that matches the schema of
+            /// `T`, and deserializes it. The schema goes out as a strict `json_schema` response
+            /// format, see `openai_func_enums::json_schema_response_format`, so the model's answer
+            /// is guaranteed to match it.
+            pub async fn run_structured<T: ::openai_func_enums::ToolArgs>(
+                prompt: &str,
+                model_name: &str,
+                max_response_tokens: Option<u16>,
+                custom_system_message: Option<(String, usize)>,
+                config: &::openai_func_enums::RunConfig,
+            ) -> Result<T, Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+                let model_name = config.model.as_deref().unwrap_or(model_name);
+                let max_response_tokens = config.max_response_tokens.or(max_response_tokens);
+                let (system_message, _) = custom_system_message
+                    .unwrap_or_else(|| (String::from("You are a helpful assistant."), 5));
+                let messages = config.system_message_strategy.messages(
+                    model_name,
+                    system_message,
+                    prompt.to_string(),
+                )?;
+
+                let mut request = ::openai_func_enums::async_openai::types::CreateChatCompletionRequestArgs::default();
+                config.max_tokens_field.apply(
+                    model_name,
+                    &mut request,
+                    max_response_tokens.unwrap_or(#max_response_tokens_const).into(),
+                );
+                request
+                    .model(model_name)
+                    .temperature(0.0)
+                    .messages(messages)
+                    .response_format(::openai_func_enums::json_schema_response_format::<T>());
+                if let Some(user) = &config.user {
+                    request.user(user);
+                }
+
+                config.check_budget()?;
+                let response = config.cancellable(config.create_chat_completion(request.build()?)).await?;
+                ::openai_func_enums::structured_response(&response)
+            }
+
+            /// Same as `run`, with additional options for how the request is made and how the
+            /// returned tool calls are executed. Returns a `RunOutput` describing each tool
+            /// call the model made.
+            #[allow(clippy::too_many_arguments)]
+            pub async fn run_with_config(
+                prompt: &str,
+                model_name: &str,
+                request_token_limit: Option<usize>,
+                max_response_tokens: Option<u16>,
+                custom_system_message: Option<(String, usize)>,
+                execution_strategy: ::openai_func_enums::ToolCallExecutionStrategy,
+                allowed_functions: Option<Vec<String>>,
+                required_functions: Option<Vec<String>>,
+                logger: ::std::sync::Arc<::openai_func_enums::Logger>,
+                config: &::openai_func_enums::RunConfig,
+            ) -> Result<::openai_func_enums::RunOutput, Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+                let config = &config.start_run(prompt).await;
+                let allowed_functions = config.ranked_functions(prompt, allowed_functions).await?;
+
+                let request = Self::build_request(
+                    prompt,
+                    model_name,
+                    request_token_limit,
+                    max_response_tokens,
+                    &custom_system_message,
+                    allowed_functions,
+                    required_functions,
+                    config,
+                )?;
+
+                Self::run_request(
+                    prompt,
+                    request,
+                    request_token_limit,
+                    custom_system_message,
+                    execution_strategy,
+                    logger,
+                    config,
+                ).await
+            }
+
+            /// Sends a request built with `build_request` and runs the tool calls the model
+            /// makes, like `run_with_config` does. The messages of the request can be replaced
+            /// first, to send a whole conversation. `prompt` is what the verifier checks calls
+            /// against, and `request_token_limit` the one the request was built with, which
+            /// follow-ups are kept under.
+            #[allow(clippy::too_many_arguments)]
+            pub async fn run_request(
+                prompt: &str,
+                mut request: ::openai_func_enums::async_openai::types::CreateChatCompletionRequest,
+                request_token_limit: Option<usize>,
+                custom_system_message: Option<(String, usize)>,
+                execution_strategy: ::openai_func_enums::ToolCallExecutionStrategy,
+                logger: ::std::sync::Arc<::openai_func_enums::Logger>,
+                config: &::openai_func_enums::RunConfig,
+            ) -> Result<::openai_func_enums::RunOutput, Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+                let execution_strategy = config.execution_strategy.clone().unwrap_or(execution_strategy);
+                #state_check
+                // The arguments of this run go to its first command, and after that those the
+                // last command returned for the next one. The runs its tools start don't get
+                // them.
+                let mut arguments = config.tool_arguments.clone();
+                // Meters the requests of this run, and of the runs its tools start, for
+                // `RunOutput::usage`.
+                let config = &::openai_func_enums::RunConfig {
+                    tool_arguments: None,
+                    ..config.with_nested_usage_meter()
+                };
+                let mut output = ::openai_func_enums::RunOutput::default();
+                let max_iterations = config.max_iterations.unwrap_or(1).max(1);
+                let first_follow_up = request.messages.len();
+                // One queue for every response of the run, so that the arrival order it breaks
+                // ties with carries on from one response to the next.
+                let mut queue = config.tool_call_queue();
+                loop {
+                    let recorded_request = config.session_recorder.as_ref().map(|_| request.clone());
+
+                    // Tools see the config as `RunConfig::current`, so nested runs inherit it.
+                    let iteration_output = config.clone().scope(Self::run_in_scope(
+                        prompt,
+                        request.clone(),
+                        custom_system_message.clone(),
+                        execution_strategy.clone(),
+                        arguments.clone(),
+                        logger.clone(),
+                        &mut queue,
+                        config,
+                    )).await?;
+
+                    // Tool calls that were stopped leave the run without an answer.
+                    if config.is_cancelled() {
+                        return Err(Box::new(::openai_func_enums::RunCancelled));
+                    }
+
+                    if let (Some(session_recorder), Some(request)) = (&config.session_recorder, recorded_request) {
+                        session_recorder.record(::openai_func_enums::RecordedSession::new(request, &iteration_output));
+                    }
+
+                    output.iterations += 1;
+                    let finished = iteration_output.tool_calls.is_empty()
+                        || iteration_output.final_answer.is_some()
+                        || output.iterations == max_iterations;
+                    request.messages.extend(iteration_output.follow_up_messages());
+                    if matches!(request.tool_choice, Some(::openai_func_enums::async_openai::types::ChatCompletionToolChoiceOption::Named(_))) {
+                        request.tool_choice = Some(::openai_func_enums::async_openai::types::ChatCompletionToolChoiceOption::Auto);
+                    }
+
+                    if let Some(tool_call) = iteration_output.tool_calls.last() {
+                        arguments = tool_call.next_arguments.clone();
+                    }
+                    output.tool_calls.extend(iteration_output.tool_calls);
+                    output.final_answer = iteration_output.final_answer;
+                    output.content = iteration_output.content;
+                    output.finish_reason = iteration_output.finish_reason;
+                    if finished {
+                        output.follow_up_request = Some(request);
+                        break;
+                    }
+                    config.fit_follow_up_request(&mut request, first_follow_up, request_token_limit, #max_request_tokens_const)?;
+                }
+                output.usage = config.usage_report();
+
+                config.emit(::openai_func_enums::RunEvent::Completed {
+                    depth: config.run_depth(),
+                    tool_calls: output.tool_calls.len(),
+                }).await;
+
+                Ok(output)
+            }
+
+            #[allow(clippy::too_many_arguments)]
+            async fn run_in_scope(
+                prompt: &str,
+                request: ::openai_func_enums::async_openai::types::CreateChatCompletionRequest,
+                custom_system_message: Option<(String, usize)>,
+                execution_strategy: ::openai_func_enums::ToolCallExecutionStrategy,
+                arguments: Option<Vec<String>>,
+                logger: ::std::sync::Arc<::openai_func_enums::Logger>,
+                queue: &mut ::openai_func_enums::ToolCallQueue,
+                config: &::openai_func_enums::RunConfig,
+            ) -> Result<::openai_func_enums::RunOutput, Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+                config.check_budget()?;
+                let mut output = ::openai_func_enums::RunOutput::default();
+                config.emit(::openai_func_enums::RunEvent::request_started(&request, config.run_depth())).await;
+                let calibration_request = config.token_calibration.as_ref().map(|_| request.clone());
+
+                if config.stream_tool_calls || config.text_sender.is_some() {
+                    let model_name = request.model.clone();
+                    let stream = config.cancellable(config.create_chat_completion_stream(request)).await?;
+                    let mut tool_call_stream = ::openai_func_enums::ToolCallStream::new(stream);
+                    if let Some(text_sender) = &config.text_sender {
+                        tool_call_stream = tool_call_stream.with_text_sender(text_sender.clone());
+                    }
+                    let mut tasks = Vec::new();
+                    let mut dispatched = 0_usize;
+
+                    while let Some(tool_call) = config.cancellable(tool_call_stream.next_tool_call()).await? {
+                        // A call that completes at the very end of the stream with nothing
+                        // dispatched before it is the only call in the response, and gets the
+                        // same treatment as a lone call from a non-streamed response.
+                        if Self::intercept_tool_call(&tool_call, prompt, config, &mut output).await? {
+                            continue;
+                        }
+
+                        if dispatched == 0 && tool_call_stream.is_exhausted() {
+                            output.content = Self::streamed_content(&tool_call_stream);
+                            output.finish_reason = tool_call_stream.finish_reason();
+                            Self::record_streamed_usage(
+                                tool_call_stream.usage().cloned(),
+                                &model_name,
+                                calibration_request.as_ref(),
+                                config,
+                                &logger,
+                            ).await;
+                            let tool_call_result = Self::execute_single_tool_call(
+                                &tool_call,
+                                &config.tools,
+                                custom_system_message,
+                                execution_strategy,
+                                arguments,
+                                logger,
+                            ).await?;
+                            output.tool_calls.push(tool_call_result);
+                            if let Some(usage_stats) = &config.usage_stats {
+                                usage_stats.record_output(&output);
                             }
-                        };
-                    } else {
-                        match execution_strategy {
-                            ToolCallExecutionStrategy::Async => {
-                                let mut tasks = Vec::new();
-
-                                let custom_system_message_clone = custom_system_message.clone();
-                                for tool_call in tool_calls.iter() {
-                                    match tool_call.r#type {
-                                        ChatCompletionToolType::Function => {
-                                            let function = tool_call.function.clone();
-                                            let prior_result_clone = prior_result.clone();
-                                            let command_clone = command.clone();
-                                            let execution_strategy_clone = execution_strategy.clone();
-                                            let logger_clone = logger.clone();
-                                            let custom_system_message_clone = custom_system_message.clone();
-
-                                            let task = tokio::spawn( async move {
-                                                match Self::parse_gpt_function_call(&function) {
-                                                    #(#match_arms_no_return,)*
-                                                    Err(e) => {
-                                                        println!("{:#?}", e);
-                                                    }
-                                                }
-                                            });
-                                            tasks.push(task);
-                                        },
-                                    }
-                                }
+                            return Ok(output);
+                        }
 
-                                for task in tasks {
-                                    let _ = task.await;
-                                }
-                            },
-                            ToolCallExecutionStrategy::Synchronous => {
-                                for tool_call in tool_calls.iter() {
-                                    match tool_call.r#type {
-                                        ChatCompletionToolType::Function => {
-                                            let prior_result_clone = prior_result.clone();
-                                            let command_clone = command.clone();
-                                            let execution_strategy_clone = execution_strategy.clone();
-                                            let logger_clone = logger.clone();
-                                            let custom_system_message_clone = custom_system_message.clone();
-
-                                            match Self::parse_gpt_function_call(&tool_call.function) {
-                                                #(#match_arms_no_return,)*
-                                                Err(e) => {
-                                                    println!("{:#?}", e);
-                                                }
-                                            }
-                                        },
-                                    }
+                        match tool_call.r#type {
+                            ::openai_func_enums::async_openai::types::ChatCompletionToolType::Function => {
+                                dispatched += 1;
+
+                                if config.tools.dispatch(
+                                    &tool_call,
+                                    &execution_strategy,
+                                    &mut tasks,
+                                    &mut output.tool_calls,
+                                ).await {
+                                    continue;
                                 }
+
+                                Self::dispatch_tool_call(
+                                    tool_call,
+                                    custom_system_message.clone(),
+                                    execution_strategy.clone(),
+                                    logger.clone(),
+                                    config,
+                                    &mut tasks,
+                                    &mut output.tool_calls,
+                                ).await;
                             },
-                            ToolCallExecutionStrategy::Parallel => {
-                                let mut handles = Vec::new();
-
-                                for tool_call in tool_calls.iter() {
-                                    match tool_call.r#type {
-                                        ChatCompletionToolType::Function => {
-                                            let function = tool_call.function.clone();
-                                            let prior_result_clone = prior_result.clone();
-                                            let command_clone = command.clone();
-
-                                            // TODO: Think through. There's a lot of overhead to
-                                            // make os threads this way. For now assume that if
-                                            // strategy is set to "Parallel" that we only want to
-                                            // put the intially returned tool calls on threads, and
-                                            // if they themselves contain something multi-step we
-                                            // will run those as if they are io-bound. Potentially
-                                            // makes sense to support letting variants get
-                                            // decorated with a execution strategy preference like
-                                            // "this is io bound" or "this is cpu bound".
-                                            // This will rarely matter.
-                                            let execution_strategy_clone = ToolCallExecutionStrategy::Async;
-                                            let logger_clone = logger.clone();
-                                            let custom_system_message_clone = custom_system_message.clone();
-
-                                            let handle = std::thread::spawn(move || {
-                                                let rt = tokio::runtime::Runtime::new().unwrap();
-                                                rt.block_on(async {
-                                                    match Self::parse_gpt_function_call(&function) {
-                                                        #(#match_arms_no_return,)*
-                                                        Err(e) => {
-                                                            println!("{:#?}", e);
-                                                        }
-                                                    }
-
-                                                })
-                                            });
-                                            handles.push(handle);
-                                        },
+                        }
+                    }
+
+                    Self::join_tool_calls(tasks, &mut output.tool_calls).await;
+                    output.content = Self::streamed_content(&tool_call_stream);
+                    output.finish_reason = tool_call_stream.finish_reason();
+                    Self::record_streamed_usage(
+                        tool_call_stream.usage().cloned(),
+                        &model_name,
+                        calibration_request.as_ref(),
+                        config,
+                        &logger,
+                    ).await;
+                    if let Some(usage_stats) = &config.usage_stats {
+                        usage_stats.record_output(&output);
+                    }
+
+                    return Ok(output);
+                }
+
+                let response = config.cancellable(config.create_chat_completion(request)).await?;
+                if let (Some(usage), Some(request)) = (&response.usage, &calibration_request) {
+                    config.calibrate_token_estimate(request, Self::function_jsons(), usage, &logger).await;
+                }
+                let choice = response
+                    .choices
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| ::openai_func_enums::CommandError::new("The response has no choices"))?;
+                let response_message = choice.message;
+
+                output.content = response_message.content;
+                output.finish_reason = choice.finish_reason;
+                if let Some(tool_calls) = response_message.tool_calls {
+                    if let [tool_call] = tool_calls.as_slice() {
+                        if !Self::intercept_tool_call(tool_call, prompt, config, &mut output).await? {
+                            let tool_call_result = Self::execute_single_tool_call(
+                                tool_call,
+                                &config.tools,
+                                custom_system_message,
+                                execution_strategy,
+                                arguments,
+                                logger,
+                            ).await?;
+                            output.tool_calls.push(tool_call_result);
+                        }
+                    } else {
+                        let mut tasks = Vec::new();
+
+                        queue.extend(tool_calls);
+
+                        while let Some(tool_call) = queue.pop() {
+                            if Self::intercept_tool_call(&tool_call, prompt, config, &mut output).await? {
+                                continue;
+                            }
+
+                            match tool_call.r#type {
+                                ::openai_func_enums::async_openai::types::ChatCompletionToolType::Function => {
+                                    if config.tools.dispatch(
+                                        &tool_call,
+                                        &execution_strategy,
+                                        &mut tasks,
+                                        &mut output.tool_calls,
+                                    ).await {
+                                        continue;
                                     }
-                                }
 
-                                for handle in handles {
-                                    let _ = handle.join();
-                                }
-                            },
+                                    Self::dispatch_tool_call(
+                                        tool_call,
+                                        custom_system_message.clone(),
+                                        execution_strategy.clone(),
+                                        logger.clone(),
+                                        config,
+                                        &mut tasks,
+                                        &mut output.tool_calls,
+                                    ).await;
+                                },
+                            }
                         }
+
+                        Self::join_tool_calls(tasks, &mut output.tool_calls).await;
                     }
-                    Ok(())
+                    if let Some(usage_stats) = &config.usage_stats {
+                        usage_stats.record_output(&output);
+                    }
+                    Ok(output)
                 } else {
-                    return Ok(());
+                    return Ok(output);
                 }
             }
-        }
-    };
 
-    let embedding_imports = quote! {
+            fn streamed_content(tool_call_stream: &::openai_func_enums::ToolCallStream) -> Option<String> {
+                Some(tool_call_stream.content().to_string()).filter(|content| !content.is_empty())
+            }
+
+            /// Records the usage a streamed response reported at its end, and checks the token
+            /// estimate of its request against it with `token_calibration`. Responses that
+            /// aren't streamed are recorded by `create_chat_completion`.
+            async fn record_streamed_usage(
+                usage: Option<::openai_func_enums::async_openai::types::CompletionUsage>,
+                model_name: &str,
+                calibration_request: Option<&::openai_func_enums::async_openai::types::CreateChatCompletionRequest>,
+                config: &::openai_func_enums::RunConfig,
+                logger: &::openai_func_enums::Logger,
+            ) {
+                let Some(usage) = usage else {
+                    return;
+                };
 
-        #[cfg(any(
-            feature = "compile_embeddings_all",
-            feature = "compile_embeddings_update",
-            feature = "function_filtering"
-        ))]
-        use openai_func_enums::FuncEnumsError;
+                config.record_usage(model_name, &usage);
+                if let Some(request) = calibration_request {
+                    config.calibrate_token_estimate(request, Self::function_jsons(), &usage, logger).await;
+                }
+            }
 
-        pub const FUNC_ENUMS_EMBED_PATH: &str = #embed_path;
+            /// Handles the tool calls that must not be executed: calls the config rejects or
+            /// answers itself, calls to functions that need scopes that weren't granted, and
+            /// calls the verifier turns down. Returns `true` if `tool_call` was one of them.
+            async fn intercept_tool_call(
+                tool_call: &::openai_func_enums::async_openai::types::ChatCompletionMessageToolCall,
+                prompt: &str,
+                config: &::openai_func_enums::RunConfig,
+                output: &mut ::openai_func_enums::RunOutput,
+            ) -> Result<bool, Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+                config.emit(::openai_func_enums::RunEvent::tool_call_parsed(tool_call)).await;
+
+                let name = &tool_call.function.name;
+                Ok(config.intercept_tool_call(tool_call, output)
+                    || config.check_scopes(tool_call, Self::scopes(name), output)
+                    || config.verify_tool_call(tool_call, Self::is_risky(name), prompt, output).await)
+            }
 
-        pub const FUNC_ENUMS_EMBED_MODEL: &str = #embed_model;
-    };
+            /// Runs the only tool call of a response. `arguments` are handed to the tool, and
+            /// the arguments it returns for the next command end up in its `ToolCallResult`.
+            #[allow(clippy::too_many_arguments)]
+            async fn execute_single_tool_call(
+                tool_call: &::openai_func_enums::async_openai::types::ChatCompletionMessageToolCall,
+                tools: &::openai_func_enums::ToolRegistry,
+                custom_system_message: Option<(String, usize)>,
+                execution_strategy: ::openai_func_enums::ToolCallExecutionStrategy,
+                arguments: Option<Vec<String>>,
+                logger: ::std::sync::Arc<::openai_func_enums::Logger>,
+            ) -> Result<::openai_func_enums::ToolCallResult, Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+                if let Some(tool_call_result) = tools.call(tool_call).await {
+                    return Ok(tool_call_result);
+                }
 
-    let gen = quote! {
-        pub const FUNC_ENUMS_MAX_RESPONSE_TOKENS: u16 = #max_response_tokens;
-        pub const FUNC_ENUMS_MAX_REQUEST_TOKENS: usize = #max_request_tokens;
-        pub const FUNC_ENUMS_MAX_FUNC_TOKENS: u16 = #max_func_tokens;
-        pub const FUNC_ENUMS_MAX_SINGLE_ARG_TOKENS: u16 = #max_single_arg_tokens;
+                ::openai_func_enums::track_tool_call(tool_call, Self::execute_derived_tool_call(
+                    tool_call,
+                    custom_system_message,
+                    execution_strategy,
+                    arguments,
+                    logger,
+                )).await
+            }
 
-        use serde::Deserialize;
-        use serde_json::{json, Value};
+            /// The part of `execute_single_tool_call` that runs derived tools.
+            async fn execute_derived_tool_call(
+                tool_call: &::openai_func_enums::async_openai::types::ChatCompletionMessageToolCall,
+                custom_system_message: Option<(String, usize)>,
+                execution_strategy: ::openai_func_enums::ToolCallExecutionStrategy,
+                arguments: Option<Vec<String>>,
+                logger: ::std::sync::Arc<::openai_func_enums::Logger>,
+            ) -> Result<::openai_func_enums::ToolCallResult, Box<dyn ::std::error::Error + Send + Sync + 'static>> {
+                let execution_strategy_clone = execution_strategy.clone();
+                let (parsed, mut tool_call_result) = Self::parse_tool_call(tool_call);
+
+                match parsed {
+                    #(#match_arms,)*
+                    Err(e) => {
+                        println!("{:#?}", e);
+                        return Err(Box::new(::openai_func_enums::CommandError::new("Error running GPT command")));
+                    }
+                };
 
-        use openai_func_enums::{
-            generate_value_arg_info, get_tool_chat_completion_args,
-            ArchivedFuncEmbedding,
-        };
+                Ok(tool_call_result)
+            }
 
-        use rkyv::{archived_root, Archived};
-        use rkyv::vec::ArchivedVec;
+            /// Runs one of several tool calls from the same response.
+            async fn execute_tool_call(
+                tool_call: ::openai_func_enums::async_openai::types::ChatCompletionMessageToolCall,
+                custom_system_message_clone: Option<(String, usize)>,
+                execution_strategy_clone: ::openai_func_enums::ToolCallExecutionStrategy,
+                logger_clone: ::std::sync::Arc<::openai_func_enums::Logger>,
+            ) -> ::openai_func_enums::ToolCallResult {
+                ::openai_func_enums::track_tool_call(&tool_call, async {
+                    let (parsed, mut tool_call_result) = Self::parse_tool_call(&tool_call);
+
+                    match parsed {
+                        #(#match_arms_no_return,)*
+                        Err(e) => {
+                            println!("{:#?}", e);
+                        }
+                    }
 
-        use async_trait::async_trait;
-        use async_openai::{
-            types::{
-                ChatCompletionFunctionCall, ChatCompletionNamedToolChoice, ChatCompletionRequestMessage,
-                ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
-                ChatCompletionToolChoiceOption, ChatCompletionToolType, CreateChatCompletionRequestArgs,
-                CreateEmbeddingRequestArgs, FunctionCall, FunctionName,
-            },
-            Client,
-        };
-        use tokio::sync::{mpsc};
+                    tool_call_result
+                }).await
+            }
+
+            /// Starts a tool call according to the execution strategy. Synchronous calls are
+            /// finished before this returns and their result is pushed onto `results`, the
+            /// others are pushed onto `tasks` for `join_tool_calls`.
+            #[allow(clippy::too_many_arguments)]
+            async fn dispatch_tool_call(
+                tool_call: ::openai_func_enums::async_openai::types::ChatCompletionMessageToolCall,
+                custom_system_message: Option<(String, usize)>,
+                execution_strategy: ::openai_func_enums::ToolCallExecutionStrategy,
+                logger: ::std::sync::Arc<::openai_func_enums::Logger>,
+                config: &::openai_func_enums::RunConfig,
+                tasks: &mut Vec<::openai_func_enums::ToolCallTask>,
+                results: &mut Vec<::openai_func_enums::ToolCallResult>,
+            ) {
+                match execution_strategy {
+                    ::openai_func_enums::ToolCallExecutionStrategy::Parallel if Self::is_cpu_bound(&tool_call.function.name) => {
+                        // The call gets a thread of the runtime's blocking pool and is driven
+                        // there by the same runtime, so no runtime is built per call. The steps
+                        // it starts keep the strategy, like those of any other call.
+                        let config = config.clone();
+                        let runtime = ::tokio::runtime::Handle::current();
+                        let task_tool_call = tool_call.clone();
+
+                        let task = ::tokio::task::spawn_blocking(move || {
+                            runtime.block_on(config.scope(Self::execute_tool_call(
+                                tool_call,
+                                custom_system_message,
+                                execution_strategy,
+                                logger,
+                            )))
+                        });
+                        tasks.push(::openai_func_enums::ToolCallTask::new(task_tool_call, task));
+                    },
+                    // Functions that aren't cpu bound wait on IO, which tasks are better at than
+                    // threads, so `Parallel` runs them the way `Async` does.
+                    ::openai_func_enums::ToolCallExecutionStrategy::Async | ::openai_func_enums::ToolCallExecutionStrategy::Parallel => {
+                        let task_tool_call = tool_call.clone();
+                        let task = ::tokio::spawn(config.clone().scope(Self::execute_tool_call(
+                            tool_call,
+                            custom_system_message,
+                            execution_strategy,
+                            logger,
+                        )));
+                        tasks.push(::openai_func_enums::ToolCallTask::new(task_tool_call, task));
+                    },
+                    ::openai_func_enums::ToolCallExecutionStrategy::Synchronous => {
+                        let tool_call_result = Self::execute_tool_call(
+                            tool_call,
+                            custom_system_message,
+                            execution_strategy,
+                            logger,
+                        ).await;
+                        results.push(tool_call_result);
+                    },
+                }
+            }
+
+            /// Waits for the tool calls started by `dispatch_tool_call` and collects their
+            /// results in the order they were dispatched. A call whose task panicked gets a
+            /// failed result with the panic message.
+            async fn join_tool_calls(
+                tasks: Vec<::openai_func_enums::ToolCallTask>,
+                results: &mut Vec<::openai_func_enums::ToolCallResult>,
+            ) {
+                for task in tasks {
+                    results.push(task.join().await);
+                }
+            }
+        }
+    };
+
+    let gen = quote! {
+        pub const #max_response_tokens_const: u16 = #max_response_tokens;
+        pub const #max_request_tokens_const: usize = #max_request_tokens;
+        pub const #max_func_tokens_const: u16 = #max_func_tokens;
+        pub const #max_single_arg_tokens_const: u16 = #max_single_arg_tokens;
+
+        pub const #embed_path_const: &str = #embed_path;
 
-        #embedding_imports
+        pub const #embed_model_const: &str = #embed_model;
 
         #(#generated_structs)*
 
         #(#json_generator_functions)*
 
-        #(#generated_clap_gpt_enum)*
+        #commands_gpt_struct
 
         #commands_gpt_impl
+
+        #tool_set_impl
     };
 
     gen.into()
@@ -1330,7 +3164,7 @@ fn get_comment_from_attr(attr: &Attribute) -> Option<String> {
 ///
 /// # Example
 ///
-/// ```
+/// ```text
 /// let text = "Hello, world!";
 /// let token_count = calculate_token_count(text);
 /// println!("Token count: {}", token_count);
@@ -1342,6 +3176,21 @@ fn calculate_token_count(text: &str) -> usize {
     bpe.encode_ordinary(text).len()
 }
 
+/// Converts a snake_case name, like that of a function, to CamelCase.
+fn to_camel_case(snake_case: &str) -> String {
+    snake_case
+        .split('_')
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect::<String>(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
 /// Convert a camelCase or PascalCase string into a snake_case string.
 ///
 /// This function iterates over each character in the input string. If the character is an uppercase letter, it adds an
@@ -1358,7 +3207,7 @@ fn calculate_token_count(text: &str) -> usize {
 ///
 /// # Example
 ///
-/// ```
+/// ```text
 /// let camel_case = "HelloWorld";
 /// let snake_case = to_snake_case(camel_case);
 /// assert_eq!(snake_case, "hello_world");
@@ -1374,32 +3223,1289 @@ fn to_snake_case(camel_case: &str) -> String {
     snake_case
 }
 
+/// Embeds the functions in `pending` and writes them to the archive at `embed_path`. The
+/// requests are sent at once, up to `embed_concurrency` at a time.
+///
+/// With `compile_embeddings_update` alone, the archive that is there is read first, and only
+/// the functions whose description or model changed since, or that are new, are embedded
+/// again. The rest of the archive, including functions of other enums, is kept. Otherwise
+/// every function is embedded and the archive is replaced.
+#[cfg(any(
+    feature = "compile_embeddings_all",
+    feature = "compile_embeddings_update"
+))]
+fn embed_functions(
+    embed_path: &Path,
+    pending: Vec<(String, String, String, String)>,
+    embed_model: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "compile_embeddings_update")]
+    let mut embeddings = if embed_path.exists() && !cfg!(feature = "compile_embeddings_all") {
+        openai_func_embeddings::EmbeddingArchive::from_bytes(&std::fs::read(embed_path)?)?.to_vec()
+    } else {
+        Vec::new()
+    };
+    #[cfg(not(feature = "compile_embeddings_update"))]
+    let mut embeddings = Vec::new();
+
+    let stale: Vec<_> = pending
+        .into_iter()
+        .filter(|(name, description, _, _)| {
+            !embeddings
+                .iter()
+                .any(|embedding: &openai_func_embeddings::FuncEmbedding| {
+                    &embedding.name == name
+                        && &embedding.description == description
+                        && embedding.embed_model == embed_model
+                        && !embedding.name_embedding.is_empty()
+                })
+        })
+        .collect();
+
+    let texts: Vec<String> = stale
+        .iter()
+        .flat_map(|(_, _, name_and_desc, name_words)| [name_and_desc.clone(), name_words.clone()])
+        .collect();
+    let client = embedding_client()?;
+    let fetched = tokio::runtime::Runtime::new()?
+        .block_on(openai_func_embeddings::embeddings_with_client(
+            &texts,
+            embed_model,
+            &client,
+            openai_func_embeddings::embed_concurrency(),
+        ))
+        .map_err(|e| e.to_string())?;
+
+    for ((name, description, _, _), pair) in stale.into_iter().zip(fetched.chunks(2)) {
+        let embedding = openai_func_embeddings::FuncEmbedding {
+            name,
+            description,
+            embedding: pair[0].clone(),
+            name_embedding: pair[1].clone(),
+            embed_model: embed_model.to_string(),
+        };
+        match embeddings
+            .iter_mut()
+            .find(|existing| existing.name == embedding.name)
+        {
+            Some(existing) => *existing = embedding,
+            None => embeddings.push(embedding),
+        }
+    }
+
+    openai_func_embeddings::write_embedding_archive(embed_path, &embeddings)
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// The client embeddings are fetched with at compile time, billed to `OPENAI_ORG_ID` and
+/// `OPENAI_PROJECT_ID` when they are set.
 #[cfg(any(
     feature = "compile_embeddings_all",
     feature = "compile_embeddings_update"
 ))]
-async fn get_single_embedding(
-    text: &String,
-    model: &String,
-) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
-    let client = Client::new();
-    let request = CreateEmbeddingRequestArgs::default()
-        .model(model)
-        .input([text])
-        .build()?;
-
-    let response = client.embeddings().create(request).await?;
-
-    match response.data.first() {
-        Some(data) => {
-            return Ok(data.embedding.to_owned());
-        }
-        None => {
-            let embedding_error = openai_func_embeddings::FuncEnumsError::OpenAIError(
-                String::from("Didn't get embedding vector back."),
+fn embedding_client() -> Result<Client<OpenAIConfig>, Box<dyn std::error::Error>> {
+    let mut config = openai_func_embeddings::default_openai_config();
+    if let Ok(organization) = std::env::var("OPENAI_ORG_ID") {
+        config = config.with_org_id(organization);
+    }
+    if let Ok(project) = std::env::var("OPENAI_PROJECT_ID") {
+        config = config.with_project_id(project);
+    }
+    Ok(Client::with_config(config).with_http_client(embedding_http_client()?))
+}
+
+/// The HTTP client embeddings are fetched with at compile time. It honors the same
+/// `FUNC_ENUMS_HTTP_PROXY`, `FUNC_ENUMS_CONNECT_TIMEOUT_MS`, `FUNC_ENUMS_TIMEOUT_MS` and
+/// `FUNC_ENUMS_HTTP_HEADERS` variables as `Config::from_env` does at runtime.
+#[cfg(any(
+    feature = "compile_embeddings_all",
+    feature = "compile_embeddings_update"
+))]
+fn embedding_http_client() -> Result<reqwest::Client, Box<dyn std::error::Error>> {
+    let mut builder = reqwest::Client::builder();
+
+    if let Ok(proxy) = std::env::var("FUNC_ENUMS_HTTP_PROXY") {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+    }
+
+    if let Ok(connect_timeout_ms) = std::env::var("FUNC_ENUMS_CONNECT_TIMEOUT_MS") {
+        builder = builder.connect_timeout(std::time::Duration::from_millis(
+            connect_timeout_ms.parse()?,
+        ));
+    }
+
+    if let Ok(timeout_ms) = std::env::var("FUNC_ENUMS_TIMEOUT_MS") {
+        builder = builder.timeout(std::time::Duration::from_millis(timeout_ms.parse()?));
+    }
+
+    if let Ok(headers) = std::env::var("FUNC_ENUMS_HTTP_HEADERS") {
+        let mut header_map = reqwest::header::HeaderMap::new();
+        for (name, value) in
+            openai_func_embeddings::parse_headers(&headers).map_err(|e| e.to_string())?
+        {
+            header_map.insert(
+                reqwest::header::HeaderName::from_bytes(name.as_bytes())?,
+                reqwest::header::HeaderValue::from_str(&value)?,
             );
-            let boxed_error: Box<dyn std::error::Error + Send + Sync> = Box::new(embedding_error);
-            return Err(boxed_error);
         }
+        builder = builder.default_headers(header_map);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Returns `true` for the primitive integer and float types.
+fn is_numeric_type(ty: &syn::Type) -> bool {
+    match ty {
+        syn::Type::Path(typepath) if typepath.qself.is_none() => matches!(
+            typepath
+                .path
+                .segments
+                .last()
+                .map(|segment| segment.ident.to_string())
+                .as_deref(),
+            Some(
+                "f32"
+                    | "f64"
+                    | "u8"
+                    | "u16"
+                    | "u32"
+                    | "u64"
+                    | "u128"
+                    | "usize"
+                    | "i8"
+                    | "i16"
+                    | "i32"
+                    | "i64"
+                    | "i128"
+                    | "isize"
+            )
+        ),
+        _ => false,
+    }
+}
+
+/// Returns `true` for field types whose schema comes from `generate_enum_info`.
+fn uses_enum_info(ty: &syn::Type) -> bool {
+    match ty {
+        syn::Type::Path(typepath) if typepath.qself.is_none() => {
+            !is_numeric_type(ty)
+                && !matches!(
+                    typepath
+                        .path
+                        .segments
+                        .last()
+                        .map(|segment| segment.ident.to_string())
+                        .as_deref(),
+                    Some("String" | "Vec" | "bool" | "HashMap" | "BTreeMap")
+                )
+        }
+        _ => false,
+    }
+}
+
+/// Settings for a single argument, read from `#[arg(...)]` or `#[func_enums(...)]` on a field.
+#[derive(Default)]
+struct FieldAttributes {
+    /// The description expression and an expression for its token count.
+    description: Option<(proc_macro2::TokenStream, proc_macro2::TokenStream)>,
+    /// Example values for the schema's `examples` keyword.
+    examples: Vec<Expr>,
+    /// An expression for the token count of each example.
+    example_tokens: Vec<proc_macro2::TokenStream>,
+    /// A schema that replaces the generated one, as compact JSON, and its token count.
+    schema: Option<(String, usize)>,
+    /// The field's `serde` attributes, copied to the generated struct.
+    serde: Vec<syn::Attribute>,
+    /// The name serde deserializes the field from, if it is renamed.
+    rename: Option<String>,
+    /// The rename came from `#[func_enums(rename = "...")]` rather than from `serde`, so the
+    /// generated struct needs a serde attribute for it.
+    own_rename: bool,
+    /// Schema keywords like `minimum` or `pattern`, with their value as a literal and the
+    /// token count of both, along with the JSON around them.
+    constraints: Vec<(&'static str, Lit, usize)>,
+    /// The value the argument takes when the model leaves it out, and an expression for its
+    /// token count.
+    default: Option<(Expr, proc_macro2::TokenStream)>,
+}
+
+/// The constraint keys a field takes, the schema keywords they become and the literal their
+/// value has to be.
+const CONSTRAINT_KEYWORDS: &[(&str, &str, ConstraintValue)] = &[
+    ("min", "minimum", ConstraintValue::Number),
+    ("max", "maximum", ConstraintValue::Number),
+    ("min_length", "minLength", ConstraintValue::Count),
+    ("max_length", "maxLength", ConstraintValue::Count),
+    ("pattern", "pattern", ConstraintValue::Pattern),
+    ("format", "format", ConstraintValue::Text),
+    ("min_items", "minItems", ConstraintValue::Count),
+    ("max_items", "maxItems", ConstraintValue::Count),
+];
+
+/// The tokens the quotes, colon and comma around a constraint's keyword and value add to a
+/// schema, on top of the keyword and value themselves.
+const CONSTRAINT_STRUCTURE_TOKENS: usize = 3;
+
+/// The tokens the `"description"` key of an argument's schema adds along with its quotes,
+/// colon and comma, on top of the description.
+const ARG_DESCRIPTION_STRUCTURE_TOKENS: usize = 6;
+
+/// The tokens the `{"name": {...}}` around an argument's schema add, on top of the name.
+const ARG_NAME_STRUCTURE_TOKENS: usize = 6;
+
+/// The tokens `"type": "string",` adds to the schema of an enum argument.
+const STRING_TYPE_TOKENS: usize = 7;
+
+/// The tokens the `"enum": [...]` key and brackets add around the names of an enum's
+/// variants, their quotes and commas being counted with the variants.
+const ENUM_LIST_STRUCTURE_TOKENS: usize = 7;
+
+/// The tokens the keys and braces of a tool definition, from `"type": "function"` to
+/// `"required"`, add around the function's name, description and arguments.
+const FUNCTION_STRUCTURE_TOKENS: usize = 43;
+
+/// The tokens the `"examples": [...]` key and brackets add to an argument's schema.
+const EXAMPLES_STRUCTURE_TOKENS: usize = 6;
+
+/// The tokens the comma and spacing between the values of `"examples"` add for each one.
+const EXAMPLE_STRUCTURE_TOKENS: usize = 3;
+
+/// The tokens `"strict": true,` adds to a tool definition.
+const STRICT_FLAG_TOKENS: usize = 4;
+
+/// The system message of a tool request that doesn't bring its own.
+const DEFAULT_SYSTEM_MESSAGE: &str = "You are a helpful function calling bot.";
+
+/// The tokens the `"default"` key of an argument's schema adds along with its quotes, colon
+/// and comma, on top of the value.
+const DEFAULT_STRUCTURE_TOKENS: usize = 4;
+
+/// The literals a constraint takes.
+#[derive(Clone, Copy)]
+enum ConstraintValue {
+    /// An integer or a float, like `min = 0.5`.
+    Number,
+    /// A non-negative integer, like `max_length = 10`.
+    Count,
+    /// A string, like `format = "email"`.
+    Text,
+    /// A string that has to compile as a regex.
+    Pattern,
+}
+
+impl ConstraintValue {
+    /// The text of `value` for counting its tokens, or an error if it isn't the literal this
+    /// constraint takes.
+    fn check(&self, key: &str, value: &Lit) -> syn::Result<String> {
+        let expected = match (self, value) {
+            (ConstraintValue::Number, Lit::Int(_) | Lit::Float(_)) => {
+                return Ok(value.to_token_stream().to_string())
+            }
+            (ConstraintValue::Count, Lit::Int(count)) => {
+                count.base10_parse::<u64>()?;
+                return Ok(count.to_string());
+            }
+            (ConstraintValue::Text, Lit::Str(text)) => return Ok(text.value()),
+            (ConstraintValue::Pattern, Lit::Str(text)) => {
+                return match regex::Regex::new(&text.value()) {
+                    Ok(_) => Ok(text.value()),
+                    Err(e) => Err(syn::Error::new_spanned(
+                        text,
+                        format!("pattern is not a valid regex: {}", e),
+                    )),
+                };
+            }
+            (ConstraintValue::Number, _) => "a number",
+            (ConstraintValue::Count, _) => "a non-negative integer",
+            (ConstraintValue::Text | ConstraintValue::Pattern, _) => "a string",
+        };
+
+        Err(syn::Error::new_spanned(
+            value,
+            format!("{} takes {}", key, expected),
+        ))
+    }
+}
+
+/// Settings for a whole function, read from `#[func_enums(...)]` on a variant.
+#[derive(Default)]
+struct VariantAttributes {
+    /// The type whose `ReturnSchema` describes what the function returns.
+    returns: Option<syn::Type>,
+    /// Calls have to be approved by the `ToolCallVerifier` before they run.
+    risky: bool,
+    /// The scopes a caller needs to be granted to use the function.
+    scopes: Vec<syn::LitStr>,
+    /// Calls run in the `Sandbox`.
+    sandboxed: bool,
+    /// Calls get a thread of the blocking pool under the `Parallel` strategy.
+    cpu_bound: bool,
+    /// The function is sent with `"strict": true`, for OpenAI's structured outputs.
+    strict: bool,
+    /// Calls that take longer are cancelled.
+    timeout_ms: Option<u64>,
+    /// The file the description is read from instead of the doc comment, and what it says.
+    description_file: Option<(syn::LitStr, String)>,
+    /// The name the function is sent with, in place of the variant's.
+    rename: Option<syn::LitStr>,
+    /// The variant isn't a function the model can call.
+    skip: bool,
+}
+
+/// The `func_enums` attributes of a `ToolSet` enum.
+#[derive(Default)]
+struct ToolSetAttributes {
+    /// The name of the variant that takes a prompt.
+    entry: Option<syn::LitStr>,
+    /// The generated items are named after the enum, so more than one `ToolSet` can live in
+    /// the same module.
+    namespaced: bool,
+    /// The type of the `RunConfig::state` that `RunCommand::run` is handed.
+    state: Option<syn::Type>,
+}
+
+/// Reads the `func_enums` attributes of a `ToolSet` enum. `entry` takes a variant name,
+/// `namespaced` is a flag and `state` takes a type, bare or as a string.
+fn parse_tool_set_attributes(attrs: &[syn::Attribute]) -> syn::Result<ToolSetAttributes> {
+    let mut attributes = ToolSetAttributes::default();
+    for attr in attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("func_enums"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("entry") {
+                attributes.entry = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("namespaced") {
+                attributes.namespaced = true;
+            } else if meta.path.is_ident("state") {
+                let value = meta.value()?;
+                attributes.state = Some(if value.peek(syn::LitStr) {
+                    value.parse::<syn::LitStr>()?.parse()?
+                } else {
+                    value.parse()?
+                });
+            } else {
+                return Err(meta.error("unrecognized func_enums attribute on a ToolSet enum"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(attributes)
+}
+
+/// Reads the `func_enums` attributes of a variant. `returns` takes a type, bare or as a
+/// string, `risky`, `sandboxed`, `skip`, `strict` and either of `cpu_bound` and `io_bound` are
+/// flags, `scope` can be repeated, `timeout_ms` takes a positive integer, `description_file` a
+/// path relative to the crate root and `rename` a function name of up to 64 letters, digits,
+/// `_` or `-`, the ones OpenAI allows. Unknown keys are rejected.
+fn parse_variant_attributes(variant: &syn::Variant) -> syn::Result<VariantAttributes> {
+    let mut attributes = VariantAttributes::default();
+    // `io_bound` is what functions are anyway, it's only kept to reject it next to `cpu_bound`.
+    let mut io_bound = None;
+
+    for attr in &variant.attrs {
+        if !attr.path().is_ident("func_enums") {
+            continue;
+        }
+
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("returns") {
+                let value = meta.value()?;
+                attributes.returns = Some(if value.peek(syn::LitStr) {
+                    value.parse::<syn::LitStr>()?.parse()?
+                } else {
+                    value.parse()?
+                });
+            } else if meta.path.is_ident("risky") {
+                attributes.risky = true;
+            } else if meta.path.is_ident("sandboxed") {
+                attributes.sandboxed = true;
+            } else if meta.path.is_ident("cpu_bound") {
+                attributes.cpu_bound = true;
+            } else if meta.path.is_ident("io_bound") {
+                io_bound = Some(meta.path.clone());
+            } else if meta.path.is_ident("skip") {
+                attributes.skip = true;
+            } else if meta.path.is_ident("strict") {
+                attributes.strict = true;
+            } else if meta.path.is_ident("scope") {
+                attributes.scopes.push(meta.value()?.parse()?);
+            } else if meta.path.is_ident("timeout_ms") {
+                let timeout_ms: syn::LitInt = meta.value()?.parse()?;
+                let value = timeout_ms.base10_parse()?;
+                if value == 0 {
+                    return Err(syn::Error::new_spanned(
+                        timeout_ms,
+                        "a timeout of 0 would stop every call before it starts",
+                    ));
+                }
+                attributes.timeout_ms = Some(value);
+            } else if meta.path.is_ident("description_file") {
+                let path: syn::LitStr = meta.value()?.parse()?;
+                let contents = read_description_file(&path)?;
+                attributes.description_file = Some((path, contents));
+            } else if meta.path.is_ident("rename") {
+                let rename: syn::LitStr = meta.value()?.parse()?;
+                let name = rename.value();
+                if name.is_empty()
+                    || name.len() > 64
+                    || !name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                {
+                    return Err(syn::Error::new_spanned(
+                        rename,
+                        "function names can only have up to 64 letters, digits, `_` or `-`",
+                    ));
+                }
+                attributes.rename = Some(rename);
+            } else {
+                return Err(meta.error("unrecognized func_enums attribute"));
+            }
+            Ok(())
+        })?;
+    }
+
+    if let (true, Some(io_bound)) = (attributes.cpu_bound, io_bound) {
+        return Err(syn::Error::new_spanned(
+            io_bound,
+            "a function can't be both cpu_bound and io_bound",
+        ));
+    }
+
+    Ok(attributes)
+}
+
+/// Reads a description kept in a file, with `path` relative to the root of the crate being
+/// compiled, the way `include_str!` paths are relative to the source file.
+fn read_description_file(path: &syn::LitStr) -> syn::Result<String> {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let full_path = std::path::Path::new(&manifest_dir).join(path.value());
+
+    match std::fs::read_to_string(&full_path) {
+        Ok(contents) if !contents.trim().is_empty() => Ok(contents.trim().to_string()),
+        Ok(_) => Err(syn::Error::new_spanned(
+            path,
+            format!("{} is empty", full_path.display()),
+        )),
+        Err(e) => Err(syn::Error::new_spanned(
+            path,
+            format!("Failed to read {}: {}", full_path.display(), e),
+        )),
+    }
+}
+
+/// Makes the crate recompile when a description file changes, which the macro reading it
+/// doesn't do on its own.
+fn track_description_file(path: &syn::LitStr) -> proc_macro2::TokenStream {
+    quote! {
+        const _: &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/", #path));
+    }
+}
+
+/// Reads the `arg` and `func_enums` attributes of a variant field.
+///
+/// Both take the same keys: `description`, or `desc` for short, an optional `tokens` count
+/// for the description, `example`, which can be repeated, and `schema`, a JSON string that
+/// replaces the generated schema. Without a `description` the field's doc comment is used.
+/// The constraints in `CONSTRAINT_KEYWORDS`, like `min = 0` or `pattern = "^[A-Z]{3}$"`, go
+/// into the schema under their JSON Schema names. Their values are checked against the
+/// literal each one takes, so `min_length = -1` or a `pattern` that isn't a regex fails to
+/// compile. `default` takes the value the argument gets when the model leaves it out, as
+/// anything `serde_json::json!` takes. `rename` sets the name of the argument. `serde`
+/// attributes are kept, and a `rename` in them renames the argument too.
+///
+/// Keys this crate doesn't know about are errors. With the `clap` feature `arg` is shared with
+/// clap, so they are skipped there, and only `func_enums` rejects them. Enums that also derive
+/// clap's `Subcommand` should use `func_enums`, since clap rejects keys it doesn't know about.
+fn parse_field_attributes(field: &syn::Field) -> syn::Result<FieldAttributes> {
+    let mut description: Option<Expr> = None;
+    let mut tokens: Option<usize> = None;
+    let mut examples: Vec<Expr> = Vec::new();
+    let mut schema: Option<(String, usize)> = None;
+    let mut serde = Vec::new();
+    let mut rename = None;
+    let mut own_rename = false;
+    let mut constraints = Vec::new();
+    let mut default: Option<Expr> = None;
+
+    for attr in &field.attrs {
+        if attr.path().is_ident("serde") {
+            rename = serde_rename(attr)?.or(rename);
+            serde.push(attr.clone());
+            continue;
+        }
+
+        let is_arg = attr.path().is_ident("arg");
+        if !is_arg && !attr.path().is_ident("func_enums") {
+            continue;
+        }
+        let is_shared = is_arg && cfg!(feature = "clap");
+
+        // `#[arg]` without arguments is valid for clap.
+        if matches!(attr.meta, Meta::Path(_)) {
+            continue;
+        }
+
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("description") || meta.path.is_ident("desc") {
+                description = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("example") {
+                examples.push(meta.value()?.parse()?);
+            } else if meta.path.is_ident("default") {
+                default = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("rename") {
+                rename = Some(meta.value()?.parse::<syn::LitStr>()?.value());
+                own_rename = true;
+            } else if meta.path.is_ident("tokens") {
+                tokens = Some(meta.value()?.parse::<syn::LitInt>()?.base10_parse()?);
+            } else if let Some((key, keyword, kind)) = CONSTRAINT_KEYWORDS
+                .iter()
+                .find(|(key, _, _)| meta.path.is_ident(key))
+            {
+                let value: Lit = meta.value()?.parse()?;
+                let text = kind.check(key, &value)?;
+                let tokens = CONSTRAINT_STRUCTURE_TOKENS
+                    + calculate_token_count(keyword)
+                    + calculate_token_count(&text);
+                constraints.push((*keyword, value, tokens));
+            } else if meta.path.is_ident("schema") {
+                let text: syn::LitStr = meta.value()?.parse()?;
+                let value: serde_json::Value =
+                    serde_json::from_str(&text.value()).map_err(|e| {
+                        syn::Error::new_spanned(&text, format!("schema is not valid JSON: {}", e))
+                    })?;
+                let compact = value.to_string();
+                let schema_tokens = calculate_token_count(&compact);
+                schema = Some((compact, schema_tokens));
+            } else if is_shared {
+                skip_meta_value(&meta)?;
+            } else if is_arg {
+                return Err(meta.error("unrecognized arg attribute"));
+            } else {
+                return Err(meta.error("unrecognized func_enums attribute"));
+            }
+            Ok(())
+        })?;
+    }
+
+    // Without a `description`, the field's doc comment describes it.
+    let doc_comment = field
+        .attrs
+        .iter()
+        .filter_map(get_comment_from_attr)
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    if description.is_none() && !doc_comment.is_empty() {
+        description = Some(Expr::Lit(syn::ExprLit {
+            attrs: Vec::new(),
+            lit: Lit::Str(syn::LitStr::new(
+                &doc_comment,
+                proc_macro2::Span::call_site(),
+            )),
+        }));
+    }
+
+    let description = description.map(|description| {
+        let description_tokens = match (tokens, &description) {
+            (Some(tokens), _) => quote! { #tokens },
+            (
+                None,
+                Expr::Lit(syn::ExprLit {
+                    lit: Lit::Str(text),
+                    ..
+                }),
+            ) => {
+                let tokens = calculate_token_count(&text.value());
+                quote! { #tokens }
+            }
+            // Descriptions that come from constants or other macros can only be counted at
+            // runtime. Pass `tokens` to avoid that.
+            (None, _) => quote! { ::openai_func_enums::count_tokens(#description) },
+        };
+
+        (description.to_token_stream(), description_tokens)
+    });
+
+    let example_tokens = examples
+        .iter()
+        .map(|example| match example {
+            Expr::Lit(syn::ExprLit { lit, .. }) => {
+                let text = match lit {
+                    Lit::Str(text) => text.value(),
+                    lit => lit.to_token_stream().to_string(),
+                };
+                let tokens = calculate_token_count(&text);
+                quote! { #tokens }
+            }
+            example => {
+                quote! { ::openai_func_enums::count_tokens(&::openai_func_enums::serde_json::json!(#example).to_string()) }
+            }
+        })
+        .collect();
+
+    let default = default.map(|default| {
+        let default_tokens = match &default {
+            Expr::Lit(syn::ExprLit { lit, .. }) => {
+                let text = match lit {
+                    Lit::Str(text) => text.value(),
+                    lit => lit.to_token_stream().to_string(),
+                };
+                let tokens = calculate_token_count(&text);
+                quote! { #tokens }
+            }
+            default => {
+                quote! { ::openai_func_enums::count_tokens(&::openai_func_enums::serde_json::json!(#default).to_string()) }
+            }
+        };
+        (default, default_tokens)
+    });
+
+    Ok(FieldAttributes {
+        description,
+        examples,
+        example_tokens,
+        schema,
+        serde,
+        rename,
+        own_rename,
+        constraints,
+        default,
+    })
+}
+
+/// The name a `#[serde(...)]` attribute deserializes its field from, if it renames it.
+fn serde_rename(attr: &syn::Attribute) -> syn::Result<Option<String>> {
+    let mut rename = None;
+
+    attr.parse_nested_meta(|meta| {
+        if !meta.path.is_ident("rename") {
+            return skip_meta_value(&meta);
+        }
+
+        if meta.input.peek(syn::Token![=]) {
+            rename = Some(meta.value()?.parse::<syn::LitStr>()?.value());
+            return Ok(());
+        }
+
+        meta.parse_nested_meta(|inner| {
+            let name = inner.value()?.parse::<syn::LitStr>()?.value();
+            if inner.path.is_ident("deserialize") {
+                rename = Some(name);
+            }
+            Ok(())
+        })
+    })?;
+
+    Ok(rename)
+}
+
+/// Consumes the value of an attribute key that belongs to another crate.
+fn skip_meta_value(meta: &syn::meta::ParseNestedMeta) -> syn::Result<()> {
+    if meta.input.peek(syn::Token![=]) {
+        meta.value()?.parse::<Expr>()?;
+    } else if meta.input.peek(syn::token::Paren) {
+        let content;
+        syn::parenthesized!(content in meta.input);
+        content.parse::<proc_macro2::TokenStream>()?;
+    }
+    Ok(())
+}
+
+/// Whether each field is a required argument, which every field but an `Option` or one with
+/// a `default` is.
+fn fields_required(fields: &syn::Fields, field_attributes: &[FieldAttributes]) -> Vec<bool> {
+    fields
+        .iter()
+        .enumerate()
+        .map(|(index, field)| {
+            option_inner_type(&field.ty).is_none()
+                && field_attributes
+                    .get(index)
+                    .and_then(|attributes| attributes.default.as_ref())
+                    .is_none()
+        })
+        .collect()
+}
+
+/// The expressions that build the schema of each field and its token count, with the
+/// descriptions, examples, renames and schema overrides of `field_attributes` applied.
+fn fields_arg_info(
+    fields: &syn::Fields,
+    field_attributes: &[FieldAttributes],
+) -> Vec<proc_macro2::TokenStream> {
+    let field_info: Vec<_> = fields
+        .iter()
+        .map(|f| {
+            let field_name = if let Some(ident) = &f.ident {
+                format_ident!("{}", ident)
+            } else {
+                format_ident!("{}", to_snake_case(&f.ty.to_token_stream().to_string()))
+            };
+            arg_info_tokens(&field_name, &f.ty)
+        })
+        .collect();
+
+    let field_info: Vec<_> = field_info
+        .into_iter()
+        .zip(fields.iter().zip(field_attributes))
+        .map(|(arg_info, (f, attributes))| {
+            let field_name = match (&attributes.rename, &f.ident) {
+                (Some(rename), _) => rename.clone(),
+                (None, Some(ident)) => ident.to_string(),
+                (None, None) => to_snake_case(&f.ty.to_token_stream().to_string()),
+            };
+
+            if let Some((schema, schema_tokens)) = &attributes.schema {
+                let name_tokens = calculate_token_count(&field_name);
+                return quote! {
+                    (
+                        ::openai_func_enums::serde_json::json!({ #field_name: ::openai_func_enums::serde_json::from_str::<::openai_func_enums::serde_json::Value>(#schema).unwrap() }),
+                        #name_tokens + #schema_tokens
+                    )
+                };
+            }
+
+            let name_tokens = calculate_token_count(&field_name);
+            let field_type = option_inner_type(&f.ty).unwrap_or(&f.ty);
+            if attributes.rename.is_none() {
+                if f.ident.is_none() || !uses_enum_info(field_type) {
+                    return arg_info;
+                }
+
+                // Nested structs are named after the field, enums after their type.
+                return quote! {
+                    {
+                        let (mut arg_json, arg_tokens) = #arg_info;
+                        if <#field_type as ::openai_func_enums::EnumDescriptor>::named_after_field() {
+                            let replaced_tokens = ::openai_func_enums::rename_arg(&mut arg_json, #field_name)
+                                .map_or(0, |name| ::openai_func_enums::count_tokens(&name));
+                            (arg_json, arg_tokens - replaced_tokens + #name_tokens)
+                        } else {
+                            (arg_json, arg_tokens)
+                        }
+                    }
+                };
+            }
+
+            quote! {
+                {
+                    let (mut arg_json, arg_tokens) = #arg_info;
+                    let replaced_tokens = ::openai_func_enums::rename_arg(&mut arg_json, #field_name)
+                        .map_or(0, |name| ::openai_func_enums::count_tokens(&name));
+                    (arg_json, arg_tokens - replaced_tokens + #name_tokens)
+                }
+            }
+        })
+        .collect();
+
+    field_info
+        .into_iter()
+        .zip(fields.iter().zip(field_attributes))
+        .map(|(arg_info, (f, attributes))| {
+            if attributes.description.is_none()
+                && attributes.examples.is_empty()
+                && attributes.constraints.is_empty()
+                && attributes.default.is_none()
+            {
+                return arg_info;
+            }
+
+            let describe = attributes.description.as_ref().map(|(description, description_tokens)| {
+                let field_type = option_inner_type(&f.ty).unwrap_or(&f.ty);
+                // An enum argument already carries the description from its
+                // `arg_description`, which the field's own description replaces.
+                let replaced_tokens = if uses_enum_info(field_type) && attributes.schema.is_none() {
+                    quote! { <#field_type as ::openai_func_enums::EnumDescriptor>::arg_description_with_token_count().1 }
+                } else {
+                    quote! { 0 }
+                };
+
+                quote! {
+                    let had_description = ::openai_func_enums::set_arg_description(&mut arg_json, #description);
+                    let structure_tokens = if had_description { 0 } else { #ARG_DESCRIPTION_STRUCTURE_TOKENS };
+                    arg_tokens = arg_tokens - #replaced_tokens + structure_tokens + #description_tokens;
+                }
+            });
+
+            let add_examples = (!attributes.examples.is_empty()).then(|| {
+                let examples = &attributes.examples;
+                let example_tokens = &attributes.example_tokens;
+                let example_structure_tokens = EXAMPLE_STRUCTURE_TOKENS;
+
+                quote! {
+                    ::openai_func_enums::set_arg_property(
+                        &mut arg_json,
+                        "examples",
+                        ::openai_func_enums::serde_json::Value::Array(vec![#(::openai_func_enums::serde_json::json!(#examples)),*]),
+                    );
+                    arg_tokens += #EXAMPLES_STRUCTURE_TOKENS #(+ #example_structure_tokens + #example_tokens)*;
+                }
+            });
+
+            let add_constraints = attributes.constraints.iter().map(|(keyword, value, tokens)| {
+                quote! {
+                    ::openai_func_enums::set_arg_property(&mut arg_json, #keyword, ::openai_func_enums::serde_json::json!(#value));
+                    arg_tokens += #tokens;
+                }
+            });
+
+            let add_default = attributes.default.as_ref().map(|(default, default_tokens)| {
+                let default_structure_tokens = DEFAULT_STRUCTURE_TOKENS;
+                quote! {
+                    ::openai_func_enums::set_arg_property(&mut arg_json, "default", ::openai_func_enums::serde_json::json!(#default));
+                    arg_tokens += #default_structure_tokens + #default_tokens;
+                }
+            });
+
+            quote! {
+                {
+                    let (mut arg_json, mut arg_tokens) = #arg_info;
+                    #describe
+                    #add_examples
+                    #(#add_constraints)*
+                    #add_default
+                    (arg_json, arg_tokens)
+                }
+            }
+        })
+        .collect()
+}
+
+/// Generates the expression that builds the schema of a single argument and its token count.
+fn arg_info_tokens(field_name: &Ident, field_type: &syn::Type) -> proc_macro2::TokenStream {
+    let number_ident = format_ident!("number");
+    let integer_ident = format_ident!("integer");
+    let string_ident = format_ident!("string");
+    let boolean_ident = format_ident!("boolean");
+    let object_ident = format_ident!("object");
+    let array_ident = format_ident!("array");
+
+    // Optional arguments use the schema of the type they wrap and are left out of the required
+    // list, see `fields_required`.
+    if let Some(inner_type) = option_inner_type(field_type) {
+        return arg_info_tokens(field_name, inner_type);
+    }
+
+    match field_type {
+        syn::Type::Path(typepath) if typepath.qself.is_none() => {
+            let type_ident = &typepath.path.segments.last().unwrap().ident;
+
+            match type_ident.to_string().as_str() {
+                "f32" | "f64" => {
+                    return quote! {
+                        ::openai_func_enums::generate_value_arg_info!(#number_ident, #field_name)
+                    };
+                }
+                "u8" | "u16" | "u32" | "u64" | "u128" | "usize" | "i8" | "i16" | "i32" | "i64"
+                | "i128" | "isize" => {
+                    return quote! {
+                        ::openai_func_enums::generate_value_arg_info!(#integer_ident, #field_name)
+                    };
+                }
+                "String" | "&str" => {
+                    return quote! {
+                        ::openai_func_enums::generate_value_arg_info!(#string_ident, #field_name)
+                    };
+                }
+                "bool" => {
+                    return quote! {
+                        ::openai_func_enums::generate_value_arg_info!(#boolean_ident, #field_name)
+                    };
+                }
+                "HashMap" | "BTreeMap" => {
+                    let Some(value_type) = map_value_type(field_type) else {
+                        return quote! {
+                            ::openai_func_enums::generate_value_arg_info!(#object_ident, #field_name)
+                        };
+                    };
+
+                    // The keys are whatever the model sends, the values get the schema an
+                    // argument of their type would have.
+                    return nested_arg_info_tokens(
+                        field_name,
+                        value_type,
+                        "object",
+                        "additionalProperties",
+                        MAP_STRUCTURE_TOKENS,
+                    );
+                }
+                "Vec" => {
+                    let Some(item_type) = generic_argument(field_type, "Vec") else {
+                        return quote! {
+                            ::openai_func_enums::generate_value_arg_info!(#array_ident, #field_name)
+                        };
+                    };
+
+                    // The items get the schema an argument of their type would have.
+                    return nested_arg_info_tokens(
+                        field_name,
+                        item_type,
+                        "array",
+                        "items",
+                        ARRAY_STRUCTURE_TOKENS,
+                    );
+                }
+                _ => {
+                    return quote! {
+                        ::openai_func_enums::generate_enum_info!(#field_type)
+                    };
+                }
+            }
+        }
+        syn::Type::Tuple(_) => {
+            println!("Field {} is of tuple type", field_name);
+        }
+        syn::Type::Array(_) => {
+            println!("Field {} is of array type", field_name);
+            return quote! {
+                ::openai_func_enums::generate_value_arg_info!(#array_ident, #field_name)
+            };
+        }
+        _ => {
+            println!("Field {} is of another type.", field_name);
+        }
+    }
+    quote! {}
+}
+
+/// Returns `T` if `ty` is `Option<T>`.
+fn option_inner_type(ty: &syn::Type) -> Option<&syn::Type> {
+    generic_argument(ty, "Option")
+}
+
+/// The default of a field as a Rust expression of its type, when the field is a number, a
+/// `bool` or a `String`, or an `Option` of one. The compiler then checks the default fits, so
+/// `default = "20"` on a `u32` doesn't build. Other types go through `serde_json` and
+/// `check_defaults`.
+fn typed_default(field_type: &syn::Type, default: &Expr) -> Option<proc_macro2::TokenStream> {
+    let (inner_type, optional) = match option_inner_type(field_type) {
+        Some(inner_type) => (inner_type, true),
+        None => (field_type, false),
+    };
+    let syn::Type::Path(typepath) = inner_type else {
+        return None;
+    };
+    let type_name = typepath.path.get_ident()?.to_string();
+
+    let value = match (type_name.as_str(), default) {
+        (
+            "f32" | "f64",
+            Expr::Lit(syn::ExprLit {
+                lit: Lit::Int(int), ..
+            }),
+        ) => {
+            // JSON doesn't tell `20` from `20.0`, Rust does.
+            let float = syn::LitFloat::new(&format!("{}.0", int.base10_digits()), int.span());
+            quote! { #float }
+        }
+        (
+            "u8" | "u16" | "u32" | "u64" | "u128" | "usize" | "i8" | "i16" | "i32" | "i64" | "i128"
+            | "isize" | "f32" | "f64" | "bool",
+            default,
+        ) => quote! { #default },
+        ("String", default) => quote! { String::from(#default) },
+        _ => return None,
+    };
+
+    Some(if optional {
+        quote! { Some(#value) }
+    } else {
+        value
+    })
+}
+
+/// The tokens a struct argument's `{"name": ...}` wrapper and the `"type": "object"` of its
+/// parameters add, on top of the properties themselves.
+const NESTED_OBJECT_STRUCTURE_TOKENS: usize = 10;
+
+/// The tokens `{"name": {"type": "object", "additionalProperties": ...}}` adds around the
+/// schema of a map's values.
+const MAP_STRUCTURE_TOKENS: usize = 13;
+
+/// The tokens `{"name": {"type": "array", "items": ...}}` adds around the schema of an
+/// array's items.
+const ARRAY_STRUCTURE_TOKENS: usize = 11;
+
+/// Generates the schema of a map or array argument, `{"name": {"type": container, inner_key:
+/// schema}}`, where the schema is the one an argument of `inner_type` would have, and its token
+/// count with `structure_tokens` for the JSON around it.
+fn nested_arg_info_tokens(
+    field_name: &Ident,
+    inner_type: &syn::Type,
+    container: &str,
+    inner_key: &str,
+    structure_tokens: usize,
+) -> proc_macro2::TokenStream {
+    let inner_info = arg_info_tokens(field_name, inner_type);
+    quote! {
+        {
+            let (inner_json, inner_tokens) = #inner_info;
+            let inner_schema = inner_json
+                .as_object()
+                .and_then(|arg| arg.values().next())
+                .cloned()
+                .unwrap_or_else(|| ::openai_func_enums::serde_json::json!({ "type": "string" }));
+            (
+                ::openai_func_enums::serde_json::json!({ stringify!(#field_name): { "type": #container, #inner_key: inner_schema } }),
+                inner_tokens + #structure_tokens
+            )
+        }
+    }
+}
+
+/// Returns `V` if `ty` is a `HashMap<K, V>` or `BTreeMap<K, V>`.
+fn map_value_type(ty: &syn::Type) -> Option<&syn::Type> {
+    let syn::Type::Path(typepath) = ty else {
+        return None;
+    };
+
+    let segment = typepath.path.segments.last()?;
+    if segment.ident != "HashMap" && segment.ident != "BTreeMap" {
+        return None;
+    }
+
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(arguments) => match arguments.args.iter().nth(1)? {
+            syn::GenericArgument::Type(value_type) => Some(value_type),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Returns `T` if `ty` is `outer<T, ..>`.
+fn generic_argument<'a>(ty: &'a syn::Type, outer: &str) -> Option<&'a syn::Type> {
+    let syn::Type::Path(typepath) = ty else {
+        return None;
+    };
+    if typepath.qself.is_some() {
+        return None;
+    }
+
+    let segment = typepath.path.segments.last()?;
+    if segment.ident != outer {
+        return None;
+    }
+
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(arguments) => match arguments.args.first()? {
+            syn::GenericArgument::Type(inner_type) => Some(inner_type),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Mistakes the derives reject when they expand, rather than with a panic or with broken
+/// generated code. Each of these has to fail to compile.
+///
+/// An `entry` that names no variant:
+///
+/// ```compile_fail
+/// # use openai_func_enums::*;
+/// #[derive(Debug, ToolSet)]
+/// #[func_enums(entry = "Ask")]
+/// pub enum Commands {
+///     /// Gets the weather in a city
+///     GetWeather { city: String },
+/// }
+/// # #[async_trait::async_trait]
+/// # impl RunCommand for Commands {
+/// #     async fn run(
+/// #         &self,
+/// #         _: ToolCallExecutionStrategy,
+/// #         _: Option<Vec<String>>,
+/// #         _: std::sync::Arc<Logger>,
+/// #         _: Option<(String, usize)>,
+/// #         _: &(),
+/// #     ) -> Result<ToolOutput, Box<dyn std::error::Error + Send + Sync + 'static>> {
+/// #         Ok(ToolOutput::none())
+/// #     }
+/// # }
+/// ```
+///
+/// A function name the API doesn't accept:
+///
+/// ```compile_fail
+/// # use openai_func_enums::*;
+/// #[derive(Debug, ToolSet)]
+/// pub enum Commands {
+///     /// Gets the weather in a city
+///     #[func_enums(rename = "get weather")]
+///     GetWeather { city: String },
+/// }
+/// # #[async_trait::async_trait]
+/// # impl RunCommand for Commands {
+/// #     async fn run(
+/// #         &self,
+/// #         _: ToolCallExecutionStrategy,
+/// #         _: Option<Vec<String>>,
+/// #         _: std::sync::Arc<Logger>,
+/// #         _: Option<(String, usize)>,
+/// #         _: &(),
+/// #     ) -> Result<ToolOutput, Box<dyn std::error::Error + Send + Sync + 'static>> {
+/// #         Ok(ToolOutput::none())
+/// #     }
+/// # }
+/// ```
+///
+/// A default that doesn't fit the type of its field:
+///
+/// ```compile_fail
+/// # use openai_func_enums::*;
+/// #[derive(Debug, ToolSet)]
+/// pub enum Commands {
+///     /// Lists the open orders
+///     ListOrders {
+///         #[func_enums(default = "20")]
+///         limit: u32,
+///     },
+/// }
+/// # #[async_trait::async_trait]
+/// # impl RunCommand for Commands {
+/// #     async fn run(
+/// #         &self,
+/// #         _: ToolCallExecutionStrategy,
+/// #         _: Option<Vec<String>>,
+/// #         _: std::sync::Arc<Logger>,
+/// #         _: Option<(String, usize)>,
+/// #         _: &(),
+/// #     ) -> Result<ToolOutput, Box<dyn std::error::Error + Send + Sync + 'static>> {
+/// #         Ok(ToolOutput::none())
+/// #     }
+/// # }
+/// ```
+///
+/// `ToolArg` on something other than an enum:
+///
+/// ```compile_fail
+/// # use openai_func_enums::ToolArg;
+/// #[derive(ToolArg)]
+/// pub struct Location {
+///     city: String,
+/// }
+/// ```
+///
+/// `ToolArgs` on something other than a struct with named fields:
+///
+/// ```compile_fail
+/// # use openai_func_enums::ToolArgs;
+/// #[derive(serde::Deserialize, ToolArgs)]
+/// pub struct Point(f64, f64);
+/// ```
+#[cfg(doctest)]
+struct CompileFailTests;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(tokens: proc_macro2::TokenStream) -> syn::Field {
+        let fields: syn::FieldsNamed = syn::parse2(quote! { { #tokens } }).unwrap();
+        fields.named.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn names_convert_between_cases() {
+        assert_eq!(to_snake_case("HelloWorld"), "hello_world");
+        assert_eq!(to_snake_case("GPT"), "g_p_t");
+        assert_eq!(to_camel_case("get_current_weather"), "GetCurrentWeather");
+    }
+
+    #[test]
+    fn token_counts_come_from_cl100k() {
+        assert_eq!(calculate_token_count(""), 0);
+        assert_eq!(calculate_token_count("Hello, world!"), 4);
+    }
+
+    #[test]
+    fn field_attributes_collect_constraints_and_defaults() {
+        let attributes = parse_field_attributes(&field(quote! {
+            #[func_enums(min = 1, max = 1440, default = 20)]
+            minutes: u32
+        }))
+        .unwrap();
+
+        let keywords: Vec<_> = attributes
+            .constraints
+            .iter()
+            .map(|(keyword, _, _)| *keyword)
+            .collect();
+        assert_eq!(keywords, ["minimum", "maximum"]);
+        assert!(attributes.default.is_some());
+    }
+
+    #[test]
+    fn field_renames_come_from_either_attribute() {
+        let attributes = parse_field_attributes(&field(quote! {
+            #[func_enums(rename = "limit")]
+            max_results: u32
+        }))
+        .unwrap();
+        assert_eq!(attributes.rename.as_deref(), Some("limit"));
+        assert!(attributes.own_rename);
+
+        let attributes = parse_field_attributes(&field(quote! {
+            #[serde(rename = "limit")]
+            max_results: u32
+        }))
+        .unwrap();
+        assert_eq!(attributes.rename.as_deref(), Some("limit"));
+        assert!(!attributes.own_rename);
+    }
+
+    #[test]
+    fn constraint_values_are_checked() {
+        let error = |tokens| {
+            parse_field_attributes(&field(tokens))
+                .err()
+                .map(|e| e.to_string())
+        };
+
+        assert_eq!(
+            error(quote! { #[func_enums(min = "0")] minutes: u32 }).as_deref(),
+            Some("min takes a number")
+        );
+        assert_eq!(
+            error(quote! { #[func_enums(max_length = 2.5)] name: String }).as_deref(),
+            Some("max_length takes a non-negative integer")
+        );
+        assert_eq!(
+            error(quote! { #[func_enums(format = 1)] email: String }).as_deref(),
+            Some("format takes a string")
+        );
+        assert!(
+            error(quote! { #[func_enums(pattern = "^[A-Z")] team: String })
+                .unwrap()
+                .starts_with("pattern is not a valid regex")
+        );
+        assert_eq!(
+            error(quote! { #[func_enums(min = 0.5, pattern = "^[A-Z]{3}$")] team: String }),
+            None
+        );
+    }
+
+    #[test]
+    fn unknown_field_attributes_are_errors() {
+        assert!(parse_field_attributes(&field(quote! {
+            #[func_enums(minimum = 1)]
+            minutes: u32
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn unknown_arg_keys_are_errors_without_clap() {
+        let parsed = parse_field_attributes(&field(quote! {
+            #[arg(long, description = "The minutes")]
+            minutes: u32
+        }));
+
+        assert_eq!(parsed.is_err(), !cfg!(feature = "clap"));
     }
 }
@@ -0,0 +1,330 @@
+use crate::provider::message_text;
+use crate::{http_client, LlmProvider, RequestFailure, RunConfig};
+use async_openai::error::OpenAIError;
+use async_openai::types::{CreateChatCompletionRequest, CreateChatCompletionResponse};
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+/// The Messages API endpoint used when no other is set.
+pub const DEFAULT_ANTHROPIC_API_BASE: &str = "https://api.anthropic.com";
+
+/// The `anthropic-version` header sent with every request.
+pub const DEFAULT_ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// The `max_tokens` of a request without a response limit. The Messages API requires one,
+/// and every Claude model can generate at least this many.
+pub const DEFAULT_ANTHROPIC_MAX_TOKENS: u32 = 4096;
+
+/// Claude's Messages API, for running a `ToolSet` against Anthropic models. Put it on
+/// `RunConfig::provider` and use a Claude model name for the run, like
+/// `claude-sonnet-4-5`.
+///
+/// The derived function JSON becomes Claude's `tools`, sorted by name so that the tool block
+/// is the same from one turn to the next however the functions were ranked. With
+/// `cache_tools` a `cache_control` breakpoint goes on the last tool, and with `cache_system`
+/// on the system prompt, so that repeated requests read the tools and system prompt from
+/// Anthropic's prompt cache instead of paying for them again.
+///
+/// Streamed runs get the whole response as a single chunk.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct AnthropicProvider {
+    pub api_base: String,
+    /// Uses `ANTHROPIC_API_KEY` when `None`.
+    pub api_key: Option<String>,
+    pub version: String,
+    pub cache_tools: bool,
+    pub cache_system: bool,
+}
+
+impl Default for AnthropicProvider {
+    fn default() -> Self {
+        AnthropicProvider {
+            api_base: DEFAULT_ANTHROPIC_API_BASE.to_string(),
+            api_key: None,
+            version: DEFAULT_ANTHROPIC_VERSION.to_string(),
+            cache_tools: true,
+            cache_system: true,
+        }
+    }
+}
+
+impl AnthropicProvider {
+    pub fn new() -> Self {
+        AnthropicProvider::default()
+    }
+
+    pub fn with_api_base(mut self, api_base: impl Into<String>) -> Self {
+        self.api_base = api_base.into();
+        self
+    }
+
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    pub fn with_version(mut self, version: impl Into<String>) -> Self {
+        self.version = version.into();
+        self
+    }
+
+    /// Turns the `cache_control` breakpoints on or off, for both the tools and the system
+    /// prompt.
+    pub fn with_prompt_caching(mut self, enabled: bool) -> Self {
+        self.cache_tools = enabled;
+        self.cache_system = enabled;
+        self
+    }
+
+    /// The Messages API body for an OpenAI request, with `extra_body` merged into it.
+    pub fn messages_request(
+        &self,
+        request: &CreateChatCompletionRequest,
+        config: &RunConfig,
+    ) -> Result<Value, OpenAIError> {
+        let body = serde_json::to_value(request).map_err(OpenAIError::JSONDeserialize)?;
+
+        let mut system = Vec::new();
+        let mut messages: Vec<Value> = Vec::new();
+        for message in body["messages"].as_array().into_iter().flatten() {
+            let content = message_text(&message["content"]);
+            let (role, blocks) = match message["role"].as_str() {
+                Some("system") | Some("developer") => {
+                    if !content.is_empty() {
+                        system.push(json!({ "type": "text", "text": content }));
+                    }
+                    continue;
+                }
+                Some("user") => ("user", vec![json!({ "type": "text", "text": content })]),
+                Some("assistant") => {
+                    let mut blocks = Vec::new();
+                    if !content.is_empty() {
+                        blocks.push(json!({ "type": "text", "text": content }));
+                    }
+                    for tool_call in message["tool_calls"].as_array().into_iter().flatten() {
+                        let input = tool_call["function"]["arguments"]
+                            .as_str()
+                            .and_then(|arguments| serde_json::from_str(arguments).ok())
+                            .unwrap_or_else(|| json!({}));
+                        blocks.push(json!({
+                            "type": "tool_use",
+                            "id": tool_call["id"],
+                            "name": tool_call["function"]["name"],
+                            "input": input,
+                        }));
+                    }
+                    ("assistant", blocks)
+                }
+                Some("tool") => (
+                    "user",
+                    vec![json!({
+                        "type": "tool_result",
+                        "tool_use_id": message["tool_call_id"],
+                        "content": content,
+                    })],
+                ),
+                _ => continue,
+            };
+
+            if blocks.is_empty() {
+                continue;
+            }
+
+            // Claude expects the roles to alternate, so the results of parallel tool calls
+            // go in one user message.
+            match messages.last_mut() {
+                Some(last) if last["role"] == role => {
+                    if let Some(content) = last["content"].as_array_mut() {
+                        content.extend(blocks);
+                    }
+                }
+                _ => messages.push(json!({ "role": role, "content": blocks })),
+            }
+        }
+
+        let mut tools: Vec<Value> = request
+            .tools
+            .iter()
+            .flatten()
+            .map(|tool| {
+                json!({
+                    "name": tool.function.name,
+                    "description": tool.function.description.clone().unwrap_or_default(),
+                    "input_schema": tool
+                        .function
+                        .parameters
+                        .clone()
+                        .unwrap_or_else(|| json!({ "type": "object", "properties": {} })),
+                })
+            })
+            .collect();
+        tools.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+
+        if self.cache_tools {
+            if let Some(last) = tools.last_mut() {
+                last["cache_control"] = json!({ "type": "ephemeral" });
+            }
+        }
+        if self.cache_system {
+            if let Some(last) = system.last_mut() {
+                last["cache_control"] = json!({ "type": "ephemeral" });
+            }
+        }
+
+        #[allow(deprecated)]
+        let max_tokens = request
+            .max_completion_tokens
+            .or(request.max_tokens)
+            .unwrap_or(DEFAULT_ANTHROPIC_MAX_TOKENS);
+
+        let mut anthropic_request = Map::new();
+        anthropic_request.insert("model".to_string(), request.model.clone().into());
+        anthropic_request.insert("max_tokens".to_string(), max_tokens.into());
+        anthropic_request.insert("messages".to_string(), messages.into());
+        if !system.is_empty() {
+            anthropic_request.insert("system".to_string(), system.into());
+        }
+        if let Some(temperature) = request.temperature {
+            anthropic_request.insert("temperature".to_string(), temperature.into());
+        }
+
+        if !tools.is_empty() {
+            anthropic_request.insert("tools".to_string(), tools.into());
+
+            let mut tool_choice = match &body["tool_choice"] {
+                Value::String(choice) if choice == "required" => json!({ "type": "any" }),
+                Value::String(choice) if choice == "none" => json!({ "type": "none" }),
+                Value::Object(choice) => {
+                    json!({ "type": "tool", "name": choice["function"]["name"] })
+                }
+                _ => json!({ "type": "auto" }),
+            };
+            if request.parallel_tool_calls == Some(false) {
+                tool_choice["disable_parallel_tool_use"] = true.into();
+            }
+            anthropic_request.insert("tool_choice".to_string(), tool_choice);
+        }
+
+        for (key, value) in &config.extra_body {
+            anthropic_request.insert(key.clone(), value.clone());
+        }
+
+        Ok(Value::Object(anthropic_request))
+    }
+}
+
+#[async_trait]
+impl LlmProvider for AnthropicProvider {
+    fn name(&self) -> &str {
+        "anthropic"
+    }
+
+    async fn chat_completion(
+        &self,
+        request: CreateChatCompletionRequest,
+        config: &RunConfig,
+    ) -> Result<CreateChatCompletionResponse, OpenAIError> {
+        self.try_chat_completion(request, config)
+            .await
+            .map_err(|failure| failure.error)
+    }
+
+    async fn try_chat_completion(
+        &self,
+        request: CreateChatCompletionRequest,
+        config: &RunConfig,
+    ) -> Result<CreateChatCompletionResponse, RequestFailure> {
+        let body = self.messages_request(&request, config)?;
+        let api_key = match &self.api_key {
+            Some(api_key) => api_key.clone(),
+            None => std::env::var("ANTHROPIC_API_KEY").unwrap_or_default(),
+        };
+
+        let client = config
+            .http_client
+            .clone()
+            .unwrap_or_else(|| http_client().clone());
+        let response = client
+            .post(format!(
+                "{}/v1/messages",
+                self.api_base.trim_end_matches('/')
+            ))
+            .header("x-api-key", api_key)
+            .header("anthropic-version", &self.version)
+            .header("content-type", "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .map_err(OpenAIError::Reqwest)?;
+
+        let status = response.status();
+        let headers = response.headers().clone();
+        let text = response.text().await.map_err(OpenAIError::Reqwest)?;
+        if !status.is_success() {
+            return Err(RequestFailure::from_response(status, &headers, &text));
+        }
+
+        let response: Value = serde_json::from_str(&text).map_err(OpenAIError::JSONDeserialize)?;
+        Ok(chat_completion_response(&response)?)
+    }
+}
+
+/// The OpenAI counterpart of a Messages API response. The cached tokens are counted in the
+/// prompt tokens and reported in its details.
+fn chat_completion_response(response: &Value) -> Result<CreateChatCompletionResponse, OpenAIError> {
+    let mut text = Vec::new();
+    let mut tool_calls = Vec::new();
+    for block in response["content"].as_array().into_iter().flatten() {
+        match block["type"].as_str() {
+            Some("text") => text.extend(block["text"].as_str()),
+            Some("tool_use") => tool_calls.push(json!({
+                "id": block["id"],
+                "type": "function",
+                "function": {
+                    "name": block["name"],
+                    "arguments": block["input"].to_string(),
+                }
+            })),
+            _ => {}
+        }
+    }
+
+    let text = (!text.is_empty()).then(|| text.join("\n"));
+    let mut message = json!({ "role": "assistant", "content": text });
+    if !tool_calls.is_empty() {
+        message["tool_calls"] = tool_calls.into();
+    }
+    let finish_reason = match response["stop_reason"].as_str() {
+        Some("tool_use") => "tool_calls",
+        Some("max_tokens") => "length",
+        _ => "stop",
+    };
+
+    let usage = &response["usage"];
+    let cached_tokens = usage["cache_read_input_tokens"]
+        .as_u64()
+        .unwrap_or_default();
+    let prompt_tokens = usage["input_tokens"].as_u64().unwrap_or_default()
+        + usage["cache_creation_input_tokens"]
+            .as_u64()
+            .unwrap_or_default()
+        + cached_tokens;
+    let completion_tokens = usage["output_tokens"].as_u64().unwrap_or_default();
+
+    serde_json::from_value(json!({
+        "id": response["id"].as_str().unwrap_or_default(),
+        "object": "chat.completion",
+        "created": 0,
+        "model": response["model"].as_str().unwrap_or_default(),
+        "choices": [{ "index": 0, "message": message, "finish_reason": finish_reason }],
+        "usage": {
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": completion_tokens,
+            "total_tokens": prompt_tokens + completion_tokens,
+            "prompt_tokens_details": { "cached_tokens": cached_tokens },
+        }
+    }))
+    .map_err(OpenAIError::JSONDeserialize)
+}
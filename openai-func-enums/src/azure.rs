@@ -0,0 +1,149 @@
+use crate::config::{post_chat_completion, send_chat_completion_stream};
+use crate::{http_client, LlmProvider, RequestFailure, RunConfig};
+use async_openai::config::AzureConfig;
+use async_openai::error::OpenAIError;
+use async_openai::types::{
+    ChatCompletionResponseStream, CreateChatCompletionRequest, CreateChatCompletionResponse,
+};
+use async_openai::Client;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::sync::OnceLock;
+
+/// The API version used when `AZURE_OPENAI_API_VERSION` isn't set.
+pub const DEFAULT_AZURE_API_VERSION: &str = "2024-10-21";
+
+/// An Azure OpenAI deployment to send chat requests to instead of the OpenAI API. Put it on
+/// `RunConfig::provider`, or set the `AZURE_OPENAI_*` variables `from_env` reads and every
+/// run without a provider uses it.
+///
+/// Azure picks the model from the deployment, so the model name of a run only matters for
+/// the token limits. The cassette and API key pool only apply to the OpenAI API.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct AzureOpenAiProvider {
+    /// The resource's endpoint, like `https://my-resource.openai.azure.com`.
+    pub endpoint: String,
+    pub deployment: String,
+    pub api_version: String,
+    /// Uses `OPENAI_API_KEY` when `None`, like async-openai does.
+    pub api_key: Option<String>,
+}
+
+impl AzureOpenAiProvider {
+    pub fn new(endpoint: impl Into<String>, deployment: impl Into<String>) -> Self {
+        AzureOpenAiProvider {
+            endpoint: endpoint.into(),
+            deployment: deployment.into(),
+            api_version: DEFAULT_AZURE_API_VERSION.to_string(),
+            api_key: None,
+        }
+    }
+
+    pub fn with_api_version(mut self, api_version: impl Into<String>) -> Self {
+        self.api_version = api_version.into();
+        self
+    }
+
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// Reads the deployment from `AZURE_OPENAI_ENDPOINT`, `AZURE_OPENAI_DEPLOYMENT`,
+    /// `AZURE_OPENAI_API_VERSION` and `AZURE_OPENAI_API_KEY`. `None` unless both the endpoint
+    /// and the deployment are set.
+    pub fn from_env() -> Option<Self> {
+        let endpoint = std::env::var("AZURE_OPENAI_ENDPOINT").ok()?;
+        let deployment = std::env::var("AZURE_OPENAI_DEPLOYMENT").ok()?;
+
+        let mut provider = AzureOpenAiProvider::new(endpoint, deployment);
+        if let Ok(api_version) = std::env::var("AZURE_OPENAI_API_VERSION") {
+            provider = provider.with_api_version(api_version);
+        }
+        if let Ok(api_key) = std::env::var("AZURE_OPENAI_API_KEY") {
+            provider = provider.with_api_key(api_key);
+        }
+
+        Some(provider)
+    }
+
+    pub fn azure_config(&self) -> AzureConfig {
+        let azure_config = AzureConfig::new()
+            .with_api_base(&self.endpoint)
+            .with_deployment_id(&self.deployment)
+            .with_api_version(&self.api_version);
+
+        match &self.api_key {
+            Some(api_key) => azure_config.with_api_key(api_key),
+            None => azure_config,
+        }
+    }
+
+    /// The client requests are sent with, sharing the config's HTTP client and retry policy.
+    pub fn client(&self, config: &RunConfig) -> Client<AzureConfig> {
+        let http_client = config
+            .http_client
+            .clone()
+            .unwrap_or_else(|| http_client().clone());
+        let client = Client::with_config(self.azure_config()).with_http_client(http_client);
+
+        match &config.retry_policy {
+            Some(retry_policy) => client.with_backoff(retry_policy.backoff()),
+            None => client,
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for AzureOpenAiProvider {
+    fn name(&self) -> &str {
+        "azure"
+    }
+
+    async fn chat_completion(
+        &self,
+        request: CreateChatCompletionRequest,
+        config: &RunConfig,
+    ) -> Result<CreateChatCompletionResponse, OpenAIError> {
+        self.try_chat_completion(request, config)
+            .await
+            .map_err(|failure| failure.error)
+    }
+
+    async fn try_chat_completion(
+        &self,
+        request: CreateChatCompletionRequest,
+        config: &RunConfig,
+    ) -> Result<CreateChatCompletionResponse, RequestFailure> {
+        // Retried by `RunConfig::create_chat_completion`.
+        let http_client = config
+            .http_client
+            .clone()
+            .unwrap_or_else(|| http_client().clone());
+        post_chat_completion(
+            &http_client,
+            &self.azure_config(),
+            &request,
+            &config.extra_body,
+        )
+        .await
+    }
+
+    async fn chat_completion_stream(
+        &self,
+        request: CreateChatCompletionRequest,
+        config: &RunConfig,
+    ) -> Result<ChatCompletionResponseStream, OpenAIError> {
+        let client = self.client(config);
+        let request = config.with_stream_usage(client.config(), request);
+        send_chat_completion_stream(&client, request, &config.extra_body).await
+    }
+}
+
+/// The deployment from the environment, read once.
+pub(crate) fn env_azure_provider() -> Option<&'static AzureOpenAiProvider> {
+    static ENV_AZURE_PROVIDER: OnceLock<Option<AzureOpenAiProvider>> = OnceLock::new();
+    ENV_AZURE_PROVIDER
+        .get_or_init(AzureOpenAiProvider::from_env)
+        .as_ref()
+}
@@ -0,0 +1,304 @@
+//! Manages the embedding archive of a `ToolSet` outside of compilation.
+//!
+//! ```text
+//! cargo func-enums build-embeddings [--manifest <path>] [--archive <path>] [--catalog <path>]
+//! cargo func-enums diff [--manifest <path>] [--archive <path>] [--catalog <path>]
+//! cargo func-enums verify [--manifest <path>] [--archive <path>] [--catalog <path>]
+//! cargo func-enums inspect [--archive <path>]
+//! cargo func-enums query <prompt> [--archive <path>] [--model <model>]
+//! cargo func-enums compress --max-tokens <n> --output <path> [--manifest <path>] [--chat-model <model>]
+//! ```
+//!
+//! The archive defaults to `FUNC_ENUMS_EMBED_PATH` and the manifest to
+//! `FUNC_ENUMS_MANIFEST_PATH`, or the manifest next to the archive. `query` embeds the prompt
+//! with `--model`, `FUNC_ENUMS_EMBED_MODEL`, or the model named in the manifest. With
+//! `--catalog`, the descriptions of a `DescriptionCatalog` are embedded instead of the
+//! original ones, which makes an archive for that locale.
+//!
+//! `compress` writes a catalog with shortened versions of the descriptions that are longer
+//! than `--max-tokens`, written by `--chat-model` if one is given, see `shorten_descriptions`.
+//!
+//! Requests go out with the API and HTTP settings of `Config::from_env`, so a proxy, timeouts
+//! or headers set with the `FUNC_ENUMS_RUNTIME_*` variables apply here too.
+
+use openai_func_enums::{
+    build_embedding_archive_from, count_tokens, diff_manifest_with_archive,
+    inspect_embedding_archive, manifest_path, query_embedding_archive, shorten_descriptions,
+    ArchiveDiff, Config, DescriptionCatalog, DescriptionManifest, RunConfig,
+};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const USAGE: &str =
+    "Usage: cargo func-enums <build-embeddings|diff|verify|inspect|query <prompt>|compress> [--manifest <path>] [--archive <path>] [--catalog <path>] [--model <model>] [--max-tokens <n>] [--output <path>] [--chat-model <model>]";
+
+struct Args {
+    command: String,
+    prompt: Option<String>,
+    manifest: PathBuf,
+    archive: PathBuf,
+    catalog: Option<PathBuf>,
+    model: Option<String>,
+    max_tokens: Option<usize>,
+    output: Option<PathBuf>,
+    chat_model: Option<String>,
+}
+
+impl Args {
+    /// The manifest, translated with the catalog if there is one.
+    fn load_manifest(
+        &self,
+    ) -> Result<DescriptionManifest, Box<dyn std::error::Error + Send + Sync>> {
+        let manifest = DescriptionManifest::load(&self.manifest)
+            .map_err(|e| format!("Could not read {}: {}", self.manifest.display(), e))?;
+
+        match &self.catalog {
+            Some(catalog) => Ok(DescriptionCatalog::load(catalog)?.localize_manifest(&manifest)),
+            None => Ok(manifest),
+        }
+    }
+}
+
+/// The config requests are sent with, built from `Config::from_env`.
+fn run_config() -> Result<RunConfig, Box<dyn std::error::Error + Send + Sync>> {
+    let config = Config::from_env()?;
+    let http_client = config
+        .http
+        .as_ref()
+        .map(|http| http.build_client())
+        .transpose()?;
+
+    Ok(RunConfig {
+        http_client,
+        ..config.run_config()
+    })
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let result = match args.command.as_str() {
+        "build-embeddings" => build_embeddings(&args),
+        "diff" => diff(&args).map(|_| ExitCode::SUCCESS),
+        "verify" => verify(&args),
+        "inspect" => inspect(&args),
+        "query" => query(&args),
+        "compress" => compress(&args),
+        command => Err(format!("Unknown command '{}'", command).into()),
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn build_embeddings(args: &Args) -> Result<ExitCode, Box<dyn std::error::Error + Send + Sync>> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let manifest = args.load_manifest()?;
+    let build = runtime.block_on(build_embedding_archive_from(
+        &manifest,
+        &args.archive,
+        &run_config()?.client(),
+    ))?;
+
+    println!(
+        "Wrote {}: {} embedded, {} reused, {} removed",
+        args.archive.display(),
+        build.embedded.len(),
+        build.reused.len(),
+        build.removed.len()
+    );
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn diff(args: &Args) -> Result<ArchiveDiff, Box<dyn std::error::Error + Send + Sync>> {
+    let diff = diff_manifest_with_archive(&args.load_manifest()?, &args.archive)?;
+
+    for name in &diff.added {
+        println!("+ {}", name);
+    }
+    for name in &diff.changed {
+        println!("~ {}", name);
+    }
+    for name in &diff.removed {
+        println!("- {}", name);
+    }
+    if diff.is_up_to_date() {
+        println!("{} is up to date", args.archive.display());
+    }
+
+    Ok(diff)
+}
+
+fn verify(args: &Args) -> Result<ExitCode, Box<dyn std::error::Error + Send + Sync>> {
+    if diff(args)?.is_up_to_date() {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        eprintln!(
+            "{} is out of date, run `cargo func-enums build-embeddings`",
+            args.archive.display()
+        );
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+fn inspect(args: &Args) -> Result<ExitCode, Box<dyn std::error::Error + Send + Sync>> {
+    let functions = inspect_embedding_archive(&args.archive)?;
+    let name_width = functions
+        .iter()
+        .map(|function| function.name.len())
+        .max()
+        .unwrap_or(0);
+
+    for function in &functions {
+        println!(
+            "{:name_width$}  {:>5} dims  {:>4} tokens  {}",
+            function.name,
+            function.dimensions,
+            function.description_tokens,
+            function.description.trim(),
+        );
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn query(args: &Args) -> Result<ExitCode, Box<dyn std::error::Error + Send + Sync>> {
+    let prompt = args.prompt.as_deref().ok_or("query needs a prompt")?;
+    let model = match &args.model {
+        Some(model) => model.clone(),
+        None => DescriptionManifest::load(&args.manifest)
+            .ok()
+            .and_then(|manifest| manifest.embed_model)
+            .ok_or("Pass --model, set FUNC_ENUMS_EMBED_MODEL, or build a manifest first")?,
+    };
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let scores = runtime.block_on(query_embedding_archive(
+        &args.archive,
+        prompt,
+        &model,
+        &run_config()?,
+    ))?;
+
+    for (name, similarity) in scores {
+        println!("{:.4}  {}", similarity, name);
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn compress(args: &Args) -> Result<ExitCode, Box<dyn std::error::Error + Send + Sync>> {
+    let max_tokens = args.max_tokens.ok_or("compress needs --max-tokens")?;
+    let output = args.output.as_ref().ok_or("compress needs --output")?;
+    let manifest = args.load_manifest()?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let catalog = runtime.block_on(shorten_descriptions(
+        &manifest,
+        max_tokens,
+        args.chat_model.as_deref(),
+        &run_config()?,
+    ))?;
+
+    for (name, function) in &catalog.functions {
+        let original = manifest
+            .function(name)
+            .map_or(0, |function| count_tokens(&function.description));
+        let short = function.description.as_deref().map_or(0, count_tokens);
+        println!("{}: {} -> {} tokens", name, original, short);
+    }
+
+    std::fs::write(output, serde_json::to_string_pretty(&catalog)?)?;
+    println!(
+        "Wrote {}: {} of {} descriptions shortened",
+        output.display(),
+        catalog.functions.len(),
+        manifest.functions.len()
+    );
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = std::env::args().skip(1).peekable();
+    // Cargo passes the subcommand name along when run as `cargo func-enums`.
+    if args.peek().map(String::as_str) == Some("func-enums") {
+        args.next();
+    }
+
+    let command = args.next().ok_or("Missing command")?;
+    let mut prompt = None;
+    let mut catalog = None;
+    let mut max_tokens = None;
+    let mut output = None;
+    let mut chat_model = None;
+    let mut model = std::env::var("FUNC_ENUMS_EMBED_MODEL").ok();
+    let mut manifest = std::env::var("FUNC_ENUMS_MANIFEST_PATH")
+        .ok()
+        .map(PathBuf::from);
+    let mut archive = std::env::var("FUNC_ENUMS_EMBED_PATH")
+        .ok()
+        .map(PathBuf::from);
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            if prompt.replace(arg).is_some() {
+                return Err(String::from("Pass the prompt as a single argument"));
+            }
+            continue;
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value for {}", arg))?;
+        match arg.as_str() {
+            "--manifest" => manifest = Some(PathBuf::from(value)),
+            "--archive" => archive = Some(PathBuf::from(value)),
+            "--catalog" => catalog = Some(PathBuf::from(value)),
+            "--model" => model = Some(value),
+            "--max-tokens" => {
+                max_tokens = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("--max-tokens takes a number, not '{}'", value))?,
+                )
+            }
+            "--output" => output = Some(PathBuf::from(value)),
+            "--chat-model" => chat_model = Some(value),
+            _ => return Err(format!("Unknown option '{}'", arg)),
+        }
+    }
+
+    let archive = archive.ok_or("Pass --archive or set FUNC_ENUMS_EMBED_PATH")?;
+    let manifest = manifest.unwrap_or_else(|| manifest_path(&archive));
+
+    Ok(Args {
+        command,
+        prompt,
+        manifest,
+        archive,
+        catalog,
+        model,
+        max_tokens,
+        output,
+        chat_model,
+    })
+}
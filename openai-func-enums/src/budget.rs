@@ -0,0 +1,144 @@
+use crate::UsageReport;
+use std::error::Error;
+use std::fmt;
+
+/// A cap on the tokens or money a run, or everything metered by a `UsageMeter`, may spend, so
+/// that an agent loop that keeps going can't run up a bill.
+///
+/// Put it on `RunConfig::budget` to cap every run, including the runs its tools start, or on a
+/// shared meter with `UsageMeter::with_budget` to cap a whole conversation. Once the usage has
+/// reached the budget, further requests are refused with `BudgetExceeded`. The request that
+/// crosses the budget is not stopped, so the usage can end up past it by one response.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Budget {
+    pub max_tokens: Option<u64>,
+    pub max_cost_usd: Option<f64>,
+}
+
+impl Budget {
+    pub fn tokens(max_tokens: u64) -> Self {
+        Budget {
+            max_tokens: Some(max_tokens),
+            max_cost_usd: None,
+        }
+    }
+
+    pub fn cost_usd(max_cost_usd: f64) -> Self {
+        Budget {
+            max_tokens: None,
+            max_cost_usd: Some(max_cost_usd),
+        }
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: u64) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    pub fn with_max_cost_usd(mut self, max_cost_usd: f64) -> Self {
+        self.max_cost_usd = Some(max_cost_usd);
+        self
+    }
+
+    /// Returns `true` once `usage` has spent the tokens or the money of the budget.
+    pub fn is_spent_by(&self, usage: &UsageReport) -> bool {
+        self.max_tokens
+            .is_some_and(|max_tokens| usage.total_tokens() >= max_tokens)
+            || self
+                .max_cost_usd
+                .is_some_and(|max_cost_usd| usage.cost_usd >= max_cost_usd)
+    }
+}
+
+/// A request was refused because the budget it falls under was spent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BudgetExceeded {
+    pub budget: Budget,
+    /// The usage that spent the budget.
+    pub usage: UsageReport,
+}
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The budget is spent after {} tokens costing ${:.4}",
+            self.usage.total_tokens(),
+            self.usage.cost_usd
+        )?;
+        match (self.budget.max_tokens, self.budget.max_cost_usd) {
+            (Some(max_tokens), Some(max_cost_usd)) => {
+                write!(
+                    f,
+                    ", the limit is {} tokens or ${}",
+                    max_tokens, max_cost_usd
+                )
+            }
+            (Some(max_tokens), None) => write!(f, ", the limit is {} tokens", max_tokens),
+            (None, Some(max_cost_usd)) => write!(f, ", the limit is ${}", max_cost_usd),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+impl Error for BudgetExceeded {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::UsageMeter;
+    use async_openai::types::CompletionUsage;
+
+    fn usage(prompt_tokens: u64, completion_tokens: u64, cost_usd: f64) -> UsageReport {
+        UsageReport {
+            prompt_tokens,
+            completion_tokens,
+            cost_usd,
+            requests: 1,
+            unpriced_requests: 0,
+        }
+    }
+
+    #[test]
+    fn token_budget_is_spent_at_its_limit() {
+        let budget = Budget::tokens(1000);
+
+        assert!(!budget.is_spent_by(&usage(600, 399, 0.0)));
+        assert!(budget.is_spent_by(&usage(600, 400, 0.0)));
+    }
+
+    #[test]
+    fn either_limit_spends_the_budget() {
+        let budget = Budget::tokens(1000).with_max_cost_usd(0.5);
+
+        assert!(budget.is_spent_by(&usage(10, 10, 0.5)));
+        assert!(budget.is_spent_by(&usage(2000, 0, 0.0)));
+        assert!(!budget.is_spent_by(&usage(10, 10, 0.1)));
+    }
+
+    #[test]
+    fn empty_budget_is_never_spent() {
+        assert!(!Budget::default().is_spent_by(&usage(u64::MAX / 2, 0, f64::MAX)));
+    }
+
+    #[test]
+    fn nested_meters_check_their_parents() {
+        let session = UsageMeter::new().with_budget(Budget::tokens(100));
+        let run = UsageMeter::nested(Some(&session));
+        let usage = CompletionUsage {
+            prompt_tokens: 80,
+            completion_tokens: 30,
+            total_tokens: 110,
+            prompt_tokens_details: None,
+            completion_tokens_details: None,
+        };
+
+        assert!(run.check_budget().is_ok());
+        run.record("gpt-4o", &usage);
+
+        let error = run.check_budget().unwrap_err();
+        assert_eq!(error.budget, Budget::tokens(100));
+        assert_eq!(error.usage.total_tokens(), 110);
+        assert_eq!(run.report().total_tokens(), 110);
+    }
+}
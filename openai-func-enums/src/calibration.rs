@@ -0,0 +1,163 @@
+use crate::config::tool_tokens;
+use crate::{count_tokens, estimate_prompt_tokens};
+use async_openai::types::{ChatCompletionRequestMessage, CreateChatCompletionRequest};
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// What one request was estimated at, next to the prompt tokens the API reported for it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TokenEstimateSample {
+    pub model: String,
+    pub tools: usize,
+    /// The estimate of the tool definitions, from the compile time counts of derived
+    /// functions. This is what `FUNC_ENUMS_MAX_FUNC_TOKENS` is compared against.
+    pub estimated_tool_tokens: usize,
+    /// The messages of the request, estimated like `build_request` estimates the prompt, see
+    /// `estimate_prompt_tokens`.
+    pub message_tokens: usize,
+    pub actual_prompt_tokens: u32,
+}
+
+impl TokenEstimateSample {
+    /// Estimates `request` the way `build_request` does: the tools by their compile time
+    /// counts, and the text of every message with `estimate_prompt_tokens`. Messages without
+    /// text, like tool calls, are counted with `count_tokens`. `function_jsons` are the cached
+    /// definitions of the derived functions and their token counts.
+    pub fn new(
+        request: &CreateChatCompletionRequest,
+        function_jsons: &[&(Value, usize)],
+        actual_prompt_tokens: u32,
+    ) -> Self {
+        let tools = request.tools.as_deref().unwrap_or_default();
+
+        TokenEstimateSample {
+            model: request.model.clone(),
+            tools: tools.len(),
+            estimated_tool_tokens: tools
+                .iter()
+                .map(|tool| tool_tokens(tool, function_jsons))
+                .sum(),
+            message_tokens: request.messages.iter().map(message_tokens).sum(),
+            actual_prompt_tokens,
+        }
+    }
+
+    pub fn estimated_prompt_tokens(&self) -> usize {
+        self.estimated_tool_tokens + self.message_tokens
+    }
+
+    /// How many more tokens the API counted than were estimated, negative for an
+    /// overestimate.
+    pub fn delta(&self) -> i64 {
+        i64::from(self.actual_prompt_tokens) - self.estimated_prompt_tokens() as i64
+    }
+}
+
+fn message_tokens(message: &ChatCompletionRequestMessage) -> usize {
+    let message = serde_json::to_value(message).unwrap_or_default();
+    match &message["content"] {
+        Value::String(text) => estimate_prompt_tokens(text),
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| part["text"].as_str())
+            .map(estimate_prompt_tokens)
+            .sum(),
+        _ => count_tokens(&message.to_string()),
+    }
+}
+
+impl fmt::Display for TokenEstimateSample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let estimated = self.estimated_prompt_tokens();
+        write!(
+            f,
+            "Token estimate for {} with {} tools: {} estimated ({} for tools, {} for messages), {} actual, off by {:+}",
+            self.model,
+            self.tools,
+            estimated,
+            self.estimated_tool_tokens,
+            self.message_tokens,
+            self.actual_prompt_tokens,
+            self.delta()
+        )?;
+        if estimated > 0 {
+            write!(
+                f,
+                " ({:+.1}%)",
+                self.delta() as f64 * 100.0 / estimated as f64
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// An opt-in check of the token estimates requests are limited by. The counts of derived
+/// functions are worked out when the macro expands, with fixed allowances for the JSON around
+/// them, so they drift from what the API actually charges as models and tokenizers change.
+///
+/// Put it on `RunConfig::token_calibration`, or set `calibrate_tokens` in the runtime config,
+/// and every request that reports its usage is logged to the run's `Logger` with its estimate
+/// and the difference. Clones share their samples, so the numbers of a whole session can be
+/// summed up with `suggested_max_func_tokens`.
+#[derive(Clone, Debug, Default)]
+pub struct TokenCalibration {
+    samples: Arc<Mutex<Vec<TokenEstimateSample>>>,
+}
+
+impl TokenCalibration {
+    pub fn new() -> Self {
+        TokenCalibration::default()
+    }
+
+    pub fn record(&self, sample: TokenEstimateSample) {
+        self.lock().push(sample);
+    }
+
+    pub fn samples(&self) -> Vec<TokenEstimateSample> {
+        self.lock().clone()
+    }
+
+    /// The average `delta` of the samples, `None` without any.
+    pub fn mean_delta(&self) -> Option<f64> {
+        let samples = self.lock();
+        if samples.is_empty() {
+            return None;
+        }
+
+        Some(
+            samples
+                .iter()
+                .map(|sample| sample.delta() as f64)
+                .sum::<f64>()
+                / samples.len() as f64,
+        )
+    }
+
+    /// What `max_func_tokens` would have to be for the estimates to allow the same tools the
+    /// API actually counted. The whole difference is put down to the tool definitions, though
+    /// some of it comes from the message estimates. `None` until a sample with tools was
+    /// recorded.
+    pub fn suggested_max_func_tokens(&self, max_func_tokens: usize) -> Option<usize> {
+        let samples = self.lock();
+        let (estimated, actual) = samples
+            .iter()
+            .filter(|sample| sample.estimated_tool_tokens > 0)
+            .fold((0_i64, 0_i64), |(estimated, actual), sample| {
+                (
+                    estimated + sample.estimated_tool_tokens as i64,
+                    actual + i64::from(sample.actual_prompt_tokens) - sample.message_tokens as i64,
+                )
+            });
+        if estimated == 0 || actual <= 0 {
+            return None;
+        }
+
+        Some((max_func_tokens as f64 * estimated as f64 / actual as f64).round() as usize)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<TokenEstimateSample>> {
+        self.samples.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -0,0 +1,104 @@
+use crate::RunConfig;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use tokio_util::sync::CancellationToken;
+
+/// A run that was stopped through its `RunConfig::cancellation_token`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RunCancelled;
+
+impl fmt::Display for RunCancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The run was cancelled")
+    }
+}
+
+impl Error for RunCancelled {}
+
+pub(crate) fn current_cancellation_token() -> Option<CancellationToken> {
+    RunConfig::with_current(|config| config.cancellation_token.clone()).flatten()
+}
+
+/// Runs `future` unless `cancellation_token` is cancelled first, in which case the future is
+/// dropped and `RunCancelled` returned.
+pub(crate) async fn run_unless_cancelled<F, T, E>(
+    cancellation_token: Option<&CancellationToken>,
+    future: F,
+) -> Result<T, Box<dyn Error + Send + Sync + 'static>>
+where
+    F: Future<Output = Result<T, E>>,
+    E: Into<Box<dyn Error + Send + Sync + 'static>>,
+{
+    let Some(cancellation_token) = cancellation_token else {
+        return future.await.map_err(Into::into);
+    };
+
+    match cancellation_token.run_until_cancelled(future).await {
+        Some(output) => output.map_err(Into::into),
+        None => Err(Box::new(RunCancelled)),
+    }
+}
+
+/// Returns `true` if `output` is the error of a cancelled run.
+pub(crate) fn was_cancelled<T>(output: &Result<T, Box<dyn Error + Send + Sync + 'static>>) -> bool {
+    output
+        .as_ref()
+        .is_err_and(|e| e.downcast_ref::<RunCancelled>().is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn runs_without_a_token_finish() {
+        let output = run_unless_cancelled(None, async { Ok::<_, RunCancelled>(1) }).await;
+
+        assert_eq!(output.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn cancelled_runs_are_dropped() {
+        let cancellation_token = CancellationToken::new();
+        cancellation_token.cancel();
+
+        let output = run_unless_cancelled(Some(&cancellation_token), async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok::<_, RunCancelled>(1)
+        })
+        .await;
+
+        assert!(was_cancelled(&output));
+    }
+
+    #[tokio::test]
+    async fn errors_of_the_run_are_not_cancellations() {
+        let cancellation_token = CancellationToken::new();
+
+        let output: Result<(), _> = run_unless_cancelled(Some(&cancellation_token), async {
+            Err(crate::CommandError::new("failed"))
+        })
+        .await;
+
+        assert!(output.is_err());
+        assert!(!was_cancelled(&output));
+    }
+
+    #[tokio::test]
+    async fn the_current_token_comes_from_the_config_in_scope() {
+        let cancellation_token = CancellationToken::new();
+        let config = RunConfig {
+            cancellation_token: Some(cancellation_token.clone()),
+            ..Default::default()
+        };
+
+        assert!(current_cancellation_token().is_none());
+        let current = config.scope(async { current_cancellation_token() }).await;
+        assert!(current.is_some_and(|current| {
+            cancellation_token.cancel();
+            current.is_cancelled()
+        }));
+    }
+}
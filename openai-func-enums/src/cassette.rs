@@ -0,0 +1,292 @@
+use crate::config::{request_with_extra_body, send_chat_completion, send_chat_completion_stream};
+use crate::{stable_hash, CommandError};
+use async_openai::config::OpenAIConfig;
+use async_openai::error::OpenAIError;
+use async_openai::types::{
+    ChatCompletionResponseStream, CreateChatCompletionRequest, CreateChatCompletionResponse,
+    CreateChatCompletionStreamResponse,
+};
+use async_openai::Client;
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Whether a `Cassette` talks to the API or plays back what it recorded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CassetteMode {
+    /// Play back recorded responses, and record the ones that are missing.
+    #[default]
+    Auto,
+    /// Always call the API and record the response, replacing any earlier recording of the
+    /// same request.
+    Record,
+    /// Only play back recorded responses. A request that was never recorded is an error, which
+    /// is what CI should use so that it never reaches the API.
+    Replay,
+}
+
+impl CassetteMode {
+    /// Reads the mode from `FUNC_ENUMS_CASSETTE_MODE` (`auto`, `record` or `replay`), falling
+    /// back to `Auto`.
+    pub fn from_env() -> Result<CassetteMode, CommandError> {
+        match std::env::var("FUNC_ENUMS_CASSETTE_MODE") {
+            Ok(mode) => match mode.to_lowercase().as_str() {
+                "auto" => Ok(CassetteMode::Auto),
+                "record" => Ok(CassetteMode::Record),
+                "replay" => Ok(CassetteMode::Replay),
+                _ => Err(CommandError::new(&format!(
+                    "Invalid value for FUNC_ENUMS_CASSETTE_MODE: {}",
+                    mode
+                ))),
+            },
+            Err(_) => Ok(CassetteMode::Auto),
+        }
+    }
+}
+
+/// Records the responses of chat and embedding requests to a file and plays them back, so the
+/// full generated run loop can be tested deterministically and without an API key.
+///
+/// Put it on `RunConfig::cassette` and every chat request `run_with_config` makes goes
+/// through it, and so do embeddings made with `RunConfig::embedding`. Recordings are keyed by a hash of
+/// the request, so any change to the prompt, the tools or their descriptions is a new
+/// recording. The file is written every time something new is recorded, without blocking the
+/// executor, and is plain JSON that can be committed next to the tests.
+///
+/// ```no_run
+/// # use openai_func_enums::{Cassette, RunConfig};
+/// # fn main() -> Result<(), openai_func_enums::CommandError> {
+/// let config = RunConfig {
+///     cassette: Some(Cassette::open("tests/cassettes/weather.json")?),
+///     ..Default::default()
+/// };
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Streamed responses are recorded chunk by chunk. While recording, a streamed response is
+/// only handed on once it has arrived in full.
+#[derive(Clone, Debug)]
+pub struct Cassette {
+    path: PathBuf,
+    mode: CassetteMode,
+    interactions: Arc<Mutex<Map<String, Value>>>,
+    // Held while the file is written, so that writes happen one at a time.
+    writing: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Cassette {
+    /// Opens the cassette at `path`, with the mode from `CassetteMode::from_env`. The file does
+    /// not have to exist yet.
+    pub fn open(path: impl AsRef<Path>) -> Result<Cassette, CommandError> {
+        Cassette::open_with_mode(path, CassetteMode::from_env()?)
+    }
+
+    pub fn open_with_mode(
+        path: impl AsRef<Path>,
+        mode: CassetteMode,
+    ) -> Result<Cassette, CommandError> {
+        let path = path.as_ref();
+        let interactions = if path.exists() {
+            let text = std::fs::read_to_string(path).map_err(|e| {
+                CommandError::new(&format!("Failed to read {}: {}", path.display(), e))
+            })?;
+            serde_json::from_str(&text).map_err(|e| {
+                CommandError::new(&format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            Map::new()
+        };
+
+        Ok(Cassette {
+            path: path.to_path_buf(),
+            mode,
+            interactions: Arc::new(Mutex::new(interactions)),
+            writing: Arc::default(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    /// The number of recorded interactions.
+    pub fn len(&self) -> usize {
+        self.interactions().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.interactions().is_empty()
+    }
+
+    /// Sends a chat request, or plays back its recorded response.
+    pub async fn chat_completion(
+        &self,
+        client: &Client<OpenAIConfig>,
+        request: CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse, OpenAIError> {
+        self.chat_completion_with_extra_body(client, request, &Map::new())
+            .await
+    }
+
+    /// Same as `chat_completion`, with fields added to the request, see
+    /// `RunConfig::extra_body`. They are part of what the recording is keyed by.
+    pub async fn chat_completion_with_extra_body(
+        &self,
+        client: &Client<OpenAIConfig>,
+        request: CreateChatCompletionRequest,
+        extra_body: &Map<String, Value>,
+    ) -> Result<CreateChatCompletionResponse, OpenAIError> {
+        let key = self.chat_key("chat", &request, extra_body)?;
+        if let Some(response) = self.replay(&key)? {
+            return Ok(response);
+        }
+
+        let request_json = request_with_extra_body(&request, extra_body)?;
+        let response = send_chat_completion(client, request, extra_body).await?;
+        self.record(key, request_json, &response).await?;
+
+        Ok(response)
+    }
+
+    /// Sends a streamed chat request, or plays back its recorded chunks.
+    pub async fn chat_completion_stream(
+        &self,
+        client: &Client<OpenAIConfig>,
+        request: CreateChatCompletionRequest,
+    ) -> Result<ChatCompletionResponseStream, OpenAIError> {
+        self.chat_completion_stream_with_extra_body(client, request, &Map::new())
+            .await
+    }
+
+    /// Same as `chat_completion_stream`, with fields added to the request, see
+    /// `RunConfig::extra_body`.
+    pub async fn chat_completion_stream_with_extra_body(
+        &self,
+        client: &Client<OpenAIConfig>,
+        request: CreateChatCompletionRequest,
+        extra_body: &Map<String, Value>,
+    ) -> Result<ChatCompletionResponseStream, OpenAIError> {
+        let key = self.chat_key("chat_stream", &request, extra_body)?;
+        let chunks: Vec<CreateChatCompletionStreamResponse> = match self.replay(&key)? {
+            Some(chunks) => chunks,
+            None => {
+                let request_json = request_with_extra_body(&request, extra_body)?;
+                let mut stream = send_chat_completion_stream(client, request, extra_body).await?;
+                let mut chunks = Vec::new();
+                while let Some(chunk) = stream.next().await {
+                    chunks.push(chunk?);
+                }
+                self.record(key, request_json, &chunks).await?;
+                chunks
+            }
+        };
+
+        Ok(Box::pin(futures::stream::iter(chunks.into_iter().map(Ok))))
+    }
+
+    /// Same as `single_embedding_with_client`, or plays back the recorded embedding.
+    pub async fn embedding(
+        &self,
+        client: &Client<OpenAIConfig>,
+        text: &String,
+        model: &str,
+    ) -> Result<Vec<f32>, Box<dyn std::error::Error + Send + Sync>> {
+        let request = json!({ "model": model, "input": text });
+        let key = self.key("embedding", &request)?;
+        if let Some(embedding) = self.replay(&key)? {
+            return Ok(embedding);
+        }
+
+        let embedding = crate::single_embedding_with_client(text, model, client).await?;
+        self.record(key, request, &embedding).await?;
+
+        Ok(embedding)
+    }
+
+    /// Without extra fields the key is the one of the request itself, so that recordings made
+    /// before `extra_body` existed still match.
+    fn chat_key(
+        &self,
+        kind: &str,
+        request: &CreateChatCompletionRequest,
+        extra_body: &Map<String, Value>,
+    ) -> Result<String, OpenAIError> {
+        if extra_body.is_empty() {
+            self.key(kind, request)
+        } else {
+            self.key(kind, &request_with_extra_body(request, extra_body)?)
+        }
+    }
+
+    fn key(&self, kind: &str, request: &impl Serialize) -> Result<String, OpenAIError> {
+        let request = serde_json::to_string(request).map_err(OpenAIError::JSONDeserialize)?;
+        Ok(format!("{}-{:016x}", kind, stable_hash(request.as_bytes())))
+    }
+
+    fn replay<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, OpenAIError> {
+        if self.mode == CassetteMode::Record {
+            return Ok(None);
+        }
+
+        match self.interactions().get(key) {
+            Some(interaction) => serde_json::from_value(interaction["response"].clone())
+                .map(Some)
+                .map_err(OpenAIError::JSONDeserialize),
+            None if self.mode == CassetteMode::Replay => {
+                Err(OpenAIError::InvalidArgument(format!(
+                    "No recording for request {} in {}",
+                    key,
+                    self.path.display()
+                )))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn record(
+        &self,
+        key: String,
+        request: Value,
+        response: &impl Serialize,
+    ) -> Result<(), OpenAIError> {
+        let response = to_value(response)?;
+        self.interactions()
+            .insert(key, json!({ "request": request, "response": response }));
+
+        // Every write has all the recordings so far, so one that waited here never replaces
+        // a newer file with an older one.
+        let _writing = self.writing.lock().await;
+        let text = serde_json::to_string_pretty(&*self.interactions())
+            .map_err(OpenAIError::JSONDeserialize)?;
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| cassette_write_error(&self.path, e))?;
+        }
+        tokio::fs::write(&self.path, text)
+            .await
+            .map_err(|e| cassette_write_error(&self.path, e))
+    }
+
+    fn interactions(&self) -> MutexGuard<'_, Map<String, Value>> {
+        self.interactions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn to_value(value: &impl Serialize) -> Result<Value, OpenAIError> {
+    serde_json::to_value(value).map_err(OpenAIError::JSONDeserialize)
+}
+
+fn cassette_write_error(path: &Path, e: std::io::Error) -> OpenAIError {
+    OpenAIError::InvalidArgument(format!("Failed to write {}: {}", path.display(), e))
+}
@@ -0,0 +1,1747 @@
+use crate::azure::env_azure_provider;
+use crate::cancel::run_unless_cancelled;
+use crate::retry::{with_retries, RequestFailure};
+use crate::{
+    count_tokens, default_openai_config, http_client, load_embedding_archive_async,
+    model_request_token_limit, parse_headers, score_functions_weighted,
+    single_embedding_with_client, ApiKeyPool, AzureOpenAiProvider, Budget, BudgetExceeded,
+    CancellationToken, Cassette, CommandError, DescriptionCatalog, FewShotExamples, FinalAnswer,
+    LlmProvider, Logger, MissingScopes, OutputSanitizer, RateLimiter, RunEvent, RunEvents,
+    RunOutput, RunState, Sandbox, ScoreWeights, SessionRecorder, TokenCalibration,
+    TokenEstimateSample, ToolCallExecutionStrategy, ToolCallQueue, ToolCallResult,
+    ToolCallVerifier, ToolPriority, ToolRegistry, ToolSetFunction, UsageMeter, UsageReport,
+    UsageStats, Verdict,
+};
+use async_openai::config::{OpenAIConfig, OPENAI_API_BASE};
+use async_openai::error::OpenAIError;
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionNamedToolChoice,
+    ChatCompletionRequestDeveloperMessageArgs, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestToolMessage,
+    ChatCompletionRequestToolMessageContent, ChatCompletionRequestUserMessageArgs,
+    ChatCompletionResponseStream, ChatCompletionStreamOptions, ChatCompletionTool,
+    ChatCompletionToolChoiceOption, ChatCompletionToolType, CompletionUsage,
+    CreateChatCompletionRequest, CreateChatCompletionRequestArgs, CreateChatCompletionResponse,
+    FunctionName,
+};
+use async_openai::Client;
+use backoff::{ExponentialBackoff, ExponentialBackoffBuilder};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Options that control how a generated `run` talks to the API and executes the tool calls
+/// it gets back.
+///
+/// Every field has a default that matches the behavior of `run`, so you only need to set the
+/// ones you care about:
+///
+/// ```
+/// # use openai_func_enums::RunConfig;
+/// let config = RunConfig {
+///     stream_tool_calls: true,
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct RunConfig {
+    /// Stream the response and start executing each tool call as soon as its arguments have
+    /// finished streaming, instead of waiting for the entire response to arrive.
+    pub stream_tool_calls: bool,
+
+    /// Where the text of the model's responses goes as it streams in, a piece at a time, for
+    /// rendering it live. Setting it streams every request, as `stream_tool_calls` does. Runs
+    /// nested in tools send their text here too.
+    pub text_sender: Option<mpsc::Sender<String>>,
+
+    /// How the system message is sent. Defaults to picking the right form for the model.
+    pub system_message_strategy: SystemMessageStrategy,
+
+    /// The model a run sends its requests to, instead of the `model_name` it was called with,
+    /// so that it can differ between environments. Set from `Config::model`.
+    pub model: Option<String>,
+
+    /// The request token limit of a run, instead of the one it was called with.
+    pub request_token_limit: Option<usize>,
+
+    /// The response token limit of a run, instead of the one it was called with.
+    pub max_response_tokens: Option<u16>,
+
+    /// How a run executes its tool calls, instead of the strategy it was called with.
+    pub execution_strategy: Option<ToolCallExecutionStrategy>,
+
+    /// Ranks the functions against the prompt of a run that wasn't given `allowed_functions`,
+    /// see `ranked_functions`. Its `required_functions` are added to the ones of every run.
+    pub ranking: Option<RankingConfig>,
+
+    /// Which field the response token limit is sent in. Defaults to picking the one the model
+    /// accepts.
+    pub max_tokens_field: MaxTokensField,
+
+    /// How failed chat requests are retried, see `RetryPolicy`. Uses the default policy when
+    /// `None`.
+    pub retry_policy: Option<RetryPolicy>,
+
+    /// Scheduling hints, keyed by function name, that decide the order the tool calls of a
+    /// single response are started in. Calls keep the order the model returned them in when
+    /// this is empty. Streamed tool calls always start as soon as they arrive.
+    pub tool_priorities: HashMap<String, ToolPriority>,
+
+    /// Tools registered at runtime, offered to the model next to the derived ones.
+    pub tools: ToolRegistry,
+
+    /// Example exchanges sent ahead of the prompt for the tools that are presented, as far as
+    /// the request token limit allows.
+    pub few_shot_examples: FewShotExamples,
+
+    /// Where the tool calls of every run are counted. Ranking adds their usage prior to the
+    /// similarity of each function, see `UsageStats::prior`.
+    pub usage_stats: Option<UsageStats>,
+
+    /// Records chat responses and plays them back, for testing without the API.
+    pub cassette: Option<Cassette>,
+
+    /// The HTTP client to send this run's requests with, instead of the shared `http_client`.
+    /// See `HttpConfig` for building one.
+    pub http_client: Option<reqwest::Client>,
+
+    /// The client to send OpenAI requests with, for sharing one that was configured once
+    /// with its own retries, proxy and timeouts between runs. It replaces the client that is
+    /// otherwise built from `http_client`, `retry_policy`, the API settings and `api_keys`.
+    pub openai_client: Option<Arc<Client<OpenAIConfig>>>,
+
+    /// The OpenAI organization requests are billed to. Uses the key's default when `None`.
+    pub organization: Option<String>,
+
+    /// The OpenAI project requests are billed to. Uses the key's default when `None`.
+    pub project: Option<String>,
+
+    /// Keys to spread requests across. `OPENAI_API_KEY` is used when `None`.
+    pub api_keys: Option<ApiKeyPool>,
+
+    /// Requests and tokens per minute budgets that every chat request waits for room in. Share
+    /// one limiter between concurrent runs to keep all of them under the account's limits.
+    pub rate_limiter: Option<RateLimiter>,
+
+    /// Adds up the tokens and cost of every chat request, see `UsageMeter`. Every run meters
+    /// its own requests for `RunOutput::usage` either way, this is for totals across runs.
+    pub usage_meter: Option<UsageMeter>,
+
+    /// The most a run may spend, counting the runs its tools start. Once spent, the run's
+    /// further requests fail with `BudgetExceeded`. Put a budget on the `usage_meter` to cap
+    /// several runs together.
+    pub budget: Option<Budget>,
+
+    /// Compares the token estimate of every request with the prompt tokens the API reports,
+    /// logging the difference, see `TokenCalibration`.
+    pub token_calibration: Option<TokenCalibration>,
+
+    /// The URL of an OpenAI compatible server to send chat and embedding requests to, like
+    /// `http://localhost:8000/v1` for vLLM. `OPENAI_BASE_URL`, or the OpenAI API, when `None`.
+    pub api_base: Option<String>,
+
+    /// Whether streamed requests ask for their usage with `stream_options`, which is how they
+    /// get counted in `RunOutput::usage`. `None` asks OpenAI's own API only, since other
+    /// OpenAI compatible servers may reject the field. A request that sets `stream_options`
+    /// itself is left alone.
+    pub stream_usage: Option<bool>,
+
+    /// The key requests are sent with instead of `OPENAI_API_KEY`. Keys from `api_keys` take
+    /// precedence.
+    pub api_key: Option<String>,
+
+    /// Functions that are never presented to the model, however they rank and whatever the
+    /// allowed and required lists say. If the model calls one anyway, the call is rejected
+    /// without running it. Meant for per-user permissions.
+    pub banned_functions: Option<Vec<String>>,
+
+    /// Functions presented ahead of the others. When an allowed list is passed they go ahead of
+    /// the ranked ones as long as they fit. Unlike required functions they are left out when
+    /// the function token limit runs out, though only once every required function is in.
+    pub preferred_functions: Vec<String>,
+
+    /// The end user requests are made for, sent as the `user` field so that OpenAI can
+    /// monitor abuse and break usage down per user.
+    pub user: Option<String>,
+
+    /// Have OpenAI retain the completions, for use in evals and distillation.
+    pub store: bool,
+
+    /// Tags sent along with every request, for example a tenant ID. Stored completions can be
+    /// filtered by them.
+    pub metadata: HashMap<String, String>,
+
+    /// Sent as `parallel_tool_calls`. `Some(false)` has the model make at most one tool call
+    /// per response, for tools that have to run one after the other. Leave it `None` for
+    /// models that don't take the parameter.
+    pub parallel_tool_calls: Option<bool>,
+
+    /// Whether the model has to call a tool, see `ToolChoiceMode`.
+    pub tool_choice: ToolChoiceMode,
+
+    /// Every tool output goes through this before it is used, see `OutputSanitizer`.
+    pub sanitizer: Option<OutputSanitizer>,
+
+    /// Offer the `FinalAnswer` tool, which the model calls to say it is done.
+    pub final_answer: bool,
+
+    /// Has calls to risky functions approved by a second model before they run.
+    pub verifier: Option<ToolCallVerifier>,
+
+    /// The scopes the caller was granted. Functions that need others, declared with
+    /// `#[func_enums(scope = "...")]` or `Tool::scopes`, are left out of the request and their
+    /// calls are rejected. Every function is available when this is `None`.
+    pub granted_scopes: Option<Vec<String>>,
+
+    /// Runs untrusted tools with panic isolation and a timeout.
+    pub sandbox: Option<Sandbox>,
+
+    /// Translations sent instead of the original descriptions, see `DescriptionCatalog`.
+    pub descriptions: Option<Arc<DescriptionCatalog>>,
+
+    /// Fields added to the top level of every chat request sent with this config, for
+    /// parameters this crate doesn't expose, like `logit_bias` or the vendor specific ones of
+    /// an OpenAI compatible gateway. They replace fields of the same name.
+    pub extra_body: Map<String, Value>,
+
+    /// Where progress is reported, see `RunEvent`.
+    pub events: Option<RunEvents>,
+
+    /// Timeouts by function name. They replace the ones declared with
+    /// `#[func_enums(timeout_ms = ...)]`, and are the only way to give runtime tools one.
+    pub tool_timeouts: HashMap<String, Duration>,
+
+    /// The output recorded for a call that timed out, so that the model is told what happened
+    /// instead of getting no result. Timed out calls have no output when this is `None`.
+    pub timeout_fallback: Option<String>,
+
+    /// Send the results of the tool calls back to the model as `tool` messages and ask it
+    /// again, for at most this many requests, until it answers without calling tools or calls
+    /// `FinalAnswer`. The answer ends up in `RunOutput::content`, or `final_answer`. A run makes
+    /// a single request when `None`.
+    pub max_iterations: Option<usize>,
+
+    /// Keeps every request and its tool calls, for exporting as fine-tuning data.
+    pub session_recorder: Option<SessionRecorder>,
+
+    /// The backend chat requests are sent to. When `None`, that is `OpenAiProvider`, or the
+    /// Azure deployment set in the environment if there is one and none of the OpenAI
+    /// settings above are set either, see `AzureOpenAiProvider::from_env`.
+    pub provider: Option<Arc<dyn LlmProvider>>,
+
+    /// The application state the tools of a run share, see `RunState`. Nested runs inherit it
+    /// through `RunConfig::current`.
+    pub state: Option<RunState>,
+
+    /// The `arguments` the first command of a run is handed, like the `next_arguments` a
+    /// command before it returned. Later commands get what the one before them returned.
+    /// `run` sets it from its `command`.
+    pub tool_arguments: Option<Vec<String>>,
+
+    /// Cancelling it stops the run: requests in flight are dropped, tool calls that are still
+    /// running are stopped with `ToolCallResult::cancelled` set, and the run returns
+    /// `RunCancelled`. Nested runs share it, and tools can read it from `RunConfig::current`
+    /// to stop work of their own.
+    pub cancellation_token: Option<CancellationToken>,
+}
+
+/// Sends a chat request with `extra_body` merged into its top level fields.
+pub(crate) async fn send_chat_completion<C: async_openai::config::Config>(
+    client: &Client<C>,
+    request: CreateChatCompletionRequest,
+    extra_body: &Map<String, Value>,
+) -> Result<CreateChatCompletionResponse, OpenAIError> {
+    if extra_body.is_empty() {
+        return client.chat().create(request).await;
+    }
+
+    client
+        .chat()
+        .create_byot(request_with_extra_body(&request, extra_body)?)
+        .await
+}
+
+/// Sends a chat request with `extra_body` merged into it with `http_client`, the way the
+/// client of `api` would, but keeping the status and headers of an error response for
+/// `with_retries` to decide by. Error bodies that aren't JSON, like a gateway's HTML page,
+/// become API errors with the body as the message.
+pub(crate) async fn post_chat_completion<C: async_openai::config::Config>(
+    http_client: &reqwest::Client,
+    api: &C,
+    request: &CreateChatCompletionRequest,
+    extra_body: &Map<String, Value>,
+) -> Result<CreateChatCompletionResponse, RequestFailure> {
+    let body = request_with_extra_body(request, extra_body)?;
+    let response = http_client
+        .post(api.url("/chat/completions"))
+        .query(&api.query())
+        .headers(api.headers())
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .send()
+        .await
+        .map_err(OpenAIError::Reqwest)?;
+
+    let status = response.status();
+    let headers = response.headers().clone();
+    let text = response.text().await.map_err(OpenAIError::Reqwest)?;
+    if !status.is_success() {
+        return Err(RequestFailure::from_response(status, &headers, &text));
+    }
+
+    serde_json::from_str(&text).map_err(|e| OpenAIError::JSONDeserialize(e).into())
+}
+
+/// The streamed counterpart of `send_chat_completion`.
+pub(crate) async fn send_chat_completion_stream<C: async_openai::config::Config>(
+    client: &Client<C>,
+    mut request: CreateChatCompletionRequest,
+    extra_body: &Map<String, Value>,
+) -> Result<ChatCompletionResponseStream, OpenAIError> {
+    // A request sent as raw JSON isn't marked as streamed for us.
+    request.stream = Some(true);
+    if extra_body.is_empty() {
+        return client.chat().create_stream(request).await;
+    }
+
+    client
+        .chat()
+        .create_stream_byot(request_with_extra_body(&request, extra_body)?)
+        .await
+}
+
+/// The JSON of `request` with `extra_body` merged into it.
+pub(crate) fn request_with_extra_body(
+    request: &CreateChatCompletionRequest,
+    extra_body: &Map<String, Value>,
+) -> Result<Value, OpenAIError> {
+    let mut body = serde_json::to_value(request).map_err(OpenAIError::JSONDeserialize)?;
+    if let Some(fields) = body.as_object_mut() {
+        fields.extend(extra_body.clone());
+    }
+    Ok(body)
+}
+
+tokio::task_local! {
+    static CURRENT_RUN_CONFIG: RunConfig;
+}
+
+impl RunConfig {
+    /// Builds the client requests are sent with, or clones `openai_client` if there is one.
+    /// Unless `http_client` is set, every client shares the same HTTP client, see
+    /// `http_client()`. With `api_keys`, every client takes the next key from the pool.
+    pub fn client(&self) -> Client<OpenAIConfig> {
+        self.keyed_client().0
+    }
+
+    /// The API settings of `client()`, with `organization`, `project`, `api_base` and
+    /// `api_key` applied.
+    pub fn openai_config(&self) -> OpenAIConfig {
+        let mut config = openai_config(self.organization.as_deref(), self.project.as_deref());
+        if let Some(api_base) = &self.api_base {
+            config = config.with_api_base(api_base);
+        }
+        if let Some(api_key) = &self.api_key {
+            config = config.with_api_key(api_key);
+        }
+        config
+    }
+
+    /// The Azure deployment set in the environment, unless this config says where requests
+    /// go itself. Any of `provider`, `openai_client`, `api_base`, `api_key`, `api_keys`,
+    /// `organization`, `project` or `cassette` does, so the environment is only a fallback
+    /// for runs that leave all of them unset.
+    fn env_azure_provider(&self) -> Option<&'static AzureOpenAiProvider> {
+        let configured = self.provider.is_some()
+            || self.openai_client.is_some()
+            || self.api_base.is_some()
+            || self.api_key.is_some()
+            || self.api_keys.is_some()
+            || self.organization.is_some()
+            || self.project.is_some()
+            || self.cassette.is_some();
+        if configured {
+            None
+        } else {
+            env_azure_provider()
+        }
+    }
+
+    /// Sends a chat request to the `provider`, retrying transient failures as the
+    /// `retry_policy` says. Requests sent with `openai_client` are left to its own retries.
+    pub async fn create_chat_completion(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse, OpenAIError> {
+        if let (None, None, Some(_)) = (
+            &self.provider,
+            self.env_azure_provider(),
+            &self.openai_client,
+        ) {
+            return self
+                .send_chat_request(&request)
+                .await
+                .map_err(|failure| failure.error);
+        }
+
+        let retry_policy = self.retry_policy.clone().unwrap_or_default();
+        with_retries(self, &retry_policy, || self.send_chat_request(&request)).await
+    }
+
+    /// Sends a chat request to the `provider` once, within the `rate_limiter`'s budgets.
+    async fn send_chat_request(
+        &self,
+        request: &CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse, RequestFailure> {
+        let estimated_tokens = match &self.rate_limiter {
+            Some(rate_limiter) => Some(rate_limiter.acquire_for(request).await),
+            None => None,
+        };
+
+        let request_model = request.model.clone();
+        let request = request.clone();
+        let response = match (&self.provider, self.env_azure_provider()) {
+            (Some(provider), _) => provider.try_chat_completion(request, self).await,
+            (None, Some(azure)) => azure.try_chat_completion(request, self).await,
+            (None, None) => self.try_openai_chat_completion(request).await,
+        };
+
+        if let Ok(CreateChatCompletionResponse {
+            usage: Some(usage), ..
+        }) = &response
+        {
+            self.record_usage(&request_model, usage);
+            if let (Some(rate_limiter), Some(estimated_tokens)) =
+                (&self.rate_limiter, estimated_tokens)
+            {
+                rate_limiter.record_usage(estimated_tokens, usage);
+            }
+        }
+
+        response
+    }
+
+    /// Continues a run with the results of the tool calls it ended on, by sending its
+    /// `follow_up_request`. The response is returned as is, so any further tool calls in it are
+    /// not executed.
+    pub async fn submit_tool_results(
+        &self,
+        output: &RunOutput,
+    ) -> Result<CreateChatCompletionResponse, OpenAIError> {
+        let request = output.follow_up_request.clone().ok_or_else(|| {
+            OpenAIError::InvalidArgument("The run output has no request to continue".to_string())
+        })?;
+
+        self.create_chat_completion(request).await
+    }
+
+    /// Makes a request that a run continued with the results of its tool calls fit in the
+    /// request token limit again before it is sent, since every iteration adds to it. The
+    /// limit is the one `build_request` enforced: the `request_token_limit` of the config, or
+    /// else the `request_token_limit` the run was given, or else the one of the request's
+    /// model, or else `default_request_token_limit`.
+    ///
+    /// Only the messages from `first_follow_up` on were added by the run. Tool results among
+    /// them are left out, oldest first, until the request fits. Their messages stay, with a
+    /// note in place of the output, because every call the model made needs its `tool`
+    /// message.
+    ///
+    /// # Errors
+    /// Returns an error if the request doesn't fit even without those results.
+    pub fn fit_follow_up_request(
+        &self,
+        request: &mut CreateChatCompletionRequest,
+        first_follow_up: usize,
+        request_token_limit: Option<usize>,
+        default_request_token_limit: usize,
+    ) -> Result<(), CommandError> {
+        #[allow(deprecated)]
+        let max_response_tokens = request.max_completion_tokens.or(request.max_tokens);
+        let request_token_limit = self
+            .request_token_limit
+            .or(request_token_limit)
+            .or_else(|| {
+                model_request_token_limit(
+                    &request.model,
+                    max_response_tokens.unwrap_or_default() as usize,
+                )
+            })
+            .unwrap_or(default_request_token_limit);
+
+        let tools_tokens = request.tools.as_ref().map_or(0, |tools| {
+            count_tokens(&serde_json::to_string(tools).unwrap_or_default())
+        });
+        let mut message_tokens: Vec<usize> = request
+            .messages
+            .iter()
+            .map(|message| count_tokens(&serde_json::to_string(message).unwrap_or_default()))
+            .collect();
+
+        let mut index = first_follow_up;
+        while tools_tokens + message_tokens.iter().sum::<usize>() > request_token_limit {
+            let Some(offset) = request.messages.iter().skip(index).position(|message| {
+                matches!(message, ChatCompletionRequestMessage::Tool(tool) if !is_left_out(tool))
+            }) else {
+                return Err(CommandError::new("Request token count is too high"));
+            };
+            index += offset;
+
+            if let ChatCompletionRequestMessage::Tool(tool) = &mut request.messages[index] {
+                tool.content = LEFT_OUT_TOOL_RESULT.into();
+            }
+            message_tokens[index] =
+                count_tokens(&serde_json::to_string(&request.messages[index]).unwrap_or_default());
+            index += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Sends a streamed chat request to the `provider`, within the `rate_limiter`'s budgets.
+    pub async fn create_chat_completion_stream(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<ChatCompletionResponseStream, OpenAIError> {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire_for(&request).await;
+        }
+
+        match (&self.provider, self.env_azure_provider()) {
+            (Some(provider), _) => provider.chat_completion_stream(request, self).await,
+            (None, Some(azure)) => azure.chat_completion_stream(request, self).await,
+            (None, None) => self.openai_chat_completion_stream(request).await,
+        }
+    }
+
+    /// Sends a chat request to OpenAI with `client()`, through the cassette if there is one.
+    /// The request is sent once, retrying is up to `create_chat_completion`.
+    pub async fn openai_chat_completion(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse, OpenAIError> {
+        self.try_openai_chat_completion(request)
+            .await
+            .map_err(|failure| failure.error)
+    }
+
+    /// `openai_chat_completion` with the status and `Retry-After` of a failed response.
+    pub async fn try_openai_chat_completion(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse, RequestFailure> {
+        let (client, key) = match self.keyed_client() {
+            (client, key) if self.openai_client.is_none() => {
+                (client.with_backoff(RetryPolicy::no_retries()), key)
+            }
+            keyed_client => keyed_client,
+        };
+        let response = match (&self.cassette, &self.openai_client) {
+            (Some(cassette), _) => cassette
+                .chat_completion_with_extra_body(&client, request, &self.extra_body)
+                .await
+                .map_err(RequestFailure::from),
+            (None, Some(_)) => send_chat_completion(&client, request, &self.extra_body)
+                .await
+                .map_err(RequestFailure::from),
+            (None, None) => {
+                let http_client = self
+                    .http_client
+                    .clone()
+                    .unwrap_or_else(|| http_client().clone());
+                post_chat_completion(&http_client, client.config(), &request, &self.extra_body)
+                    .await
+            }
+        };
+
+        if let (Some(api_keys), Some(key)) = (&self.api_keys, &key) {
+            match &response {
+                Ok(response) => {
+                    if let Some(usage) = &response.usage {
+                        api_keys.record_usage(key, usage);
+                    }
+                }
+                Err(failure) => api_keys.record_error(key, &failure.error),
+            }
+        }
+
+        response
+    }
+
+    /// Sends a streamed chat request to OpenAI with `client()`, through the cassette if there
+    /// is one.
+    pub async fn openai_chat_completion_stream(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<ChatCompletionResponseStream, OpenAIError> {
+        let (client, key) = self.keyed_client();
+        let request = self.with_stream_usage(client.config(), request);
+        let stream = match &self.cassette {
+            Some(cassette) => {
+                cassette
+                    .chat_completion_stream_with_extra_body(&client, request, &self.extra_body)
+                    .await
+            }
+            None => send_chat_completion_stream(&client, request, &self.extra_body).await,
+        };
+
+        if let (Some(api_keys), Some(key), Err(e)) = (&self.api_keys, &key, &stream) {
+            api_keys.record_error(key, e);
+        }
+
+        stream
+    }
+
+    /// Asks `request` for its usage as `stream_usage` says, for a request sent to `api`.
+    pub(crate) fn with_stream_usage<C: async_openai::config::Config>(
+        &self,
+        api: &C,
+        mut request: CreateChatCompletionRequest,
+    ) -> CreateChatCompletionRequest {
+        let include_usage = self
+            .stream_usage
+            .unwrap_or_else(|| api.api_base().starts_with(OPENAI_API_BASE));
+        if include_usage && request.stream_options.is_none() {
+            request.stream_options = Some(ChatCompletionStreamOptions {
+                include_usage: true,
+            });
+        }
+        request
+    }
+
+    /// Embeds `text` with `client()`, through the cassette if there is one.
+    pub async fn embedding(
+        &self,
+        text: &String,
+        model: &str,
+    ) -> Result<Vec<f32>, Box<dyn std::error::Error + Send + Sync>> {
+        let client = self.client();
+        match &self.cassette {
+            Some(cassette) => cassette.embedding(&client, text, model).await,
+            None => single_embedding_with_client(text, model, &client).await,
+        }
+    }
+
+    /// The config of the `run_with_config` whose tool is being executed, so that a tool that
+    /// calls `run` again makes its requests with the same settings. The default config
+    /// outside of a run.
+    pub fn current() -> RunConfig {
+        RunConfig::with_current(RunConfig::clone).unwrap_or_default()
+    }
+
+    pub(crate) fn with_current<R>(f: impl FnOnce(&RunConfig) -> R) -> Option<R> {
+        CURRENT_RUN_CONFIG.try_with(f).ok()
+    }
+
+    /// Runs `future` with this config as the `current` one.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT_RUN_CONFIG.scope(self, future).await
+    }
+
+    /// The config a run starts with. Inside a tool it is nested in the current run, which is
+    /// reported as `NestedRunStarted`.
+    pub async fn start_run(&self, prompt: &str) -> RunConfig {
+        let mut config = self.clone();
+
+        let outer_depth =
+            RunConfig::with_current(|current| current.events.as_ref().map_or(0, RunEvents::depth));
+        if let (Some(outer_depth), Some(events)) = (outer_depth, &config.events) {
+            let events = events.nested(outer_depth + 1);
+            events
+                .send(RunEvent::NestedRunStarted {
+                    prompt: prompt.to_string(),
+                    depth: events.depth(),
+                })
+                .await;
+            config.events = Some(events);
+        }
+
+        config
+    }
+
+    /// A copy of the config for a run of its own, whose requests are metered on a
+    /// `UsageMeter` nested in the `usage_meter` of this one, under the `budget`.
+    pub fn with_nested_usage_meter(&self) -> RunConfig {
+        let mut usage_meter = UsageMeter::nested(self.usage_meter.as_ref());
+        if let Some(budget) = self.budget {
+            usage_meter = usage_meter.with_budget(budget);
+        }
+
+        RunConfig {
+            usage_meter: Some(usage_meter),
+            ..self.clone()
+        }
+    }
+
+    /// Checks that the budgets of the `usage_meter` have room for another request.
+    ///
+    /// # Errors
+    /// Returns `BudgetExceeded` if one of them is spent.
+    pub fn check_budget(&self) -> Result<(), BudgetExceeded> {
+        match &self.usage_meter {
+            Some(usage_meter) => usage_meter.check_budget(),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds the usage of a request to `model_name` to the `usage_meter`, if there is one.
+    pub fn record_usage(&self, model_name: &str, usage: &CompletionUsage) {
+        if let Some(usage_meter) = &self.usage_meter {
+            usage_meter.record(model_name, usage);
+        }
+    }
+
+    /// The usage the `usage_meter` has seen, empty without one.
+    pub fn usage_report(&self) -> UsageReport {
+        self.usage_meter
+            .as_ref()
+            .map(UsageMeter::report)
+            .unwrap_or_default()
+    }
+
+    /// With `token_calibration`, records how far the estimate of `request` was from the
+    /// `usage` of its response and logs it.
+    pub async fn calibrate_token_estimate(
+        &self,
+        request: &CreateChatCompletionRequest,
+        function_jsons: &[&(Value, usize)],
+        usage: &CompletionUsage,
+        logger: &Logger,
+    ) {
+        if let Some(token_calibration) = &self.token_calibration {
+            let sample = TokenEstimateSample::new(request, function_jsons, usage.prompt_tokens);
+            logger.log(sample.to_string()).await;
+            token_calibration.record(sample);
+        }
+    }
+
+    /// Returns `true` if the `cancellation_token` was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation_token
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+    }
+
+    /// Runs `future` unless the `cancellation_token` is cancelled first, in which case the
+    /// future is dropped and `RunCancelled` is returned.
+    pub async fn cancellable<F, T, E>(
+        &self,
+        future: F,
+    ) -> Result<T, Box<dyn std::error::Error + Send + Sync + 'static>>
+    where
+        F: Future<Output = Result<T, E>>,
+        E: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
+    {
+        run_unless_cancelled(self.cancellation_token.as_ref(), future).await
+    }
+
+    /// How deep in nested runs the run using this config is, 0 without `events`.
+    pub fn run_depth(&self) -> usize {
+        self.events.as_ref().map_or(0, RunEvents::depth)
+    }
+
+    /// Sends `event` to `events`, if there are any.
+    pub async fn emit(&self, event: RunEvent) {
+        if let Some(events) = &self.events {
+            events.send(event).await;
+        }
+    }
+
+    /// Returns `true` if `name` is in `banned_functions`.
+    pub fn is_banned(&self, name: &str) -> bool {
+        self.banned_functions
+            .as_deref()
+            .unwrap_or_default()
+            .iter()
+            .any(|banned| banned == name)
+    }
+
+    /// `names` without the `banned_functions`.
+    pub fn without_banned_functions(&self, mut names: Vec<String>) -> Vec<String> {
+        names.retain(|name| !self.is_banned(name));
+        names
+    }
+
+    /// The required functions of a run, with those of `ranking` added.
+    pub fn required_functions(
+        &self,
+        required_functions: Option<Vec<String>>,
+    ) -> Option<Vec<String>> {
+        let configured = match &self.ranking {
+            Some(ranking) if !ranking.required_functions.is_empty() => &ranking.required_functions,
+            _ => return required_functions,
+        };
+
+        let mut required_functions = required_functions.unwrap_or_default();
+        for name in configured {
+            if !required_functions.contains(name) {
+                required_functions.push(name.clone());
+            }
+        }
+        Some(required_functions)
+    }
+
+    /// The allowed functions of a run. Those it was given are kept as they are. Otherwise, when
+    /// `ranking` names an archive and a model, the archived functions ranked against `prompt`
+    /// with its weights, most similar first. With `usage_stats`, the usage prior of each
+    /// function is added to its similarity, see `UsageStats::prior`.
+    ///
+    /// # Errors
+    /// Returns an error if the archive can't be read or the prompt can't be embedded.
+    pub async fn ranked_functions(
+        &self,
+        prompt: &str,
+        allowed_functions: Option<Vec<String>>,
+    ) -> Result<Option<Vec<String>>, Box<dyn std::error::Error + Send + Sync>> {
+        let (embed_path, embed_model, weights) = match (allowed_functions, &self.ranking) {
+            (Some(allowed_functions), _) => return Ok(Some(allowed_functions)),
+            (
+                None,
+                Some(RankingConfig {
+                    embed_path: Some(embed_path),
+                    embed_model: Some(embed_model),
+                    weights,
+                    ..
+                }),
+            ) => (embed_path, embed_model, weights.unwrap_or_default()),
+            (None, _) => return Ok(None),
+        };
+
+        let archive = load_embedding_archive_async(embed_path).await?;
+        let prompt_embedding = self.embedding(&prompt.to_string(), embed_model).await?;
+
+        Ok(Some(
+            score_functions_weighted(archive.embeddings(), &prompt_embedding, &weights, |name| {
+                self.usage_stats
+                    .as_ref()
+                    .map_or(0.0, |stats| stats.prior(name))
+            })
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect(),
+        ))
+    }
+
+    /// The allowed functions of a run, with the preferred ones moved to the front. An empty
+    /// list allows every function, so it is kept as it is.
+    pub fn with_preferred_functions(&self, allowed_functions: Vec<String>) -> Vec<String> {
+        if allowed_functions.is_empty() || self.preferred_functions.is_empty() {
+            return allowed_functions;
+        }
+
+        let mut preferred_first = self.preferred_functions.clone();
+        preferred_first.extend(
+            allowed_functions
+                .into_iter()
+                .filter(|name| !self.preferred_functions.contains(name)),
+        );
+        preferred_first
+    }
+
+    /// Moves the tools of the preferred functions to the front, in the order they are
+    /// preferred, keeping the order of the rest.
+    pub fn prefer_tools(&self, tools: &mut [ChatCompletionTool]) {
+        if self.preferred_functions.is_empty() {
+            return;
+        }
+
+        tools.sort_by_key(|tool| {
+            self.preferred_functions
+                .iter()
+                .position(|name| *name == tool.function.name)
+                .unwrap_or(usize::MAX)
+        });
+    }
+
+    /// Removes the banned functions from the tools of a request, along with their tokens.
+    /// `function_jsons` are the definitions of the derived functions, whose token counts were
+    /// worked out ahead of time. Other tools are counted the way `ToolRegistry` counts them.
+    pub fn remove_banned_tools(
+        &self,
+        tool_args: &mut (Vec<ChatCompletionTool>, usize),
+        function_jsons: &[&(Value, usize)],
+    ) {
+        remove_tools(tool_args, function_jsons, |name| self.is_banned(name));
+    }
+
+    /// The scopes the function called `name` needs that weren't granted. `derived_scopes` are
+    /// the scopes of the derived function by that name, tools from `tools` are looked up.
+    pub fn missing_scopes(&self, name: &str, derived_scopes: &[&str]) -> Vec<String> {
+        let Some(granted_scopes) = &self.granted_scopes else {
+            return Vec::new();
+        };
+
+        derived_scopes
+            .iter()
+            .map(|scope| scope.to_string())
+            .chain(self.tools.scopes(name))
+            .filter(|scope| !granted_scopes.contains(scope))
+            .collect()
+    }
+
+    /// Removes the functions that need scopes that weren't granted from the tools of a
+    /// request, along with their tokens. `scopes` gives the scopes of a derived function.
+    pub fn remove_ungranted_tools(
+        &self,
+        tool_args: &mut (Vec<ChatCompletionTool>, usize),
+        function_jsons: &[&(Value, usize)],
+        scopes: impl Fn(&str) -> &'static [&'static str],
+    ) {
+        if self.granted_scopes.is_none() {
+            return;
+        }
+        remove_tools(tool_args, function_jsons, |name| {
+            !self.missing_scopes(name, scopes(name)).is_empty()
+        });
+    }
+
+    /// Rejects `tool_call` if its function needs scopes that weren't granted. Returns `true`
+    /// if it did, in which case the rejection is already on `output`.
+    pub fn check_scopes(
+        &self,
+        tool_call: &ChatCompletionMessageToolCall,
+        derived_scopes: &[&str],
+        output: &mut RunOutput,
+    ) -> bool {
+        let missing = self.missing_scopes(&tool_call.function.name, derived_scopes);
+        if missing.is_empty() {
+            return false;
+        }
+
+        let missing_scopes = MissingScopes {
+            tool: tool_call.function.name.clone(),
+            missing,
+        };
+        output.tool_calls.push(ToolCallResult {
+            error: Some(missing_scopes.to_string()),
+            missing_scopes: Some(missing_scopes),
+            ..ToolCallResult::new(tool_call)
+        });
+        true
+    }
+
+    /// Handles the tool calls that are not run: calls to banned functions are rejected, and a
+    /// `FinalAnswer` call records the answer. The outcome is pushed onto `output`.
+    ///
+    /// # Returns
+    /// `true` if the call was handled and must not be executed.
+    pub fn intercept_tool_call(
+        &self,
+        tool_call: &ChatCompletionMessageToolCall,
+        output: &mut RunOutput,
+    ) -> bool {
+        if self.is_banned(&tool_call.function.name) {
+            output.tool_calls.push(ToolCallResult {
+                error: Some(format!(
+                    "The function {} is not allowed",
+                    tool_call.function.name
+                )),
+                ..ToolCallResult::new(tool_call)
+            });
+            return true;
+        }
+
+        if self.final_answer {
+            if let Some(answer) = FinalAnswer::from_tool_call(tool_call) {
+                output.tool_calls.push(ToolCallResult {
+                    output: Some(answer.clone()),
+                    attempts: 1,
+                    ..ToolCallResult::new(tool_call)
+                });
+                output.final_answer = Some(answer);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Runs `tool_call` past the `verifier` if it covers the function. `risky` is whether the
+    /// function is marked `#[func_enums(risky)]`. Returns `true` if the call was rejected, in
+    /// which case its result is already on `output` and it must not be executed.
+    ///
+    /// A call the verifier couldn't be asked about, because its request failed, is rejected
+    /// as well, so that one failed check fails that call instead of the whole run.
+    pub async fn verify_tool_call(
+        &self,
+        tool_call: &ChatCompletionMessageToolCall,
+        risky: bool,
+        conversation_summary: &str,
+        output: &mut RunOutput,
+    ) -> bool {
+        let Some(verifier) = &self.verifier else {
+            return false;
+        };
+        if !verifier.covers(&tool_call.function.name, risky) {
+            return false;
+        }
+
+        let error = match verifier.verify(self, tool_call, conversation_summary).await {
+            Ok(Verdict::Approved) => return false,
+            Ok(Verdict::Rejected { reason }) => format!(
+                "The call to {} was rejected by the verifier: {}",
+                tool_call.function.name, reason
+            ),
+            Err(e) => format!(
+                "The call to {} could not be verified: {}",
+                tool_call.function.name, e
+            ),
+        };
+        output.tool_calls.push(ToolCallResult {
+            error: Some(error),
+            ..ToolCallResult::new(tool_call)
+        });
+        true
+    }
+
+    /// Returns an empty queue that orders tool calls by `tool_priorities`. A run keeps one for
+    /// all of its responses.
+    pub fn tool_call_queue(&self) -> ToolCallQueue {
+        ToolCallQueue::new(self.tool_priorities.clone())
+    }
+
+    /// Builds a client along with the pool key it was given, if there is a pool.
+    fn keyed_client(&self) -> (Client<OpenAIConfig>, Option<String>) {
+        if let Some(openai_client) = &self.openai_client {
+            return (Client::clone(openai_client), None);
+        }
+
+        let http_client = self
+            .http_client
+            .clone()
+            .unwrap_or_else(|| http_client().clone());
+        let key = self.api_keys.as_ref().map(ApiKeyPool::next_key);
+
+        let openai_config = match &key {
+            Some(key) => self.openai_config().with_api_key(key),
+            None => self.openai_config(),
+        };
+        let client = Client::with_config(openai_config).with_http_client(http_client);
+
+        match &self.retry_policy {
+            Some(retry_policy) => (client.with_backoff(retry_policy.backoff()), key),
+            None => (client, key),
+        }
+    }
+}
+
+/// Exponential backoff settings for retrying rate limited requests and server errors.
+///
+/// Chat requests that fail with a rate limit (other than an exhausted quota), a server error,
+/// a timeout or a connection error are sent again after a randomized, growing wait, up to five
+/// times by default. Failures are told apart by their HTTP status, so a 4xx other than 429 and
+/// 408 is returned at once, whatever its message says. When the API says how long to wait,
+/// with a `Retry-After` header or in a rate limit message, that wait is used instead.
+/// Each retry is reported as a `RunEvent::RequestRetried`. Streamed requests are not retried,
+/// as their errors arrive with the stream.
+///
+/// The backoff defaults are the same as async-openai's.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// How long to wait before the first retry.
+    pub initial_interval_ms: u64,
+    /// The longest to wait between two retries.
+    pub max_interval_ms: u64,
+    /// How much the wait grows after each retry.
+    pub multiplier: f64,
+    /// Stop retrying once this much time has passed. `None` retries forever.
+    pub max_elapsed_ms: Option<u64>,
+    /// How many times a request is sent at most, counting the first one. `None` leaves it to
+    /// `max_elapsed_ms`.
+    pub max_attempts: Option<u32>,
+    /// How far each wait is randomized, as a fraction of it, so that concurrent runs that hit
+    /// the same rate limit don't all retry at once. 0 waits exactly.
+    pub jitter: f64,
+    /// Wait as long as the API asks to, like the "Please try again in 20s." of a rate limit,
+    /// rather than the backoff.
+    pub honor_retry_after: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            initial_interval_ms: 500,
+            max_interval_ms: 60_000,
+            multiplier: 1.5,
+            max_elapsed_ms: Some(900_000),
+            max_attempts: Some(5),
+            jitter: 0.5,
+            honor_retry_after: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn backoff(&self) -> ExponentialBackoff {
+        ExponentialBackoffBuilder::new()
+            .with_initial_interval(Duration::from_millis(self.initial_interval_ms))
+            .with_max_interval(Duration::from_millis(self.max_interval_ms))
+            .with_multiplier(self.multiplier)
+            .with_randomization_factor(self.jitter)
+            .with_max_elapsed_time(self.max_elapsed_ms.map(Duration::from_millis))
+            .build()
+    }
+
+    /// A backoff that never retries, for clients whose requests are retried by the run.
+    pub(crate) fn no_retries() -> ExponentialBackoff {
+        ExponentialBackoffBuilder::new()
+            .with_max_elapsed_time(Some(Duration::ZERO))
+            .build()
+    }
+}
+
+/// Settings for the HTTP client requests are sent with, for environments that need an
+/// outbound proxy, a private certificate authority or extra headers.
+///
+/// `init_with` installs a client built from these as the shared `http_client`, which covers
+/// both chat and embedding requests. `build_client` builds one to put on
+/// `RunConfig::http_client` instead.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// The proxy every request goes through, e.g. `http://proxy.internal:3128`. The usual
+    /// `HTTPS_PROXY` variables are honored when this is not set.
+    pub proxy: Option<String>,
+    /// PEM files with certificates to trust on top of the default roots.
+    pub root_certificates: Vec<PathBuf>,
+    pub connect_timeout_ms: Option<u64>,
+    /// The longest a whole request, including reading the response, may take.
+    pub timeout_ms: Option<u64>,
+    /// Headers sent with every request.
+    pub headers: HashMap<String, String>,
+}
+
+impl HttpConfig {
+    /// Returns these settings with everything that is set in `other` taking precedence.
+    /// Headers are combined.
+    pub fn merge(self, other: HttpConfig) -> HttpConfig {
+        let mut headers = self.headers;
+        headers.extend(other.headers);
+
+        HttpConfig {
+            proxy: other.proxy.or(self.proxy),
+            root_certificates: if other.root_certificates.is_empty() {
+                self.root_certificates
+            } else {
+                other.root_certificates
+            },
+            connect_timeout_ms: other.connect_timeout_ms.or(self.connect_timeout_ms),
+            timeout_ms: other.timeout_ms.or(self.timeout_ms),
+            headers,
+        }
+    }
+
+    /// Builds a client with these settings.
+    ///
+    /// # Errors
+    /// Returns a `CommandError` if the proxy URL, a certificate or a header is invalid.
+    pub fn build_client(&self) -> Result<reqwest::Client, CommandError> {
+        let mut builder = reqwest::Client::builder();
+
+        if let Some(proxy) = &self.proxy {
+            let proxy = reqwest::Proxy::all(proxy)
+                .map_err(|e| CommandError::new(&format!("Invalid proxy {}: {}", proxy, e)))?;
+            builder = builder.proxy(proxy);
+        }
+
+        for path in &self.root_certificates {
+            let pem = std::fs::read(path).map_err(|e| {
+                CommandError::new(&format!("Failed to read {}: {}", path.display(), e))
+            })?;
+            let certificate = reqwest::Certificate::from_pem(&pem).map_err(|e| {
+                CommandError::new(&format!("Invalid certificate in {}: {}", path.display(), e))
+            })?;
+            builder = builder.add_root_certificate(certificate);
+        }
+
+        if let Some(connect_timeout_ms) = self.connect_timeout_ms {
+            builder = builder.connect_timeout(Duration::from_millis(connect_timeout_ms));
+        }
+
+        if let Some(timeout_ms) = self.timeout_ms {
+            builder = builder.timeout(Duration::from_millis(timeout_ms));
+        }
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        for (name, value) in &self.headers {
+            let header_name = reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| CommandError::new(&format!("Invalid header name {}: {}", name, e)))?;
+            let header_value = reqwest::header::HeaderValue::from_str(value).map_err(|e| {
+                CommandError::new(&format!("Invalid value for header {}: {}", name, e))
+            })?;
+            headers.insert(header_name, header_value);
+        }
+
+        builder
+            .default_headers(headers)
+            .build()
+            .map_err(|e| CommandError::new(&format!("Failed to build the HTTP client: {}", e)))
+    }
+}
+
+/// Settings that control how functions are ranked against a prompt.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RankingConfig {
+    /// The embedding archive to rank functions with.
+    pub embed_path: Option<PathBuf>,
+    /// The model used to embed prompts. It should be the one the archive was built with.
+    pub embed_model: Option<String>,
+    /// Functions that are always presented, however they rank.
+    pub required_functions: Vec<String>,
+    /// Functions presented ahead of the ranked ones when they fit, see
+    /// `RunConfig::preferred_functions`.
+    pub preferred_functions: Vec<String>,
+    /// How the name and description similarities are combined, see `ScoreWeights`.
+    pub weights: Option<ScoreWeights>,
+}
+
+impl RankingConfig {
+    /// Returns these settings with everything that is set in `other` taking precedence.
+    pub fn merge(self, other: RankingConfig) -> RankingConfig {
+        RankingConfig {
+            embed_path: other.embed_path.or(self.embed_path),
+            embed_model: other.embed_model.or(self.embed_model),
+            required_functions: if other.required_functions.is_empty() {
+                self.required_functions
+            } else {
+                other.required_functions
+            },
+            preferred_functions: if other.preferred_functions.is_empty() {
+                self.preferred_functions
+            } else {
+                other.preferred_functions
+            },
+            weights: other.weights.or(self.weights),
+        }
+    }
+}
+
+/// Runtime settings loaded from a TOML file and/or environment variables.
+///
+/// The limits baked in at build time through the `FUNC_ENUMS_*` variables are the same for
+/// every environment the binary runs in. `Config` is for the values that differ between
+/// environments like dev, staging and prod:
+///
+/// ```toml
+/// model = "gpt-4o-mini"
+/// organization = "org-..."
+/// project = "proj_..."
+/// request_token_limit = 4191
+/// max_response_tokens = 1000
+/// execution_strategy = "async"
+/// calibrate_tokens = true
+///
+/// [retry]
+/// max_elapsed_ms = 60000
+/// max_attempts = 5
+///
+/// [ranking]
+/// embed_path = "embedding/function_embeddings.bin"
+/// embed_model = "text-embedding-3-small"
+/// required_functions = ["CallMultiStep"]
+/// preferred_functions = ["Search"]
+/// weights = { name = 0.3, description = 0.7 }
+///
+/// [http]
+/// proxy = "http://proxy.internal:3128"
+/// connect_timeout_ms = 5000
+///
+/// [profiles.prod]
+/// model = "gpt-4o"
+/// ```
+///
+/// Keys set in a profile override the top level ones, including the keys of `[ranking]` and
+/// `[http]`. A
+/// `[profiles.prod.retry]` table replaces the top level `[retry]` table entirely.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub model: Option<String>,
+    pub organization: Option<String>,
+    pub project: Option<String>,
+    pub api_base: Option<String>,
+    pub request_token_limit: Option<usize>,
+    pub max_response_tokens: Option<u16>,
+    pub execution_strategy: Option<ToolCallExecutionStrategy>,
+    pub stream_tool_calls: Option<bool>,
+    pub system_message_strategy: Option<SystemMessageStrategy>,
+    pub max_tokens_field: Option<MaxTokensField>,
+    pub calibrate_tokens: Option<bool>,
+    pub retry: Option<RetryPolicy>,
+    pub ranking: Option<RankingConfig>,
+    pub http: Option<HttpConfig>,
+}
+
+#[derive(Deserialize)]
+struct ConfigFile {
+    #[serde(flatten)]
+    base: Config,
+    #[serde(default)]
+    profiles: HashMap<String, Config>,
+}
+
+impl Config {
+    /// Reads a config file, applying the profile named by `FUNC_ENUMS_PROFILE` if it is set.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Config, CommandError> {
+        match std::env::var("FUNC_ENUMS_PROFILE") {
+            Ok(profile) => Config::from_file_with_profile(path, &profile),
+            Err(_) => Ok(Config::read_file(path.as_ref())?.base),
+        }
+    }
+
+    /// Reads a config file and applies the named profile on top of the top level settings.
+    ///
+    /// # Errors
+    /// Returns an error if the file can't be read or parsed, or doesn't define the profile.
+    pub fn from_file_with_profile(
+        path: impl AsRef<Path>,
+        profile: &str,
+    ) -> Result<Config, CommandError> {
+        let path = path.as_ref();
+        let mut file = Config::read_file(path)?;
+
+        match file.profiles.remove(profile) {
+            Some(profile) => Ok(file.base.merge(profile)),
+            None => Err(CommandError::new(&format!(
+                "Profile {} is not defined in {}",
+                profile,
+                path.display()
+            ))),
+        }
+    }
+
+    /// Reads settings from environment variables at runtime. The variables that have a build
+    /// time counterpart read by the `ToolSet` derive are prefixed with `FUNC_ENUMS_RUNTIME_`,
+    /// so that the limits a binary was built with don't leak into its runtime settings:
+    ///
+    /// * `FUNC_ENUMS_MODEL`
+    /// * `OPENAI_ORG_ID`
+    /// * `OPENAI_PROJECT_ID`
+    /// * `OPENAI_BASE_URL`
+    /// * `FUNC_ENUMS_RUNTIME_MAX_REQUEST_TOKENS`
+    /// * `FUNC_ENUMS_RUNTIME_MAX_RESPONSE_TOKENS`
+    /// * `FUNC_ENUMS_EXECUTION_STRATEGY`: `async`, `synchronous` or `parallel`.
+    /// * `FUNC_ENUMS_STREAM_TOOL_CALLS`
+    /// * `FUNC_ENUMS_SYSTEM_MESSAGE_STRATEGY`: `auto`, `system`, `developer` or `merge_into_user`.
+    /// * `FUNC_ENUMS_MAX_TOKENS_FIELD`: `auto`, `max_tokens` or `max_completion_tokens`.
+    /// * `FUNC_ENUMS_CALIBRATE_TOKENS`
+    /// * `FUNC_ENUMS_RUNTIME_EMBED_PATH`
+    /// * `FUNC_ENUMS_RUNTIME_EMBED_MODEL`
+    /// * `FUNC_ENUMS_RUNTIME_HTTP_PROXY`
+    /// * `FUNC_ENUMS_RUNTIME_CONNECT_TIMEOUT_MS`
+    /// * `FUNC_ENUMS_RUNTIME_TIMEOUT_MS`
+    /// * `FUNC_ENUMS_RUNTIME_HTTP_HEADERS`: `name=value` pairs separated by commas.
+    ///
+    /// # Errors
+    /// Returns an error if a variable is set to a value that can't be parsed.
+    pub fn from_env() -> Result<Config, CommandError> {
+        let embed_path = std::env::var("FUNC_ENUMS_RUNTIME_EMBED_PATH").ok();
+        let embed_model = std::env::var("FUNC_ENUMS_RUNTIME_EMBED_MODEL").ok();
+
+        let ranking = if embed_path.is_some() || embed_model.is_some() {
+            Some(RankingConfig {
+                embed_path: embed_path.map(PathBuf::from),
+                embed_model,
+                ..Default::default()
+            })
+        } else {
+            None
+        };
+
+        let http = HttpConfig {
+            proxy: std::env::var("FUNC_ENUMS_RUNTIME_HTTP_PROXY").ok(),
+            connect_timeout_ms: env_value("FUNC_ENUMS_RUNTIME_CONNECT_TIMEOUT_MS")?,
+            timeout_ms: env_value("FUNC_ENUMS_RUNTIME_TIMEOUT_MS")?,
+            headers: match std::env::var("FUNC_ENUMS_RUNTIME_HTTP_HEADERS") {
+                Ok(headers) => {
+                    parse_headers(&headers).map_err(|e| CommandError::new(&e.to_string()))?
+                }
+                Err(_) => HashMap::new(),
+            },
+            ..Default::default()
+        };
+
+        Ok(Config {
+            model: std::env::var("FUNC_ENUMS_MODEL").ok(),
+            organization: std::env::var("OPENAI_ORG_ID").ok(),
+            project: std::env::var("OPENAI_PROJECT_ID").ok(),
+            api_base: std::env::var("OPENAI_BASE_URL").ok(),
+            request_token_limit: env_value("FUNC_ENUMS_RUNTIME_MAX_REQUEST_TOKENS")?,
+            max_response_tokens: env_value("FUNC_ENUMS_RUNTIME_MAX_RESPONSE_TOKENS")?,
+            execution_strategy: env_value("FUNC_ENUMS_EXECUTION_STRATEGY")?,
+            stream_tool_calls: env_value("FUNC_ENUMS_STREAM_TOOL_CALLS")?,
+            system_message_strategy: env_value("FUNC_ENUMS_SYSTEM_MESSAGE_STRATEGY")?,
+            max_tokens_field: env_value("FUNC_ENUMS_MAX_TOKENS_FIELD")?,
+            calibrate_tokens: env_value("FUNC_ENUMS_CALIBRATE_TOKENS")?,
+            retry: None,
+            ranking,
+            http: if http == HttpConfig::default() {
+                None
+            } else {
+                Some(http)
+            },
+        })
+    }
+
+    /// Reads a config file with `from_file` and overrides it with `from_env`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Config, CommandError> {
+        Ok(Config::from_file(path)?.merge(Config::from_env()?))
+    }
+
+    /// Returns these settings with everything that is set in `other` taking precedence.
+    pub fn merge(self, other: Config) -> Config {
+        Config {
+            model: other.model.or(self.model),
+            organization: other.organization.or(self.organization),
+            project: other.project.or(self.project),
+            api_base: other.api_base.or(self.api_base),
+            request_token_limit: other.request_token_limit.or(self.request_token_limit),
+            max_response_tokens: other.max_response_tokens.or(self.max_response_tokens),
+            execution_strategy: other.execution_strategy.or(self.execution_strategy),
+            stream_tool_calls: other.stream_tool_calls.or(self.stream_tool_calls),
+            system_message_strategy: other
+                .system_message_strategy
+                .or(self.system_message_strategy),
+            max_tokens_field: other.max_tokens_field.or(self.max_tokens_field),
+            calibrate_tokens: other.calibrate_tokens.or(self.calibrate_tokens),
+            retry: other.retry.or(self.retry),
+            ranking: match (self.ranking, other.ranking) {
+                (Some(ranking), Some(other_ranking)) => Some(ranking.merge(other_ranking)),
+                (ranking, other_ranking) => other_ranking.or(ranking),
+            },
+            http: match (self.http, other.http) {
+                (Some(http), Some(other_http)) => Some(http.merge(other_http)),
+                (http, other_http) => other_http.or(http),
+            },
+        }
+    }
+
+    /// The execution strategy to run tool calls with, `Async` if none is configured.
+    pub fn execution_strategy(&self) -> ToolCallExecutionStrategy {
+        self.execution_strategy
+            .clone()
+            .unwrap_or(ToolCallExecutionStrategy::Async)
+    }
+
+    /// The options to pass to a generated `run_with_config`. Every setting is applied to the
+    /// run, the model, limits and execution strategy in place of the ones it is called with.
+    pub fn run_config(&self) -> RunConfig {
+        RunConfig {
+            stream_tool_calls: self.stream_tool_calls.unwrap_or_default(),
+            system_message_strategy: self.system_message_strategy.unwrap_or_default(),
+            max_tokens_field: self.max_tokens_field.unwrap_or_default(),
+            model: self.model.clone(),
+            request_token_limit: self.request_token_limit,
+            max_response_tokens: self.max_response_tokens,
+            execution_strategy: self.execution_strategy.clone(),
+            preferred_functions: self
+                .ranking
+                .as_ref()
+                .map(|ranking| ranking.preferred_functions.clone())
+                .unwrap_or_default(),
+            ranking: self.ranking.clone(),
+            retry_policy: self.retry.clone(),
+            organization: self.organization.clone(),
+            project: self.project.clone(),
+            api_base: self.api_base.clone(),
+            token_calibration: self
+                .calibrate_tokens
+                .unwrap_or_default()
+                .then(TokenCalibration::new),
+            ..Default::default()
+        }
+    }
+
+    fn read_file(path: &Path) -> Result<ConfigFile, CommandError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| CommandError::new(&format!("Failed to read {}: {}", path.display(), e)))?;
+
+        toml::from_str(&contents)
+            .map_err(|e| CommandError::new(&format!("Failed to parse {}: {}", path.display(), e)))
+    }
+}
+
+/// Removes the tools `remove` picks from `tool_args`, along with their tokens. The token
+/// counts of derived functions come from `function_jsons`.
+fn remove_tools(
+    tool_args: &mut (Vec<ChatCompletionTool>, usize),
+    function_jsons: &[&(Value, usize)],
+    remove: impl Fn(&str) -> bool,
+) {
+    let (tools, total_tokens) = tool_args;
+    tools.retain(|tool| {
+        if !remove(&tool.function.name) {
+            return true;
+        }
+
+        *total_tokens = total_tokens.saturating_sub(tool_tokens(tool, function_jsons));
+        false
+    });
+}
+
+/// The tokens `tool` was estimated at: the compile time count for derived functions in
+/// `function_jsons`, otherwise a count of its name, description and parameters.
+pub(crate) fn tool_tokens(tool: &ChatCompletionTool, function_jsons: &[&(Value, usize)]) -> usize {
+    function_jsons
+        .iter()
+        .find(|(json, _)| json["name"] == tool.function.name.as_str())
+        .map(|(_, tokens)| *tokens)
+        .unwrap_or_else(|| {
+            count_tokens(&tool.function.name)
+                + tool.function.description.as_deref().map_or(0, count_tokens)
+                + tool
+                    .function
+                    .parameters
+                    .as_ref()
+                    .map_or(0, |parameters| count_tokens(&parameters.to_string()))
+        })
+}
+
+/// What a tool result left out by `fit_follow_up_request` is replaced with.
+const LEFT_OUT_TOOL_RESULT: &str =
+    "The output of this call was left out to keep the request under its token limit.";
+
+fn is_left_out(message: &ChatCompletionRequestToolMessage) -> bool {
+    matches!(
+        &message.content,
+        ChatCompletionRequestToolMessageContent::Text(text) if text == LEFT_OUT_TOOL_RESULT
+    )
+}
+
+/// Builds the API settings for an organization and project, either of which may be left to
+/// the key's default.
+pub(crate) fn openai_config(organization: Option<&str>, project: Option<&str>) -> OpenAIConfig {
+    let mut config = default_openai_config();
+    if let Some(organization) = organization {
+        config = config.with_org_id(organization);
+    }
+    if let Some(project) = project {
+        config = config.with_project_id(project);
+    }
+    config
+}
+
+/// Reads an environment variable into any type that can be deserialized from a string or,
+/// for numbers and booleans, from its JSON representation.
+fn env_value<T: DeserializeOwned>(name: &str) -> Result<Option<T>, CommandError> {
+    let value = match std::env::var(name) {
+        Ok(value) => value,
+        Err(_) => return Ok(None),
+    };
+
+    serde_json::from_str(&value)
+        .or_else(|_| serde_json::from_value(serde_json::Value::String(value.clone())))
+        .map(Some)
+        .map_err(|_| CommandError::new(&format!("Invalid value for {}: {}", name, value)))
+}
+
+/// How the system message is sent to the model.
+///
+/// Some models and OpenAI-compatible gateways reject requests that contain a system message.
+/// The reasoning models expect the developer role instead, and a few older ones refuse
+/// anything but user and assistant messages.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SystemMessageStrategy {
+    /// Pick one of the other strategies based on the model name.
+    #[default]
+    Auto,
+    /// Send it as a system message.
+    System,
+    /// Send it as a developer message.
+    Developer,
+    /// Prepend it to the user message.
+    MergeIntoUser,
+}
+
+impl SystemMessageStrategy {
+    /// Resolves `Auto` to the strategy that works for `model_name`. The other strategies are
+    /// returned unchanged.
+    pub fn resolve(&self, model_name: &str) -> SystemMessageStrategy {
+        if *self != SystemMessageStrategy::Auto {
+            return *self;
+        }
+
+        // Gateways often prefix model names with the provider, e.g. "openai/o1-mini".
+        let model_name = model_name.rsplit('/').next().unwrap_or(model_name);
+
+        if model_name.starts_with("o1-mini") || model_name.starts_with("o1-preview") {
+            SystemMessageStrategy::MergeIntoUser
+        } else if model_name.starts_with('o')
+            && model_name[1..].starts_with(|c: char| c.is_ascii_digit())
+        {
+            SystemMessageStrategy::Developer
+        } else {
+            SystemMessageStrategy::System
+        }
+    }
+
+    /// Builds the messages for a request made of a system message and a user prompt, sending
+    /// the system message the way this strategy resolves to for `model_name`.
+    pub fn messages(
+        &self,
+        model_name: &str,
+        system_message: String,
+        prompt: String,
+    ) -> Result<Vec<ChatCompletionRequestMessage>, OpenAIError> {
+        let user_message = |content: String| -> Result<ChatCompletionRequestMessage, OpenAIError> {
+            Ok(ChatCompletionRequestMessage::User(
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(content)
+                    .build()?,
+            ))
+        };
+
+        match self.resolve(model_name) {
+            SystemMessageStrategy::MergeIntoUser => Ok(vec![user_message(format!(
+                "{}\n\n{}",
+                system_message, prompt
+            ))?]),
+            SystemMessageStrategy::Developer => Ok(vec![
+                ChatCompletionRequestMessage::Developer(
+                    ChatCompletionRequestDeveloperMessageArgs::default()
+                        .content(system_message)
+                        .build()?,
+                ),
+                user_message(prompt)?,
+            ]),
+            _ => Ok(vec![
+                ChatCompletionRequestMessage::System(
+                    ChatCompletionRequestSystemMessageArgs::default()
+                        .content(system_message)
+                        .build()?,
+                ),
+                user_message(prompt)?,
+            ]),
+        }
+    }
+}
+
+/// The field of a request the response token limit is sent in.
+///
+/// OpenAI deprecated `max_tokens` in favor of `max_completion_tokens`, and its reasoning models
+/// only accept the new one. Azure deployments on older API versions and most OpenAI-compatible
+/// servers only know `max_tokens`.
+///
+/// ```
+/// # use openai_func_enums::MaxTokensField;
+/// assert_eq!(
+///     MaxTokensField::Auto.resolve("o3-mini"),
+///     MaxTokensField::MaxCompletionTokens
+/// );
+/// assert_eq!(MaxTokensField::Auto.resolve("openai/gpt-4o"), MaxTokensField::MaxTokens);
+/// assert_eq!(
+///     MaxTokensField::MaxCompletionTokens.resolve("gpt-4o"),
+///     MaxTokensField::MaxCompletionTokens
+/// );
+/// ```
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MaxTokensField {
+    /// `max_completion_tokens` for the reasoning models, `max_tokens` for everything else.
+    #[default]
+    Auto,
+    MaxTokens,
+    MaxCompletionTokens,
+}
+
+impl MaxTokensField {
+    /// Resolves `Auto` to the field that works for `model_name`. The other fields are returned
+    /// unchanged.
+    pub fn resolve(&self, model_name: &str) -> MaxTokensField {
+        if *self != MaxTokensField::Auto {
+            return *self;
+        }
+
+        let model_name = model_name.rsplit('/').next().unwrap_or(model_name);
+        let reasoning = (model_name.starts_with('o')
+            && model_name[1..].starts_with(|c: char| c.is_ascii_digit()))
+            || model_name.starts_with("gpt-5");
+        if reasoning {
+            MaxTokensField::MaxCompletionTokens
+        } else {
+            MaxTokensField::MaxTokens
+        }
+    }
+
+    /// Sets the response token limit of `request` in the field this resolves to for
+    /// `model_name`.
+    pub fn apply(
+        &self,
+        model_name: &str,
+        request: &mut CreateChatCompletionRequestArgs,
+        max_tokens: u32,
+    ) {
+        match self.resolve(model_name) {
+            MaxTokensField::MaxCompletionTokens => {
+                request.max_completion_tokens(max_tokens);
+            }
+            _ => {
+                #[allow(deprecated)]
+                request.max_tokens(max_tokens);
+            }
+        }
+    }
+}
+
+/// Whether and which tools the model has to call, sent as `tool_choice`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolChoiceMode {
+    /// The model decides between calling tools and answering in text.
+    #[default]
+    Auto,
+    /// The model has to call at least one tool.
+    Required,
+    /// The model answers in text only, with the tools still in the request.
+    None,
+    /// The model has to call the function with this name. It is kept in the request when the
+    /// functions are filtered, and only the first request of an agent loop forces it, so the
+    /// model can answer once it has the result.
+    Named(String),
+}
+
+impl ToolChoiceMode {
+    /// `Named` with the function generated for a variant.
+    pub fn forcing<F: ToolSetFunction>() -> Self {
+        ToolChoiceMode::Named(F::NAME.to_string())
+    }
+
+    pub fn to_tool_choice(&self) -> ChatCompletionToolChoiceOption {
+        match self {
+            ToolChoiceMode::Auto => ChatCompletionToolChoiceOption::Auto,
+            ToolChoiceMode::Required => ChatCompletionToolChoiceOption::Required,
+            ToolChoiceMode::None => ChatCompletionToolChoiceOption::None,
+            ToolChoiceMode::Named(name) => {
+                ChatCompletionToolChoiceOption::Named(ChatCompletionNamedToolChoice {
+                    r#type: ChatCompletionToolType::Function,
+                    function: FunctionName { name: name.clone() },
+                })
+            }
+        }
+    }
+
+    /// `required_functions` with the named function added, so that filtering can't leave it
+    /// out of the request.
+    pub fn required_functions(
+        &self,
+        required_functions: Option<Vec<String>>,
+    ) -> Option<Vec<String>> {
+        let ToolChoiceMode::Named(name) = self else {
+            return required_functions;
+        };
+
+        let mut required_functions = required_functions.unwrap_or_default();
+        if !required_functions.contains(name) {
+            required_functions.push(name.clone());
+        }
+        Some(required_functions)
+    }
+}
@@ -0,0 +1,232 @@
+use crate::CommandError;
+use regex::Regex;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+
+/// Checks the arguments of a call to `function_name` against the constraints in its
+/// parameters schema, the ones declared with `#[func_enums(min = ..., max = ...)]` and the
+/// like. Generated code calls this before a derived function or a `#[tool]` function runs.
+///
+/// The checks cover `minimum`, `maximum`, `minLength`, `maxLength`, `pattern`, `minItems` and
+/// `maxItems`, in nested objects, arrays and maps too. Numbers sent as strings are checked as
+/// numbers, the way they are deserialized. `format` is only a hint to the model.
+///
+/// # Errors
+/// Returns an error that says which argument broke which constraint.
+pub fn validate_arguments(
+    function_name: &str,
+    parameters: &Value,
+    arguments: &Value,
+) -> Result<(), CommandError> {
+    check_constraints(parameters, arguments, "").map_err(|(path, expected)| {
+        CommandError::new(&format!(
+            "The arguments of {} break a constraint at '{}': expected {}",
+            function_name, path, expected
+        ))
+    })
+}
+
+fn check_constraints(schema: &Value, value: &Value, path: &str) -> Result<(), (String, String)> {
+    let mismatch = |expected: String| Err((path.to_string(), expected));
+
+    let number = match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) if has_numeric_type(schema) => text.trim().parse().ok(),
+        _ => None,
+    };
+    if let Some(number) = number {
+        if let Some(minimum) = schema.get("minimum").and_then(Value::as_f64) {
+            if number < minimum {
+                return mismatch(format!("at least {}", minimum));
+            }
+        }
+        if let Some(maximum) = schema.get("maximum").and_then(Value::as_f64) {
+            if number > maximum {
+                return mismatch(format!("at most {}", maximum));
+            }
+        }
+    }
+
+    if let (Value::String(text), false) = (value, has_numeric_type(schema)) {
+        let length = text.chars().count() as u64;
+        if let Some(min_length) = schema.get("minLength").and_then(Value::as_u64) {
+            if length < min_length {
+                return mismatch(format!("at least {} characters", min_length));
+            }
+        }
+        if let Some(max_length) = schema.get("maxLength").and_then(Value::as_u64) {
+            if length > max_length {
+                return mismatch(format!("at most {} characters", max_length));
+            }
+        }
+        if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+            match pattern_matches(pattern, text) {
+                Ok(true) => {}
+                Ok(false) => return mismatch(format!("a match for {}", pattern)),
+                Err(e) => {
+                    return mismatch(format!(
+                        "a match for {}, which is not a valid regex: {}",
+                        pattern, e
+                    ))
+                }
+            }
+        }
+    }
+
+    match value {
+        Value::Array(items) => {
+            if let Some(min_items) = schema.get("minItems").and_then(Value::as_u64) {
+                if (items.len() as u64) < min_items {
+                    return mismatch(format!("at least {} items", min_items));
+                }
+            }
+            if let Some(max_items) = schema.get("maxItems").and_then(Value::as_u64) {
+                if items.len() as u64 > max_items {
+                    return mismatch(format!("at most {} items", max_items));
+                }
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    check_constraints(item_schema, item, &format!("{}/{}", path, index))?;
+                }
+            }
+        }
+        Value::Object(object) => {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            let additional = schema
+                .get("additionalProperties")
+                .filter(|value| value.is_object());
+            for (name, property) in object {
+                let property_schema = properties
+                    .and_then(|properties| properties.get(name))
+                    .or(additional);
+                if let Some(property_schema) = property_schema {
+                    check_constraints(property_schema, property, &format!("{}/{}", path, name))?;
+                }
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Whether `text` matches `pattern`. Patterns are compiled once and kept for the life of the
+/// process, as the same few are checked on every call. The patterns of derived functions were
+/// checked when the macro expanded, so only hand written schemas can have invalid ones.
+fn pattern_matches(pattern: &str, text: &str) -> Result<bool, regex::Error> {
+    static PATTERNS: OnceLock<RwLock<HashMap<String, Regex>>> = OnceLock::new();
+    let patterns = PATTERNS.get_or_init(|| RwLock::new(HashMap::new()));
+
+    if let Some(regex) = patterns
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(pattern)
+    {
+        return Ok(regex.is_match(text));
+    }
+
+    let regex = Regex::new(pattern)?;
+    let matches = regex.is_match(text);
+    patterns
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(pattern.to_string(), regex);
+    Ok(matches)
+}
+
+fn has_numeric_type(schema: &Value) -> bool {
+    match schema.get("type") {
+        Some(Value::String(schema_type)) => schema_type == "number" || schema_type == "integer",
+        Some(Value::Array(schema_types)) => schema_types
+            .iter()
+            .any(|schema_type| schema_type == "number" || schema_type == "integer"),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parameters() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "minutes": { "type": "integer", "minimum": 1, "maximum": 1440 },
+                "team": { "type": "string", "pattern": "^[A-Z]{3}$" },
+                "tags": {
+                    "type": "array",
+                    "maxItems": 2,
+                    "items": { "type": "string", "minLength": 2 }
+                },
+                "headers": {
+                    "type": "object",
+                    "additionalProperties": { "type": "string", "maxLength": 5 }
+                }
+            }
+        })
+    }
+
+    fn check(arguments: Value) -> Result<(), String> {
+        validate_arguments("ScheduleReminder", &parameters(), &arguments).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn arguments_within_the_constraints_pass() {
+        assert!(check(json!({
+            "minutes": 30,
+            "team": "OPS",
+            "tags": ["ab", "cd"],
+            "headers": { "x": "short" }
+        }))
+        .is_ok());
+    }
+
+    #[test]
+    fn numbers_are_checked_against_their_range() {
+        let error = check(json!({ "minutes": 0 })).unwrap_err();
+        assert!(error.contains("'/minutes'"), "{}", error);
+        assert!(error.contains("at least 1"), "{}", error);
+
+        assert!(check(json!({ "minutes": 1441 })).is_err());
+    }
+
+    #[test]
+    fn numbers_sent_as_strings_are_checked_as_numbers() {
+        assert!(check(json!({ "minutes": "30" })).is_ok());
+        assert!(check(json!({ "minutes": "2000" })).is_err());
+    }
+
+    #[test]
+    fn strings_are_checked_against_their_pattern() {
+        let error = check(json!({ "team": "ops" })).unwrap_err();
+        assert!(error.contains("a match for ^[A-Z]{3}$"), "{}", error);
+    }
+
+    #[test]
+    fn invalid_patterns_reject_the_call() {
+        let parameters = json!({
+            "type": "object",
+            "properties": { "team": { "type": "string", "pattern": "^[A-Z" } }
+        });
+
+        let error = validate_arguments("AssignTeam", &parameters, &json!({ "team": "OPS" }))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("not a valid regex"), "{}", error);
+    }
+
+    #[test]
+    fn arrays_and_maps_are_checked_item_by_item() {
+        let error = check(json!({ "tags": ["ab", "c"] })).unwrap_err();
+        assert!(error.contains("'/tags/1'"), "{}", error);
+
+        assert!(check(json!({ "tags": ["ab", "cd", "ef"] })).is_err());
+
+        let error = check(json!({ "headers": { "x": "too long" } })).unwrap_err();
+        assert!(error.contains("'/headers/x'"), "{}", error);
+    }
+}
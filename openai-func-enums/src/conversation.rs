@@ -0,0 +1,365 @@
+use crate::provider::message_text;
+use crate::{
+    count_tokens, CommandError, Logger, RunConfig, RunOutput, ToolCallExecutionStrategy, ToolSet,
+};
+use async_openai::types::{
+    ChatCompletionRequestAssistantMessage, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessage, ChatCompletionRequestToolMessage,
+    ChatCompletionRequestUserMessage, ChatCompletionRequestUserMessageContent,
+    CreateChatCompletionRequestArgs,
+};
+use std::error::Error;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// How a `Conversation` makes room when it no longer fits in the request token limit. Either
+/// way the system messages and the latest turn are kept, and the oldest turns go first. A turn
+/// is a user message along with the replies and tool results that follow it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum HistoryTrimming {
+    /// Drop the oldest turns.
+    #[default]
+    DropOldest,
+    /// Replace the oldest turns with a summary written by `model`, which can be a cheap one.
+    /// The summary is sent as a system message after the others.
+    Summarize { model: String },
+    /// Send the transcript as it is, and fail the request when it is too long.
+    Off,
+}
+
+/// The transcript of a multi-turn chat, for using a `ToolSet` across turns instead of with
+/// one prompt at a time.
+///
+/// ```no_run
+/// # use openai_func_enums::*;
+/// # use std::sync::Arc;
+/// # #[derive(Debug, ToolSet)]
+/// # pub enum FunctionDef {
+/// #     /// Gets the current weather in a city
+/// #     GetCurrentWeather { city: String },
+/// # }
+/// # #[async_trait::async_trait]
+/// # impl RunCommand for FunctionDef {
+/// #     async fn run(
+/// #         &self,
+/// #         _: ToolCallExecutionStrategy,
+/// #         _: Option<Vec<String>>,
+/// #         _: Arc<Logger>,
+/// #         _: Option<(String, usize)>,
+/// #         _: &(),
+/// #     ) -> Result<ToolOutput, Box<dyn std::error::Error + Send + Sync + 'static>> {
+/// #         Ok(ToolOutput::none())
+/// #     }
+/// # }
+/// # async fn chat(
+/// #     strategy: ToolCallExecutionStrategy,
+/// #     logger: Arc<Logger>,
+/// #     config: RunConfig,
+/// # ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+/// let mut conversation = Conversation::new();
+/// conversation.add_user("What's the weather in Boston?");
+/// let output = conversation.run_tools::<FunctionDef>("gpt-4o", strategy, logger, &config).await?;
+/// conversation.add_user("And in Atlanta?");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Conversation {
+    pub messages: Vec<ChatCompletionRequestMessage>,
+    pub trimming: HistoryTrimming,
+    /// What the turns that were summarized away were about, see `HistoryTrimming::Summarize`.
+    pub summary: Option<String>,
+}
+
+impl Conversation {
+    pub fn new() -> Self {
+        Conversation::default()
+    }
+
+    /// A conversation that starts with `system_message` in place of the default one.
+    pub fn with_system_message(system_message: impl Into<String>) -> Self {
+        let mut conversation = Conversation::new();
+        conversation.add_system(system_message);
+        conversation
+    }
+
+    pub fn add_system(&mut self, content: impl Into<String>) -> &mut Self {
+        self.messages.push(ChatCompletionRequestMessage::System(
+            ChatCompletionRequestSystemMessage {
+                content: content.into().into(),
+                name: None,
+            },
+        ));
+        self
+    }
+
+    pub fn add_user(&mut self, content: impl Into<String>) -> &mut Self {
+        self.messages.push(ChatCompletionRequestMessage::User(
+            ChatCompletionRequestUserMessage {
+                content: content.into().into(),
+                name: None,
+            },
+        ));
+        self
+    }
+
+    pub fn add_assistant(&mut self, content: impl Into<String>) -> &mut Self {
+        self.messages.push(ChatCompletionRequestMessage::Assistant(
+            ChatCompletionRequestAssistantMessage {
+                content: Some(content.into().into()),
+                ..Default::default()
+            },
+        ));
+        self
+    }
+
+    /// Adds the result of a tool call the model made, matched to it by `tool_call_id`.
+    pub fn add_tool_result(
+        &mut self,
+        tool_call_id: impl Into<String>,
+        content: impl Into<String>,
+    ) -> &mut Self {
+        self.messages.push(ChatCompletionRequestMessage::Tool(
+            ChatCompletionRequestToolMessage {
+                content: content.into().into(),
+                tool_call_id: tool_call_id.into(),
+            },
+        ));
+        self
+    }
+
+    /// The text of the latest user message, which the tools are picked for.
+    pub fn last_user_message(&self) -> Option<&str> {
+        self.messages.iter().rev().find_map(user_text)
+    }
+
+    /// Sends the conversation with the tools of `T` and runs the tool calls the model makes.
+    /// The tools are picked and the calls verified against the latest user message, and
+    /// without a system message of its own the conversation goes out with the default one.
+    ///
+    /// When the transcript doesn't fit in the request token limit it is trimmed first, see
+    /// `trimming`. The model's reply, its tool calls and their results are added to the
+    /// transcript, so the next turn only needs `add_user`.
+    pub async fn run_tools<T: ToolSet>(
+        &mut self,
+        model_name: &str,
+        execution_strategy: ToolCallExecutionStrategy,
+        logger: Arc<Logger>,
+        config: &RunConfig,
+    ) -> Result<RunOutput, Box<dyn Error + Send + Sync + 'static>> {
+        let prompt = self.last_user_message().unwrap_or_default().to_string();
+        let mut request = T::build_request(&prompt, model_name, None, None, config)?;
+
+        // Keeps what goes ahead of the prompt, the system message and any few-shot examples,
+        // unless the conversation has a system message of its own. The prompt is the last
+        // user message. When the system message was merged into it, see
+        // `SystemMessageStrategy::MergeIntoUser`, it is merged into the first user message of
+        // the transcript instead.
+        let mut merged_system_message = None;
+        if self.messages.iter().any(is_system_message) {
+            request.messages.clear();
+        } else if let Some(index) = request
+            .messages
+            .iter()
+            .rposition(|message| matches!(message, ChatCompletionRequestMessage::User(_)))
+        {
+            let prompt_message = request.messages.remove(index);
+            merged_system_message = user_text(&prompt_message)
+                .and_then(|text| text.strip_suffix(prompt.as_str()))
+                .filter(|system_message| !system_message.is_empty())
+                .map(str::to_string);
+        }
+
+        let tools_tokens = count_tokens(&serde_json::to_string(&request.tools).unwrap_or_default());
+        let merged_tokens = merged_system_message.as_deref().map_or(0, count_tokens);
+        let budget = T::request_token_limit(model_name)
+            .saturating_sub(tools_tokens + messages_tokens(&request.messages) + merged_tokens);
+        self.trim_to(budget, config).await?;
+
+        let mut transcript = self.request_messages();
+        if let (Some(system_message), Some(ChatCompletionRequestMessage::User(user_message))) = (
+            merged_system_message,
+            transcript
+                .iter_mut()
+                .find(|message| matches!(message, ChatCompletionRequestMessage::User(_))),
+        ) {
+            if let ChatCompletionRequestUserMessageContent::Text(text) = &mut user_message.content {
+                text.insert_str(0, &system_message);
+            }
+        }
+        request.messages.extend(transcript);
+        let sent = request.messages.len();
+
+        let output = T::run_request(&prompt, request, execution_strategy, logger, config).await?;
+        if let Some(follow_up_request) = &output.follow_up_request {
+            self.messages
+                .extend(follow_up_request.messages.iter().skip(sent).cloned());
+        }
+
+        Ok(output)
+    }
+
+    /// The tokens the transcript takes up in a request, summary included.
+    pub fn token_count(&self) -> usize {
+        messages_tokens(&self.request_messages())
+    }
+
+    /// Trims the oldest turns, according to `trimming`, until the transcript takes up no more
+    /// than `budget` tokens.
+    ///
+    /// # Errors
+    /// Returns an error if the transcript still doesn't fit once only the latest turn is left,
+    /// or if the summary request fails.
+    pub async fn trim_to(
+        &mut self,
+        budget: usize,
+        config: &RunConfig,
+    ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+        if self.trimming == HistoryTrimming::Off {
+            return Ok(());
+        }
+
+        let mut dropped = Vec::new();
+        while self.token_count() > budget {
+            let Some(turn) = self.oldest_turn() else {
+                break;
+            };
+            dropped.extend(self.messages.drain(turn));
+        }
+
+        if let (HistoryTrimming::Summarize { model }, false) = (&self.trimming, dropped.is_empty())
+        {
+            self.summary = Some(self.summarize(model, &dropped, config).await?);
+
+            // Makes room for the summary if it needs it.
+            while self.token_count() > budget {
+                let Some(turn) = self.oldest_turn() else {
+                    break;
+                };
+                self.messages.drain(turn);
+            }
+        }
+
+        if self.token_count() > budget {
+            return Err(Box::new(CommandError::new(
+                "Request token count is too high",
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// The messages sent for the transcript: the summary, if there is one, goes after the
+    /// leading system messages.
+    fn request_messages(&self) -> Vec<ChatCompletionRequestMessage> {
+        let mut messages = self.messages.clone();
+        if let Some(summary) = &self.summary {
+            let index = messages
+                .iter()
+                .position(|message| !is_system_message(message))
+                .unwrap_or(messages.len());
+            messages.insert(
+                index,
+                ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+                    content: format!("Summary of the conversation so far: {}", summary).into(),
+                    name: None,
+                }),
+            );
+        }
+        messages
+    }
+
+    /// The oldest turn after the system messages, unless it's the latest one.
+    fn oldest_turn(&self) -> Option<Range<usize>> {
+        let start = self
+            .messages
+            .iter()
+            .position(|message| !is_system_message(message))?;
+        let length = self.messages[start + 1..]
+            .iter()
+            .position(|message| matches!(message, ChatCompletionRequestMessage::User(_)))?;
+        Some(start..start + 1 + length)
+    }
+
+    async fn summarize(
+        &self,
+        model: &str,
+        dropped: &[ChatCompletionRequestMessage],
+        config: &RunConfig,
+    ) -> Result<String, Box<dyn Error + Send + Sync + 'static>> {
+        let mut transcript = String::new();
+        if let Some(summary) = &self.summary {
+            transcript.push_str(&format!("Earlier summary: {}\n", summary));
+        }
+        for message in dropped {
+            transcript.push_str(&transcript_line(message));
+            transcript.push('\n');
+        }
+
+        let request = CreateChatCompletionRequestArgs::default()
+            .model(model)
+            .temperature(0.0)
+            .messages([
+                ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+                    content: "Summarize this conversation in a few sentences. Keep the facts, \
+                        decisions and tool results the assistant will need later."
+                        .into(),
+                    name: None,
+                }),
+                ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                    content: transcript.into(),
+                    name: None,
+                }),
+            ])
+            .build()?;
+
+        let response = config.create_chat_completion(request).await?;
+        Ok(response
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|choice| choice.message.content)
+            .unwrap_or_default())
+    }
+}
+
+fn user_text(message: &ChatCompletionRequestMessage) -> Option<&str> {
+    match message {
+        ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: ChatCompletionRequestUserMessageContent::Text(text),
+            ..
+        }) => Some(text),
+        _ => None,
+    }
+}
+
+fn is_system_message(message: &ChatCompletionRequestMessage) -> bool {
+    matches!(
+        message,
+        ChatCompletionRequestMessage::System(_) | ChatCompletionRequestMessage::Developer(_)
+    )
+}
+
+fn messages_tokens(messages: &[ChatCompletionRequestMessage]) -> usize {
+    messages
+        .iter()
+        .map(|message| count_tokens(&serde_json::to_string(message).unwrap_or_default()))
+        .sum()
+}
+
+/// A message as a line of plain text, for the summary request.
+fn transcript_line(message: &ChatCompletionRequestMessage) -> String {
+    let message = serde_json::to_value(message).unwrap_or_default();
+    let role = message["role"].as_str().unwrap_or_default();
+    let mut line = format!("{}: {}", role, message_text(&message["content"]));
+    for tool_call in message["tool_calls"].as_array().into_iter().flatten() {
+        line.push_str(&format!(
+            " [called {} with {}]",
+            tool_call["function"]["name"].as_str().unwrap_or_default(),
+            tool_call["function"]["arguments"]
+                .as_str()
+                .unwrap_or_default()
+        ));
+    }
+    line
+}
@@ -0,0 +1,289 @@
+use crate::models::longest_prefix_match;
+use crate::{Budget, BudgetExceeded};
+use async_openai::types::CompletionUsage;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, RwLock};
+
+static CUSTOM_PRICES: OnceLock<RwLock<HashMap<String, ModelPrice>>> = OnceLock::new();
+
+/// What a model costs, in US dollars per million tokens.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ModelPrice {
+    pub prompt_per_million: f64,
+    pub completion_per_million: f64,
+}
+
+impl ModelPrice {
+    pub const fn new(prompt_per_million: f64, completion_per_million: f64) -> Self {
+        ModelPrice {
+            prompt_per_million,
+            completion_per_million,
+        }
+    }
+
+    /// The cost of a request that used `prompt_tokens` and `completion_tokens`.
+    pub fn cost(&self, prompt_tokens: u32, completion_tokens: u32) -> f64 {
+        (f64::from(prompt_tokens) * self.prompt_per_million
+            + f64::from(completion_tokens) * self.completion_per_million)
+            / 1_000_000.0
+    }
+}
+
+// Standard tier list prices, matched by prefix like the token limits in `models`.
+const KNOWN_PRICES: &[(&str, ModelPrice)] = &[
+    ("gpt-5", ModelPrice::new(1.25, 10.0)),
+    ("gpt-5-mini", ModelPrice::new(0.25, 2.0)),
+    ("gpt-5-nano", ModelPrice::new(0.05, 0.4)),
+    ("gpt-4.1", ModelPrice::new(2.0, 8.0)),
+    ("gpt-4.1-mini", ModelPrice::new(0.4, 1.6)),
+    ("gpt-4.1-nano", ModelPrice::new(0.1, 0.4)),
+    ("gpt-4o", ModelPrice::new(2.5, 10.0)),
+    ("gpt-4o-mini", ModelPrice::new(0.15, 0.6)),
+    ("gpt-4-turbo", ModelPrice::new(10.0, 30.0)),
+    ("gpt-4-32k", ModelPrice::new(60.0, 120.0)),
+    ("gpt-4", ModelPrice::new(30.0, 60.0)),
+    ("gpt-3.5-turbo", ModelPrice::new(0.5, 1.5)),
+    ("o1", ModelPrice::new(15.0, 60.0)),
+    ("o1-mini", ModelPrice::new(1.1, 4.4)),
+    ("o1-preview", ModelPrice::new(15.0, 60.0)),
+    ("o3", ModelPrice::new(2.0, 8.0)),
+    ("o3-mini", ModelPrice::new(1.1, 4.4)),
+    ("o4-mini", ModelPrice::new(1.1, 4.4)),
+];
+
+/// Looks up the price of a model. Prices registered with `register_model_price` take
+/// precedence over the built in ones, which are OpenAI's list prices and don't know about
+/// discounts like cached prompts or the batch API.
+///
+/// Names are matched by prefix, and a provider prefix like the one in `"openai/gpt-4o"` is
+/// ignored.
+pub fn model_price(model_name: &str) -> Option<ModelPrice> {
+    let model_name = model_name.rsplit('/').next().unwrap_or(model_name);
+
+    let custom = CUSTOM_PRICES.get().and_then(|prices| {
+        let prices = prices
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        longest_prefix_match(
+            prices
+                .iter()
+                .map(|(prefix, price)| (prefix.as_str(), *price)),
+            model_name,
+        )
+    });
+
+    custom.or_else(|| {
+        longest_prefix_match(
+            KNOWN_PRICES.iter().map(|(prefix, price)| (*prefix, *price)),
+            model_name,
+        )
+    })
+}
+
+/// Sets the price of a model, or overrides a built in one. `name_prefix` matches every model
+/// whose name starts with it.
+pub fn register_model_price(name_prefix: impl Into<String>, price: ModelPrice) {
+    CUSTOM_PRICES
+        .get_or_init(|| RwLock::new(HashMap::new()))
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(name_prefix.into(), price);
+}
+
+/// The tokens and money the chat requests of a run, and the runs its tools started, went
+/// through.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct UsageReport {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// What the tokens cost according to `model_price`. Requests to models without a price
+    /// count as free, see `unpriced_requests`.
+    pub cost_usd: f64,
+    /// How many requests reported their usage.
+    pub requests: u64,
+    /// How many of the requests were to models without a price.
+    pub unpriced_requests: u64,
+}
+
+impl UsageReport {
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    /// Adds the usage of one request to `model_name`.
+    pub fn record(&mut self, model_name: &str, usage: &CompletionUsage) {
+        self.prompt_tokens += u64::from(usage.prompt_tokens);
+        self.completion_tokens += u64::from(usage.completion_tokens);
+        self.requests += 1;
+        match model_price(model_name) {
+            Some(price) => {
+                self.cost_usd += price.cost(usage.prompt_tokens, usage.completion_tokens)
+            }
+            None => self.unpriced_requests += 1,
+        }
+    }
+
+    /// Adds another report to this one, like the reports of the steps of a pipeline.
+    pub fn add(&mut self, other: &UsageReport) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.cost_usd += other.cost_usd;
+        self.requests += other.requests;
+        self.unpriced_requests += other.unpriced_requests;
+    }
+}
+
+/// Adds up the usage of every chat request sent with a config, for totals that span several
+/// runs, like a conversation or a whole session.
+///
+/// Put it on `RunConfig::usage_meter`. Each run meters its own requests on a meter nested in
+/// this one, so the run's `RunOutput::usage` covers just that run while this meter sees them
+/// all. Clones share their report. With a `Budget`, requests are refused once everything this
+/// meter has seen spent it.
+#[derive(Clone, Debug, Default)]
+pub struct UsageMeter {
+    report: Arc<Mutex<UsageReport>>,
+    parent: Option<Box<UsageMeter>>,
+    budget: Option<Budget>,
+}
+
+impl UsageMeter {
+    pub fn new() -> Self {
+        UsageMeter::default()
+    }
+
+    /// A meter of its own whose requests are counted by `parent` as well.
+    pub fn nested(parent: Option<&UsageMeter>) -> Self {
+        UsageMeter {
+            report: Arc::default(),
+            parent: parent.cloned().map(Box::new),
+            budget: None,
+        }
+    }
+
+    pub fn with_budget(mut self, budget: Budget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Checks the budget of this meter and of every meter it is nested in.
+    ///
+    /// # Errors
+    /// Returns `BudgetExceeded` for the first of them that is spent.
+    pub fn check_budget(&self) -> Result<(), BudgetExceeded> {
+        if let Some(budget) = self.budget {
+            let usage = self.report();
+            if budget.is_spent_by(&usage) {
+                return Err(BudgetExceeded { budget, usage });
+            }
+        }
+
+        match &self.parent {
+            Some(parent) => parent.check_budget(),
+            None => Ok(()),
+        }
+    }
+
+    /// The usage so far.
+    pub fn report(&self) -> UsageReport {
+        self.lock().clone()
+    }
+
+    /// Adds the usage of one request to `model_name`, here and in every meter this one is
+    /// nested in.
+    pub fn record(&self, model_name: &str, usage: &CompletionUsage) {
+        self.lock().record(model_name, usage);
+        if let Some(parent) = &self.parent {
+            parent.record(model_name, usage);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, UsageReport> {
+        self.report.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(prompt_tokens: u32, completion_tokens: u32) -> CompletionUsage {
+        CompletionUsage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            prompt_tokens_details: None,
+            completion_tokens_details: None,
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-12,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn prices_are_matched_by_the_longest_prefix() {
+        assert_eq!(
+            model_price("gpt-4o-mini-2024-07-18"),
+            model_price("gpt-4o-mini")
+        );
+        assert_ne!(model_price("gpt-4o-mini"), model_price("gpt-4o"));
+        assert_eq!(model_price("openai/gpt-4o"), model_price("gpt-4o"));
+        assert_eq!(model_price("llama3"), None);
+    }
+
+    #[test]
+    fn costs_are_per_million_tokens() {
+        let price = ModelPrice::new(2.5, 10.0);
+
+        assert_close(price.cost(1_000_000, 0), 2.5);
+        assert_close(price.cost(2_000, 500), 0.01);
+    }
+
+    #[test]
+    fn reports_count_unpriced_requests_apart() {
+        let mut report = UsageReport::default();
+        report.record("gpt-4o", &usage(2_000, 500));
+        report.record("llama3", &usage(100, 10));
+
+        assert_eq!(report.total_tokens(), 2_610);
+        assert_eq!((report.requests, report.unpriced_requests), (2, 1));
+        assert_close(report.cost_usd, 0.01);
+
+        let mut total = report.clone();
+        total.add(&report);
+        assert_eq!((total.prompt_tokens, total.requests), (4_200, 4));
+        assert_close(total.cost_usd, 0.02);
+    }
+
+    #[test]
+    fn registered_prices_come_first() {
+        register_model_price("gpt-4o-func-enums-test", ModelPrice::new(1.0, 1.0));
+
+        assert_eq!(
+            model_price("gpt-4o-func-enums-test"),
+            Some(ModelPrice::new(1.0, 1.0))
+        );
+    }
+
+    #[test]
+    fn nested_meters_record_in_their_parents() {
+        let session = UsageMeter::new();
+        let run = UsageMeter::nested(Some(&session));
+        let other_run = UsageMeter::nested(Some(&session));
+
+        run.record("gpt-4o", &usage(100, 10));
+        other_run.record("gpt-4o", &usage(200, 20));
+
+        assert_eq!(run.report().total_tokens(), 110);
+        assert_eq!(other_run.report().total_tokens(), 220);
+        assert_eq!(session.report().total_tokens(), 330);
+        assert_eq!(session.clone().report().requests, 2);
+    }
+}
@@ -0,0 +1,295 @@
+use crate::CommandError;
+use async_openai::types::ChatCompletionMessageToolCall;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::path::Path;
+
+/// A prompt together with the tool call the model is expected to make for it.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct EvalCase {
+    pub prompt: String,
+    pub expected_function: String,
+    /// The arguments the call must have. Only the keys given here are compared, so a case can
+    /// check the arguments that matter and ignore the rest. Numbers sent as strings count as
+    /// numbers, like they do when the arguments are deserialized.
+    #[serde(default)]
+    pub expected_arguments: Option<Value>,
+}
+
+/// A set of `EvalCase`s, usually kept next to the tool descriptions they test.
+///
+/// Corpus files are JSON, either an array of cases or an object with a `cases` array, or TOML
+/// with `[[cases]]` tables when the file name ends in `.toml`. TOML takes the place of YAML
+/// for hand written corpora, since the runtime config file is TOML and its parser is already
+/// a dependency:
+///
+/// ```json
+/// [
+///     {
+///         "prompt": "What's the weather like in Boston?",
+///         "expected_function": "GetCurrentWeather",
+///         "expected_arguments": { "location": "Boston" }
+///     }
+/// ]
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct EvalCorpus {
+    pub cases: Vec<EvalCase>,
+}
+
+impl EvalCorpus {
+    pub fn load(path: impl AsRef<Path>) -> Result<EvalCorpus, CommandError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| CommandError::new(&format!("Failed to read {}: {}", path.display(), e)))?;
+
+        let parse_error = |e: &dyn fmt::Display| {
+            CommandError::new(&format!("Failed to parse {}: {}", path.display(), e))
+        };
+
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "toml")
+        {
+            return toml::from_str(&text).map_err(|e| parse_error(&e));
+        }
+
+        match serde_json::from_str::<Value>(&text).map_err(|e| parse_error(&e))? {
+            Value::Array(cases) => Ok(EvalCorpus {
+                cases: serde_json::from_value(Value::Array(cases)).map_err(|e| parse_error(&e))?,
+            }),
+            corpus => serde_json::from_value(corpus).map_err(|e| parse_error(&e)),
+        }
+    }
+}
+
+/// How the model did on one `EvalCase`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EvalCaseResult {
+    pub case: EvalCase,
+    /// The function of the first tool call the model made, if it made one.
+    pub selected_function: Option<String>,
+    pub selected_arguments: Option<Value>,
+    pub function_matched: bool,
+    /// `true` if the function matched and so did every expected argument.
+    pub arguments_matched: bool,
+    /// Why no selection could be made, if selecting failed.
+    pub error: Option<String>,
+}
+
+/// The results of one tool's cases.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ToolAccuracy {
+    pub cases: usize,
+    pub function_matches: usize,
+    pub argument_matches: usize,
+}
+
+impl ToolAccuracy {
+    /// The share of cases where the right function was called, from `0.0` to `1.0`.
+    pub fn function_accuracy(&self) -> f64 {
+        ratio(self.function_matches, self.cases)
+    }
+
+    /// The share of cases where the right function was called with the right arguments.
+    pub fn argument_accuracy(&self) -> f64 {
+        ratio(self.argument_matches, self.cases)
+    }
+}
+
+/// The outcome of running a corpus through `evaluate`.
+///
+/// Its `Display` output is a per tool table, handy for printing from a test or a CI job.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EvalReport {
+    pub results: Vec<EvalCaseResult>,
+}
+
+impl EvalReport {
+    /// The accuracy of each expected function, keyed by its name.
+    pub fn per_tool(&self) -> BTreeMap<String, ToolAccuracy> {
+        let mut per_tool: BTreeMap<String, ToolAccuracy> = BTreeMap::new();
+
+        for result in &self.results {
+            let accuracy = per_tool
+                .entry(result.case.expected_function.clone())
+                .or_default();
+            accuracy.cases += 1;
+            accuracy.function_matches += result.function_matched as usize;
+            accuracy.argument_matches += result.arguments_matched as usize;
+        }
+
+        per_tool
+    }
+
+    /// The accuracy over every case.
+    pub fn total(&self) -> ToolAccuracy {
+        self.per_tool()
+            .values()
+            .fold(ToolAccuracy::default(), |total, accuracy| ToolAccuracy {
+                cases: total.cases + accuracy.cases,
+                function_matches: total.function_matches + accuracy.function_matches,
+                argument_matches: total.argument_matches + accuracy.argument_matches,
+            })
+    }
+
+    /// The cases where the wrong function was called or the arguments were wrong.
+    pub fn failures(&self) -> impl Iterator<Item = &EvalCaseResult> {
+        self.results
+            .iter()
+            .filter(|result| !result.arguments_matched)
+    }
+}
+
+impl fmt::Display for EvalReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let per_tool = self.per_tool();
+        let width = per_tool
+            .keys()
+            .map(|name| name.len())
+            .chain(std::iter::once("Total".len()))
+            .max()
+            .unwrap_or(0);
+
+        writeln!(
+            f,
+            "{:width$}  cases  function  arguments",
+            "tool",
+            width = width
+        )?;
+        for (name, accuracy) in per_tool
+            .iter()
+            .map(|(name, accuracy)| (name.as_str(), *accuracy))
+            .chain(std::iter::once(("Total", self.total())))
+        {
+            writeln!(
+                f,
+                "{:width$}  {:>5}  {:>7.1}%  {:>8.1}%",
+                name,
+                accuracy.cases,
+                accuracy.function_accuracy() * 100.0,
+                accuracy.argument_accuracy() * 100.0,
+                width = width
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs every case through `select` and compares the first tool call it returns with what the
+/// case expects.
+///
+/// `select` decides how the tool calls are obtained. Against the live API it is usually the
+/// generated `select_tool_calls`:
+///
+/// ```no_run
+/// # use openai_func_enums::*;
+/// # use std::sync::Arc;
+/// # #[derive(Debug, ToolSet)]
+/// # pub enum Commands {
+/// #     /// Gets the current weather in a city
+/// #     GetCurrentWeather { city: String },
+/// # }
+/// # #[async_trait::async_trait]
+/// # impl RunCommand for Commands {
+/// #     async fn run(
+/// #         &self,
+/// #         _: ToolCallExecutionStrategy,
+/// #         _: Option<Vec<String>>,
+/// #         _: Arc<Logger>,
+/// #         _: Option<(String, usize)>,
+/// #         _: &(),
+/// #     ) -> Result<ToolOutput, Box<dyn std::error::Error + Send + Sync + 'static>> {
+/// #         Ok(ToolOutput::none())
+/// #     }
+/// # }
+/// # async fn report(corpus: EvalCorpus, model_name: &str, config: &RunConfig) {
+/// let report = evaluate(&corpus.cases, |case| async move {
+///     CommandsGPT::select_tool_calls(&case.prompt, model_name, None, None, None, None, None, &config)
+///         .await
+/// })
+/// .await;
+/// println!("{}", report);
+/// # }
+/// ```
+///
+/// It can just as well return canned or replayed tool calls, so reports can be produced
+/// without an API key. A case whose selection fails counts as a miss and keeps its error.
+pub async fn evaluate<F, Fut, E>(cases: &[EvalCase], mut select: F) -> EvalReport
+where
+    F: FnMut(EvalCase) -> Fut,
+    Fut: Future<Output = Result<Vec<ChatCompletionMessageToolCall>, E>>,
+    E: fmt::Display,
+{
+    let mut report = EvalReport::default();
+
+    for case in cases {
+        let mut result = EvalCaseResult {
+            case: case.clone(),
+            selected_function: None,
+            selected_arguments: None,
+            function_matched: false,
+            arguments_matched: false,
+            error: None,
+        };
+
+        match select(case.clone()).await {
+            Ok(tool_calls) => {
+                if let Some(tool_call) = tool_calls.first() {
+                    let arguments = serde_json::from_str::<Value>(&tool_call.function.arguments)
+                        .unwrap_or(Value::String(tool_call.function.arguments.clone()));
+
+                    result.function_matched = tool_call.function.name == case.expected_function;
+                    result.arguments_matched = result.function_matched
+                        && match &case.expected_arguments {
+                            Some(expected) => arguments_match(expected, &arguments),
+                            None => true,
+                        };
+                    result.selected_function = Some(tool_call.function.name.clone());
+                    result.selected_arguments = Some(arguments);
+                }
+            }
+            Err(e) => result.error = Some(e.to_string()),
+        }
+
+        report.results.push(result);
+    }
+
+    report
+}
+
+fn arguments_match(expected: &Value, actual: &Value) -> bool {
+    match (expected, actual) {
+        (Value::Object(expected), Value::Object(actual)) => {
+            expected.iter().all(|(key, expected)| {
+                actual
+                    .get(key)
+                    .is_some_and(|actual| arguments_match(expected, actual))
+            })
+        }
+        (Value::Array(expected), Value::Array(actual)) => {
+            expected.len() == actual.len()
+                && expected
+                    .iter()
+                    .zip(actual)
+                    .all(|(expected, actual)| arguments_match(expected, actual))
+        }
+        (Value::Number(expected), Value::Number(actual)) => expected.as_f64() == actual.as_f64(),
+        (Value::Number(expected), Value::String(actual)) => {
+            actual.trim().parse::<f64>().ok() == expected.as_f64()
+        }
+        (expected, actual) => expected == actual,
+    }
+}
+
+fn ratio(count: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 / total as f64
+    }
+}
@@ -0,0 +1,176 @@
+use crate::{RunConfig, ToolCallResult};
+use async_openai::types::{ChatCompletionMessageToolCall, CreateChatCompletionRequest};
+use std::fmt;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// What a run is doing, for showing live progress. See `RunEvents`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RunEvent {
+    /// A request was sent to the model. `depth` is 0 for a run started by your code and one
+    /// more for every run a tool starts inside of it.
+    RequestStarted {
+        model: String,
+        tools: Vec<String>,
+        depth: usize,
+    },
+    /// The model asked for a tool call.
+    ToolCallParsed {
+        id: String,
+        name: String,
+        arguments: String,
+    },
+    ToolStarted {
+        id: String,
+        name: String,
+    },
+    ToolFinished {
+        id: String,
+        name: String,
+        error: Option<String>,
+        duration: Duration,
+    },
+    /// A chat request failed with a transient error and is sent again after `wait`. `attempt`
+    /// is the number of the attempt that failed, starting at 1.
+    RequestRetried {
+        attempt: u32,
+        wait: Duration,
+        error: String,
+    },
+    /// A tool started a run of its own.
+    NestedRunStarted {
+        prompt: String,
+        depth: usize,
+    },
+    /// A run finished, after `tool_calls` calls.
+    Completed {
+        depth: usize,
+        tool_calls: usize,
+    },
+}
+
+impl RunEvent {
+    pub fn request_started(request: &CreateChatCompletionRequest, depth: usize) -> Self {
+        RunEvent::RequestStarted {
+            model: request.model.clone(),
+            tools: request
+                .tools
+                .iter()
+                .flatten()
+                .map(|tool| tool.function.name.clone())
+                .collect(),
+            depth,
+        }
+    }
+
+    pub fn tool_call_parsed(tool_call: &ChatCompletionMessageToolCall) -> Self {
+        RunEvent::ToolCallParsed {
+            id: tool_call.id.clone(),
+            name: tool_call.function.name.clone(),
+            arguments: tool_call.function.arguments.clone(),
+        }
+    }
+}
+
+/// Where runs send their `RunEvent`s. Put it on `RunConfig::events`, and the runs tools start
+/// report to the same channel.
+///
+/// Sending waits for room in the channel, so a slow receiver slows the run down rather than
+/// missing events. Events are dropped once the receiver is gone.
+#[derive(Clone, Debug)]
+pub struct RunEvents {
+    sender: mpsc::Sender<RunEvent>,
+    depth: usize,
+}
+
+impl RunEvents {
+    pub fn new(sender: mpsc::Sender<RunEvent>) -> Self {
+        RunEvents { sender, depth: 0 }
+    }
+
+    /// How deep in nested runs the run these events come from is.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub(crate) fn nested(&self, depth: usize) -> Self {
+        RunEvents {
+            sender: self.sender.clone(),
+            depth,
+        }
+    }
+
+    pub async fn send(&self, event: RunEvent) {
+        let _ = self.sender.send(event).await;
+    }
+}
+
+/// A finished tool call, as far as `track_tool_call` is concerned.
+pub trait TrackedToolCall {
+    fn tool_error(&self) -> Option<String>;
+}
+
+impl TrackedToolCall for ToolCallResult {
+    fn tool_error(&self) -> Option<String> {
+        self.error.clone()
+    }
+}
+
+impl<E: fmt::Display> TrackedToolCall for Result<ToolCallResult, E> {
+    fn tool_error(&self) -> Option<String> {
+        match self {
+            Ok(tool_call_result) => tool_call_result.error.clone(),
+            Err(e) => Some(e.to_string()),
+        }
+    }
+}
+
+/// Runs the future that executes `tool_call`, with `ToolStarted` and `ToolFinished` events
+/// around it if the current config has `events`.
+pub async fn track_tool_call<F>(tool_call: &ChatCompletionMessageToolCall, future: F) -> F::Output
+where
+    F: Future,
+    F::Output: TrackedToolCall,
+{
+    track_tool_call_with(current_events().as_ref(), tool_call, future).await
+}
+
+pub(crate) fn current_events() -> Option<RunEvents> {
+    RunConfig::with_current(|config| config.events.clone()).flatten()
+}
+
+pub(crate) async fn track_tool_call_with<F>(
+    events: Option<&RunEvents>,
+    tool_call: &ChatCompletionMessageToolCall,
+    future: F,
+) -> F::Output
+where
+    F: Future,
+    F::Output: TrackedToolCall,
+{
+    let Some(events) = events else {
+        return future.await;
+    };
+
+    events
+        .send(RunEvent::ToolStarted {
+            id: tool_call.id.clone(),
+            name: tool_call.function.name.clone(),
+        })
+        .await;
+
+    let started = Instant::now();
+    let output = future.await;
+
+    events
+        .send(RunEvent::ToolFinished {
+            id: tool_call.id.clone(),
+            name: tool_call.function.name.clone(),
+            error: output.tool_error(),
+            duration: started.elapsed(),
+        })
+        .await;
+
+    output
+}
@@ -0,0 +1,194 @@
+use crate::{count_tokens, SystemMessageStrategy};
+use async_openai::error::OpenAIError;
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessageArgs,
+    ChatCompletionRequestMessage, ChatCompletionRequestToolMessageArgs,
+    ChatCompletionRequestUserMessageArgs, ChatCompletionRequestUserMessageContent,
+    ChatCompletionTool, ChatCompletionToolType, FunctionCall,
+};
+use serde_json::Value;
+
+// What the role and the markers around a message add to its text, the way OpenAI counts chat
+// messages.
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+
+// The id, the type and the function object around the name and arguments of a tool call.
+const TOOL_CALL_OVERHEAD_TOKENS: usize = 8;
+
+// An example is three messages: the prompt, the assistant's tool call and the tool's answer.
+const EXAMPLE_OVERHEAD_TOKENS: usize = 3 * MESSAGE_OVERHEAD_TOKENS + TOOL_CALL_OVERHEAD_TOKENS;
+
+// What a tool answers in an example that doesn't say.
+const DEFAULT_RESULT: &str = "Ok.";
+
+/// An example exchange showing the model how a tool should be called.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FewShotExample {
+    /// What the user asked.
+    pub prompt: String,
+    /// The arguments the tool should be called with for that prompt.
+    pub arguments: Value,
+    /// What the tool returned. Defaults to `"Ok."`, since every tool call has to be answered.
+    pub result: Option<String>,
+}
+
+impl FewShotExample {
+    pub fn new(prompt: impl Into<String>, arguments: Value) -> Self {
+        FewShotExample {
+            prompt: prompt.into(),
+            arguments,
+            result: None,
+        }
+    }
+
+    pub fn with_result(mut self, result: impl Into<String>) -> Self {
+        self.result = Some(result.into());
+        self
+    }
+
+    fn token_count(&self, tool_name: &str) -> usize {
+        EXAMPLE_OVERHEAD_TOKENS
+            + count_tokens(&self.prompt)
+            + count_tokens(tool_name)
+            + count_tokens(&self.arguments.to_string())
+            + count_tokens(self.result.as_deref().unwrap_or(DEFAULT_RESULT))
+    }
+
+    /// The user message, the assistant's tool call, and the tool's answer.
+    fn messages(
+        &self,
+        tool_name: &str,
+        tool_call_id: String,
+    ) -> Result<Vec<ChatCompletionRequestMessage>, OpenAIError> {
+        let tool_call = ChatCompletionMessageToolCall {
+            id: tool_call_id.clone(),
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionCall {
+                name: tool_name.to_string(),
+                arguments: self.arguments.to_string(),
+            },
+        };
+
+        Ok(vec![
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(self.prompt.clone())
+                .build()?
+                .into(),
+            ChatCompletionRequestAssistantMessageArgs::default()
+                .tool_calls(vec![tool_call])
+                .build()?
+                .into(),
+            ChatCompletionRequestToolMessageArgs::default()
+                .content(self.result.as_deref().unwrap_or(DEFAULT_RESULT))
+                .tool_call_id(tool_call_id)
+                .build()?
+                .into(),
+        ])
+    }
+}
+
+/// Example exchanges for the tools of a `ToolSet`, set on `RunConfig::few_shot_examples`.
+///
+/// Whenever a tool is presented to the model, its examples are sent ahead of the prompt for as
+/// long as they fit in what is left of the request token limit. Examples help most with tools
+/// whose arguments are unusual or easy to get subtly wrong.
+#[derive(Clone, Debug, Default)]
+pub struct FewShotExamples {
+    examples: Vec<(String, FewShotExample)>,
+}
+
+impl FewShotExamples {
+    pub fn new() -> Self {
+        FewShotExamples::default()
+    }
+
+    /// Adds an example for the tool called `tool_name`. Examples are sent in the order they
+    /// were added.
+    pub fn add(&mut self, tool_name: impl Into<String>, example: FewShotExample) -> &mut Self {
+        self.examples.push((tool_name.into(), example));
+        self
+    }
+
+    pub fn for_tool<'a>(&'a self, tool_name: &'a str) -> impl Iterator<Item = &'a FewShotExample> {
+        self.examples
+            .iter()
+            .filter(move |(name, _)| name == tool_name)
+            .map(|(_, example)| example)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.examples.is_empty()
+    }
+
+    /// Builds the messages of a request like `SystemMessageStrategy::messages`, with the
+    /// examples of the presented tools between the system message and the prompt, see
+    /// `insert_into`.
+    ///
+    /// When the strategy merges the system message into the user message, it is merged into the
+    /// first one, which is the first example's prompt when there are examples. That way the
+    /// instructions still come first instead of after the examples.
+    pub fn request_messages(
+        &self,
+        system_message_strategy: SystemMessageStrategy,
+        model_name: &str,
+        system_message: String,
+        prompt: String,
+        tools: &[ChatCompletionTool],
+        token_budget: usize,
+    ) -> Result<Vec<ChatCompletionRequestMessage>, OpenAIError> {
+        if system_message_strategy.resolve(model_name) != SystemMessageStrategy::MergeIntoUser {
+            let mut messages =
+                system_message_strategy.messages(model_name, system_message, prompt)?;
+            self.insert_into(&mut messages, tools, token_budget)?;
+            return Ok(messages);
+        }
+
+        let mut messages = vec![ChatCompletionRequestUserMessageArgs::default()
+            .content(prompt)
+            .build()?
+            .into()];
+        self.insert_into(&mut messages, tools, token_budget)?;
+
+        if let Some(ChatCompletionRequestMessage::User(user_message)) = messages.first_mut() {
+            if let ChatCompletionRequestUserMessageContent::Text(text) = &mut user_message.content {
+                text.insert_str(0, &format!("{}\n\n", system_message));
+            }
+        }
+
+        Ok(messages)
+    }
+
+    /// Inserts the examples of the presented tools right before the last message of
+    /// `messages`, which is the user's prompt, skipping any that would go over `token_budget`.
+    ///
+    /// # Returns
+    /// The number of tokens the inserted examples add to the request.
+    pub fn insert_into(
+        &self,
+        messages: &mut Vec<ChatCompletionRequestMessage>,
+        tools: &[ChatCompletionTool],
+        token_budget: usize,
+    ) -> Result<usize, OpenAIError> {
+        let mut example_messages = Vec::new();
+        let mut used_tokens = 0;
+
+        for (index, (tool_name, example)) in self.examples.iter().enumerate() {
+            if !tools.iter().any(|tool| tool.function.name == *tool_name) {
+                continue;
+            }
+
+            let tokens = example.token_count(tool_name);
+            if used_tokens + tokens > token_budget {
+                continue;
+            }
+
+            example_messages.extend(example.messages(tool_name, format!("example_{}", index))?);
+            used_tokens += tokens;
+        }
+
+        let prompt_index = messages.len().saturating_sub(1);
+        messages.splice(prompt_index..prompt_index, example_messages);
+
+        Ok(used_tokens)
+    }
+}
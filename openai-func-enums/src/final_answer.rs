@@ -0,0 +1,67 @@
+use crate::count_tokens;
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionTool, ChatCompletionToolType, FunctionObject,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+/// The synthetic tool the model calls to say it is done, added to every request when
+/// `RunConfig::final_answer` is set.
+///
+/// Taking "no tool calls" to mean done lumps refusals and clarifying questions in with
+/// answers. With this tool the end of an agent loop is explicit, and the answer ends up on
+/// `RunOutput::final_answer`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct FinalAnswer {
+    pub text: String,
+}
+
+impl FinalAnswer {
+    pub const NAME: &'static str = "FinalAnswer";
+    const DESCRIPTION: &'static str =
+        "Gives the final answer to the user's request, once no more tool calls are needed.";
+
+    /// The tool definition along with its token count.
+    pub fn chat_completion_tool() -> (ChatCompletionTool, usize) {
+        let parameters = json!({
+            "type": "object",
+            "properties": {
+                "text": {
+                    "type": "string",
+                    "description": "The answer, as it should be shown to the user."
+                }
+            },
+            "required": ["text"]
+        });
+
+        let tokens = count_tokens(FinalAnswer::NAME)
+            + count_tokens(FinalAnswer::DESCRIPTION)
+            + count_tokens(&parameters.to_string());
+
+        let tool = ChatCompletionTool {
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionObject {
+                name: FinalAnswer::NAME.to_string(),
+                description: Some(FinalAnswer::DESCRIPTION.to_string()),
+                parameters: Some(parameters),
+                strict: None,
+            },
+        };
+
+        (tool, tokens)
+    }
+
+    /// The answer, if `tool_call` is a call to this tool. Arguments that aren't the expected
+    /// JSON are taken as the answer text itself.
+    pub fn from_tool_call(tool_call: &ChatCompletionMessageToolCall) -> Option<String> {
+        if tool_call.function.name != FinalAnswer::NAME {
+            return None;
+        }
+
+        Some(
+            serde_json::from_str::<FinalAnswer>(&tool_call.function.arguments)
+                .map(|answer| answer.text)
+                .unwrap_or_else(|_| tool_call.function.arguments.clone()),
+        )
+    }
+}
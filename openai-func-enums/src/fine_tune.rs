@@ -0,0 +1,274 @@
+use crate::{CommandError, RunOutput, ToolCallResult};
+use async_openai::types::CreateChatCompletionRequest;
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// One request of a run: what was sent to the model, and what became of the tool calls it
+/// made.
+#[derive(Clone, Debug)]
+pub struct RecordedSession {
+    pub request: CreateChatCompletionRequest,
+    pub tool_calls: Vec<ToolCallResult>,
+}
+
+impl RecordedSession {
+    pub fn new(request: CreateChatCompletionRequest, output: &RunOutput) -> Self {
+        RecordedSession {
+            request,
+            tool_calls: output.tool_calls.clone(),
+        }
+    }
+
+    /// Whether the model made tool calls and none of them failed.
+    pub fn succeeded(&self) -> bool {
+        !self.tool_calls.is_empty()
+            && self
+                .tool_calls
+                .iter()
+                .all(|tool_call| tool_call.error.is_none())
+    }
+
+    /// The session as an example in OpenAI's fine-tuning format for function calling: the
+    /// messages that were sent, followed by an assistant message with the tool calls, along
+    /// with the tools that were presented. Repaired arguments are used where there are any, so
+    /// that the model learns the arguments that worked.
+    ///
+    /// The tool outputs are left out, since the example trains the choice of calls.
+    pub fn fine_tuning_example(&self) -> Result<Value, CommandError> {
+        let mut messages = match serde_json::to_value(&self.request.messages) {
+            Ok(Value::Array(messages)) => messages,
+            Ok(_) => Vec::new(),
+            Err(e) => {
+                return Err(CommandError::new(&format!(
+                    "Failed to serialize the messages: {}",
+                    e
+                )))
+            }
+        };
+
+        let tool_calls: Vec<Value> = self
+            .tool_calls
+            .iter()
+            .map(|tool_call| {
+                json!({
+                    "id": tool_call.id,
+                    "type": "function",
+                    "function": {
+                        "name": tool_call.name,
+                        "arguments": tool_call
+                            .repaired_arguments
+                            .as_ref()
+                            .unwrap_or(&tool_call.arguments),
+                    }
+                })
+            })
+            .collect();
+        messages.push(json!({ "role": "assistant", "tool_calls": tool_calls }));
+
+        let tools = serde_json::to_value(self.request.tools.as_deref().unwrap_or_default())
+            .map_err(|e| CommandError::new(&format!("Failed to serialize the tools: {}", e)))?;
+
+        let mut example = json!({ "messages": messages, "tools": tools });
+        if let Some(parallel_tool_calls) = self.request.parallel_tool_calls {
+            example["parallel_tool_calls"] = parallel_tool_calls.into();
+        }
+
+        Ok(example)
+    }
+}
+
+/// Which sessions an export includes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SessionFilter {
+    #[default]
+    All,
+    /// Only sessions where every tool call went through, see `RecordedSession::succeeded`.
+    Succeeded,
+    Failed,
+}
+
+impl SessionFilter {
+    pub fn includes(&self, session: &RecordedSession) -> bool {
+        match self {
+            SessionFilter::All => true,
+            SessionFilter::Succeeded => session.succeeded(),
+            SessionFilter::Failed => !session.succeeded(),
+        }
+    }
+}
+
+/// Keeps every request a generated `run_with_config` sends along with its tool calls, to
+/// turn them into a fine-tuning dataset.
+///
+/// Put it on `RunConfig::session_recorder`. Clones share their sessions, so the same recorder
+/// can be handed to every run and exported at the end, typically to distill the tool calls of
+/// a large model into a smaller fine-tuned one:
+///
+/// ```no_run
+/// # use openai_func_enums::{SessionFilter, SessionRecorder};
+/// # fn main() -> Result<(), openai_func_enums::CommandError> {
+/// let recorder = SessionRecorder::new();
+/// // ... runs with `session_recorder: Some(recorder.clone())` ...
+/// recorder.export_fine_tuning("tool_calls.jsonl", SessionFilter::Succeeded)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct SessionRecorder {
+    sessions: Arc<Mutex<Vec<RecordedSession>>>,
+}
+
+impl SessionRecorder {
+    pub fn new() -> Self {
+        SessionRecorder::default()
+    }
+
+    pub fn record(&self, session: RecordedSession) {
+        self.lock().push(session);
+    }
+
+    pub fn sessions(&self) -> Vec<RecordedSession> {
+        self.lock().clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    /// The sessions `filter` includes as fine-tuning JSONL, one example per line. Sessions
+    /// without tool calls are skipped, since there is nothing in them to learn.
+    pub fn fine_tuning_jsonl(&self, filter: SessionFilter) -> Result<String, CommandError> {
+        let mut jsonl = String::new();
+        for session in self.lock().iter() {
+            if session.tool_calls.is_empty() || !filter.includes(session) {
+                continue;
+            }
+
+            jsonl.push_str(&session.fine_tuning_example()?.to_string());
+            jsonl.push('\n');
+        }
+
+        Ok(jsonl)
+    }
+
+    /// Writes `fine_tuning_jsonl` to `path`, ready to upload as a fine-tuning file.
+    ///
+    /// # Returns
+    /// The number of examples written.
+    pub fn export_fine_tuning(
+        &self,
+        path: impl AsRef<Path>,
+        filter: SessionFilter,
+    ) -> Result<usize, CommandError> {
+        let path = path.as_ref();
+        let jsonl = self.fine_tuning_jsonl(filter)?;
+        std::fs::write(path, &jsonl).map_err(|e| {
+            CommandError::new(&format!("Failed to write {}: {}", path.display(), e))
+        })?;
+
+        Ok(jsonl.lines().count())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<RecordedSession>> {
+        self.sessions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::{
+        ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs,
+    };
+
+    fn request() -> CreateChatCompletionRequest {
+        CreateChatCompletionRequestArgs::default()
+            .model("gpt-4o")
+            .messages([ChatCompletionRequestUserMessageArgs::default()
+                .content("Weather in Boston?")
+                .build()
+                .unwrap()
+                .into()])
+            .parallel_tool_calls(false)
+            .build()
+            .unwrap()
+    }
+
+    fn tool_call(id: &str, error: Option<&str>) -> ToolCallResult {
+        ToolCallResult {
+            id: id.to_string(),
+            name: "GetCurrentWeather".to_string(),
+            arguments: "{\"location\": Boston}".to_string(),
+            error: error.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    fn session(tool_calls: Vec<ToolCallResult>) -> RecordedSession {
+        RecordedSession {
+            request: request(),
+            tool_calls,
+        }
+    }
+
+    #[test]
+    fn examples_end_with_the_calls_and_their_repaired_arguments() {
+        let mut repaired = tool_call("call_1", None);
+        repaired.repaired_arguments = Some("{\"location\": \"Boston\"}".to_string());
+
+        let example = session(vec![repaired]).fine_tuning_example().unwrap();
+        let messages = example["messages"].as_array().unwrap();
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["role"], "user");
+        assert_eq!(messages[1]["role"], "assistant");
+        assert_eq!(messages[1]["tool_calls"][0]["id"], "call_1");
+        assert_eq!(
+            messages[1]["tool_calls"][0]["function"]["arguments"],
+            "{\"location\": \"Boston\"}"
+        );
+        assert_eq!(example["tools"], json!([]));
+        assert_eq!(example["parallel_tool_calls"], false);
+    }
+
+    #[test]
+    fn sessions_are_filtered_by_their_outcome() {
+        let recorder = SessionRecorder::new();
+        recorder.record(session(vec![tool_call("call_1", None)]));
+        recorder.record(session(vec![
+            tool_call("call_2", None),
+            tool_call("call_3", Some("Unknown location")),
+        ]));
+        recorder.record(session(Vec::new()));
+
+        assert_eq!(recorder.clone().len(), 3);
+        let lines = |filter| {
+            recorder
+                .fine_tuning_jsonl(filter)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<Value>(line).unwrap())
+                .map(|example| example["messages"][1]["tool_calls"][0]["id"].clone())
+                .collect::<Vec<_>>()
+        };
+
+        // The session without tool calls is never exported.
+        assert_eq!(
+            lines(SessionFilter::All),
+            [json!("call_1"), json!("call_2")]
+        );
+        assert_eq!(lines(SessionFilter::Succeeded), [json!("call_1")]);
+        assert_eq!(lines(SessionFilter::Failed), [json!("call_2")]);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::provider::message_text;
+use crate::{LlmProvider, RunConfig};
+use async_openai::error::OpenAIError;
+use async_openai::types::{CreateChatCompletionRequest, CreateChatCompletionResponse};
+use async_trait::async_trait;
+use genai::chat::{ChatMessage, ChatOptions, ChatRequest, Tool, ToolCall, ToolResponse};
+use genai::Client;
+use serde_json::{json, Value};
+
+/// Sends chat requests through the genai crate, which talks to Anthropic, Gemini, Ollama and
+/// others with the same derived tool schemas. The backend is picked by genai from the model
+/// name, `claude-...` for Anthropic for example, and keys come from the environment variables
+/// genai reads.
+///
+/// Available with the `genai` feature. Put it on `RunConfig::provider`, or use the generated
+/// `run_with_genai`.
+#[derive(Clone, Debug, Default)]
+pub struct GenaiProvider {
+    pub client: Client,
+}
+
+impl GenaiProvider {
+    pub fn new(client: Client) -> Self {
+        GenaiProvider { client }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for GenaiProvider {
+    fn name(&self) -> &str {
+        "genai"
+    }
+
+    async fn chat_completion(
+        &self,
+        request: CreateChatCompletionRequest,
+        config: &RunConfig,
+    ) -> Result<CreateChatCompletionResponse, OpenAIError> {
+        let (chat_request, options) = genai_request(&request, config)?;
+        let response = self
+            .client
+            .exec_chat(request.model.as_str(), chat_request, Some(&options))
+            .await
+            .map_err(|e| OpenAIError::InvalidArgument(format!("genai request failed: {}", e)))?;
+
+        let text = response.first_text().map(str::to_string);
+        let tool_calls: Vec<Value> = response
+            .tool_calls()
+            .into_iter()
+            .map(|tool_call| {
+                json!({
+                    "id": tool_call.call_id,
+                    "type": "function",
+                    "function": {
+                        "name": tool_call.fn_name,
+                        "arguments": tool_call.fn_arguments.to_string(),
+                    }
+                })
+            })
+            .collect();
+
+        let mut message = json!({ "role": "assistant", "content": text });
+        let finish_reason = if tool_calls.is_empty() {
+            "stop"
+        } else {
+            message["tool_calls"] = tool_calls.into();
+            "tool_calls"
+        };
+
+        let usage = &response.usage;
+        let prompt_tokens = usage.prompt_tokens.unwrap_or_default();
+        let completion_tokens = usage.completion_tokens.unwrap_or_default();
+
+        serde_json::from_value(json!({
+            "id": response.response_id.clone().unwrap_or_default(),
+            "object": "chat.completion",
+            "created": 0,
+            "model": response.provider_model_iden.model_name.to_string(),
+            "choices": [{ "index": 0, "message": message, "finish_reason": finish_reason }],
+            "usage": {
+                "prompt_tokens": prompt_tokens,
+                "completion_tokens": completion_tokens,
+                "total_tokens": usage.total_tokens.unwrap_or(prompt_tokens + completion_tokens),
+            }
+        }))
+        .map_err(OpenAIError::JSONDeserialize)
+    }
+}
+
+/// The genai counterpart of an OpenAI request. Messages are read from the request's JSON,
+/// where every kind of message has the same shape.
+fn genai_request(
+    request: &CreateChatCompletionRequest,
+    config: &RunConfig,
+) -> Result<(ChatRequest, ChatOptions), OpenAIError> {
+    let body = serde_json::to_value(request).map_err(OpenAIError::JSONDeserialize)?;
+
+    let mut messages = Vec::new();
+    for message in body["messages"].as_array().into_iter().flatten() {
+        let content = message_text(&message["content"]);
+        match message["role"].as_str() {
+            Some("system") | Some("developer") => messages.push(ChatMessage::system(content)),
+            Some("user") => messages.push(ChatMessage::user(content)),
+            Some("assistant") => {
+                let tool_calls: Vec<ToolCall> = message["tool_calls"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|tool_call| ToolCall {
+                        call_id: tool_call["id"].as_str().unwrap_or_default().to_string(),
+                        fn_name: tool_call["function"]["name"]
+                            .as_str()
+                            .unwrap_or_default()
+                            .to_string(),
+                        fn_arguments: tool_call["function"]["arguments"]
+                            .as_str()
+                            .and_then(|arguments| serde_json::from_str(arguments).ok())
+                            .unwrap_or_else(|| json!({})),
+                        thought_signatures: None,
+                    })
+                    .collect();
+
+                if !content.is_empty() {
+                    messages.push(ChatMessage::assistant(content));
+                }
+                if !tool_calls.is_empty() {
+                    messages.push(ChatMessage::from(tool_calls));
+                }
+            }
+            Some("tool") => messages.push(ChatMessage::from(ToolResponse::new(
+                message["tool_call_id"].as_str().unwrap_or_default(),
+                content,
+            ))),
+            _ => {}
+        }
+    }
+
+    let tools: Vec<Tool> = request
+        .tools
+        .iter()
+        .flatten()
+        .map(|tool| {
+            let mut genai_tool = Tool::new(tool.function.name.as_str());
+            if let Some(description) = &tool.function.description {
+                genai_tool = genai_tool.with_description(description);
+            }
+            if let Some(parameters) = &tool.function.parameters {
+                genai_tool = genai_tool.with_schema(parameters.clone());
+            }
+            genai_tool
+        })
+        .collect();
+
+    let mut chat_request = ChatRequest::new(messages);
+    if !tools.is_empty() {
+        chat_request = chat_request.with_tools(tools);
+    }
+
+    let mut options = ChatOptions::default();
+    #[allow(deprecated)]
+    let max_tokens = request.max_completion_tokens.or(request.max_tokens);
+    if let Some(max_tokens) = max_tokens {
+        options = options.with_max_tokens(max_tokens);
+    }
+    if let Some(temperature) = request.temperature {
+        options = options.with_temperature(temperature.into());
+    }
+    if !config.extra_body.is_empty() {
+        options.extra_body = Some(Value::Object(config.extra_body.clone()));
+    }
+
+    Ok((chat_request, options))
+}
@@ -0,0 +1,174 @@
+use crate::{load_embedding_archive_async, CommandError, Config, HttpConfig, RunConfig};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use tiktoken_rs::CoreBPE;
+
+static TOKENIZER: OnceLock<CoreBPE> = OnceLock::new();
+static HTTP_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// What `init_with` should get ready ahead of the first request.
+#[derive(Clone, Debug)]
+pub struct InitOptions {
+    /// The embedding archive to load and validate. Skipped when `None`.
+    pub embed_path: Option<PathBuf>,
+    /// Send a cheap request so the connection is open and the API key is known to work before
+    /// the first real request goes out.
+    pub warm_client: bool,
+    /// Settings for the shared HTTP client. It is built with reqwest's defaults when `None`.
+    pub http: Option<HttpConfig>,
+    /// The config the runs will use, whose client the warm up request is sent with, so that
+    /// it checks the same endpoint, key, organization and project. See `warm_client`.
+    pub run_config: RunConfig,
+}
+
+impl Default for InitOptions {
+    fn default() -> Self {
+        InitOptions {
+            embed_path: None,
+            warm_client: true,
+            http: None,
+            run_config: RunConfig::default(),
+        }
+    }
+}
+
+/// Does the setup the first request would otherwise pay for, so that problems show up right
+/// away instead of in the middle of a conversation.
+///
+/// This builds the tokenizer, warms up the HTTP client, and, if `FUNC_ENUMS_RUNTIME_EMBED_PATH`
+/// is set, loads and validates the embedding archive. The HTTP client is built with the
+/// `FUNC_ENUMS_RUNTIME_HTTP_PROXY` and timeout variables `Config::from_env` reads. Use `init_with` to
+/// pick the archive explicitly, for example with the `FUNC_ENUMS_EMBED_PATH` constant the
+/// `ToolSet` derive generates.
+///
+/// # Errors
+/// Returns a `CommandError` if any of these steps fail.
+pub async fn init() -> Result<(), CommandError> {
+    let config = Config::from_env()?;
+    let options = InitOptions {
+        embed_path: config
+            .ranking
+            .as_ref()
+            .and_then(|ranking| ranking.embed_path.clone()),
+        run_config: config.run_config(),
+        http: config.http,
+        ..Default::default()
+    };
+
+    init_with(&options).await
+}
+
+/// Same as `init`, with control over what gets prepared.
+///
+/// # Errors
+/// Returns a `CommandError` if the tokenizer can't be built, the embedding archive can't be
+/// read or fails validation, or the warm up request fails. Also if `http` is set but the
+/// shared HTTP client was already built, which happens as soon as the first request is sent.
+pub async fn init_with(options: &InitOptions) -> Result<(), CommandError> {
+    try_tokenizer()?;
+
+    if let Some(http) = &options.http {
+        let http_client = http.build_client()?;
+        HTTP_CLIENT.set(http_client).map_err(|_| {
+            CommandError::new("The HTTP client was already built before init_with was called")
+        })?;
+    }
+
+    if let Some(embed_path) = &options.embed_path {
+        load_embedding_archive_async(embed_path)
+            .await
+            .map_err(|e| {
+                CommandError::new(&format!(
+                    "Could not load embeddings from {}: {}",
+                    embed_path.display(),
+                    e
+                ))
+            })?;
+    }
+
+    try_http_client()?;
+    if options.warm_client {
+        warm_client(&options.run_config).await?;
+    }
+
+    Ok(())
+}
+
+/// Sends a cheap request with `config.client()`, so that its connection is open and its API
+/// key is known to work before the first real request goes out.
+///
+/// # Errors
+/// Returns a `CommandError` if the shared HTTP client can't be built or the request fails.
+pub async fn warm_client(config: &RunConfig) -> Result<(), CommandError> {
+    if config.http_client.is_none() && config.openai_client.is_none() {
+        try_http_client()?;
+    }
+
+    config.client().models().list().await?;
+
+    Ok(())
+}
+
+/// Returns the `cl100k_base` tokenizer, building it the first time it is needed.
+///
+/// # Panics
+/// Panics if the tokenizer can't be built. Call `init` first to get an error instead.
+pub fn tokenizer() -> &'static CoreBPE {
+    try_tokenizer().expect("Failed to build the cl100k_base tokenizer")
+}
+
+/// Counts the tokens in `text` with the `cl100k_base` tokenizer.
+pub fn count_tokens(text: &str) -> usize {
+    tokenizer().encode_ordinary(text).len()
+}
+
+/// Prompts shorter than this many words are estimated from their word count instead of being
+/// tokenized, which is close enough for them and saves the tokenizer.
+const ESTIMATED_PROMPT_WORDS: usize = 200;
+
+/// About three words to four tokens, for English text.
+const WORDS_PER_TOKEN: f64 = 0.75;
+
+/// Estimates the tokens in the text of a message the way generated `build_request` functions
+/// check a prompt against the request token limit.
+pub fn estimate_prompt_tokens(text: &str) -> usize {
+    let word_count = text.split_whitespace().count();
+    if word_count < ESTIMATED_PROMPT_WORDS {
+        (word_count as f64 / WORDS_PER_TOKEN).round() as usize
+    } else {
+        count_tokens(text)
+    }
+}
+
+/// Returns the HTTP client every request is sent with, so its connection pool is shared
+/// between runs.
+///
+/// # Panics
+/// Panics if the client can't be built. Call `init` first to get an error instead.
+pub fn http_client() -> &'static reqwest::Client {
+    try_http_client().expect("Failed to build the HTTP client")
+}
+
+fn try_tokenizer() -> Result<&'static CoreBPE, CommandError> {
+    if let Some(tokenizer) = TOKENIZER.get() {
+        return Ok(tokenizer);
+    }
+
+    let tokenizer = tiktoken_rs::cl100k_base().map_err(|e| {
+        CommandError::new(&format!("Failed to build the cl100k_base tokenizer: {}", e))
+    })?;
+
+    Ok(TOKENIZER.get_or_init(|| tokenizer))
+}
+
+fn try_http_client() -> Result<&'static reqwest::Client, CommandError> {
+    if let Some(http_client) = HTTP_CLIENT.get() {
+        return Ok(http_client);
+    }
+
+    let http_client = reqwest::Client::builder()
+        .build()
+        .map_err(|e| CommandError::new(&format!("Failed to build the HTTP client: {}", e)))?;
+
+    Ok(HTTP_CLIENT.get_or_init(|| http_client))
+}
@@ -0,0 +1,53 @@
+use crate::{count_tokens, load_embedding_archive, score_functions, RunConfig};
+use std::path::Path;
+
+/// One function in an embedding archive, as `inspect_embedding_archive` reports it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchivedFunction {
+    pub name: String,
+    pub description: String,
+    /// The length of the embedding vector.
+    pub dimensions: usize,
+    /// The tokens in the description, counted with `cl100k_base`.
+    pub description_tokens: usize,
+}
+
+/// Lists the functions in the archive at `embed_path`, in the order they were archived.
+///
+/// # Errors
+/// Returns an error if the archive can't be read or fails validation.
+pub fn inspect_embedding_archive(
+    embed_path: &Path,
+) -> Result<Vec<ArchivedFunction>, Box<dyn std::error::Error + Send + Sync>> {
+    let archive = load_embedding_archive(embed_path)?;
+
+    Ok(archive
+        .embeddings()
+        .iter()
+        .map(|embedding| ArchivedFunction {
+            name: embedding.name.to_string(),
+            description: embedding.description.to_string(),
+            dimensions: embedding.embedding.len(),
+            description_tokens: count_tokens(embedding.description.as_str()),
+        })
+        .collect())
+}
+
+/// Embeds `prompt` with `embed_model` and scores every function in the archive at
+/// `embed_path` against it, most similar first. This is the ranking `function_filtering`
+/// would do for the prompt, with the similarities that led to it. The prompt is embedded with
+/// the client of `config`.
+///
+/// # Errors
+/// Returns an error if the archive can't be read or the embedding request fails.
+pub async fn query_embedding_archive(
+    embed_path: &Path,
+    prompt: &str,
+    embed_model: &str,
+    config: &RunConfig,
+) -> Result<Vec<(String, f32)>, Box<dyn std::error::Error + Send + Sync>> {
+    let archive = load_embedding_archive(embed_path)?;
+    let prompt_embedding = config.embedding(&prompt.to_string(), embed_model).await?;
+
+    Ok(score_functions(archive.embeddings(), &prompt_embedding))
+}
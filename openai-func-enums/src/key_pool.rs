@@ -0,0 +1,276 @@
+use crate::CommandError;
+use async_openai::error::OpenAIError;
+use async_openai::types::CompletionUsage;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+/// How an `ApiKeyPool` picks the key for the next request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyRotation {
+    /// Take turns.
+    #[default]
+    RoundRobin,
+    /// Prefer keys that were never rate limited, then the one whose last rate limit is the
+    /// longest ago. Ties go to the key that was used least recently.
+    LeastRecentlyThrottled,
+}
+
+/// What one key of an `ApiKeyPool` has been used for.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyUsage {
+    /// The last four characters of the key, enough to tell keys apart in logs.
+    pub key_hint: String,
+    pub requests: u64,
+    /// How many requests failed because the key was rate limited.
+    pub throttled: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+struct PooledKey {
+    key: String,
+    usage: KeyUsage,
+    last_used: Option<Instant>,
+    last_throttled: Option<Instant>,
+}
+
+/// Several API keys that requests are spread across, for workloads where the tokens per
+/// minute of a single key are the bottleneck.
+///
+/// Put it on `RunConfig::api_keys` and every request picks its key from the pool. Clones
+/// share their keys and usage, so one pool can serve every concurrent run.
+///
+/// A key is known to be throttled once a request fails with a rate limit error, and the
+/// retry of that request is sent with the next key.
+#[derive(Clone)]
+pub struct ApiKeyPool {
+    keys: Arc<Mutex<Vec<PooledKey>>>,
+    next: Arc<Mutex<usize>>,
+    pub rotation: KeyRotation,
+}
+
+impl ApiKeyPool {
+    /// # Errors
+    /// Returns a `CommandError` if `keys` is empty.
+    pub fn new<I, K>(keys: I) -> Result<ApiKeyPool, CommandError>
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        let keys: Vec<PooledKey> = keys
+            .into_iter()
+            .map(|key| {
+                let key = key.into();
+                PooledKey {
+                    usage: KeyUsage {
+                        key_hint: key_hint(&key),
+                        ..Default::default()
+                    },
+                    key,
+                    last_used: None,
+                    last_throttled: None,
+                }
+            })
+            .collect();
+
+        if keys.is_empty() {
+            return Err(CommandError::new("An API key pool needs at least one key"));
+        }
+
+        Ok(ApiKeyPool {
+            keys: Arc::new(Mutex::new(keys)),
+            next: Arc::new(Mutex::new(0)),
+            rotation: KeyRotation::default(),
+        })
+    }
+
+    /// Reads the keys from `OPENAI_API_KEYS`, separated by commas.
+    pub fn from_env() -> Result<ApiKeyPool, CommandError> {
+        let keys = std::env::var("OPENAI_API_KEYS")
+            .map_err(|_| CommandError::new("OPENAI_API_KEYS is not set"))?;
+
+        ApiKeyPool::new(keys.split(',').map(str::trim).filter(|key| !key.is_empty()))
+    }
+
+    pub fn with_rotation(mut self, rotation: KeyRotation) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys().is_empty()
+    }
+
+    /// Picks the key for the next request and counts the request against it.
+    pub fn next_key(&self) -> String {
+        let mut keys = self.keys();
+
+        let index = match self.rotation {
+            KeyRotation::RoundRobin => {
+                let mut next = self
+                    .next
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                let index = *next % keys.len();
+                *next = index + 1;
+                index
+            }
+            KeyRotation::LeastRecentlyThrottled => keys
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, key)| (key.last_throttled, key.last_used))
+                .map(|(index, _)| index)
+                .unwrap_or(0),
+        };
+
+        let key = &mut keys[index];
+        key.usage.requests += 1;
+        key.last_used = Some(Instant::now());
+        key.key.clone()
+    }
+
+    /// Records the tokens a request sent with `key` used.
+    pub fn record_usage(&self, key: &str, usage: &CompletionUsage) {
+        if let Some(key) = self.keys().iter_mut().find(|pooled| pooled.key == key) {
+            key.usage.prompt_tokens += usage.prompt_tokens as u64;
+            key.usage.completion_tokens += usage.completion_tokens as u64;
+        }
+    }
+
+    /// Records that a request sent with `key` was rate limited.
+    pub fn record_throttled(&self, key: &str) {
+        if let Some(key) = self.keys().iter_mut().find(|pooled| pooled.key == key) {
+            key.usage.throttled += 1;
+            key.last_throttled = Some(Instant::now());
+        }
+    }
+
+    /// Records the outcome of a request sent with `key`.
+    pub fn record_error(&self, key: &str, error: &OpenAIError) {
+        if is_rate_limit_error(error) {
+            self.record_throttled(key);
+        }
+    }
+
+    /// The usage of every key, in the order the keys were given.
+    pub fn usage(&self) -> Vec<KeyUsage> {
+        self.keys().iter().map(|key| key.usage.clone()).collect()
+    }
+
+    fn keys(&self) -> MutexGuard<'_, Vec<PooledKey>> {
+        self.keys
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// Keys are secrets, so only their hints are printed.
+impl fmt::Debug for ApiKeyPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiKeyPool")
+            .field("keys", &self.usage())
+            .field("rotation", &self.rotation)
+            .finish()
+    }
+}
+
+/// Returns `true` if the API turned a request down because of a rate limit.
+pub fn is_rate_limit_error(error: &OpenAIError) -> bool {
+    match error {
+        OpenAIError::ApiError(api_error) => {
+            api_error.code.as_deref() == Some("rate_limit_exceeded")
+                || matches!(api_error.r#type.as_deref(), Some("requests" | "tokens"))
+        }
+        _ => false,
+    }
+}
+
+fn key_hint(key: &str) -> String {
+    let start = key
+        .char_indices()
+        .rev()
+        .nth(3)
+        .map(|(index, _)| index)
+        .unwrap_or(0);
+    format!("...{}", &key[start..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(prompt_tokens: u32, completion_tokens: u32) -> CompletionUsage {
+        CompletionUsage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            prompt_tokens_details: None,
+            completion_tokens_details: None,
+        }
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let pool = ApiKeyPool::new(["sk-a", "sk-b", "sk-c"]).unwrap();
+        let keys: Vec<String> = (0..4).map(|_| pool.next_key()).collect();
+
+        assert_eq!(keys, ["sk-a", "sk-b", "sk-c", "sk-a"]);
+        // Clones share the rotation.
+        assert_eq!(pool.clone().next_key(), "sk-b");
+    }
+
+    #[test]
+    fn throttled_keys_wait_until_the_others_were_throttled_too() {
+        let pool = ApiKeyPool::new(["sk-a", "sk-b", "sk-c"])
+            .unwrap()
+            .with_rotation(KeyRotation::LeastRecentlyThrottled);
+
+        pool.record_throttled("sk-a");
+        assert_eq!(pool.next_key(), "sk-b");
+        assert_eq!(pool.next_key(), "sk-c");
+        assert_eq!(pool.next_key(), "sk-b");
+
+        pool.record_throttled("sk-b");
+        pool.record_throttled("sk-c");
+        assert_eq!(pool.next_key(), "sk-a");
+    }
+
+    #[test]
+    fn usage_is_counted_per_key() {
+        let pool = ApiKeyPool::new(["sk-first-key", "sk-other-key"]).unwrap();
+        let key = pool.next_key();
+        pool.record_usage(&key, &usage(100, 20));
+        pool.record_error(
+            &key,
+            &OpenAIError::ApiError(async_openai::error::ApiError {
+                message: "Rate limit reached".to_string(),
+                r#type: Some("tokens".to_string()),
+                param: None,
+                code: None,
+            }),
+        );
+
+        let usage = pool.usage();
+        assert_eq!(usage[0].key_hint, "...-key");
+        assert_eq!(
+            (
+                usage[0].requests,
+                usage[0].throttled,
+                usage[0].prompt_tokens
+            ),
+            (1, 1, 100)
+        );
+        assert_eq!(usage[1].requests, 0);
+        assert!(!format!("{:?}", pool).contains("sk-first"));
+    }
+
+    #[test]
+    fn a_pool_needs_a_key() {
+        assert!(ApiKeyPool::new(Vec::<String>::new()).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use serde::de::{DeserializeOwned, Error};
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static LOG_NUMERIC_COERCIONS: AtomicBool = AtomicBool::new(false);
+
+/// Turns logging every number that had to be parsed out of a string on or off. It is off by
+/// default. The lines go to the `log` crate at the debug level, under this module's target.
+pub fn log_numeric_coercions(enabled: bool) {
+    LOG_NUMERIC_COERCIONS.store(enabled, Ordering::Relaxed);
+}
+
+/// Deserializes a number that may have been sent as a string.
+///
+/// Models regularly send `"a": "8"` instead of `"a": 8`, which a plain `f64` or `i64` field
+/// rejects. The structs generated by `ToolSet` use this for their numeric fields, so both
+/// forms are accepted. It can also be used on your own types with
+/// `#[serde(deserialize_with = "openai_func_enums::deserialize_lenient_number")]`.
+pub fn deserialize_lenient_number<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned + FromStr,
+    T::Err: Display,
+{
+    match Value::deserialize(deserializer)? {
+        Value::String(text) => {
+            let number = text.trim().parse::<T>().map_err(|e| {
+                D::Error::custom(format!("Could not parse \"{}\" as a number: {}", text, e))
+            })?;
+
+            if LOG_NUMERIC_COERCIONS.load(Ordering::Relaxed) {
+                log::debug!("Parsed number from string argument \"{}\"", text);
+            }
+
+            Ok(number)
+        }
+        value => serde_json::from_value(value).map_err(D::Error::custom),
+    }
+}
+
+/// Same as `deserialize_lenient_number`, for optional numbers. `null` becomes `None`.
+pub fn deserialize_lenient_optional_number<'de, D, T>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned + FromStr,
+    T::Err: Display,
+{
+    match Value::deserialize(deserializer)? {
+        Value::Null => Ok(None),
+        value => deserialize_lenient_number(value)
+            .map(Some)
+            .map_err(D::Error::custom),
+    }
+}
+
+/// The variant of a `ToolArg` enum that `name` refers to. Models often send `"fahrenheit"`
+/// for `Fahrenheit`, so after the exact names and the aliases declared with
+/// `#[func_enums(alias = "...")]`, both are matched ignoring case. `aliases` pairs each alias
+/// with its variant.
+pub fn resolve_variant_name(
+    name: &str,
+    variants: &[&'static str],
+    aliases: &[(&'static str, &'static str)],
+) -> Option<&'static str> {
+    let name = name.trim();
+    variants
+        .iter()
+        .copied()
+        .find(|variant| *variant == name)
+        .or_else(|| {
+            aliases
+                .iter()
+                .find(|(alias, _)| *alias == name)
+                .map(|(_, variant)| *variant)
+        })
+        .or_else(|| {
+            variants
+                .iter()
+                .copied()
+                .find(|variant| variant.eq_ignore_ascii_case(name))
+        })
+        .or_else(|| {
+            aliases
+                .iter()
+                .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+                .map(|(_, variant)| *variant)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Debug, Deserialize)]
+    struct Arguments {
+        #[serde(deserialize_with = "deserialize_lenient_number")]
+        count: u32,
+        #[serde(default, deserialize_with = "deserialize_lenient_optional_number")]
+        degrees: Option<f64>,
+    }
+
+    fn parse(arguments: Value) -> Result<Arguments, serde_json::Error> {
+        serde_json::from_value(arguments)
+    }
+
+    #[test]
+    fn numbers_sent_as_strings_are_parsed() {
+        let arguments = parse(json!({ "count": "8", "degrees": " 21.5 " })).unwrap();
+
+        assert_eq!(arguments.count, 8);
+        assert_eq!(arguments.degrees, Some(21.5));
+        assert_eq!(parse(json!({ "count": 8 })).unwrap().count, 8);
+    }
+
+    #[test]
+    fn null_is_none() {
+        let arguments = parse(json!({ "count": 1, "degrees": null })).unwrap();
+
+        assert_eq!(arguments.degrees, None);
+    }
+
+    #[test]
+    fn strings_that_are_not_numbers_fail() {
+        let error = parse(json!({ "count": "eight" })).unwrap_err();
+
+        assert!(error.to_string().contains("\"eight\""), "{}", error);
+        assert!(parse(json!({ "count": "-1" })).is_err());
+    }
+
+    #[test]
+    fn exact_names_come_before_aliases_and_case() {
+        let variants = ["Celsius", "Fahrenheit", "celsius_legacy"];
+        let aliases = [
+            ("C", "Celsius"),
+            ("f", "Fahrenheit"),
+            ("Celsius_Legacy", "Celsius"),
+        ];
+
+        assert_eq!(
+            resolve_variant_name("Celsius", &variants, &aliases),
+            Some("Celsius")
+        );
+        assert_eq!(
+            resolve_variant_name(" C ", &variants, &aliases),
+            Some("Celsius")
+        );
+        assert_eq!(
+            resolve_variant_name("fahrenheit", &variants, &aliases),
+            Some("Fahrenheit")
+        );
+        // The alias matches exactly, before the variant matches ignoring case.
+        assert_eq!(
+            resolve_variant_name("Celsius_Legacy", &variants, &aliases),
+            Some("Celsius")
+        );
+        assert_eq!(
+            resolve_variant_name("CELSIUS_LEGACY", &variants, &aliases),
+            Some("celsius_legacy")
+        );
+        assert_eq!(
+            resolve_variant_name("F", &variants, &aliases),
+            Some("Fahrenheit")
+        );
+        assert_eq!(resolve_variant_name("Kelvin", &variants, &aliases), None);
+    }
+}
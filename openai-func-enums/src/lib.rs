@@ -1,7 +1,16 @@
+#[doc(hidden)]
+pub use ::async_openai;
+/// The async-trait crate, which the `ToolSet` and `Tool` impls the macros generate are
+/// written with, so that crates using the macros don't need it as a dependency.
+pub use ::async_trait;
+#[doc(hidden)]
+pub use ::serde;
+#[doc(hidden)]
+pub use ::serde_json;
 use async_openai::error::OpenAIError;
 use async_openai::types::{
-    ChatCompletionTool, ChatCompletionToolArgs, ChatCompletionToolType, FunctionObject,
-    FunctionObjectArgs,
+    ChatCompletionTool, ChatCompletionToolArgs, ChatCompletionToolType,
+    CreateChatCompletionRequest, FunctionObject, FunctionObjectArgs,
 };
 use async_trait::async_trait;
 pub use openai_func_embeddings::*;
@@ -12,6 +21,100 @@ use std::fmt::{self, Debug};
 use std::sync::Arc;
 use tokio::sync::mpsc;
 
+mod anthropic;
+mod azure;
+mod budget;
+mod calibration;
+mod cancel;
+mod cassette;
+mod config;
+mod constraints;
+mod conversation;
+mod cost;
+mod eval;
+mod events;
+mod few_shot;
+mod final_answer;
+mod fine_tune;
+#[cfg(feature = "genai")]
+mod genai_provider;
+mod init;
+mod inspect;
+mod key_pool;
+mod lenient;
+mod locale;
+mod models;
+mod plan;
+mod provider;
+mod rate_limit;
+mod result;
+mod retry;
+mod returns;
+mod sandbox;
+mod sanitize;
+mod scheduler;
+mod scopes;
+mod shorten;
+mod state;
+mod steps;
+mod stream;
+mod structured;
+mod template;
+mod timeout;
+mod tool;
+mod usage;
+mod verifier;
+
+pub use anthropic::*;
+pub use azure::*;
+pub use budget::*;
+pub use calibration::*;
+pub use cancel::*;
+pub use cassette::*;
+pub use config::*;
+pub use constraints::*;
+pub use conversation::*;
+pub use cost::*;
+pub use eval::*;
+pub use events::*;
+pub use few_shot::*;
+pub use final_answer::*;
+pub use fine_tune::*;
+/// The genai crate, for building the client `GenaiProvider` sends requests with.
+#[cfg(feature = "genai")]
+pub use genai;
+#[cfg(feature = "genai")]
+pub use genai_provider::*;
+pub use init::*;
+pub use inspect::*;
+pub use key_pool::*;
+pub use lenient::*;
+pub use locale::*;
+pub use models::*;
+pub use plan::*;
+pub use provider::*;
+pub use rate_limit::*;
+pub use result::*;
+pub use retry::*;
+pub use returns::*;
+pub use sandbox::*;
+pub use sanitize::*;
+pub use scheduler::*;
+pub use scopes::*;
+pub use shorten::*;
+pub use state::*;
+pub use steps::*;
+pub use stream::*;
+pub use structured::*;
+pub use template::*;
+pub use timeout::*;
+pub use tool::*;
+pub use usage::*;
+pub use verifier::*;
+
+/// The token a run is cancelled with, see `RunConfig::cancellation_token`.
+pub use tokio_util::sync::CancellationToken;
+
 /// A trait to provide a descriptor for an enumeration.
 /// This includes the name of the enum and the count of tokens in its name.
 pub trait EnumDescriptor {
@@ -24,9 +127,53 @@ pub trait EnumDescriptor {
     fn name_with_token_count() -> &'static (&'static str, usize);
 
     fn arg_description_with_token_count() -> &'static (&'static str, usize);
+
+    /// The schema of the enum as an argument and its token count, for enums that aren't a plain
+    /// list of variant names, like a `ToolArg` enum whose variants have fields. `None` for the
+    /// rest.
+    fn arg_schema_with_token_count() -> Option<&'static (Value, usize)> {
+        None
+    }
+
+    /// Whether the argument is named after its field instead of after the type, like a nested
+    /// `ToolArgs` struct is.
+    fn named_after_field() -> bool {
+        false
+    }
+}
+
+/// Implemented by `#[derive(ToolSet)]` for the enum, so that generic code like
+/// `Conversation::run_tools` can build and run requests with its generated `CommandsGPT`.
+#[async_trait]
+pub trait ToolSet {
+    /// `CommandsGPT::build_request` with the default limits and system message.
+    fn build_request(
+        prompt: &str,
+        model_name: &str,
+        allowed_functions: Option<Vec<String>>,
+        required_functions: Option<Vec<String>>,
+        config: &RunConfig,
+    ) -> Result<CreateChatCompletionRequest, Box<dyn Error + Send + Sync + 'static>>;
+
+    /// The request token limit `build_request` enforces for `model_name`.
+    fn request_token_limit(model_name: &str) -> usize;
+
+    /// `CommandsGPT::run_request` as a run of its own.
+    async fn run_request(
+        prompt: &str,
+        request: CreateChatCompletionRequest,
+        execution_strategy: ToolCallExecutionStrategy,
+        logger: Arc<Logger>,
+        config: &RunConfig,
+    ) -> Result<RunOutput, Box<dyn Error + Send + Sync + 'static>>;
 }
 
-pub trait ToolSet {}
+/// Implemented by `#[derive(ToolSet)]` for the struct generated for each variant, so that a
+/// function can be named by its type, like in `CommandsGPT::run_forcing::<GetCurrentWeather>`.
+pub trait ToolSetFunction {
+    /// The name the function is sent with.
+    const NAME: &'static str;
+}
 
 /// A trait to provide descriptors for the variants of an enumeration.
 /// This includes the names of the variants and the count of tokens in their names.
@@ -49,10 +196,15 @@ pub trait VariantDescriptors {
     fn variant_name_with_token_count(&self) -> (&'static str, usize);
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, serde::Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
 pub enum ToolCallExecutionStrategy {
+    /// Calls to functions marked `#[func_enums(cpu_bound)]` run on threads of the runtime's
+    /// blocking pool, the others the way `Async` runs them.
     Parallel,
+    /// Calls run concurrently as tasks on the runtime.
     Async,
+    /// Calls run one after the other, in the order they came back.
     Synchronous,
 }
 
@@ -99,20 +251,21 @@ pub async fn logger_task(mut receiver: mpsc::Receiver<String>) {
     }
 }
 
-// There is a better way than to keep adding return types.
-// Trying to determine which road to go down on other issues first.
+/// Runs a command the model called. The output's `next_arguments` are passed as `arguments`
+/// to the next command when the tool is one step of several.
+///
+/// `state` is what the run was given as `RunConfig::state`, as the type `S` the `ToolSet`
+/// declares with `#[func_enums(state = "AppState")]`. Enums that don't declare one get `()`.
 #[async_trait]
-pub trait RunCommand: Sync + Send {
+pub trait RunCommand<S: Send + Sync + 'static = ()>: Sync + Send {
     async fn run(
         &self,
         execution_strategy: ToolCallExecutionStrategy,
         arguments: Option<Vec<String>>,
         logger: Arc<Logger>,
         system_message: Option<(String, usize)>,
-    ) -> Result<
-        (Option<String>, Option<Vec<String>>),
-        Box<dyn std::error::Error + Send + Sync + 'static>,
-    >;
+        state: &S,
+    ) -> Result<ToolOutput, Box<dyn std::error::Error + Send + Sync + 'static>>;
 }
 
 /// A macro to parse a function call into a specified type.
@@ -172,7 +325,8 @@ pub fn get_function_chat_completion_args(
             .map(|s| s.to_string());
 
         let name = value.get("name").unwrap().as_str().unwrap().to_string();
-        let chat_completion_args = match description {
+        let strict = value.get("strict").and_then(Value::as_bool);
+        let mut chat_completion_args = match description {
             Some(desc) => FunctionObjectArgs::default()
                 .name(name)
                 .description(desc)
@@ -183,6 +337,7 @@ pub fn get_function_chat_completion_args(
                 .parameters(parameters)
                 .build()?,
         };
+        chat_completion_args.strict = strict;
         chat_completion_functions_vec.push(chat_completion_args);
     }
 
@@ -204,60 +359,15 @@ pub fn get_tool_chat_completion_args(
     tool_func: impl Fn() -> (Value, usize),
 ) -> Result<(Vec<ChatCompletionTool>, usize), OpenAIError> {
     let (tool_json, total_tokens) = tool_func();
-
-    let mut chat_completion_tool_vec = Vec::new();
-
-    let values = match tool_json {
-        Value::Object(_) => vec![tool_json],
-        Value::Array(arr) => arr,
-        _ => {
-            return Err(OpenAIError::InvalidArgument(String::from(
-                "Something went wrong parsing the json",
-            )))
-        }
-    };
-
-    for value in values {
-        let parameters = value.get("parameters").cloned();
-
-        let description = value
-            .get("description")
-            .and_then(|v| v.as_str())
-            .map(|s| s.to_string());
-
-        let name = value.get("name").unwrap().as_str().unwrap().to_string();
-
-        if name != "GPT" {
-            let chat_completion_functions_args = match description {
-                Some(desc) => FunctionObjectArgs::default()
-                    .name(name)
-                    .description(desc)
-                    .parameters(parameters)
-                    .build()?,
-                None => FunctionObjectArgs::default()
-                    .name(name)
-                    .parameters(parameters)
-                    .build()?,
-            };
-
-            let chat_completion_tool = ChatCompletionToolArgs::default()
-                .r#type(ChatCompletionToolType::Function)
-                .function(chat_completion_functions_args)
-                .build()?;
-
-            chat_completion_tool_vec.push(chat_completion_tool);
-        }
-    }
-
-    Ok((chat_completion_tool_vec, total_tokens))
+    chat_completion_tools(tool_json, total_tokens)
 }
 
-/// This function will get called if an "allowed_functions" argument is passed to the
-/// run function. If it is passed, then the presense or absence of the function_filtering
-/// feature flag will dictate what happens. If function_filtering is on, then the required
-/// functions (if some) will get included, then your ranked functions will get added until the
-/// token limit is reached. Without function_filtering feature enabled, all functions listed in
-/// allowed_func_names and required_func_names will get sent.
+// This function will get called if an "allowed_functions" argument is passed to the
+// run function. If it is passed, then the presense or absence of the function_filtering
+// feature flag will dictate what happens. If function_filtering is on, then the required
+// functions (if some) will get included, then your ranked functions will get added until the
+// token limit is reached. Without function_filtering feature enabled, all functions listed in
+// allowed_func_names and required_func_names will get sent.
 
 /// Performs selective inclusion of tools based on the provided `allowed_func_names` and the state
 /// of the `function_filtering` feature flag. When `function_filtering` is enabled and `required_func_names`
@@ -281,7 +391,15 @@ pub fn get_tools_limited(
     required_func_names: Option<Vec<String>>,
 ) -> Result<(Vec<ChatCompletionTool>, usize), OpenAIError> {
     let (tool_json, total_tokens) = tool_func(allowed_func_names, required_func_names);
+    chat_completion_tools(tool_json, total_tokens)
+}
 
+/// Turns the function definitions built by `ToolSet` into tools, moving the parameters out of
+/// the JSON instead of copying them.
+fn chat_completion_tools(
+    tool_json: Value,
+    total_tokens: usize,
+) -> Result<(Vec<ChatCompletionTool>, usize), OpenAIError> {
     let mut chat_completion_tool_vec = Vec::new();
 
     let values = match tool_json {
@@ -294,8 +412,8 @@ pub fn get_tools_limited(
         }
     };
 
-    for value in values {
-        let parameters = value.get("parameters").cloned();
+    for mut value in values {
+        let parameters = value.get_mut("parameters").map(Value::take);
 
         let description = value
             .get("description")
@@ -303,28 +421,190 @@ pub fn get_tools_limited(
             .map(|s| s.to_string());
 
         let name = value.get("name").unwrap().as_str().unwrap().to_string();
+        let strict = value.get("strict").and_then(Value::as_bool);
 
-        if name != "GPT" {
-            let chat_completion_functions_args = match description {
-                Some(desc) => FunctionObjectArgs::default()
-                    .name(name)
-                    .description(desc)
-                    .parameters(parameters)
-                    .build()?,
-                None => FunctionObjectArgs::default()
-                    .name(name)
-                    .parameters(parameters)
-                    .build()?,
-            };
-
-            let chat_completion_tool = ChatCompletionToolArgs::default()
-                .r#type(ChatCompletionToolType::Function)
-                .function(chat_completion_functions_args)
-                .build()?;
-
-            chat_completion_tool_vec.push(chat_completion_tool);
-        }
+        let mut chat_completion_functions_args = match description {
+            Some(desc) => FunctionObjectArgs::default()
+                .name(name)
+                .description(desc)
+                .parameters(parameters)
+                .build()?,
+            None => FunctionObjectArgs::default()
+                .name(name)
+                .parameters(parameters)
+                .build()?,
+        };
+        chat_completion_functions_args.strict = strict;
+
+        let chat_completion_tool = ChatCompletionToolArgs::default()
+            .r#type(ChatCompletionToolType::Function)
+            .function(chat_completion_functions_args)
+            .build()?;
+
+        chat_completion_tool_vec.push(chat_completion_tool);
     }
 
     Ok((chat_completion_tool_vec, total_tokens))
 }
+
+/// Sets the description of a single argument, as generated by `generate_value_arg_info!` or
+/// `generate_enum_info!`. This is how generated code applies `#[arg(description = "...")]`.
+///
+/// # Returns
+/// `true` if the argument already had a description that was replaced.
+pub fn set_arg_description(arg_json: &mut Value, description: &str) -> bool {
+    set_arg_property(
+        arg_json,
+        "description",
+        Value::String(description.to_string()),
+    )
+}
+
+/// Sets a schema keyword, like `examples`, on a single argument as generated by
+/// `generate_value_arg_info!` or `generate_enum_info!`.
+///
+/// # Returns
+/// `true` if the argument already had a value for `key` that was replaced.
+pub fn set_arg_property(arg_json: &mut Value, key: &str, value: Value) -> bool {
+    let property = arg_json
+        .as_object_mut()
+        .and_then(|arg| arg.values_mut().next())
+        .and_then(|property| property.as_object_mut());
+
+    match property {
+        Some(property) => property.insert(key.to_string(), value).is_some(),
+        None => false,
+    }
+}
+
+/// Renames a single argument, as generated by `generate_value_arg_info!` or
+/// `generate_enum_info!`.
+///
+/// # Returns
+/// The name it had before.
+pub fn rename_arg(arg_json: &mut Value, name: &str) -> Option<String> {
+    let arg = arg_json.as_object_mut()?;
+    let old_name = arg.keys().next()?.clone();
+    let property = arg.remove(&old_name)?;
+    arg.insert(name.to_string(), property);
+    Some(old_name)
+}
+
+/// Makes a single argument, as generated by `generate_value_arg_info!` or
+/// `generate_enum_info!`, accept `null` as well. The type becomes `["T", "null"]` and `null`
+/// is added to the allowed values of enums, which is the form OpenAI's strict mode requires for
+/// optional arguments.
+pub fn make_arg_nullable(arg_json: &mut Value) {
+    let Some(property) = arg_json
+        .as_object_mut()
+        .and_then(|arg| arg.values_mut().next())
+        .and_then(|property| property.as_object_mut())
+    else {
+        return;
+    };
+
+    make_property_nullable(property);
+}
+
+fn make_property_nullable(property: &mut serde_json::Map<String, Value>) {
+    match property.get_mut("type") {
+        Some(Value::Array(types)) if !types.iter().any(|t| t == "null") => {
+            types.push(Value::from("null"));
+        }
+        Some(Value::Array(_)) => {}
+        Some(arg_type) => {
+            let arg_type = arg_type.take();
+            property.insert(
+                String::from("type"),
+                Value::Array(vec![arg_type, Value::from("null")]),
+            );
+        }
+        None => {}
+    }
+
+    if let Some(Value::Array(variants)) = property.get_mut("enum") {
+        if !variants.contains(&Value::Null) {
+            variants.push(Value::Null);
+        }
+    }
+
+    if let Some(Value::Array(schemas)) = property.get_mut("anyOf") {
+        let null = serde_json::json!({ "type": "null" });
+        if !schemas.contains(&null) {
+            schemas.push(null);
+        }
+    }
+}
+
+/// Fills in the arguments the model left out, or sent as null, with the `default` their
+/// property declares in `parameters`, in nested objects too. `#[tool]` functions call this
+/// before deserializing their arguments, since the `ToolArgs` struct's own `Deserialize`
+/// doesn't know about `#[func_enums(default = ...)]`.
+pub fn apply_argument_defaults(parameters: &Value, arguments: &mut Value) {
+    let (Some(properties), Some(arguments)) = (
+        parameters.get("properties").and_then(Value::as_object),
+        arguments.as_object_mut(),
+    ) else {
+        return;
+    };
+
+    for (name, property) in properties {
+        match (arguments.get_mut(name), property.get("default")) {
+            (None | Some(Value::Null), Some(default)) => {
+                arguments.insert(name.clone(), default.clone());
+            }
+            (Some(argument), _) => apply_argument_defaults(property, argument),
+            _ => {}
+        }
+    }
+}
+
+/// Makes a parameters schema, and every object schema nested in it, follow the rules of
+/// OpenAI's strict mode: no properties other than the listed ones, and all of them required.
+/// Properties that weren't required are made nullable instead, the way `make_arg_nullable`
+/// does for optional arguments.
+pub fn make_schema_strict(schema: &mut Value) {
+    let object = match schema {
+        Value::Object(object) => object,
+        Value::Array(schemas) => {
+            schemas.iter_mut().for_each(make_schema_strict);
+            return;
+        }
+        _ => return,
+    };
+
+    let required: Vec<Value> = match object.get("required") {
+        Some(Value::Array(required)) => required.clone(),
+        _ => Vec::new(),
+    };
+
+    if let Some(Value::Object(properties)) = object.get_mut("properties") {
+        for (name, property) in properties.iter_mut() {
+            // A property with a default is filled in when it's left out, so the model has to
+            // send a value rather than a null.
+            if !required.iter().any(|required| required == name) {
+                if let Some(property) = property.as_object_mut() {
+                    if !property.contains_key("default") {
+                        make_property_nullable(property);
+                    }
+                }
+            }
+            make_schema_strict(property);
+        }
+
+        let names: Vec<Value> = properties.keys().cloned().map(Value::from).collect();
+        object.insert("required".to_string(), Value::Array(names));
+        object.insert("additionalProperties".to_string(), Value::Bool(false));
+    }
+
+    for key in ["items", "anyOf", "oneOf", "allOf"] {
+        if let Some(nested) = object.get_mut(key) {
+            make_schema_strict(nested);
+        }
+    }
+    for key in ["$defs", "definitions"] {
+        if let Some(Value::Object(definitions)) = object.get_mut(key) {
+            definitions.values_mut().for_each(make_schema_strict);
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use crate::{count_tokens, CommandError, DescriptionManifest, FunctionDescription};
+use async_openai::types::ChatCompletionTool;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Descriptions of functions and their arguments in another language, read from a JSON file:
+///
+/// ```json
+/// {
+///   "locale": "de",
+///   "functions": {
+///     "GetCurrentWeather": {
+///       "description": "Ruft das aktuelle Wetter für einen Ort ab.",
+///       "arguments": { "location": "Die Stadt, für die das Wetter abgefragt wird." }
+///     }
+///   }
+/// }
+/// ```
+///
+/// Put it on `RunConfig::descriptions` to send the translations instead of the doc comments,
+/// and ranking works best with an archive built from the same translations, see
+/// `localize_manifest`. Functions and arguments the catalog doesn't mention keep their
+/// original descriptions.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DescriptionCatalog {
+    pub locale: String,
+    pub functions: HashMap<String, LocalizedFunction>,
+}
+
+/// The translations for one function.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LocalizedFunction {
+    pub description: Option<String>,
+    /// Argument descriptions by argument name. Only the top level arguments of a function can
+    /// be translated.
+    pub arguments: HashMap<String, String>,
+}
+
+impl DescriptionCatalog {
+    /// # Errors
+    /// Returns a `CommandError` if the file can't be read or isn't a catalog.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, CommandError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| CommandError::new(&format!("Could not read {}: {}", path.display(), e)))?;
+
+        serde_json::from_str(&contents).map_err(|e| {
+            CommandError::new(&format!(
+                "{} is not a description catalog: {}",
+                path.display(),
+                e
+            ))
+        })
+    }
+
+    /// The translated description of `name`, if there is one.
+    pub fn description(&self, name: &str) -> Option<&str> {
+        self.functions.get(name)?.description.as_deref()
+    }
+
+    /// Swaps the descriptions of the tools of a request for their translations and adjusts
+    /// the token count to match. A return schema appended to a description is kept.
+    pub fn localize_tools(&self, tool_args: &mut (Vec<ChatCompletionTool>, usize)) {
+        let (tools, total_tokens) = tool_args;
+
+        for tool in tools.iter_mut() {
+            let Some(localized) = self.functions.get(&tool.function.name) else {
+                continue;
+            };
+
+            if let Some(description) = &localized.description {
+                let original = tool.function.description.take().unwrap_or_default();
+                let translated = translated_description(&original, description);
+
+                *total_tokens = total_tokens.saturating_sub(count_tokens(&original))
+                    + count_tokens(&translated);
+                tool.function.description = Some(translated);
+            }
+
+            let Some(properties) = tool
+                .function
+                .parameters
+                .as_mut()
+                .and_then(|parameters| parameters.get_mut("properties"))
+                .and_then(|properties| properties.as_object_mut())
+            else {
+                continue;
+            };
+
+            for (argument, description) in &localized.arguments {
+                let Some(property) = properties
+                    .get_mut(argument)
+                    .and_then(|property| property.as_object_mut())
+                else {
+                    continue;
+                };
+
+                let original_tokens = property
+                    .get("description")
+                    .and_then(|description| description.as_str())
+                    .map_or(0, count_tokens);
+                *total_tokens =
+                    total_tokens.saturating_sub(original_tokens) + count_tokens(description);
+                property.insert("description".to_string(), description.clone().into());
+            }
+        }
+    }
+
+    /// The token count `tokens` of `function_json`, a definition as `ToolSet` builds it, would
+    /// have once `localize_tools` swapped its descriptions. `build_request` limits the
+    /// functions it sends by these counts, so that the limit holds for the translations.
+    pub fn localized_tokens(&self, function_json: &Value, tokens: usize) -> usize {
+        let Some(localized) = function_json["name"]
+            .as_str()
+            .and_then(|name| self.functions.get(name))
+        else {
+            return tokens;
+        };
+
+        let mut tokens = tokens;
+        if let Some(description) = &localized.description {
+            let original = function_json["description"].as_str().unwrap_or_default();
+            tokens = tokens.saturating_sub(count_tokens(original))
+                + count_tokens(&translated_description(original, description));
+        }
+        for (argument, description) in &localized.arguments {
+            let property = &function_json["parameters"]["properties"][argument];
+            if !property.is_object() {
+                continue;
+            }
+
+            let original_tokens = property["description"].as_str().map_or(0, count_tokens);
+            tokens = tokens.saturating_sub(original_tokens) + count_tokens(description);
+        }
+        tokens
+    }
+
+    /// A copy of `manifest` with the translated descriptions, to build an archive that ranks
+    /// prompts in this locale.
+    pub fn localize_manifest(&self, manifest: &DescriptionManifest) -> DescriptionManifest {
+        DescriptionManifest {
+            embed_model: manifest.embed_model.clone(),
+            functions: manifest
+                .functions
+                .iter()
+                .map(|function| match self.description(&function.name) {
+                    Some(description) => FunctionDescription::new(&function.name, description),
+                    None => function.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// `translation` with the return schema appended to `original`, if it has one.
+fn translated_description(original: &str, translation: &str) -> String {
+    let mut translated = translation.to_string();
+    if let Some(index) = original.rfind(" Returns: ") {
+        translated.push_str(&original[index..]);
+    }
+    translated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn function_json() -> Value {
+        json!({
+            "name": "GetCurrentWeather",
+            "description": "Gets the weather Returns: {\"type\":\"string\"}",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "location": { "type": "string", "description": "The city" },
+                    "units": { "type": "string" }
+                }
+            }
+        })
+    }
+
+    fn catalog() -> DescriptionCatalog {
+        serde_json::from_value(json!({
+            "locale": "de",
+            "functions": {
+                "GetCurrentWeather": {
+                    "description": "Ruft das Wetter ab",
+                    "arguments": {
+                        "location": "Die Stadt",
+                        "units": "Die Einheit",
+                        "missing": "Gibt es nicht"
+                    }
+                },
+                "ListOrders": { "arguments": {} }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn tools_get_the_translations_and_keep_their_return_schema() {
+        let function = function_json();
+        let tokens = count_tokens(&function.to_string());
+        let tool: ChatCompletionTool =
+            serde_json::from_value(json!({ "type": "function", "function": function })).unwrap();
+        let mut tool_args = (vec![tool], tokens);
+
+        catalog().localize_tools(&mut tool_args);
+
+        let function = &tool_args.0[0].function;
+        assert_eq!(
+            function.description.as_deref(),
+            Some("Ruft das Wetter ab Returns: {\"type\":\"string\"}")
+        );
+        let properties = &function.parameters.as_ref().unwrap()["properties"];
+        assert_eq!(properties["location"]["description"], "Die Stadt");
+        assert_eq!(properties["units"]["description"], "Die Einheit");
+        assert!(properties.get("missing").is_none());
+    }
+
+    #[test]
+    fn localized_tokens_match_the_localized_tools() {
+        let catalog = catalog();
+        let function = function_json();
+        let tool: ChatCompletionTool =
+            serde_json::from_value(json!({ "type": "function", "function": function })).unwrap();
+        let mut tool_args = (vec![tool], 100);
+
+        catalog.localize_tools(&mut tool_args);
+
+        assert_eq!(catalog.localized_tokens(&function, 100), tool_args.1);
+        assert_ne!(tool_args.1, 100);
+        // Functions the catalog doesn't mention keep their count.
+        assert_eq!(
+            catalog.localized_tokens(&json!({ "name": "SetTemperature" }), 42),
+            42
+        );
+    }
+
+    #[test]
+    fn manifests_get_the_translated_descriptions() {
+        let manifest = DescriptionManifest {
+            embed_model: Some("text-embedding-3-small".to_string()),
+            functions: vec![
+                FunctionDescription::new("GetCurrentWeather", "Gets the weather"),
+                FunctionDescription::new("ListOrders", "Lists the orders"),
+            ],
+        };
+
+        let localized = catalog().localize_manifest(&manifest);
+
+        assert_eq!(localized.embed_model, manifest.embed_model);
+        assert_eq!(
+            localized.functions[0],
+            FunctionDescription::new("GetCurrentWeather", "Ruft das Wetter ab")
+        );
+        assert_eq!(localized.functions[1], manifest.functions[1]);
+    }
+}
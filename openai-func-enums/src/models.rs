@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+
+/// The share of a model's context window that is held back when the request token limit is
+/// derived from it, because prompt tokens are estimated rather than counted exactly.
+const SAFETY_MARGIN_PERCENT: usize = 5;
+
+static CUSTOM_MODELS: OnceLock<RwLock<HashMap<String, ModelInfo>>> = OnceLock::new();
+
+/// The token limits of a model.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ModelInfo {
+    /// How many tokens the prompt and the response can add up to.
+    pub context_window: usize,
+    /// The most tokens the model will generate in one response.
+    pub max_output_tokens: usize,
+}
+
+impl ModelInfo {
+    pub const fn new(context_window: usize, max_output_tokens: usize) -> Self {
+        ModelInfo {
+            context_window,
+            max_output_tokens,
+        }
+    }
+
+    /// The request token limit for this model when up to `max_response_tokens` are reserved
+    /// for the response: the context window minus the response minus a safety margin.
+    pub fn request_token_limit(&self, max_response_tokens: usize) -> usize {
+        let margin = self.context_window * SAFETY_MARGIN_PERCENT / 100;
+        self.context_window
+            .saturating_sub(max_response_tokens)
+            .saturating_sub(margin)
+    }
+}
+
+// Matched by prefix, so that dated snapshots like "gpt-4o-2024-08-06" are covered too. The
+// longest matching prefix wins.
+const KNOWN_MODELS: &[(&str, ModelInfo)] = &[
+    ("gpt-5", ModelInfo::new(400_000, 128_000)),
+    ("gpt-4.1", ModelInfo::new(1_047_576, 32_768)),
+    ("gpt-4o", ModelInfo::new(128_000, 16_384)),
+    ("gpt-4-turbo", ModelInfo::new(128_000, 4_096)),
+    ("gpt-4-32k", ModelInfo::new(32_768, 4_096)),
+    ("gpt-4", ModelInfo::new(8_192, 4_096)),
+    ("gpt-3.5-turbo", ModelInfo::new(16_385, 4_096)),
+    ("o1", ModelInfo::new(200_000, 100_000)),
+    ("o1-mini", ModelInfo::new(128_000, 65_536)),
+    ("o1-preview", ModelInfo::new(128_000, 32_768)),
+    ("o3", ModelInfo::new(200_000, 100_000)),
+    ("o4-mini", ModelInfo::new(200_000, 100_000)),
+];
+
+/// Looks up the token limits of a model. Models registered with `register_model` take
+/// precedence over the built in ones.
+///
+/// Names are matched by prefix, and a provider prefix like the one in `"openai/gpt-4o"` is
+/// ignored.
+pub fn model_info(model_name: &str) -> Option<ModelInfo> {
+    let model_name = model_name.rsplit('/').next().unwrap_or(model_name);
+
+    let custom = CUSTOM_MODELS.get().and_then(|models| {
+        let models = models
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        longest_prefix_match(
+            models.iter().map(|(prefix, info)| (prefix.as_str(), *info)),
+            model_name,
+        )
+    });
+
+    custom.or_else(|| {
+        longest_prefix_match(
+            KNOWN_MODELS.iter().map(|(prefix, info)| (*prefix, *info)),
+            model_name,
+        )
+    })
+}
+
+/// Adds a model to the registry, or overrides the limits of a built in one. `name_prefix`
+/// matches every model whose name starts with it.
+pub fn register_model(name_prefix: impl Into<String>, info: ModelInfo) {
+    CUSTOM_MODELS
+        .get_or_init(|| RwLock::new(HashMap::new()))
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(name_prefix.into(), info);
+}
+
+/// The request token limit for `model_name`, see `ModelInfo::request_token_limit`. `None` if
+/// the model is not in the registry.
+pub fn model_request_token_limit(model_name: &str, max_response_tokens: usize) -> Option<usize> {
+    model_info(model_name).map(|info| info.request_token_limit(max_response_tokens))
+}
+
+pub(crate) fn longest_prefix_match<'a, T>(
+    models: impl Iterator<Item = (&'a str, T)>,
+    model_name: &str,
+) -> Option<T> {
+    models
+        .filter(|(prefix, _)| model_name.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, info)| info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_longest_prefix_wins() {
+        assert_eq!(model_info("gpt-4o-2024-08-06"), model_info("gpt-4o"));
+        assert_eq!(model_info("gpt-4-32k-0613").unwrap().context_window, 32_768);
+        assert_eq!(model_info("gpt-4-0613").unwrap().context_window, 8_192);
+        assert_eq!(model_info("o1-mini").unwrap().max_output_tokens, 65_536);
+        assert_eq!(model_info("llama3"), None);
+    }
+
+    #[test]
+    fn provider_prefixes_are_ignored() {
+        assert_eq!(model_info("openai/gpt-4o"), model_info("gpt-4o"));
+    }
+
+    #[test]
+    fn the_limit_leaves_room_for_the_response_and_a_margin() {
+        let info = ModelInfo::new(10_000, 2_000);
+
+        assert_eq!(info.request_token_limit(1_000), 8_500);
+        assert_eq!(info.request_token_limit(20_000), 0);
+        assert_eq!(
+            model_request_token_limit("gpt-4", 1_000),
+            Some(8_192 - 1_000 - 409)
+        );
+    }
+
+    #[test]
+    fn registered_models_come_first() {
+        register_model("func-enums-test", ModelInfo::new(4_000, 1_000));
+        register_model("func-enums-test-large", ModelInfo::new(40_000, 1_000));
+
+        assert_eq!(
+            model_info("func-enums-test-large-1")
+                .unwrap()
+                .context_window,
+            40_000
+        );
+        assert_eq!(
+            model_info("func-enums-test-1").unwrap().context_window,
+            4_000
+        );
+
+        // Also over a built in model whose name matches.
+        register_model("gpt-4o-func-enums-test", ModelInfo::new(4_000, 1_000));
+        assert_eq!(
+            model_info("gpt-4o-func-enums-test").unwrap().context_window,
+            4_000
+        );
+    }
+}
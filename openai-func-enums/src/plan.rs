@@ -0,0 +1,325 @@
+use crate::{CommandError, Step, StepExecutor, Tool};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use tokio::sync::mpsc;
+
+/// A multi-step plan, as the model fills in the `Plan` tool.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Plan {
+    pub steps: Vec<PlanStep>,
+}
+
+/// One step of a `Plan`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlanStep {
+    /// A short name for the step that other steps refer to it by.
+    pub id: String,
+    /// What the step should do, written as a request of its own.
+    pub prompt: String,
+    /// The ids of the earlier steps whose results this step needs.
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// Steps in the same group don't need each other and run at the same time. A step in a
+    /// group waits for every step of the groups listed before it.
+    #[serde(default)]
+    pub parallel_group: Option<String>,
+}
+
+impl Plan {
+    /// The JSON schema of the `Plan` tool's arguments.
+    pub fn parameters_schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "steps": {
+                    "type": "array",
+                    "description": "The steps, in the order they should happen.",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "id": {
+                                "type": "string",
+                                "description": "A short, unique name for the step."
+                            },
+                            "prompt": {
+                                "type": "string",
+                                "description": "What the step should do, as a request of its own."
+                            },
+                            "depends_on": {
+                                "type": "array",
+                                "items": { "type": "string" },
+                                "description": "The ids of earlier steps whose results this step needs."
+                            },
+                            "parallel_group": {
+                                "type": ["string", "null"],
+                                "description": "Steps that share a group run at the same time."
+                            }
+                        },
+                        "required": ["id", "prompt", "depends_on", "parallel_group"]
+                    }
+                }
+            },
+            "required": ["steps"]
+        })
+    }
+
+    /// Turns the plan into steps for a `StepExecutor`.
+    ///
+    /// # Errors
+    /// Returns a `CommandError` if two steps share an id, or a step depends on one that isn't
+    /// listed before it.
+    pub fn to_steps(&self) -> Result<Vec<Step>, CommandError> {
+        let mut indices: HashMap<&str, usize> = HashMap::new();
+        let mut groups: Vec<(&str, Vec<usize>)> = Vec::new();
+        let mut steps = Vec::with_capacity(self.steps.len());
+
+        for (index, step) in self.steps.iter().enumerate() {
+            if indices.insert(step.id.as_str(), index).is_some() {
+                return Err(CommandError::new(&format!(
+                    "The plan has more than one step called {}",
+                    step.id
+                )));
+            }
+
+            let mut depends_on = Vec::new();
+            for dependency in &step.depends_on {
+                match indices.get(dependency.as_str()) {
+                    Some(dependency) if *dependency < index => depends_on.push(*dependency),
+                    _ => {
+                        return Err(CommandError::new(&format!(
+                            "Step {} depends on {}, which is not an earlier step",
+                            step.id, dependency
+                        )))
+                    }
+                }
+            }
+
+            if let Some(group) = step.parallel_group.as_deref() {
+                let position = match groups.iter().position(|(name, _)| *name == group) {
+                    Some(position) => position,
+                    None => {
+                        groups.push((group, Vec::new()));
+                        groups.len() - 1
+                    }
+                };
+
+                for member in groups[..position].iter().flat_map(|(_, members)| members) {
+                    if !depends_on.contains(member) {
+                        depends_on.push(*member);
+                    }
+                }
+                groups[position].1.push(index);
+            }
+
+            steps.push(Step {
+                prompt: step.prompt.clone(),
+                depends_on,
+            });
+        }
+
+        Ok(steps)
+    }
+}
+
+/// What a `PlanExecutor` is doing, for showing progress.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PlanEvent {
+    Started {
+        step: usize,
+        id: String,
+    },
+    Finished {
+        step: usize,
+        id: String,
+        result: Option<String>,
+    },
+    Failed {
+        step: usize,
+        id: String,
+        error: String,
+    },
+}
+
+/// Runs a `Plan`, handing each step the results of the steps it depends on.
+#[derive(Clone, Debug, Default)]
+pub struct PlanExecutor {
+    pub steps: StepExecutor,
+    events: Option<mpsc::Sender<PlanEvent>>,
+}
+
+impl PlanExecutor {
+    pub fn new(max_concurrency: usize) -> Self {
+        PlanExecutor {
+            steps: StepExecutor::new(max_concurrency),
+            events: None,
+        }
+    }
+
+    /// Sends a `PlanEvent` whenever a step starts, finishes or fails. Events are dropped when
+    /// the receiver is gone.
+    pub fn with_events(mut self, events: mpsc::Sender<PlanEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Runs every step with `run_step` and returns the step ids with their results, in plan
+    /// order.
+    ///
+    /// `run_step` is called with the step and its prompt. The prompt of a step that depends
+    /// on others starts with their results, so that the step doesn't have to be told about
+    /// them.
+    ///
+    /// # Errors
+    /// Returns an error if the plan is invalid, see `Plan::to_steps`, or the first error a
+    /// step returns.
+    pub async fn run<F, Fut, E>(
+        &self,
+        plan: &Plan,
+        run_step: F,
+    ) -> Result<Vec<(String, Option<String>)>, Box<dyn std::error::Error + Send + Sync + 'static>>
+    where
+        F: Fn(&PlanStep, String) -> Fut,
+        Fut: Future<Output = Result<Option<String>, E>>,
+        E: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
+    {
+        let steps = plan.to_steps()?;
+
+        let results = self
+            .steps
+            .run(&steps, |index, prompt, inputs| {
+                let step = &plan.steps[index];
+                let prompt = with_prior_results(&plan.steps, &steps[index], inputs, prompt);
+                let future = run_step(step, prompt);
+                let events = self.events.clone();
+
+                async move {
+                    send(
+                        &events,
+                        PlanEvent::Started {
+                            step: index,
+                            id: step.id.clone(),
+                        },
+                    )
+                    .await;
+
+                    match future.await.map_err(Into::into) {
+                        Ok(result) => {
+                            send(
+                                &events,
+                                PlanEvent::Finished {
+                                    step: index,
+                                    id: step.id.clone(),
+                                    result: result.clone(),
+                                },
+                            )
+                            .await;
+                            Ok(result)
+                        }
+                        Err(e) => {
+                            send(
+                                &events,
+                                PlanEvent::Failed {
+                                    step: index,
+                                    id: step.id.clone(),
+                                    error: e.to_string(),
+                                },
+                            )
+                            .await;
+                            Err(e)
+                        }
+                    }
+                }
+            })
+            .await?;
+
+        Ok(plan
+            .steps
+            .iter()
+            .map(|step| step.id.clone())
+            .zip(results)
+            .collect())
+    }
+}
+
+fn with_prior_results(
+    plan_steps: &[PlanStep],
+    step: &Step,
+    inputs: Vec<Option<String>>,
+    prompt: String,
+) -> String {
+    if step.depends_on.is_empty() {
+        return prompt;
+    }
+
+    let mut with_results = String::from("Results of the steps this one builds on:\n");
+    for (dependency, result) in step.depends_on.iter().zip(inputs) {
+        with_results.push_str(&format!(
+            "- {}: {}\n",
+            plan_steps[*dependency].id,
+            result.as_deref().unwrap_or("(no result)")
+        ));
+    }
+    with_results.push('\n');
+    with_results.push_str(&prompt);
+    with_results
+}
+
+async fn send(events: &Option<mpsc::Sender<PlanEvent>>, event: PlanEvent) {
+    if let Some(events) = events {
+        let _ = events.send(event).await;
+    }
+}
+
+/// The `Plan` tool, which lets the model break a request into steps that a `PlanExecutor`
+/// runs. Register it with a `ToolRegistry` in place of a hand written multi-step variant.
+///
+/// `run_step` usually runs the step's prompt through `run_with_config` and returns the output
+/// of its last tool call. The tool returns an object with the result of every step.
+pub struct PlanTool<F> {
+    pub executor: PlanExecutor,
+    run_step: F,
+}
+
+impl<F> PlanTool<F> {
+    pub fn new(executor: PlanExecutor, run_step: F) -> Self {
+        PlanTool { executor, run_step }
+    }
+}
+
+#[async_trait]
+impl<F, Fut, E> Tool for PlanTool<F>
+where
+    F: Fn(&PlanStep, String) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Option<String>, E>> + Send,
+    E: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
+{
+    fn name(&self) -> &str {
+        "Plan"
+    }
+
+    fn description(&self) -> &str {
+        "Breaks a request that needs several tool calls into steps. Steps that don't depend on each other run at the same time, and each step is given the results of the steps it depends on."
+    }
+
+    fn parameters(&self) -> Value {
+        Plan::parameters_schema()
+    }
+
+    async fn call(
+        &self,
+        arguments: Value,
+    ) -> Result<Value, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let plan: Plan = serde_json::from_value(arguments)?;
+        let results = self.executor.run(&plan, &self.run_step).await?;
+
+        Ok(Value::Object(
+            results
+                .into_iter()
+                .map(|(id, result)| (id, result.map_or(Value::Null, Value::String)))
+                .collect::<Map<String, Value>>(),
+        ))
+    }
+}
@@ -0,0 +1,137 @@
+use crate::{RequestFailure, RunConfig};
+use async_openai::error::OpenAIError;
+use async_openai::types::{
+    ChatCompletionResponseStream, CreateChatCompletionRequest, CreateChatCompletionResponse,
+    CreateChatCompletionStreamResponse,
+};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::fmt;
+
+/// The backend chat requests with tools are sent to. Put one on `RunConfig::provider` to run
+/// a `ToolSet` against Anthropic, Gemini, a local model or anything else that can call tools.
+///
+/// Requests and responses are OpenAI's chat completion types whatever the backend, so a
+/// provider translates them to and from its own API. The config of the run is passed along
+/// for settings like `extra_body` or `http_client`. Runs without a provider use
+/// `OpenAiProvider`, or `AzureOpenAiProvider` when Azure is configured in the environment.
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    /// A name for logs and `Debug` output.
+    fn name(&self) -> &str;
+
+    async fn chat_completion(
+        &self,
+        request: CreateChatCompletionRequest,
+        config: &RunConfig,
+    ) -> Result<CreateChatCompletionResponse, OpenAIError>;
+
+    /// Sends a request the way `chat_completion` does, keeping the HTTP status and
+    /// `Retry-After` of a failed response, which is what `RunConfig::create_chat_completion`
+    /// decides retries by. By default the error of `chat_completion` is returned without them,
+    /// so only what the error itself says is retried.
+    async fn try_chat_completion(
+        &self,
+        request: CreateChatCompletionRequest,
+        config: &RunConfig,
+    ) -> Result<CreateChatCompletionResponse, RequestFailure> {
+        self.chat_completion(request, config)
+            .await
+            .map_err(RequestFailure::from)
+    }
+
+    /// Sends a streamed request. By default the request is sent with `chat_completion` and the
+    /// whole response arrives as a single chunk, for backends that can't stream.
+    async fn chat_completion_stream(
+        &self,
+        request: CreateChatCompletionRequest,
+        config: &RunConfig,
+    ) -> Result<ChatCompletionResponseStream, OpenAIError> {
+        let response = self.chat_completion(request, config).await?;
+        response_as_stream(&response)
+    }
+}
+
+impl fmt::Debug for dyn LlmProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LlmProvider")
+            .field("name", &self.name())
+            .finish()
+    }
+}
+
+/// The OpenAI API, with the cassette, key pool and other settings of the config applied.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OpenAiProvider;
+
+#[async_trait]
+impl LlmProvider for OpenAiProvider {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    async fn chat_completion(
+        &self,
+        request: CreateChatCompletionRequest,
+        config: &RunConfig,
+    ) -> Result<CreateChatCompletionResponse, OpenAIError> {
+        config.openai_chat_completion(request).await
+    }
+
+    async fn try_chat_completion(
+        &self,
+        request: CreateChatCompletionRequest,
+        config: &RunConfig,
+    ) -> Result<CreateChatCompletionResponse, RequestFailure> {
+        config.try_openai_chat_completion(request).await
+    }
+
+    async fn chat_completion_stream(
+        &self,
+        request: CreateChatCompletionRequest,
+        config: &RunConfig,
+    ) -> Result<ChatCompletionResponseStream, OpenAIError> {
+        config.openai_chat_completion_stream(request).await
+    }
+}
+
+/// A response as a stream of one chunk, with every message turned into the delta it would
+/// have streamed as.
+pub fn response_as_stream(
+    response: &CreateChatCompletionResponse,
+) -> Result<ChatCompletionResponseStream, OpenAIError> {
+    let mut chunk = serde_json::to_value(response).map_err(OpenAIError::JSONDeserialize)?;
+    chunk["object"] = "chat.completion.chunk".into();
+
+    if let Some(choices) = chunk["choices"].as_array_mut() {
+        for choice in choices {
+            let Some(choice) = choice.as_object_mut() else {
+                continue;
+            };
+            let mut delta = choice.remove("message").unwrap_or(Value::Null);
+            if let Some(tool_calls) = delta["tool_calls"].as_array_mut() {
+                for (index, tool_call) in tool_calls.iter_mut().enumerate() {
+                    tool_call["index"] = index.into();
+                }
+            }
+            choice.insert("delta".to_string(), delta);
+        }
+    }
+
+    let chunk: CreateChatCompletionStreamResponse =
+        serde_json::from_value(chunk).map_err(OpenAIError::JSONDeserialize)?;
+    Ok(Box::pin(futures::stream::iter([Ok(chunk)])))
+}
+
+/// The text of a message, whose content is either a string or a list of parts.
+pub(crate) fn message_text(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| part["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
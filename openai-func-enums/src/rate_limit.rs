@@ -0,0 +1,262 @@
+use crate::count_tokens;
+use async_openai::types::{CompletionUsage, CreateChatCompletionRequest};
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Keeps requests under the requests per minute and tokens per minute limits of an account,
+/// for applications that run many tool loops at once.
+///
+/// Put it on `RunConfig::rate_limiter` and every chat request of the run, retries included,
+/// waits for room in its budgets before it is sent. Clones share their budgets, so one limiter
+/// can be handed to every concurrent run, and runs nested in tools use it too.
+///
+/// Both budgets are token buckets that start full and refill evenly over the minute. A request
+/// takes the tokens of its messages and tools plus its response token limit, which is how
+/// OpenAI counts it, and the estimate is corrected once the response reports its usage.
+///
+/// ```
+/// # use openai_func_enums::RateLimiter;
+/// let rate_limiter = RateLimiter::new()
+///     .with_requests_per_minute(500)
+///     .with_tokens_per_minute(200_000);
+/// ```
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    requests: Option<Arc<Mutex<Bucket>>>,
+    tokens: Option<Arc<Mutex<Bucket>>>,
+}
+
+struct Bucket {
+    capacity: f64,
+    available: f64,
+    per_second: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn per_minute(limit: u32) -> Arc<Mutex<Bucket>> {
+        let capacity = f64::from(limit.max(1));
+        Arc::new(Mutex::new(Bucket {
+            capacity,
+            available: capacity,
+            per_second: capacity / 60.0,
+            updated: Instant::now(),
+        }))
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.available = (self.available + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// How long until `amount` is available. More than the capacity is never available, so it
+    /// is capped there, and a request bigger than the budget waits for a full bucket.
+    fn wait_for(&self, amount: f64) -> Duration {
+        let missing = amount.min(self.capacity) - self.available;
+        if missing <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing / self.per_second)
+        }
+    }
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("requests_per_minute", &self.requests_per_minute())
+            .field("tokens_per_minute", &self.tokens_per_minute())
+            .finish()
+    }
+}
+
+impl RateLimiter {
+    /// A limiter without budgets, which never waits.
+    pub fn new() -> Self {
+        RateLimiter::default()
+    }
+
+    pub fn with_requests_per_minute(mut self, requests_per_minute: u32) -> Self {
+        self.requests = Some(Bucket::per_minute(requests_per_minute));
+        self
+    }
+
+    pub fn with_tokens_per_minute(mut self, tokens_per_minute: u32) -> Self {
+        self.tokens = Some(Bucket::per_minute(tokens_per_minute));
+        self
+    }
+
+    pub fn requests_per_minute(&self) -> Option<u32> {
+        self.requests
+            .as_ref()
+            .map(|bucket| lock(bucket).capacity as u32)
+    }
+
+    pub fn tokens_per_minute(&self) -> Option<u32> {
+        self.tokens
+            .as_ref()
+            .map(|bucket| lock(bucket).capacity as u32)
+    }
+
+    /// Waits until there is room for a request of `tokens` tokens, then takes it from the
+    /// budgets.
+    pub async fn acquire(&self, tokens: u32) {
+        loop {
+            let wait = self.try_acquire(tokens);
+            if wait.is_zero() {
+                return;
+            }
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Waits for room for `request`, returning the tokens it was estimated at.
+    pub async fn acquire_for(&self, request: &CreateChatCompletionRequest) -> u32 {
+        let tokens = estimate_request_tokens(request);
+        self.acquire(tokens).await;
+        tokens
+    }
+
+    /// Corrects the tokens a request took, estimated at `estimated`, by what the response
+    /// says it used.
+    pub fn record_usage(&self, estimated: u32, usage: &CompletionUsage) {
+        if let Some(bucket) = &self.tokens {
+            let mut bucket = lock(bucket);
+            bucket.refill(Instant::now());
+            let taken = f64::from(estimated).min(bucket.capacity);
+            bucket.available += taken - f64::from(usage.total_tokens);
+        }
+    }
+
+    /// Takes `tokens` and a request from the budgets if both have room, otherwise returns how
+    /// long to wait before trying again.
+    fn try_acquire(&self, tokens: u32) -> Duration {
+        let now = Instant::now();
+        let mut requests = self.requests.as_deref().map(lock);
+        let mut token_bucket = self.tokens.as_deref().map(lock);
+
+        let mut wait = Duration::ZERO;
+        if let Some(bucket) = requests.as_mut() {
+            bucket.refill(now);
+            wait = wait.max(bucket.wait_for(1.0));
+        }
+        if let Some(bucket) = token_bucket.as_mut() {
+            bucket.refill(now);
+            wait = wait.max(bucket.wait_for(f64::from(tokens)));
+        }
+        if !wait.is_zero() {
+            return wait;
+        }
+
+        if let Some(bucket) = requests.as_mut() {
+            bucket.available -= 1.0;
+        }
+        if let Some(bucket) = token_bucket.as_mut() {
+            bucket.available -= f64::from(tokens).min(bucket.capacity);
+        }
+        Duration::ZERO
+    }
+}
+
+/// The tokens a chat request counts against a tokens per minute limit: its messages and tools
+/// as counted by `count_tokens`, and the most it may generate.
+pub fn estimate_request_tokens(request: &CreateChatCompletionRequest) -> u32 {
+    let messages = count_tokens(&serde_json::to_string(&request.messages).unwrap_or_default());
+    let tools = request.tools.as_ref().map_or(0, |tools| {
+        count_tokens(&serde_json::to_string(tools).unwrap_or_default())
+    });
+    #[allow(deprecated)]
+    let max_tokens = request.max_completion_tokens.or(request.max_tokens);
+
+    u32::try_from(messages + tools)
+        .unwrap_or(u32::MAX)
+        .saturating_add(max_tokens.unwrap_or(0))
+}
+
+fn lock(bucket: &Mutex<Bucket>) -> MutexGuard<'_, Bucket> {
+    bucket.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::{
+        ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs,
+    };
+
+    fn usage(total_tokens: u32) -> CompletionUsage {
+        CompletionUsage {
+            prompt_tokens: total_tokens,
+            completion_tokens: 0,
+            total_tokens,
+            prompt_tokens_details: None,
+            completion_tokens_details: None,
+        }
+    }
+
+    #[test]
+    fn limiter_without_budgets_never_waits() {
+        let rate_limiter = RateLimiter::new();
+
+        for _ in 0..1000 {
+            assert_eq!(rate_limiter.try_acquire(u32::MAX), Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn requests_wait_once_the_bucket_is_empty() {
+        let rate_limiter = RateLimiter::new().with_requests_per_minute(2);
+
+        assert_eq!(rate_limiter.try_acquire(0), Duration::ZERO);
+        assert_eq!(rate_limiter.try_acquire(0), Duration::ZERO);
+
+        // One request comes back every 30 seconds.
+        let wait = rate_limiter.try_acquire(0);
+        assert!(wait > Duration::from_secs(29) && wait <= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn tokens_are_taken_and_corrected_by_usage() {
+        let rate_limiter = RateLimiter::new().with_tokens_per_minute(1000);
+
+        assert_eq!(rate_limiter.try_acquire(800), Duration::ZERO);
+        assert!(rate_limiter.try_acquire(800) > Duration::ZERO);
+
+        // The request only used 100 of the 800 it was estimated at.
+        rate_limiter.record_usage(800, &usage(100));
+        assert_eq!(rate_limiter.try_acquire(800), Duration::ZERO);
+    }
+
+    #[test]
+    fn clones_share_their_budgets() {
+        let rate_limiter = RateLimiter::new().with_requests_per_minute(1);
+        let clone = rate_limiter.clone();
+
+        assert_eq!(rate_limiter.try_acquire(0), Duration::ZERO);
+        assert!(clone.try_acquire(0) > Duration::ZERO);
+        assert_eq!(clone.requests_per_minute(), Some(1));
+    }
+
+    #[test]
+    fn estimate_includes_the_completion_limit() {
+        let request = CreateChatCompletionRequestArgs::default()
+            .model("gpt-4o")
+            .messages([ChatCompletionRequestUserMessageArgs::default()
+                .content("What's the weather in Boston?")
+                .build()
+                .unwrap()
+                .into()])
+            .build()
+            .unwrap();
+        let without_limit = estimate_request_tokens(&request);
+
+        let request = CreateChatCompletionRequest {
+            max_completion_tokens: Some(500),
+            ..request
+        };
+        assert!(without_limit > 0);
+        assert_eq!(estimate_request_tokens(&request), without_limit + 500);
+    }
+}
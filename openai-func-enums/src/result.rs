@@ -0,0 +1,279 @@
+use crate::{MissingScopes, ReturnSchemaMismatch, SandboxFailure, ToolTimedOut, UsageReport};
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessage,
+    ChatCompletionRequestMessage, ChatCompletionRequestToolMessage,
+    ChatCompletionRequestToolMessageContent, ChatCompletionToolType, CreateChatCompletionRequest,
+    FinishReason, FunctionCall,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::task::JoinHandle;
+
+/// Everything a generated `run_with_config` learned while handling one request.
+#[derive(Clone, Debug, Default)]
+pub struct RunOutput {
+    /// One entry per tool call the model made, in the order they were started.
+    pub tool_calls: Vec<ToolCallResult>,
+    /// The answer the model gave through the `FinalAnswer` tool, if it did.
+    pub final_answer: Option<String>,
+    /// The text of the model's last response, if it had any. This is the answer when the
+    /// model replies without calling tools, like it does for prompts meant for a GPT variant.
+    pub content: Option<String>,
+    /// Why the model stopped generating its last response.
+    pub finish_reason: Option<FinishReason>,
+    /// How many requests were sent, more than one when `RunConfig::max_iterations` had the
+    /// model continue after its tool calls.
+    pub iterations: usize,
+    /// The last request that was sent followed by its `follow_up_messages`. When the run ended
+    /// on tool calls, this is what `RunConfig::submit_tool_results` sends to continue it.
+    pub follow_up_request: Option<CreateChatCompletionRequest>,
+    /// The tokens and cost of every request of the run, including the runs its tools started.
+    pub usage: UsageReport,
+}
+
+impl RunOutput {
+    /// The messages that continue a conversation after this response, following the tool
+    /// protocol: the assistant's reply with the calls it made, then a `tool` message with the
+    /// result of each call. Just the reply when no tools were called, and empty without one.
+    pub fn follow_up_messages(&self) -> Vec<ChatCompletionRequestMessage> {
+        if self.tool_calls.is_empty() {
+            return self
+                .content
+                .iter()
+                .map(|content| {
+                    ChatCompletionRequestMessage::Assistant(ChatCompletionRequestAssistantMessage {
+                        content: Some(content.clone().into()),
+                        ..Default::default()
+                    })
+                })
+                .collect();
+        }
+
+        let tool_calls = self
+            .tool_calls
+            .iter()
+            .map(|tool_call| ChatCompletionMessageToolCall {
+                id: tool_call.id.clone(),
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionCall {
+                    name: tool_call.name.clone(),
+                    arguments: tool_call.arguments.clone(),
+                },
+            })
+            .collect();
+
+        let mut messages = vec![ChatCompletionRequestMessage::Assistant(
+            ChatCompletionRequestAssistantMessage {
+                content: self.content.clone().map(Into::into),
+                tool_calls: Some(tool_calls),
+                ..Default::default()
+            },
+        )];
+        messages.extend(self.tool_messages().into_iter().map(Into::into));
+        messages
+    }
+
+    /// A `tool` message for every call, matched to it by `tool_call_id`.
+    pub fn tool_messages(&self) -> Vec<ChatCompletionRequestToolMessage> {
+        self.tool_calls
+            .iter()
+            .map(ToolCallResult::tool_message)
+            .collect()
+    }
+}
+
+/// What happened to a single tool call returned by the model.
+///
+/// Besides the tool's output, this records whether the arguments had to be repaired before
+/// they would deserialize, which is useful for keeping an eye on how often a model emits
+/// broken arguments for a given function.
+#[derive(Clone, Debug, Default)]
+pub struct ToolCallResult {
+    /// The id the model assigned to the tool call.
+    pub id: String,
+    /// The name of the function the model called.
+    pub name: String,
+    /// The arguments exactly as the model sent them.
+    pub arguments: String,
+    /// The arguments that finally deserialized, if they only did so after being repaired.
+    pub repaired_arguments: Option<String>,
+    /// How many parse attempts it took for the arguments to deserialize. `1` means they were
+    /// fine as sent and `0` means they never deserialized.
+    pub attempts: usize,
+    /// The result the tool returned, as the text the model is sent.
+    pub output: Option<String>,
+    /// The result the tool returned, as it returned it, see `ToolOutput`. It is dropped along
+    /// with `output` when it breaks the tool's return schema.
+    pub value: Option<Value>,
+    /// The arguments the tool returned for the next command, see `ToolOutput::next_arguments`.
+    pub next_arguments: Option<Vec<String>>,
+    /// Why the tool call failed, if it did.
+    pub error: Option<String>,
+    /// Why the sanitizer found the output suspicious, see `OutputSanitizer`.
+    pub flags: Vec<String>,
+    /// How the output broke the tool's declared return schema, if it did. The output is
+    /// dropped when this is set.
+    pub return_mismatch: Option<ReturnSchemaMismatch>,
+    /// The scopes the call was rejected for, see `RunConfig::granted_scopes`.
+    pub missing_scopes: Option<MissingScopes>,
+    /// Why the call didn't finish in the sandbox, see `Sandbox`.
+    pub sandbox_failure: Option<SandboxFailure>,
+    /// The timeout the call ran past, if it did. The output is `RunConfig::timeout_fallback`.
+    pub timed_out: Option<ToolTimedOut>,
+    /// The call was stopped by `RunConfig::cancellation_token` before it finished.
+    pub cancelled: bool,
+}
+
+impl ToolCallResult {
+    pub fn new(tool_call: &ChatCompletionMessageToolCall) -> Self {
+        ToolCallResult {
+            id: tool_call.id.clone(),
+            name: tool_call.function.name.clone(),
+            arguments: tool_call.function.arguments.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// The `tool` message that reports this call's result back to the model: the output, or
+    /// the error if the call failed.
+    pub fn tool_message(&self) -> ChatCompletionRequestToolMessage {
+        let content = match (&self.output, &self.error) {
+            (Some(output), _) => output.clone(),
+            (None, Some(error)) => format!("Error: {}", error),
+            (None, None) => String::new(),
+        };
+
+        ChatCompletionRequestToolMessage {
+            content: ChatCompletionRequestToolMessageContent::Text(content),
+            tool_call_id: self.id.clone(),
+        }
+    }
+
+    /// The result the tool returned, deserialized into `T`.
+    pub fn value_as<T: DeserializeOwned>(&self) -> Option<T> {
+        self.value
+            .clone()
+            .and_then(|value| serde_json::from_value(value).ok())
+    }
+
+    /// Returns `true` if the arguments only deserialized after being repaired.
+    pub fn was_repaired(&self) -> bool {
+        self.repaired_arguments.is_some()
+    }
+}
+
+/// A tool call started on a task of its own by the `Async` or `Parallel` strategy, kept with
+/// the call so that it can be reported even when the task doesn't finish.
+pub struct ToolCallTask {
+    tool_call: ChatCompletionMessageToolCall,
+    handle: JoinHandle<ToolCallResult>,
+}
+
+impl ToolCallTask {
+    pub fn new(
+        tool_call: ChatCompletionMessageToolCall,
+        handle: JoinHandle<ToolCallResult>,
+    ) -> Self {
+        ToolCallTask { tool_call, handle }
+    }
+
+    /// Waits for the call to finish. A call whose task panicked or was aborted gets a failed
+    /// result saying why, so that the model is still told about it and it counts as a failure.
+    pub async fn join(self) -> ToolCallResult {
+        match self.handle.await {
+            Ok(tool_call_result) => tool_call_result,
+            Err(e) => {
+                let error = if e.is_panic() {
+                    let panic = e.into_panic();
+                    let message = panic
+                        .downcast_ref::<&str>()
+                        .map(|message| message.to_string())
+                        .or_else(|| panic.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "no message".to_string());
+                    format!("The tool panicked: {}", message)
+                } else {
+                    "The tool call was aborted before it finished".to_string()
+                };
+
+                ToolCallResult {
+                    error: Some(error),
+                    ..ToolCallResult::new(&self.tool_call)
+                }
+            }
+        }
+    }
+}
+
+/// What a `RunCommand` returns: the tool's result, and the arguments for the next command
+/// when the tool is one step of several.
+///
+/// The result is any JSON value, so typed results reach the caller through
+/// `ToolCallResult::value` without being turned into text and back. The model is sent strings
+/// as they are and anything else serialized.
+///
+/// ```
+/// # use openai_func_enums::ToolOutput;
+/// # fn run(forecast: Vec<f64>) -> Result<ToolOutput, serde_json::Error> {
+/// return Ok(ToolOutput::json(&forecast)?);
+/// # }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ToolOutput {
+    pub value: Option<Value>,
+    pub next_arguments: Option<Vec<String>>,
+}
+
+impl ToolOutput {
+    /// An output without a result.
+    pub fn none() -> Self {
+        ToolOutput::default()
+    }
+
+    pub fn text(text: impl Into<String>) -> Self {
+        ToolOutput {
+            value: Some(Value::String(text.into())),
+            next_arguments: None,
+        }
+    }
+
+    /// The output for `value` serialized to JSON.
+    pub fn json<T: Serialize>(value: &T) -> Result<Self, serde_json::Error> {
+        Ok(ToolOutput {
+            value: Some(serde_json::to_value(value)?),
+            next_arguments: None,
+        })
+    }
+
+    pub fn with_next_arguments(mut self, next_arguments: Vec<String>) -> Self {
+        self.next_arguments = Some(next_arguments);
+        self
+    }
+
+    /// The result as the text the model is sent.
+    pub fn text_output(&self) -> Option<String> {
+        self.value.as_ref().map(|value| match value {
+            Value::String(text) => text.clone(),
+            value => value.to_string(),
+        })
+    }
+}
+
+impl From<Value> for ToolOutput {
+    fn from(value: Value) -> Self {
+        ToolOutput {
+            value: Some(value),
+            next_arguments: None,
+        }
+    }
+}
+
+/// The text result and next arguments that `RunCommand::run` used to return.
+impl From<(Option<String>, Option<Vec<String>>)> for ToolOutput {
+    fn from((output, next_arguments): (Option<String>, Option<Vec<String>>)) -> Self {
+        ToolOutput {
+            value: output.map(Value::String),
+            next_arguments,
+        }
+    }
+}
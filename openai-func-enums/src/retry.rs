@@ -0,0 +1,430 @@
+use crate::{RetryPolicy, RunConfig, RunEvent};
+use async_openai::error::{ApiError, OpenAIError};
+use backoff::backoff::Backoff;
+use regex::Regex;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// A chat request that failed, along with what its HTTP response said, which is what
+/// retries are decided by. See `LlmProvider::try_chat_completion`.
+#[derive(Debug)]
+pub struct RequestFailure {
+    pub error: OpenAIError,
+    /// The status of the response, `None` when the request got no response or the error
+    /// didn't come with one.
+    pub status: Option<u16>,
+    /// How long the response's `Retry-After` or `retry-after-ms` header asked to wait.
+    pub retry_after: Option<Duration>,
+}
+
+impl RequestFailure {
+    /// The failure of an error response. The error is the API's error object when the body
+    /// has one, and otherwise carries the body as its message, like the HTML page of a
+    /// gateway. Either way its `code` is the status unless the API gave a code of its own.
+    pub fn from_response(status: StatusCode, headers: &HeaderMap, body: &str) -> Self {
+        #[derive(Deserialize)]
+        struct ErrorBody {
+            error: ApiError,
+        }
+
+        let mut api_error = match serde_json::from_str::<ErrorBody>(body) {
+            Ok(ErrorBody { error }) => error,
+            Err(_) => ApiError {
+                message: match body.trim() {
+                    "" => status.to_string(),
+                    body => body.to_string(),
+                },
+                r#type: None,
+                param: None,
+                code: None,
+            },
+        };
+        if api_error.code.is_none() {
+            api_error.code = Some(status.as_u16().to_string());
+        }
+
+        RequestFailure {
+            error: OpenAIError::ApiError(api_error),
+            status: Some(status.as_u16()),
+            retry_after: retry_after_header(headers),
+        }
+    }
+}
+
+impl From<OpenAIError> for RequestFailure {
+    fn from(error: OpenAIError) -> Self {
+        RequestFailure {
+            error,
+            status: None,
+            retry_after: None,
+        }
+    }
+}
+
+impl fmt::Display for RequestFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "HTTP {}: {}", status, self.error),
+            None => self.error.fmt(f),
+        }
+    }
+}
+
+impl Error for RequestFailure {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Whether `failure` is worth retrying. With an HTTP status that is a rate limit that isn't
+/// an exhausted quota, a server error or a request timeout, and otherwise whatever
+/// `is_transient_error` says of the error.
+pub fn is_transient(failure: &RequestFailure) -> bool {
+    match failure.status {
+        Some(429) => !is_insufficient_quota(&failure.error),
+        Some(status) => matches!(status, 408 | 500..=599),
+        None => is_transient_error(&failure.error),
+    }
+}
+
+/// Whether `error` is worth retrying, for errors without an HTTP status: a rate limit that
+/// isn't an exhausted quota, a status in the code the way providers report it, or a request
+/// that timed out or couldn't connect. An API error with nothing but a message could be any
+/// status, so it is not retried.
+pub fn is_transient_error(error: &OpenAIError) -> bool {
+    match error {
+        OpenAIError::ApiError(api_error) => {
+            if is_insufficient_quota(error) {
+                return false;
+            }
+
+            let kind = api_error.r#type.as_deref();
+            let code = api_error.code.as_deref();
+            let status = code.and_then(|code| code.parse::<u16>().ok());
+
+            code == Some("rate_limit_exceeded")
+                || matches!(kind, Some("requests" | "tokens" | "rate_limit_error"))
+                || matches!(status, Some(408 | 429 | 500..=599))
+        }
+        OpenAIError::Reqwest(e) => e.is_timeout() || e.is_connect(),
+        _ => false,
+    }
+}
+
+fn is_insufficient_quota(error: &OpenAIError) -> bool {
+    match error {
+        OpenAIError::ApiError(api_error) => {
+            api_error.r#type.as_deref() == Some("insufficient_quota")
+                || api_error.code.as_deref() == Some("insufficient_quota")
+        }
+        _ => false,
+    }
+}
+
+/// The wait a response asks for in seconds with `Retry-After`, or in milliseconds with the
+/// `retry-after-ms` OpenAI also sends. Retry-After dates aren't read.
+fn retry_after_header(headers: &HeaderMap) -> Option<Duration> {
+    let seconds = |name: &str, scale: f64| {
+        headers
+            .get(name)?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|amount| amount.is_finite() && *amount >= 0.0)
+            .map(|amount| Duration::from_secs_f64(amount * scale))
+    };
+
+    seconds("retry-after-ms", 0.001).or_else(|| seconds("retry-after", 1.0))
+}
+
+/// How long the API asked to wait before retrying in the message of `error`, like the
+/// "Please try again in 1.2s." of OpenAI's rate limit messages.
+pub fn retry_after(error: &OpenAIError) -> Option<Duration> {
+    static RETRY_HINT: OnceLock<Regex> = OnceLock::new();
+    static PART: OnceLock<Regex> = OnceLock::new();
+
+    let OpenAIError::ApiError(api_error) = error else {
+        return None;
+    };
+    let hint = RETRY_HINT
+        .get_or_init(|| Regex::new(r"(?i)try again in ([0-9.hms]+)").unwrap())
+        .captures(&api_error.message)?;
+
+    // Go style durations, like "20ms", "1.2s" or "6m0s".
+    let mut seconds = None;
+    for part in PART
+        .get_or_init(|| Regex::new(r"(\d+(?:\.\d+)?)(ms|h|m|s)").unwrap())
+        .captures_iter(&hint[1])
+    {
+        let amount = part[1].parse::<f64>().ok()?;
+        let scale = match &part[2] {
+            "ms" => 0.001,
+            "h" => 3600.0,
+            "m" => 60.0,
+            _ => 1.0,
+        };
+        seconds = Some(seconds.unwrap_or(0.0) + amount * scale);
+    }
+
+    seconds.map(Duration::from_secs_f64)
+}
+
+/// Sends the request `send` makes until it succeeds, fails with an error that isn't
+/// transient, or `policy` gives up. Every retry is reported as a `RequestRetried` event.
+pub(crate) async fn with_retries<F, Fut, T>(
+    config: &RunConfig,
+    policy: &RetryPolicy,
+    mut send: F,
+) -> Result<T, OpenAIError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, RequestFailure>>,
+{
+    let mut backoff = policy.backoff();
+    let mut attempt = 1;
+
+    loop {
+        let failure = match send().await {
+            Ok(response) => return Ok(response),
+            Err(failure) => failure,
+        };
+
+        let out_of_attempts = policy
+            .max_attempts
+            .is_some_and(|max_attempts| attempt >= max_attempts);
+        if out_of_attempts || !is_transient(&failure) {
+            return Err(failure.error);
+        }
+
+        let Some(interval) = backoff.next_backoff() else {
+            return Err(failure.error);
+        };
+        let retry_after = failure.retry_after.or_else(|| retry_after(&failure.error));
+        let wait = match retry_after {
+            Some(retry_after) if policy.honor_retry_after => retry_after,
+            _ => interval,
+        };
+
+        config
+            .emit(RunEvent::RequestRetried {
+                attempt,
+                wait,
+                error: failure.to_string(),
+            })
+            .await;
+        tokio::time::sleep(wait).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn api_error(message: &str, kind: Option<&str>, code: Option<&str>) -> OpenAIError {
+        OpenAIError::ApiError(ApiError {
+            message: message.to_string(),
+            r#type: kind.map(str::to_string),
+            param: None,
+            code: code.map(str::to_string),
+        })
+    }
+
+    fn quick_policy() -> RetryPolicy {
+        RetryPolicy {
+            initial_interval_ms: 1,
+            max_interval_ms: 1,
+            jitter: 0.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rate_limits_and_server_errors_are_transient() {
+        assert!(is_transient_error(&api_error(
+            "Rate limit reached",
+            Some("requests"),
+            Some("rate_limit_exceeded")
+        )));
+        assert!(is_transient_error(&api_error(
+            "Overloaded",
+            Some("rate_limit_error"),
+            None
+        )));
+        assert!(is_transient_error(&api_error(
+            "Bad gateway",
+            None,
+            Some("502")
+        )));
+        assert!(is_transient_error(&api_error(
+            "Too many",
+            None,
+            Some("429")
+        )));
+    }
+
+    #[test]
+    fn quotas_and_bad_requests_are_not_transient() {
+        assert!(!is_transient_error(&api_error(
+            "You exceeded your current quota",
+            Some("insufficient_quota"),
+            Some("insufficient_quota")
+        )));
+        assert!(!is_transient_error(&api_error(
+            "Invalid schema",
+            Some("invalid_request_error"),
+            None
+        )));
+        assert!(!is_transient_error(&api_error(
+            "Not found",
+            None,
+            Some("404")
+        )));
+        assert!(!is_transient_error(&OpenAIError::InvalidArgument(
+            "bad".to_string()
+        )));
+    }
+
+    #[test]
+    fn messages_alone_are_not_transient() {
+        // A compatible server's 400 can come back with nothing but a message.
+        assert!(!is_transient_error(&api_error(
+            "The server had an error processing your request",
+            None,
+            None
+        )));
+    }
+
+    #[test]
+    fn failures_are_classified_by_status() {
+        let headers = HeaderMap::new();
+        let failure = |status: u16, body: &str| {
+            RequestFailure::from_response(StatusCode::from_u16(status).unwrap(), &headers, body)
+        };
+
+        assert!(is_transient(&failure(
+            503,
+            "<html>Service Unavailable</html>"
+        )));
+        assert!(is_transient(&failure(408, "")));
+        assert!(is_transient(&failure(
+            429,
+            r#"{"error": {"message": "Rate limit reached", "type": "requests"}}"#
+        )));
+        assert!(!is_transient(&failure(
+            429,
+            r#"{"error": {"message": "Quota", "type": "insufficient_quota"}}"#
+        )));
+        assert!(!is_transient(&failure(
+            400,
+            r#"{"error": {"message": "The server had an error", "type": null}}"#
+        )));
+        assert!(!is_transient(&failure(404, "Not found")));
+    }
+
+    #[test]
+    fn non_json_bodies_keep_the_status_and_body() {
+        let failure = RequestFailure::from_response(
+            StatusCode::BAD_GATEWAY,
+            &HeaderMap::new(),
+            "<html>Bad Gateway</html>\n",
+        );
+
+        assert_eq!(failure.status, Some(502));
+        let OpenAIError::ApiError(api_error) = &failure.error else {
+            panic!("expected an API error, got {:?}", failure.error);
+        };
+        assert_eq!(api_error.message, "<html>Bad Gateway</html>");
+        assert_eq!(api_error.code.as_deref(), Some("502"));
+    }
+
+    #[test]
+    fn retry_after_headers_are_read() {
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", HeaderValue::from_static("7"));
+        let failure = RequestFailure::from_response(StatusCode::TOO_MANY_REQUESTS, &headers, "");
+        assert_eq!(failure.retry_after, Some(Duration::from_secs(7)));
+
+        headers.insert("retry-after-ms", HeaderValue::from_static("250"));
+        let failure = RequestFailure::from_response(StatusCode::TOO_MANY_REQUESTS, &headers, "");
+        assert_eq!(failure.retry_after, Some(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn retry_after_reads_go_durations() {
+        let wait = |message: &str| retry_after(&api_error(message, Some("tokens"), None));
+
+        assert_eq!(
+            wait("Rate limit reached. Please try again in 1.5s."),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            wait("Please try again in 20ms."),
+            Some(Duration::from_millis(20))
+        );
+        assert_eq!(
+            wait("Please try again in 6m0s."),
+            Some(Duration::from_secs(360))
+        );
+        assert_eq!(wait("Rate limit reached."), None);
+    }
+
+    #[tokio::test]
+    async fn transient_errors_are_retried_until_success() {
+        let attempts = AtomicU32::new(0);
+
+        let output = with_retries(&RunConfig::default(), &quick_policy(), || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(api_error("Bad gateway", None, Some("502")).into()),
+                _ => Ok("done"),
+            }
+        })
+        .await;
+
+        assert_eq!(output.unwrap(), "done");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn other_errors_are_returned_at_once() {
+        let attempts = AtomicU32::new(0);
+
+        let output: Result<(), _> =
+            with_retries(&RunConfig::default(), &quick_policy(), || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(api_error("Invalid schema", Some("invalid_request_error"), None).into())
+            })
+            .await;
+
+        assert!(output.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_stop_after_max_attempts() {
+        let attempts = AtomicU32::new(0);
+        let policy = RetryPolicy {
+            max_attempts: Some(3),
+            ..quick_policy()
+        };
+
+        let output: Result<(), _> = with_retries(&RunConfig::default(), &policy, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(api_error("Bad gateway", None, Some("502")).into())
+        })
+        .await;
+
+        assert!(output.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+}
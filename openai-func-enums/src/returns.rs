@@ -0,0 +1,241 @@
+use crate::ToolCallResult;
+use serde_json::{json, Value};
+use std::fmt;
+
+/// The JSON schema of what a tool returns, declared on a variant with
+/// `#[func_enums(returns = "WeatherReport")]`.
+///
+/// The schema is added to the function's description, so the model knows what a call will
+/// give it, for example when it plans which step feeds into which. Implement it for your own
+/// result types:
+///
+/// ```
+/// # use openai_func_enums::ReturnSchema;
+/// # use serde_json::{json, Value};
+/// # struct WeatherReport;
+/// impl ReturnSchema for WeatherReport {
+///     fn return_schema() -> Value {
+///         json!({
+///             "type": "object",
+///             "properties": {
+///                 "temperature": { "type": "number" },
+///                 "conditions": { "type": "string" }
+///             },
+///             "required": ["temperature", "conditions"]
+///         })
+///     }
+/// }
+/// ```
+pub trait ReturnSchema {
+    fn return_schema() -> Value;
+}
+
+macro_rules! impl_return_schema {
+    ($schema_type:literal, $($rust_type:ty),*) => {
+        $(
+            impl ReturnSchema for $rust_type {
+                fn return_schema() -> Value {
+                    json!({ "type": $schema_type })
+                }
+            }
+        )*
+    };
+}
+
+impl_return_schema!("string", String, &str, char);
+impl_return_schema!("boolean", bool);
+impl_return_schema!("integer", u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+impl_return_schema!("number", f32, f64);
+
+impl<T: ReturnSchema> ReturnSchema for Vec<T> {
+    fn return_schema() -> Value {
+        json!({ "type": "array", "items": T::return_schema() })
+    }
+}
+
+impl<T: ReturnSchema> ReturnSchema for Option<T> {
+    fn return_schema() -> Value {
+        json!({ "anyOf": [T::return_schema(), { "type": "null" }] })
+    }
+}
+
+/// Any JSON at all.
+impl ReturnSchema for Value {
+    fn return_schema() -> Value {
+        json!({})
+    }
+}
+
+/// A tool returned something its `ReturnSchema` doesn't allow.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReturnSchemaMismatch {
+    pub tool: String,
+    /// Where in the output the mismatch is, as a JSON pointer. Empty for the output itself.
+    pub path: String,
+    /// What the schema expects there.
+    pub expected: String,
+    /// What the output has there.
+    pub found: String,
+}
+
+impl fmt::Display for ReturnSchemaMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} returned {} at '{}', but its return schema expects {}",
+            self.tool, self.found, self.path, self.expected
+        )
+    }
+}
+
+impl std::error::Error for ReturnSchemaMismatch {}
+
+/// Checks the output of `tool_name` against `schema`.
+///
+/// Outputs are strings, so the output is read as JSON first. When that doesn't match, the
+/// output is tried as a plain string, which is what a tool returning text for a `"string"`
+/// schema produces.
+///
+/// The checks cover `type`, `enum`, `properties`, `required`, `items`, `anyOf` and `oneOf`.
+/// Other keywords are ignored.
+pub fn validate_return(
+    tool_name: &str,
+    schema: &Value,
+    output: &str,
+) -> Result<(), ReturnSchemaMismatch> {
+    let mismatch = match serde_json::from_str::<Value>(output) {
+        Ok(value) => match check(schema, &value, "") {
+            Ok(()) => return Ok(()),
+            Err(mismatch) => Some(mismatch),
+        },
+        Err(_) => None,
+    };
+
+    match check(schema, &Value::String(output.to_string()), "") {
+        Ok(()) => Ok(()),
+        Err(string_mismatch) => {
+            let (path, expected, found) = mismatch.unwrap_or(string_mismatch);
+            Err(ReturnSchemaMismatch {
+                tool: tool_name.to_string(),
+                path,
+                expected,
+                found,
+            })
+        }
+    }
+}
+
+/// Validates the output of a tool call against its declared schema. A mismatching output is
+/// dropped, so that it isn't fed into the next step, and the mismatch is recorded on
+/// `tool_call_result`. Generated code calls this on every output of a derived tool.
+pub fn validate_tool_output(
+    tool_call_result: &mut ToolCallResult,
+    schema: Option<Value>,
+    output: Option<String>,
+) -> Option<String> {
+    let (Some(schema), Some(output)) = (schema, output.as_deref()) else {
+        return output;
+    };
+
+    match validate_return(&tool_call_result.name, &schema, output) {
+        Ok(()) => Some(output.to_string()),
+        Err(mismatch) => {
+            tool_call_result.error = Some(mismatch.to_string());
+            tool_call_result.return_mismatch = Some(mismatch);
+            None
+        }
+    }
+}
+
+type Mismatch = (String, String, String);
+
+fn check(schema: &Value, value: &Value, path: &str) -> Result<(), Mismatch> {
+    let mismatch = |expected: String| Err((path.to_string(), expected, describe(value)));
+
+    for keyword in ["anyOf", "oneOf"] {
+        if let Some(options) = schema.get(keyword).and_then(Value::as_array) {
+            if !options
+                .iter()
+                .any(|option| check(option, value, path).is_ok())
+            {
+                return mismatch(format!("one of {}", Value::Array(options.clone())));
+            }
+        }
+    }
+
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(value) {
+            return mismatch(format!("one of {}", Value::Array(allowed.clone())));
+        }
+    }
+
+    if let Some(expected) = schema.get("type") {
+        let types: Vec<&str> = match expected {
+            Value::String(schema_type) => vec![schema_type.as_str()],
+            Value::Array(schema_types) => schema_types.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+
+        if !types.is_empty() && !types.iter().any(|schema_type| has_type(value, schema_type)) {
+            return mismatch(types.join(" or "));
+        }
+    }
+
+    if let Value::Object(object) = value {
+        for required in schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+        {
+            if !object.contains_key(required) {
+                return Err((
+                    format!("{}/{}", path, required),
+                    String::from("a value"),
+                    String::from("nothing"),
+                ));
+            }
+        }
+
+        if let Some(properties) = schema.get("properties").and_then(Value::as_object) {
+            for (name, property_schema) in properties {
+                if let Some(property) = object.get(name) {
+                    check(property_schema, property, &format!("{}/{}", path, name))?;
+                }
+            }
+        }
+    }
+
+    if let (Value::Array(items), Some(item_schema)) = (value, schema.get("items")) {
+        for (index, item) in items.iter().enumerate() {
+            check(item_schema, item, &format!("{}/{}", path, index))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn has_type(value: &Value, schema_type: &str) -> bool {
+    match schema_type {
+        "string" => value.is_string(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "boolean" => value.is_boolean(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+fn describe(value: &Value) -> String {
+    match value {
+        Value::Null => String::from("null"),
+        Value::Bool(_) => String::from("a boolean"),
+        Value::Number(_) => String::from("a number"),
+        Value::String(_) => String::from("a string"),
+        Value::Array(_) => String::from("an array"),
+        Value::Object(_) => String::from("an object"),
+    }
+}
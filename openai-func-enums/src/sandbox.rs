@@ -0,0 +1,174 @@
+use crate::cancel::{current_cancellation_token, run_unless_cancelled, was_cancelled};
+use crate::timeout::{current_tool_timeout, run_with_timeout};
+use crate::{RunConfig, ToolCallResult};
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+/// Containment for tools that can't be trusted to behave, like third party plugin code. Put
+/// it on `RunConfig::sandbox`.
+///
+/// Derived functions are sandboxed when marked `#[func_enums(sandboxed)]`, runtime tools when
+/// they are listed in `functions`. A sandboxed call runs as a task of its own, so a panic is
+/// caught there instead of taking down the run, and with a `timeout` a call that hangs is
+/// abandoned. Either way the call fails with a `SandboxFailure` on its `ToolCallResult`.
+///
+/// The call still shares the process with everything else, so memory use is not limited and
+/// a tool that blocks its thread without yielding can't be stopped by the timeout. Tools that
+/// need that kind of isolation have to be run out of process, for example by a `Tool` that
+/// talks to a subprocess.
+#[derive(Clone, Debug, Default)]
+pub struct Sandbox {
+    /// How long a sandboxed call may take before it is abandoned.
+    pub timeout: Option<Duration>,
+    /// Further functions to sandbox, on top of the ones marked sandboxed.
+    pub functions: Vec<String>,
+}
+
+/// Why a sandboxed call didn't finish.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SandboxFailure {
+    TimedOut(Duration),
+    /// The call panicked, with the panic message if it was a string.
+    Panicked(String),
+}
+
+impl fmt::Display for SandboxFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SandboxFailure::TimedOut(timeout) => {
+                write!(f, "The tool did not finish within {:?}", timeout)
+            }
+            SandboxFailure::Panicked(message) => write!(f, "The tool panicked: {}", message),
+        }
+    }
+}
+
+impl Error for SandboxFailure {}
+
+impl Sandbox {
+    pub fn new() -> Self {
+        Sandbox::default()
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_function(mut self, name: impl Into<String>) -> Self {
+        self.functions.push(name.into());
+        self
+    }
+
+    /// Whether calls to `name` run in the sandbox. `sandboxed` is whether the function is
+    /// marked `#[func_enums(sandboxed)]`.
+    pub fn covers(&self, name: &str, sandboxed: bool) -> bool {
+        sandboxed || self.functions.iter().any(|function| function == name)
+    }
+
+    /// Runs `future` as a task of its own, under the timeout if there is one.
+    pub async fn run<F, T>(&self, future: F) -> Result<T, SandboxFailure>
+    where
+        F: Future<Output = T> + Send + 'static,
+        T: Send + 'static,
+    {
+        // Aborted when dropped, so that a call given up on by its timeout doesn't keep running.
+        let mut handle = AbortOnDrop(tokio::spawn(future));
+
+        let joined = match self.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, &mut handle.0).await {
+                Ok(joined) => joined,
+                Err(_) => return Err(SandboxFailure::TimedOut(timeout)),
+            },
+            None => (&mut handle.0).await,
+        };
+
+        joined.map_err(|e| {
+            if e.is_panic() {
+                SandboxFailure::Panicked(panic_message(e.into_panic()))
+            } else {
+                SandboxFailure::Panicked("The task was cancelled".to_string())
+            }
+        })
+    }
+}
+
+/// Runs the future of a tool call, in the current config's sandbox if it covers the
+/// function and under its timeout if it has one. `sandboxed` is whether the function is marked
+/// `#[func_enums(sandboxed)]` and `timeout` is the one declared with `timeout_ms`. A sandbox
+/// failure, timeout or cancellation is recorded on `tool_call_result` and returned as the
+/// error.
+pub async fn run_tool_future<F, T>(
+    tool_call_result: &mut ToolCallResult,
+    sandboxed: bool,
+    timeout: Option<Duration>,
+    future: F,
+) -> Result<T, Box<dyn Error + Send + Sync + 'static>>
+where
+    F: Future<Output = Result<T, Box<dyn Error + Send + Sync + 'static>>> + Send + 'static,
+    T: Send + 'static,
+{
+    let timeout = current_tool_timeout(&tool_call_result.name, timeout);
+    let output = run_unless_cancelled(
+        current_cancellation_token().as_ref(),
+        run_with_timeout(
+            timeout.as_ref(),
+            current_sandbox().as_ref(),
+            tool_call_result,
+            sandboxed,
+            future,
+        ),
+    )
+    .await;
+    tool_call_result.cancelled = was_cancelled(&output);
+    output
+}
+
+pub(crate) fn current_sandbox() -> Option<Sandbox> {
+    RunConfig::with_current(|config| config.sandbox.clone()).flatten()
+}
+
+pub(crate) async fn run_in_sandbox<F, T>(
+    sandbox: Option<&Sandbox>,
+    tool_call_result: &mut ToolCallResult,
+    sandboxed: bool,
+    future: F,
+) -> Result<T, Box<dyn Error + Send + Sync + 'static>>
+where
+    F: Future<Output = Result<T, Box<dyn Error + Send + Sync + 'static>>> + Send + 'static,
+    T: Send + 'static,
+{
+    match sandbox {
+        Some(sandbox) if sandbox.covers(&tool_call_result.name, sandboxed) => {
+            match sandbox.run(future).await {
+                Ok(output) => output,
+                Err(failure) => {
+                    tool_call_result.sandbox_failure = Some(failure.clone());
+                    Err(Box::new(failure))
+                }
+            }
+        }
+        _ => future.await,
+    }
+}
+
+struct AbortOnDrop<T>(tokio::task::JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "The panic payload is not a string".to_string(),
+        },
+    }
+}
@@ -0,0 +1,197 @@
+use async_openai::types::ChatCompletionMessageToolCall;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+/// Scheduling hints for a tool, used by `ToolCallQueue` to decide what runs first.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ToolPriority {
+    /// Calls with a higher priority run first.
+    pub priority: i32,
+    /// Among calls with the same priority, cheaper calls run first. The unit is up to you, as
+    /// long as it is the same for every tool.
+    pub estimated_cost: f64,
+}
+
+/// A queue of pending tool calls that hands them out by priority instead of arrival order.
+///
+/// Calls are ordered by their tool's `ToolPriority`: highest priority first, then lowest
+/// estimated cost, then the order they were pushed in. Tools without an entry get the default
+/// priority of `0` and cost of `0.0`. Long running agents can keep pushing calls across turns
+/// so that cheap, unblocking tools don't wait behind expensive ones.
+#[derive(Clone, Debug, Default)]
+pub struct ToolCallQueue {
+    priorities: HashMap<String, ToolPriority>,
+    heap: BinaryHeap<QueuedToolCall>,
+    pushed: u64,
+}
+
+impl ToolCallQueue {
+    pub fn new(priorities: HashMap<String, ToolPriority>) -> Self {
+        ToolCallQueue {
+            priorities,
+            heap: BinaryHeap::new(),
+            pushed: 0,
+        }
+    }
+
+    /// Sets the scheduling hints for a tool. They apply to calls pushed from now on.
+    pub fn set_priority(&mut self, tool_name: &str, priority: ToolPriority) {
+        self.priorities.insert(tool_name.to_string(), priority);
+    }
+
+    pub fn push(&mut self, tool_call: ChatCompletionMessageToolCall) {
+        let priority = self
+            .priorities
+            .get(&tool_call.function.name)
+            .copied()
+            .unwrap_or_default();
+
+        self.heap.push(QueuedToolCall {
+            priority,
+            sequence: self.pushed,
+            tool_call,
+        });
+        self.pushed += 1;
+    }
+
+    /// Removes and returns the tool call that should run next.
+    pub fn pop(&mut self) -> Option<ChatCompletionMessageToolCall> {
+        self.heap.pop().map(|queued| queued.tool_call)
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+}
+
+impl Extend<ChatCompletionMessageToolCall> for ToolCallQueue {
+    fn extend<I: IntoIterator<Item = ChatCompletionMessageToolCall>>(&mut self, iter: I) {
+        for tool_call in iter {
+            self.push(tool_call);
+        }
+    }
+}
+
+impl Iterator for ToolCallQueue {
+    type Item = ChatCompletionMessageToolCall;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.pop()
+    }
+}
+
+#[derive(Clone, Debug)]
+struct QueuedToolCall {
+    priority: ToolPriority,
+    sequence: u64,
+    tool_call: ChatCompletionMessageToolCall,
+}
+
+// BinaryHeap is a max-heap, so "greater" means "runs sooner".
+impl Ord for QueuedToolCall {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .priority
+            .cmp(&other.priority.priority)
+            .then_with(|| {
+                other
+                    .priority
+                    .estimated_cost
+                    .total_cmp(&self.priority.estimated_cost)
+            })
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+impl PartialOrd for QueuedToolCall {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for QueuedToolCall {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for QueuedToolCall {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::{ChatCompletionToolType, FunctionCall};
+
+    fn tool_call(id: &str, name: &str) -> ChatCompletionMessageToolCall {
+        ChatCompletionMessageToolCall {
+            id: id.to_string(),
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionCall {
+                name: name.to_string(),
+                arguments: "{}".to_string(),
+            },
+        }
+    }
+
+    fn priority(priority: i32, estimated_cost: f64) -> ToolPriority {
+        ToolPriority {
+            priority,
+            estimated_cost,
+        }
+    }
+
+    fn ids(queue: ToolCallQueue) -> Vec<String> {
+        queue.map(|tool_call| tool_call.id).collect()
+    }
+
+    #[test]
+    fn higher_priorities_run_first() {
+        let mut queue = ToolCallQueue::new(HashMap::from([
+            ("Urgent".to_string(), priority(10, 100.0)),
+            ("Later".to_string(), priority(-1, 0.0)),
+        ]));
+        queue.extend([
+            tool_call("1", "Later"),
+            tool_call("2", "Unlisted"),
+            tool_call("3", "Urgent"),
+        ]);
+
+        assert_eq!(ids(queue), ["3", "2", "1"]);
+    }
+
+    #[test]
+    fn cheaper_calls_run_first_within_a_priority() {
+        let mut queue = ToolCallQueue::default();
+        queue.set_priority("Expensive", priority(1, 5.0));
+        queue.set_priority("Cheap", priority(1, 0.5));
+        queue.extend([tool_call("1", "Expensive"), tool_call("2", "Cheap")]);
+
+        assert_eq!(ids(queue), ["2", "1"]);
+    }
+
+    #[test]
+    fn ties_keep_the_order_calls_were_pushed_in() {
+        let mut queue = ToolCallQueue::default();
+        queue.extend([tool_call("1", "A"), tool_call("2", "B")]);
+        queue.pop();
+        queue.extend([tool_call("3", "A"), tool_call("4", "B")]);
+
+        assert_eq!(queue.len(), 3);
+        assert_eq!(ids(queue), ["2", "3", "4"]);
+    }
+
+    #[test]
+    fn priorities_apply_to_calls_pushed_after_they_are_set() {
+        let mut queue = ToolCallQueue::default();
+        queue.push(tool_call("1", "A"));
+        queue.set_priority("A", priority(1, 0.0));
+        queue.push(tool_call("2", "B"));
+        queue.push(tool_call("3", "A"));
+
+        assert_eq!(ids(queue), ["3", "1", "2"]);
+    }
+}
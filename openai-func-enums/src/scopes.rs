@@ -0,0 +1,24 @@
+use std::error::Error;
+use std::fmt;
+
+/// Why a call was rejected under `RunConfig::granted_scopes`: the function needs scopes the
+/// caller wasn't granted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MissingScopes {
+    pub tool: String,
+    /// The required scopes that weren't granted, in the order the tool declares them.
+    pub missing: Vec<String>,
+}
+
+impl fmt::Display for MissingScopes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The function {} needs the scopes {}, which were not granted",
+            self.tool,
+            self.missing.join(", ")
+        )
+    }
+}
+
+impl Error for MissingScopes {}
@@ -0,0 +1,88 @@
+use crate::{
+    compress_description, count_tokens, DescriptionCatalog, DescriptionManifest, LocalizedFunction,
+    RunConfig,
+};
+use async_openai::error::OpenAIError;
+use async_openai::types::{
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+    CreateChatCompletionRequestArgs,
+};
+
+/// Shortens every description in `manifest` that is longer than `max_tokens`, and returns the
+/// short ones as a catalog to put on `RunConfig::descriptions`. Descriptions that fit are left
+/// out of it.
+///
+/// With a `model`, the model writes the short version, and `compress_description` trims
+/// whatever is still too long. Without one, `compress_description` does it all and no request
+/// is made. This is what `cargo func-enums compress` runs.
+pub async fn shorten_descriptions(
+    manifest: &DescriptionManifest,
+    max_tokens: usize,
+    model: Option<&str>,
+    config: &RunConfig,
+) -> Result<DescriptionCatalog, OpenAIError> {
+    let mut catalog = DescriptionCatalog::default();
+
+    for function in &manifest.functions {
+        if count_tokens(&function.description) <= max_tokens {
+            continue;
+        }
+
+        let short = match model {
+            Some(model) => {
+                let short =
+                    shorten_with_model(&function.description, max_tokens, model, config).await?;
+                compress_description(&short, max_tokens, count_tokens)
+            }
+            None => compress_description(&function.description, max_tokens, count_tokens),
+        };
+
+        catalog.functions.insert(
+            function.name.clone(),
+            LocalizedFunction {
+                description: Some(short),
+                ..Default::default()
+            },
+        );
+    }
+
+    Ok(catalog)
+}
+
+async fn shorten_with_model(
+    description: &str,
+    max_tokens: usize,
+    model: &str,
+    config: &RunConfig,
+) -> Result<String, OpenAIError> {
+    let instructions = format!(
+        "Shorten the description of a function an assistant can call to at most {} tokens. \
+        Keep what the function does and when to use it. Answer with the shortened description \
+        only.",
+        max_tokens
+    );
+
+    let request = CreateChatCompletionRequestArgs::default()
+        .model(model)
+        .temperature(0.0)
+        .messages(vec![
+            ChatCompletionRequestSystemMessageArgs::default()
+                .content(instructions)
+                .build()?
+                .into(),
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(description)
+                .build()?
+                .into(),
+        ])
+        .build()?;
+
+    let response = config.create_chat_completion(request).await?;
+    Ok(response
+        .choices
+        .first()
+        .and_then(|choice| choice.message.content.as_deref())
+        .unwrap_or(description)
+        .trim()
+        .to_string())
+}
@@ -0,0 +1,77 @@
+use crate::{CommandError, RunConfig};
+use std::any::{type_name, Any};
+use std::fmt;
+use std::sync::Arc;
+
+/// Application state, like database pools, HTTP clients and settings, that the tools of a run
+/// share. It is set with `RunConfig::state` and handed to `RunCommand::run` as the type the
+/// `ToolSet` declares with `#[func_enums(state = "AppState")]`.
+///
+/// ```
+/// # use openai_func_enums::{RunConfig, RunState};
+/// # struct AppState { pool: Vec<String> }
+/// # let pool = Vec::new();
+/// let config = RunConfig {
+///     state: Some(RunState::new(AppState { pool })),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Clone)]
+pub struct RunState {
+    state: Arc<dyn Any + Send + Sync>,
+    type_name: &'static str,
+}
+
+impl RunState {
+    pub fn new<S: Send + Sync + 'static>(state: S) -> Self {
+        RunState::from_arc(Arc::new(state))
+    }
+
+    /// State that is already shared, so the caller keeps a handle on it.
+    pub fn from_arc<S: Send + Sync + 'static>(state: Arc<S>) -> Self {
+        RunState {
+            state,
+            type_name: type_name::<S>(),
+        }
+    }
+
+    /// The state as `S`, or `None` if it is of another type.
+    pub fn get<S: Send + Sync + 'static>(&self) -> Option<Arc<S>> {
+        self.state.clone().downcast().ok()
+    }
+}
+
+impl fmt::Debug for RunState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("RunState").field(&self.type_name).finish()
+    }
+}
+
+/// The state of the run whose tool is being executed, for runtime `Tool`s and anything else
+/// that isn't handed it as a parameter.
+///
+/// # Errors
+/// Returns a `CommandError` outside of a run, when the run has no state, or when its state is
+/// not an `S`.
+pub fn current_state<S: Send + Sync + 'static>() -> Result<Arc<S>, CommandError> {
+    RunConfig::with_current(state_of::<S>).unwrap_or_else(|| Err(missing_state::<S>()))
+}
+
+/// The state of `config` as `S`.
+///
+/// # Errors
+/// Returns a `CommandError` when `config` has no state, or when its state is not an `S`.
+pub fn state_of<S: Send + Sync + 'static>(config: &RunConfig) -> Result<Arc<S>, CommandError> {
+    config
+        .state
+        .as_ref()
+        .and_then(RunState::get)
+        .ok_or_else(missing_state::<S>)
+}
+
+fn missing_state<S>() -> CommandError {
+    CommandError::new(&format!(
+        "The run has no state of type {}, see RunConfig::state",
+        type_name::<S>()
+    ))
+}
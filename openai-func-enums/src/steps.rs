@@ -0,0 +1,175 @@
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::VecDeque;
+use std::future::Future;
+
+/// One entry of a multi-step prompt list, along with the earlier steps it has to wait for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    /// The prompt with any dependency marker removed.
+    pub prompt: String,
+    /// Indices of the earlier steps whose results this step needs. Indices that don't point at
+    /// an earlier step are ignored.
+    pub depends_on: Vec<usize>,
+}
+
+impl Step {
+    /// Turns a prompt list, like the one a `CallMultiStep` style variant receives, into steps.
+    ///
+    /// Each prompt may start with a marker that says what it depends on:
+    /// - `[independent]` doesn't depend on anything and can run right away.
+    /// - `[after 1, 3]` waits for the first and third steps (counting from 1).
+    ///
+    /// A prompt without a marker depends on the one before it, which keeps the existing
+    /// behavior of running the list in order.
+    pub fn parse_list(prompts: &[String]) -> Vec<Step> {
+        prompts
+            .iter()
+            .enumerate()
+            .map(|(index, prompt)| Step::parse(index, prompt))
+            .collect()
+    }
+
+    fn parse(index: usize, prompt: &str) -> Step {
+        let trimmed = prompt.trim_start();
+
+        if let Some(rest) = strip_marker(trimmed, "[independent]") {
+            return Step {
+                prompt: rest.to_string(),
+                depends_on: Vec::new(),
+            };
+        }
+
+        if let Some(rest) = strip_marker(trimmed, "[after") {
+            if let Some((steps, rest)) = rest.split_once(']') {
+                let depends_on = steps
+                    .split(',')
+                    .filter_map(|step| step.trim().parse::<usize>().ok())
+                    .filter(|step| *step >= 1 && *step <= index)
+                    .map(|step| step - 1)
+                    .collect();
+
+                return Step {
+                    prompt: rest.trim_start().to_string(),
+                    depends_on,
+                };
+            }
+        }
+
+        Step {
+            prompt: prompt.to_string(),
+            depends_on: if index == 0 {
+                Vec::new()
+            } else {
+                vec![index - 1]
+            },
+        }
+    }
+}
+
+fn strip_marker<'a>(prompt: &'a str, marker: &str) -> Option<&'a str> {
+    match prompt.get(..marker.len()) {
+        Some(prefix) if prefix.eq_ignore_ascii_case(marker) => {
+            Some(prompt[marker.len()..].trim_start())
+        }
+        _ => None,
+    }
+}
+
+/// Runs a list of steps, starting each one as soon as the steps it depends on have finished.
+///
+/// Awaiting each nested run in turn leaves a lot of time on the table when some steps don't
+/// need each other's results. The executor keeps up to `max_concurrency` steps in flight and
+/// starts the next ready step whenever one finishes.
+#[derive(Clone, Debug)]
+pub struct StepExecutor {
+    pub max_concurrency: usize,
+}
+
+impl Default for StepExecutor {
+    fn default() -> Self {
+        StepExecutor { max_concurrency: 4 }
+    }
+}
+
+impl StepExecutor {
+    pub fn new(max_concurrency: usize) -> Self {
+        StepExecutor {
+            max_concurrency: max_concurrency.max(1),
+        }
+    }
+
+    /// Runs every step with `run_step` and returns their results in step order.
+    ///
+    /// `run_step` is called with the step's index, its prompt, and the results of the steps it
+    /// depends on, in the order they are listed in `depends_on`.
+    ///
+    /// # Errors
+    /// Returns the first error a step returns. Steps that are still running at that point are
+    /// dropped and no further steps are started.
+    pub async fn run<F, Fut, E>(
+        &self,
+        steps: &[Step],
+        mut run_step: F,
+    ) -> Result<Vec<Option<String>>, E>
+    where
+        F: FnMut(usize, String, Vec<Option<String>>) -> Fut,
+        Fut: Future<Output = Result<Option<String>, E>>,
+    {
+        let dependencies: Vec<Vec<usize>> = steps
+            .iter()
+            .enumerate()
+            .map(|(index, step)| {
+                step.depends_on
+                    .iter()
+                    .copied()
+                    .filter(|dependency| *dependency < index)
+                    .collect()
+            })
+            .collect();
+
+        let mut waiting_on: Vec<usize> = dependencies.iter().map(Vec::len).collect();
+        let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); steps.len()];
+        for (index, step_dependencies) in dependencies.iter().enumerate() {
+            for dependency in step_dependencies {
+                dependents[*dependency].push(index);
+            }
+        }
+
+        let mut ready: VecDeque<usize> = (0..steps.len())
+            .filter(|index| waiting_on[*index] == 0)
+            .collect();
+        let mut results: Vec<Option<String>> = vec![None; steps.len()];
+        let mut in_flight = FuturesUnordered::new();
+        let max_concurrency = self.max_concurrency.max(1);
+
+        loop {
+            while in_flight.len() < max_concurrency {
+                let Some(index) = ready.pop_front() else {
+                    break;
+                };
+
+                let inputs = dependencies[index]
+                    .iter()
+                    .map(|dependency| results[*dependency].clone())
+                    .collect();
+                let future = run_step(index, steps[index].prompt.clone(), inputs);
+                in_flight.push(async move { (index, future.await) });
+            }
+
+            let Some((index, result)) = in_flight.next().await else {
+                break;
+            };
+
+            results[index] = result?;
+
+            for dependent in &dependents[index] {
+                waiting_on[*dependent] -= 1;
+                if waiting_on[*dependent] == 0 {
+                    ready.push_back(*dependent);
+                }
+            }
+        }
+
+        Ok(results)
+    }
+}
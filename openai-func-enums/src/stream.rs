@@ -0,0 +1,224 @@
+use async_openai::error::OpenAIError;
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionMessageToolCallChunk,
+    ChatCompletionResponseStream, ChatCompletionToolType, CompletionUsage, FinishReason,
+    FunctionCall,
+};
+use futures::StreamExt;
+use std::collections::VecDeque;
+use tokio::sync::mpsc;
+
+/// Reassembles tool calls from a streamed chat completion.
+///
+/// The deltas for a tool call all arrive before the deltas of the next one, so a call is
+/// complete as soon as a delta with a higher index shows up or the stream ends.
+/// `next_tool_call` hands each call back at that point, which lets the caller start executing
+/// it while the rest of the response is still streaming in. Some servers go back to a call
+/// after starting the next one. Those deltas still go to the call as long as it hasn't been
+/// handed back.
+pub struct ToolCallStream {
+    stream: ChatCompletionResponseStream,
+    pending: Option<(u32, ChatCompletionMessageToolCall)>,
+    completed: VecDeque<(u32, ChatCompletionMessageToolCall)>,
+    content: String,
+    finish_reason: Option<FinishReason>,
+    usage: Option<CompletionUsage>,
+    text_sender: Option<mpsc::Sender<String>>,
+    finished: bool,
+}
+
+impl ToolCallStream {
+    pub fn new(stream: ChatCompletionResponseStream) -> Self {
+        ToolCallStream {
+            stream,
+            pending: None,
+            completed: VecDeque::new(),
+            content: String::new(),
+            finish_reason: None,
+            usage: None,
+            text_sender: None,
+            finished: false,
+        }
+    }
+
+    /// Sends every piece of text the model streams to `sender` as it arrives, while tool calls
+    /// are reassembled as usual.
+    pub fn with_text_sender(mut self, sender: mpsc::Sender<String>) -> Self {
+        self.text_sender = Some(sender);
+        self
+    }
+
+    /// Waits for the next tool call whose arguments have finished streaming.
+    ///
+    /// # Returns
+    /// - `Ok(Some(tool_call))` for each completed tool call, in the order the model emitted them.
+    /// - `Ok(None)` once the stream has ended and every tool call has been returned.
+    ///
+    /// # Errors
+    /// Returns the `OpenAIError` of the first stream item that failed.
+    pub async fn next_tool_call(
+        &mut self,
+    ) -> Result<Option<ChatCompletionMessageToolCall>, OpenAIError> {
+        loop {
+            if let Some((_, tool_call)) = self.completed.pop_front() {
+                return Ok(Some(tool_call));
+            }
+
+            if self.finished {
+                return Ok(None);
+            }
+
+            match self.stream.next().await {
+                Some(response) => {
+                    let response = response?;
+                    if response.usage.is_some() {
+                        self.usage = response.usage;
+                    }
+                    for choice in response.choices {
+                        if let Some(content) = choice.delta.content {
+                            self.content.push_str(&content);
+                            if let Some(text_sender) = &self.text_sender {
+                                let _ = text_sender.send(content).await;
+                            }
+                        }
+                        if choice.finish_reason.is_some() {
+                            self.finish_reason = choice.finish_reason;
+                        }
+                        for chunk in choice.delta.tool_calls.unwrap_or_default() {
+                            self.push_chunk(chunk);
+                        }
+                    }
+                }
+                None => {
+                    self.finished = true;
+                    if let Some(pending) = self.pending.take() {
+                        self.completed.push_back(pending);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns `true` once the stream has ended and there are no tool calls left to hand out.
+    /// Checking this right after `next_tool_call` returns a call tells you whether it was the
+    /// last one in the response.
+    pub fn is_exhausted(&self) -> bool {
+        self.finished && self.completed.is_empty() && self.pending.is_none()
+    }
+
+    /// The text the model has streamed so far, alongside or instead of tool calls.
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    /// Why the model stopped, once the chunk that says so has arrived.
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.finish_reason
+    }
+
+    /// The tokens the response used, once the chunk that reports them has arrived. That is the
+    /// last one, and only sent when the request asks for it with `stream_options`.
+    pub fn usage(&self) -> Option<&CompletionUsage> {
+        self.usage.as_ref()
+    }
+
+    fn push_chunk(&mut self, chunk: ChatCompletionMessageToolCallChunk) {
+        let is_pending = matches!(&self.pending, Some((index, _)) if *index == chunk.index);
+        let is_completed = self
+            .completed
+            .iter()
+            .any(|(index, _)| *index == chunk.index);
+
+        if !is_pending && !is_completed {
+            if let Some(pending) = self.pending.take() {
+                self.completed.push_back(pending);
+            }
+
+            self.pending = Some((
+                chunk.index,
+                ChatCompletionMessageToolCall {
+                    id: String::new(),
+                    r#type: ChatCompletionToolType::Function,
+                    function: FunctionCall {
+                        name: String::new(),
+                        arguments: String::new(),
+                    },
+                },
+            ));
+        }
+
+        let tool_call = match self.pending.as_mut() {
+            Some((index, tool_call)) if *index == chunk.index => tool_call,
+            _ => match self
+                .completed
+                .iter_mut()
+                .find(|(index, _)| *index == chunk.index)
+            {
+                Some((_, tool_call)) => tool_call,
+                None => return,
+            },
+        };
+
+        if let Some(id) = chunk.id {
+            tool_call.id.push_str(&id);
+        }
+
+        if let Some(function) = chunk.function {
+            if let Some(name) = function.name {
+                tool_call.function.name.push_str(&name);
+            }
+            if let Some(arguments) = function.arguments {
+                tool_call.function.arguments.push_str(&arguments);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::FunctionCallStream;
+
+    fn chunk(
+        index: u32,
+        id: Option<&str>,
+        name: Option<&str>,
+        arguments: &str,
+    ) -> ChatCompletionMessageToolCallChunk {
+        ChatCompletionMessageToolCallChunk {
+            index,
+            id: id.map(str::to_string),
+            r#type: id.map(|_| ChatCompletionToolType::Function),
+            function: Some(FunctionCallStream {
+                name: name.map(str::to_string),
+                arguments: Some(arguments.to_string()),
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn chunks_are_put_back_together_by_index() {
+        let mut stream = ToolCallStream::new(Box::pin(futures::stream::empty()));
+
+        stream.push_chunk(chunk(0, Some("call_"), Some("Get"), ""));
+        stream.push_chunk(chunk(0, Some("1"), Some("Weather"), "{\"location\":"));
+        stream.push_chunk(chunk(1, Some("call_2"), Some("ListOrders"), "{}"));
+        // Back to the first call after the second one started.
+        stream.push_chunk(chunk(0, None, None, "\"Boston\"}"));
+        assert!(!stream.is_exhausted());
+
+        let first = stream.next_tool_call().await.unwrap().unwrap();
+        assert_eq!(first.id, "call_1");
+        assert_eq!(first.function.name, "GetWeather");
+        assert_eq!(first.function.arguments, r#"{"location":"Boston"}"#);
+        assert!(!stream.is_exhausted());
+
+        let second = stream.next_tool_call().await.unwrap().unwrap();
+        assert_eq!(second.id, "call_2");
+        assert_eq!(second.function.name, "ListOrders");
+        assert_eq!(second.function.arguments, "{}");
+        assert!(stream.is_exhausted());
+
+        assert!(stream.next_tool_call().await.unwrap().is_none());
+    }
+}
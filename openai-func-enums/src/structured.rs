@@ -0,0 +1,67 @@
+use crate::{make_schema_strict, CommandError, ToolArgs};
+use async_openai::types::{CreateChatCompletionResponse, ResponseFormat, ResponseFormatJsonSchema};
+use serde::de::DeserializeOwned;
+use std::error::Error;
+
+/// A `json_schema` response format built from the schema of `T`, made strict so that the
+/// answer is guaranteed to match it. See `CommandsGPT::run_structured`.
+pub fn json_schema_response_format<T: ToolArgs>() -> ResponseFormat {
+    let mut schema = T::parameters_with_token_count().0.clone();
+    make_schema_strict(&mut schema);
+
+    ResponseFormat::JsonSchema {
+        json_schema: ResponseFormatJsonSchema {
+            description: None,
+            name: response_format_name::<T>(),
+            schema: Some(schema),
+            strict: Some(true),
+        },
+    }
+}
+
+/// Deserializes the answer to a request made with `json_schema_response_format`.
+///
+/// # Errors
+/// Returns an error if the model refused, or if the answer is missing or isn't a `T`, which
+/// can happen when the response was cut off.
+pub fn structured_response<T: DeserializeOwned>(
+    response: &CreateChatCompletionResponse,
+) -> Result<T, Box<dyn Error + Send + Sync + 'static>> {
+    let message = &response
+        .choices
+        .first()
+        .ok_or_else(|| CommandError::new("The response has no choices"))?
+        .message;
+
+    if let Some(refusal) = &message.refusal {
+        return Err(Box::new(CommandError::new(&format!(
+            "The model refused to answer: {}",
+            refusal
+        ))));
+    }
+
+    let content = message
+        .content
+        .as_deref()
+        .ok_or_else(|| CommandError::new("The response has no content"))?;
+
+    serde_json::from_str(content).map_err(|e| {
+        Box::new(CommandError::new(&format!(
+            "The response doesn't match the schema: {}",
+            e
+        ))) as Box<dyn Error + Send + Sync + 'static>
+    })
+}
+
+/// The name of `T` without its path, with anything OpenAI doesn't allow in a name dropped.
+fn response_format_name<T>() -> String {
+    let type_name = std::any::type_name::<T>();
+    let type_name = type_name.split('<').next().unwrap_or(type_name);
+    type_name
+        .rsplit("::")
+        .next()
+        .unwrap_or(type_name)
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+        .collect()
+}
@@ -0,0 +1,179 @@
+use crate::{count_tokens, CommandError};
+use std::collections::HashMap;
+use std::fmt::Display;
+
+/// A system message with `{name}` placeholders that are filled in when it is rendered.
+///
+/// `render` returns the message together with its token count, which is exactly what the
+/// `custom_system_message` argument of a generated `run` expects:
+///
+/// ```
+/// # use openai_func_enums::SystemTemplate;
+/// # fn main() -> Result<(), openai_func_enums::CommandError> {
+/// let template = SystemTemplate::new("You are {role}. Answer in {language}.");
+/// let system_message = template.render([("role", "a travel agent"), ("language", "French")])?;
+/// assert_eq!(system_message.0, "You are a travel agent. Answer in French.");
+///
+/// // CommandsGPT::run(&prompt, model_name, None, None, Some(system_message), ...).await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Use `{{` and `}}` for literal braces.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SystemTemplate {
+    template: String,
+}
+
+impl SystemTemplate {
+    pub fn new(template: impl Into<String>) -> Self {
+        SystemTemplate {
+            template: template.into(),
+        }
+    }
+
+    /// The names of the placeholders in the template, in the order they first appear.
+    pub fn variables(&self) -> Vec<String> {
+        let mut variables: Vec<String> = Vec::new();
+
+        for segment in self.segments() {
+            if let Segment::Variable(name) = segment {
+                if !variables.iter().any(|variable| variable == name) {
+                    variables.push(name.to_string());
+                }
+            }
+        }
+
+        variables
+    }
+
+    /// Fills in the placeholders and counts the tokens of the result.
+    ///
+    /// # Errors
+    /// Returns a `CommandError` if the template has a placeholder no value was given for, or
+    /// an unclosed `{`.
+    pub fn render<K, V>(
+        &self,
+        variables: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<(String, usize), CommandError>
+    where
+        K: Into<String>,
+        V: Display,
+    {
+        let variables: HashMap<String, String> = variables
+            .into_iter()
+            .map(|(name, value)| (name.into(), value.to_string()))
+            .collect();
+
+        let mut message = String::with_capacity(self.template.len());
+
+        for segment in self.segments() {
+            match segment {
+                Segment::Text(text) => message.push_str(text),
+                Segment::Variable(name) => match variables.get(name) {
+                    Some(value) => message.push_str(value),
+                    None => {
+                        return Err(CommandError::new(&format!(
+                            "No value was given for {{{}}} in the system template",
+                            name
+                        )))
+                    }
+                },
+                Segment::Unclosed => {
+                    return Err(CommandError::new(
+                        "The system template has a '{' that is never closed",
+                    ))
+                }
+            }
+        }
+
+        let tokens = count_tokens(&message);
+        Ok((message, tokens))
+    }
+
+    fn segments(&self) -> Vec<Segment<'_>> {
+        let mut segments = Vec::new();
+        let mut rest = self.template.as_str();
+
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix("{{") {
+                segments.push(Segment::Text("{"));
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix("}}") {
+                segments.push(Segment::Text("}"));
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix('{') {
+                match after.find('}') {
+                    Some(end) => {
+                        segments.push(Segment::Variable(after[..end].trim()));
+                        rest = &after[end + 1..];
+                    }
+                    None => {
+                        segments.push(Segment::Unclosed);
+                        break;
+                    }
+                }
+            } else {
+                let end = rest.find(['{', '}']).unwrap_or(rest.len()).max(1);
+                segments.push(Segment::Text(&rest[..end]));
+                rest = &rest[end..];
+            }
+        }
+
+        segments
+    }
+}
+
+enum Segment<'a> {
+    Text(&'a str),
+    Variable(&'a str),
+    Unclosed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_are_filled_in_everywhere_they_appear() {
+        let template = SystemTemplate::new("{ name } helps {team}. Ask {name} {{politely}}.");
+        let (message, tokens) = template
+            .render([("name", "Ada"), ("team", "support")])
+            .unwrap();
+
+        assert_eq!(message, "Ada helps support. Ask Ada {politely}.");
+        assert_eq!(tokens, count_tokens(&message));
+        assert_eq!(template.variables(), ["name", "team"]);
+    }
+
+    #[test]
+    fn values_can_be_anything_displayable() {
+        let template = SystemTemplate::new("Limit: {limit}");
+
+        assert_eq!(template.render([("limit", 20)]).unwrap().0, "Limit: 20");
+    }
+
+    #[test]
+    fn missing_values_and_unclosed_braces_fail() {
+        let template = SystemTemplate::new("Hi {name}");
+        let error = template
+            .render(Vec::<(&str, &str)>::new())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("{name}"), "{}", error);
+
+        assert!(SystemTemplate::new("Hi {name")
+            .render([("name", "Ada")])
+            .is_err());
+    }
+
+    #[test]
+    fn a_lone_closing_brace_is_text() {
+        let template = SystemTemplate::new("a } b");
+
+        assert_eq!(
+            template.render(Vec::<(&str, &str)>::new()).unwrap().0,
+            "a } b"
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use crate::sandbox::run_in_sandbox;
+use crate::{RunConfig, Sandbox, ToolCallResult};
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+/// A tool call that ran past its timeout. Timeouts are declared with
+/// `#[func_enums(timeout_ms = 5000)]` or set with `RunConfig::tool_timeouts`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ToolTimedOut {
+    pub tool: String,
+    pub timeout: Duration,
+}
+
+impl fmt::Display for ToolTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} did not finish within {:?}", self.tool, self.timeout)
+    }
+}
+
+impl Error for ToolTimedOut {}
+
+/// The timeout of one call and what to record when it runs out.
+#[derive(Clone, Debug)]
+pub(crate) struct ToolTimeout {
+    duration: Duration,
+    fallback: Option<String>,
+}
+
+/// The timeout of calls to `name` under the current config. `declared` is the one from
+/// `#[func_enums(timeout_ms = ...)]`.
+pub(crate) fn current_tool_timeout(name: &str, declared: Option<Duration>) -> Option<ToolTimeout> {
+    let (configured, fallback) = RunConfig::with_current(|config| {
+        (
+            config.tool_timeouts.get(name).copied(),
+            config.timeout_fallback.clone(),
+        )
+    })
+    .unwrap_or_default();
+
+    Some(ToolTimeout {
+        duration: configured.or(declared)?,
+        fallback,
+    })
+}
+
+/// `run_in_sandbox`, given up on when `timeout` runs out. The call is cancelled, and a
+/// `ToolTimedOut` is recorded on `tool_call_result` and returned as the error.
+pub(crate) async fn run_with_timeout<F, T>(
+    timeout: Option<&ToolTimeout>,
+    sandbox: Option<&Sandbox>,
+    tool_call_result: &mut ToolCallResult,
+    sandboxed: bool,
+    future: F,
+) -> Result<T, Box<dyn Error + Send + Sync + 'static>>
+where
+    F: Future<Output = Result<T, Box<dyn Error + Send + Sync + 'static>>> + Send + 'static,
+    T: Send + 'static,
+{
+    let Some(timeout) = timeout else {
+        return run_in_sandbox(sandbox, tool_call_result, sandboxed, future).await;
+    };
+
+    let outcome = tokio::time::timeout(
+        timeout.duration,
+        run_in_sandbox(sandbox, tool_call_result, sandboxed, future),
+    )
+    .await;
+
+    match outcome {
+        Ok(output) => output,
+        Err(_) => {
+            let timed_out = ToolTimedOut {
+                tool: tool_call_result.name.clone(),
+                timeout: timeout.duration,
+            };
+            tool_call_result.timed_out = Some(timed_out.clone());
+            tool_call_result.output = timeout.fallback.clone();
+            Err(Box::new(timed_out))
+        }
+    }
+}
@@ -0,0 +1,431 @@
+use crate::cancel::{current_cancellation_token, run_unless_cancelled, was_cancelled};
+use crate::events::{current_events, track_tool_call_with};
+use crate::sandbox::current_sandbox;
+use crate::sanitize::{current_sanitizer, sanitize_result};
+use crate::timeout::{current_tool_timeout, run_with_timeout, ToolTimeout};
+use crate::{
+    count_tokens, track_tool_call, ToolCallExecutionStrategy, ToolCallResult, ToolCallTask,
+    ToolOutput,
+};
+use crate::{CancellationToken, RunConfig, Sandbox};
+use async_openai::error::OpenAIError;
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionTool, ChatCompletionToolArgs,
+    ChatCompletionToolType, FunctionObjectArgs,
+};
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+/// A tool that is defined at runtime instead of as a variant of a `ToolSet` enum.
+///
+/// This is what plugin systems and anything else that only learns about its tools while
+/// running can use. Register tools with a `ToolRegistry` and put it on `RunConfig::tools` to
+/// offer them alongside the derived ones.
+#[async_trait]
+pub trait Tool: Send + Sync {
+    /// The function name the model calls the tool by. It must not clash with the name of a
+    /// derived tool.
+    fn name(&self) -> &str;
+
+    fn description(&self) -> &str;
+
+    /// The JSON schema of the tool's arguments.
+    fn parameters(&self) -> Value;
+
+    /// The scopes a caller needs to be granted to use the tool, see
+    /// `RunConfig::granted_scopes`.
+    fn scopes(&self) -> &[String] {
+        &[]
+    }
+
+    /// Whether the tool does heavy computation, which has its calls run on the runtime's
+    /// blocking threads under the `Parallel` strategy, like derived functions marked
+    /// `#[func_enums(cpu_bound)]`.
+    fn cpu_bound(&self) -> bool {
+        false
+    }
+
+    /// Runs the tool with the arguments the model sent.
+    async fn call(
+        &self,
+        arguments: Value,
+    ) -> Result<Value, Box<dyn std::error::Error + Send + Sync + 'static>>;
+}
+
+/// The arguments of a tool written as a function with `#[tool]`. Derive it with
+/// `#[derive(ToolArgs)]`.
+pub trait ToolArgs: DeserializeOwned + Send + 'static {
+    /// The JSON schema of the arguments and its token count.
+    fn parameters_with_token_count() -> &'static (Value, usize);
+}
+
+/// A set of runtime tools that generated `run_with_config` functions present and dispatch
+/// along with the tools derived from the enum.
+///
+/// Cloning a registry is cheap, the tools themselves are shared.
+#[derive(Clone, Default)]
+pub struct ToolRegistry {
+    tools: Vec<Arc<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        ToolRegistry::default()
+    }
+
+    /// Adds a tool, replacing any tool that was registered under the same name.
+    pub fn register(&mut self, tool: impl Tool + 'static) {
+        self.register_arc(Arc::new(tool));
+    }
+
+    /// Same as `register`, for tools that are already shared.
+    pub fn register_arc(&mut self, tool: Arc<dyn Tool>) {
+        self.remove(tool.name());
+        self.tools.push(tool);
+    }
+
+    /// Registers an async closure as a tool, see `FnTool`.
+    pub fn register_fn<A, F, Fut, R, E>(
+        &mut self,
+        name: impl Into<String>,
+        description: impl Into<String>,
+        parameters: Value,
+        function: F,
+    ) where
+        A: DeserializeOwned + Send + 'static,
+        F: Fn(A) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<R, E>> + Send + 'static,
+        R: Serialize + 'static,
+        E: Into<Box<dyn std::error::Error + Send + Sync + 'static>> + 'static,
+    {
+        self.register(FnTool::new(name, description, parameters, function));
+    }
+
+    /// Removes the tool that was registered under `name` and returns it.
+    pub fn remove(&mut self, name: &str) -> Option<Arc<dyn Tool>> {
+        let index = self.tools.iter().position(|tool| tool.name() == name)?;
+        Some(self.tools.remove(index))
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Tool>> {
+        self.tools.iter().find(|tool| tool.name() == name).cloned()
+    }
+
+    /// The scopes of the tool registered under `name`, empty if there is none.
+    pub fn scopes(&self, name: &str) -> Vec<String> {
+        self.get(name)
+            .map(|tool| tool.scopes().to_vec())
+            .unwrap_or_default()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.tools.iter().any(|tool| tool.name() == name)
+    }
+
+    /// The names of the registered tools, in the order they were registered.
+    pub fn names(&self) -> Vec<String> {
+        self.tools
+            .iter()
+            .map(|tool| tool.name().to_string())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tools.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    /// Builds the tool definitions to send to the model, along with their token count.
+    ///
+    /// The same filtering that applies to derived tools applies here: when `allowed_functions`
+    /// is a non-empty list, only tools named in it or in `required_functions` are included.
+    pub fn chat_completion_tools(
+        &self,
+        allowed_functions: Option<&[String]>,
+        required_functions: Option<&[String]>,
+    ) -> Result<(Vec<ChatCompletionTool>, usize), OpenAIError> {
+        let mut chat_completion_tools = Vec::new();
+        let mut total_tokens = 0;
+
+        for tool in &self.tools {
+            let is_allowed = match allowed_functions {
+                Some(allowed) if !allowed.is_empty() => {
+                    allowed.iter().any(|name| name == tool.name())
+                        || required_functions
+                            .unwrap_or_default()
+                            .iter()
+                            .any(|name| name == tool.name())
+                }
+                _ => true,
+            };
+
+            if !is_allowed {
+                continue;
+            }
+
+            let parameters = tool.parameters();
+            total_tokens += count_tokens(tool.name())
+                + count_tokens(tool.description())
+                + count_tokens(&parameters.to_string());
+
+            let function = FunctionObjectArgs::default()
+                .name(tool.name())
+                .description(tool.description())
+                .parameters(parameters)
+                .build()?;
+
+            chat_completion_tools.push(
+                ChatCompletionToolArgs::default()
+                    .r#type(ChatCompletionToolType::Function)
+                    .function(function)
+                    .build()?,
+            );
+        }
+
+        Ok((chat_completion_tools, total_tokens))
+    }
+
+    /// Runs a tool call against the registered tool of the same name.
+    ///
+    /// # Returns
+    /// `None` if no tool with that name is registered, otherwise the result of the call. A
+    /// string result becomes the output as is, anything else is serialized to JSON.
+    pub async fn call(&self, tool_call: &ChatCompletionMessageToolCall) -> Option<ToolCallResult> {
+        let tool = self.get(&tool_call.function.name)?;
+        let timeout = current_tool_timeout(&tool_call.function.name, None);
+        let call = async {
+            let mut tool_call_result = call_tool(
+                tool,
+                tool_call.clone(),
+                current_sandbox().as_ref(),
+                timeout.as_ref(),
+                current_cancellation_token().as_ref(),
+            )
+            .await;
+            sanitize_result(current_sanitizer().as_ref(), &mut tool_call_result);
+            tool_call_result
+        };
+        Some(track_tool_call(tool_call, call).await)
+    }
+
+    /// Starts a tool call that belongs to a registered tool, the same way generated code
+    /// dispatches derived tools. Synchronous calls are finished before this returns and their
+    /// result is pushed onto `results`, the others are spawned onto `tasks`.
+    ///
+    /// # Returns
+    /// `false`, without doing anything, if no tool with that name is registered.
+    pub async fn dispatch(
+        &self,
+        tool_call: &ChatCompletionMessageToolCall,
+        execution_strategy: &ToolCallExecutionStrategy,
+        tasks: &mut Vec<ToolCallTask>,
+        results: &mut Vec<ToolCallResult>,
+    ) -> bool {
+        let Some(tool) = self.get(&tool_call.function.name) else {
+            return false;
+        };
+
+        // Spawned tasks don't see the current config, so the sanitizer, sandbox, timeout,
+        // cancellation token and events are taken along. The call runs with the config as the
+        // current one again, so runs the tool starts inherit its budget, rate limiter, meter,
+        // scopes and depth, like those of derived tools.
+        let config = RunConfig::with_current(RunConfig::clone);
+        let sanitizer = current_sanitizer();
+        let sandbox = current_sandbox();
+        let timeout = current_tool_timeout(&tool_call.function.name, None);
+        let cancellation_token = current_cancellation_token();
+        let events = current_events();
+        let cpu_bound = tool.cpu_bound();
+        let task_tool_call = tool_call.clone();
+        let tool_call = tool_call.clone();
+        let call = async move {
+            let call = async {
+                let mut tool_call_result = call_tool(
+                    tool,
+                    tool_call.clone(),
+                    sandbox.as_ref(),
+                    timeout.as_ref(),
+                    cancellation_token.as_ref(),
+                )
+                .await;
+                sanitize_result(sanitizer.as_ref(), &mut tool_call_result);
+                tool_call_result
+            };
+            let call = track_tool_call_with(events.as_ref(), &tool_call, call);
+            match config {
+                Some(config) => config.scope(call).await,
+                None => call.await,
+            }
+        };
+
+        match execution_strategy {
+            ToolCallExecutionStrategy::Synchronous => {
+                results.push(call.await);
+            }
+            // The call is driven on a thread of the blocking pool by the runtime it came from,
+            // the way generated code runs cpu bound derived tools.
+            ToolCallExecutionStrategy::Parallel if cpu_bound => {
+                let runtime = tokio::runtime::Handle::current();
+                let handle = tokio::task::spawn_blocking(move || runtime.block_on(call));
+                tasks.push(ToolCallTask::new(task_tool_call, handle));
+            }
+            ToolCallExecutionStrategy::Async | ToolCallExecutionStrategy::Parallel => {
+                tasks.push(ToolCallTask::new(task_tool_call, tokio::spawn(call)));
+            }
+        }
+
+        true
+    }
+}
+
+/// A `Tool` made from an async closure, for when defining an enum variant and a `RunCommand`
+/// arm is more ceremony than a tool deserves.
+///
+/// The model's arguments are deserialized into `A` before the closure is called, and whatever
+/// it returns is serialized back to JSON.
+///
+/// ```
+/// # use openai_func_enums::{CommandError, ToolRegistry};
+/// # use serde::Deserialize;
+/// # use serde_json::json;
+/// # let mut registry = ToolRegistry::new();
+/// #[derive(Deserialize)]
+/// struct Shout {
+///     text: String,
+/// }
+///
+/// registry.register_fn(
+///     "Shout",
+///     "Repeats the text in upper case.",
+///     json!({
+///         "type": "object",
+///         "properties": { "text": { "type": "string" } },
+///         "required": ["text"]
+///     }),
+///     |args: Shout| async move { Ok::<_, CommandError>(args.text.to_uppercase()) },
+/// );
+/// ```
+pub struct FnTool<A, F> {
+    name: String,
+    description: String,
+    parameters: Value,
+    cpu_bound: bool,
+    function: F,
+    arguments: PhantomData<fn(A)>,
+}
+
+impl<A, F> FnTool<A, F> {
+    /// `parameters` is the JSON schema of `A`.
+    pub fn new(
+        name: impl Into<String>,
+        description: impl Into<String>,
+        parameters: Value,
+        function: F,
+    ) -> Self {
+        FnTool {
+            name: name.into(),
+            description: description.into(),
+            parameters,
+            cpu_bound: false,
+            function,
+            arguments: PhantomData,
+        }
+    }
+
+    /// Marks the tool as doing heavy computation, see `Tool::cpu_bound`.
+    pub fn with_cpu_bound(mut self, cpu_bound: bool) -> Self {
+        self.cpu_bound = cpu_bound;
+        self
+    }
+}
+
+#[async_trait]
+impl<A, F, Fut, R, E> Tool for FnTool<A, F>
+where
+    A: DeserializeOwned + Send + 'static,
+    F: Fn(A) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<R, E>> + Send,
+    R: Serialize,
+    E: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn parameters(&self) -> Value {
+        self.parameters.clone()
+    }
+
+    fn cpu_bound(&self) -> bool {
+        self.cpu_bound
+    }
+
+    async fn call(
+        &self,
+        arguments: Value,
+    ) -> Result<Value, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let arguments: A = serde_json::from_value(arguments)?;
+        let output = (self.function)(arguments).await.map_err(Into::into)?;
+        Ok(serde_json::to_value(output)?)
+    }
+}
+
+impl fmt::Debug for ToolRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ToolRegistry")
+            .field("tools", &self.names())
+            .finish()
+    }
+}
+
+async fn call_tool(
+    tool: Arc<dyn Tool>,
+    tool_call: ChatCompletionMessageToolCall,
+    sandbox: Option<&Sandbox>,
+    timeout: Option<&ToolTimeout>,
+    cancellation_token: Option<&CancellationToken>,
+) -> ToolCallResult {
+    let mut tool_call_result = ToolCallResult::new(&tool_call);
+
+    let arguments = if tool_call.function.arguments.trim().is_empty() {
+        Ok(Value::Object(Default::default()))
+    } else {
+        serde_json::from_str::<Value>(&tool_call.function.arguments)
+    };
+
+    match arguments {
+        Ok(arguments) => {
+            tool_call_result.attempts = 1;
+            let call = async move { tool.call(arguments).await };
+            let output = run_unless_cancelled(
+                cancellation_token,
+                run_with_timeout(timeout, sandbox, &mut tool_call_result, false, call),
+            )
+            .await;
+            tool_call_result.cancelled = was_cancelled(&output);
+            match output {
+                Ok(value) => {
+                    tool_call_result.output = ToolOutput::from(value.clone()).text_output();
+                    tool_call_result.value = Some(value);
+                }
+                Err(e) => tool_call_result.error = Some(e.to_string()),
+            }
+        }
+        Err(e) => tool_call_result.error = Some(e.to_string()),
+    }
+
+    tool_call_result
+}
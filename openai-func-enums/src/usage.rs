@@ -0,0 +1,174 @@
+use crate::{get_ranked_function_names_with_prior, CommandError, RunOutput};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Counts how often each tool is actually called, so rankings can favor the popular ones.
+///
+/// Put it on `RunConfig::usage_stats` and every tool call a generated `run_with_config` makes
+/// is recorded. Calls that failed are counted apart and don't add to a tool's popularity, so a
+/// tool the model keeps getting wrong isn't pushed up by it. Clones share their counts, so the
+/// same stats can be handed to every request of a session and used for ranking at the same
+/// time. Use `save` and `load` to keep them across sessions.
+///
+/// ```no_run
+/// # use openai_func_enums::UsageStats;
+/// # use std::path::Path;
+/// # const FUNC_ENUMS_EMBED_PATH: &str = "function_embeddings.bin";
+/// # async fn rank(prompt_embedding: Vec<f32>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// let usage_stats = UsageStats::load("usage.json").unwrap_or_default();
+/// let ranked = usage_stats.rank(prompt_embedding, Path::new(FUNC_ENUMS_EMBED_PATH)).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct UsageStats {
+    counts: Arc<Mutex<SavedCounts>>,
+    /// The bonus the most used tool gets on top of its similarity. Less used tools get a share
+    /// of it that grows with the logarithm of their count.
+    pub weight: f32,
+}
+
+impl Default for UsageStats {
+    fn default() -> Self {
+        UsageStats {
+            counts: Arc::default(),
+            weight: 0.05,
+        }
+    }
+}
+
+impl UsageStats {
+    pub fn new() -> Self {
+        UsageStats::default()
+    }
+
+    pub fn with_weight(mut self, weight: f32) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    /// Reads counts written by `save`. Files from before failures were counted, a plain object
+    /// of tool names to call counts, are read as calls that succeeded.
+    pub fn load(path: impl AsRef<Path>) -> Result<UsageStats, CommandError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| CommandError::new(&format!("Failed to read {}: {}", path.display(), e)))?;
+        let counts = match serde_json::from_str(&text) {
+            Ok(counts) => counts,
+            Err(e) => SavedCounts {
+                calls: serde_json::from_str(&text).map_err(|_| {
+                    CommandError::new(&format!("Failed to parse {}: {}", path.display(), e))
+                })?,
+                failures: HashMap::new(),
+            },
+        };
+
+        Ok(UsageStats {
+            counts: Arc::new(Mutex::new(counts)),
+            ..Default::default()
+        })
+    }
+
+    /// Writes the counts to `path` as JSON, with the `calls` that succeeded and the `failures`
+    /// of every tool.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), CommandError> {
+        let path = path.as_ref();
+        let text = serde_json::to_string_pretty(&*self.counts())
+            .map_err(|e| CommandError::new(&format!("Failed to serialize usage stats: {}", e)))?;
+        std::fs::write(path, text)
+            .map_err(|e| CommandError::new(&format!("Failed to write {}: {}", path.display(), e)))
+    }
+
+    /// Records a call to `tool_name` that succeeded.
+    pub fn record(&self, tool_name: &str) {
+        *self
+            .counts()
+            .calls
+            .entry(tool_name.to_string())
+            .or_insert(0) += 1;
+    }
+
+    pub fn record_failure(&self, tool_name: &str) {
+        *self
+            .counts()
+            .failures
+            .entry(tool_name.to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// Records every tool call of a run, the ones with an `error` as failures.
+    pub fn record_output(&self, output: &RunOutput) {
+        let mut counts = self.counts();
+        for tool_call in &output.tool_calls {
+            let counts = match tool_call.error {
+                Some(_) => &mut counts.failures,
+                None => &mut counts.calls,
+            };
+            *counts.entry(tool_call.name.clone()).or_insert(0) += 1;
+        }
+    }
+
+    /// How often `tool_name` was called successfully.
+    pub fn count(&self, tool_name: &str) -> u64 {
+        self.counts().calls.get(tool_name).copied().unwrap_or(0)
+    }
+
+    /// How often a call to `tool_name` failed.
+    pub fn failures(&self, tool_name: &str) -> u64 {
+        self.counts().failures.get(tool_name).copied().unwrap_or(0)
+    }
+
+    pub fn clear(&self) {
+        let mut counts = self.counts();
+        counts.calls.clear();
+        counts.failures.clear();
+    }
+
+    /// The bonus `tool_name` gets on top of its similarity, between `0.0` for a tool that was
+    /// never called and `weight` for the most called one.
+    pub fn prior(&self, tool_name: &str) -> f32 {
+        let counts = &self.counts().calls;
+        let max_count = counts.values().copied().max().unwrap_or(0);
+        self.scaled_prior(counts.get(tool_name).copied().unwrap_or(0), max_count)
+    }
+
+    /// Ranks the functions in the archive at `embed_path` the way `get_ranked_function_names`
+    /// does, with the usage prior blended into their similarity.
+    pub async fn rank(
+        &self,
+        prompt_embedding: Vec<f32>,
+        embed_path: &Path,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let counts = self.counts().calls.clone();
+        let max_count = counts.values().copied().max().unwrap_or(0);
+
+        get_ranked_function_names_with_prior(prompt_embedding, embed_path, |name| {
+            self.scaled_prior(counts.get(name).copied().unwrap_or(0), max_count)
+        })
+        .await
+    }
+
+    fn scaled_prior(&self, count: u64, max_count: u64) -> f32 {
+        if max_count == 0 {
+            return 0.0;
+        }
+
+        self.weight * ((1 + count) as f32).ln() / ((1 + max_count) as f32).ln()
+    }
+
+    fn counts(&self) -> MutexGuard<'_, SavedCounts> {
+        self.counts
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// The counts of `UsageStats`, as `save` writes them.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct SavedCounts {
+    calls: HashMap<String, u64>,
+    #[serde(default)]
+    failures: HashMap<String, u64>,
+}
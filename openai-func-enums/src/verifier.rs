@@ -0,0 +1,126 @@
+use crate::RunConfig;
+use async_openai::error::OpenAIError;
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestSystemMessageArgs,
+    ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs,
+};
+
+const DEFAULT_INSTRUCTIONS: &str = "You review function calls an assistant wants to make \
+before they are executed. Approve a call only if it is what the user asked for and its \
+arguments are sensible. Answer with APPROVE or REJECT on the first line, followed by a short \
+reason.";
+
+/// A second, usually cheaper, model that has to approve calls to risky functions before they
+/// run. Put it on `RunConfig::verifier`.
+///
+/// Functions are marked risky with `#[func_enums(risky)]`. Tools that don't come from the
+/// derive, like the ones in a `ToolRegistry`, can be added to `functions`. A rejected call is
+/// not executed, and its `ToolCallResult` carries the verifier's reason as the error.
+#[derive(Clone, Debug)]
+pub struct ToolCallVerifier {
+    pub model: String,
+    /// The system message of the verification request.
+    pub instructions: String,
+    /// Further functions to verify, on top of the ones marked risky.
+    pub functions: Vec<String>,
+    pub max_response_tokens: u32,
+}
+
+/// What the verifier decided about a call.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Approved,
+    Rejected { reason: String },
+}
+
+impl ToolCallVerifier {
+    pub fn new(model: impl Into<String>) -> Self {
+        ToolCallVerifier {
+            model: model.into(),
+            instructions: DEFAULT_INSTRUCTIONS.to_string(),
+            functions: Vec::new(),
+            max_response_tokens: 100,
+        }
+    }
+
+    pub fn with_instructions(mut self, instructions: impl Into<String>) -> Self {
+        self.instructions = instructions.into();
+        self
+    }
+
+    pub fn with_function(mut self, name: impl Into<String>) -> Self {
+        self.functions.push(name.into());
+        self
+    }
+
+    /// Whether calls to `name` have to be verified. `risky` is whether the function is marked
+    /// `#[func_enums(risky)]`.
+    pub fn covers(&self, name: &str, risky: bool) -> bool {
+        risky || self.functions.iter().any(|function| function == name)
+    }
+
+    /// Asks the verifier model about `tool_call`. `conversation_summary` is what the user asked
+    /// for. The request is sent with `config`, so it shares its client, key pool and cassette.
+    pub async fn verify(
+        &self,
+        config: &RunConfig,
+        tool_call: &ChatCompletionMessageToolCall,
+        conversation_summary: &str,
+    ) -> Result<Verdict, OpenAIError> {
+        let call = format!(
+            "Conversation so far:\n{}\n\nProposed call: {}\nArguments: {}",
+            conversation_summary, tool_call.function.name, tool_call.function.arguments
+        );
+
+        let mut request = CreateChatCompletionRequestArgs::default();
+        config
+            .max_tokens_field
+            .apply(&self.model, &mut request, self.max_response_tokens);
+        let request = request
+            .model(&self.model)
+            .temperature(0.0)
+            .messages(vec![
+                ChatCompletionRequestSystemMessageArgs::default()
+                    .content(self.instructions.as_str())
+                    .build()?
+                    .into(),
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(call)
+                    .build()?
+                    .into(),
+            ])
+            .build()?;
+
+        let response = config.create_chat_completion(request).await?;
+        let text = response
+            .choices
+            .first()
+            .and_then(|choice| choice.message.content.as_deref())
+            .unwrap_or_default();
+
+        Ok(parse_verdict(text))
+    }
+}
+
+/// Reads a verifier answer. Only an answer that starts with "APPROVE", in any case, approves
+/// the call; anything else rejects it, with the rest of the answer as the reason.
+pub fn parse_verdict(text: &str) -> Verdict {
+    let text = text.trim();
+    let word_end = text
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(text.len());
+    let (word, rest) = text.split_at(word_end);
+    let reason = rest
+        .trim_start_matches(|c: char| c == ':' || c == '.' || c == '-' || c.is_whitespace())
+        .to_string();
+
+    if word.eq_ignore_ascii_case("approve") || word.eq_ignore_ascii_case("approved") {
+        Verdict::Approved
+    } else if word.eq_ignore_ascii_case("reject") || word.eq_ignore_ascii_case("rejected") {
+        Verdict::Rejected { reason }
+    } else {
+        Verdict::Rejected {
+            reason: text.to_string(),
+        }
+    }
+}
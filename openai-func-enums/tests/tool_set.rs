@@ -0,0 +1,1142 @@
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessage,
+    ChatCompletionRequestMessage, ChatCompletionRequestToolMessage, ChatCompletionToolType,
+    CreateChatCompletionRequest, FunctionCall,
+};
+use async_trait::async_trait;
+use openai_func_enums::{
+    count_tokens, get_tools_limited, AnthropicProvider, CommandError, Config, Conversation,
+    DescriptionCatalog, FewShotExample, FewShotExamples, FnTool, LlmProvider, LocalizedFunction,
+    Logger, RankingConfig, RetryPolicy, RunCommand, RunConfig, RunOutput, ToolArg,
+    ToolCallExecutionStrategy, ToolCallResult, ToolCallVerifier, ToolOutput, ToolRegistry, ToolSet,
+    UsageStats,
+};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+#[derive(Debug, ToolSet)]
+#[func_enums(entry = "Ask")]
+pub enum Commands {
+    /// Gets the current weather in a location
+    GetCurrentWeather {
+        location: Location,
+    },
+
+    /// Lists the open orders,
+    /// newest first
+    ListOrders {
+        #[func_enums(default = 20)]
+        limit: u32,
+        status: Option<String>,
+    },
+
+    /// Schedules a reminder
+    ScheduleReminder {
+        #[func_enums(min = 1, max = 1440)]
+        minutes: u32,
+        #[func_enums(pattern = "^[A-Z]{3}$")]
+        team: String,
+    },
+
+    /// Sets the thermostat
+    SetTemperature {
+        #[func_enums(default = "Boston")]
+        location: Location,
+        #[func_enums(default = 21)]
+        degrees: f64,
+        #[func_enums(default = "celsius")]
+        units: Option<String>,
+    },
+
+    /// Books a table
+    BookTable {
+        // Not a `Location`, which only fails once a call comes in.
+        #[func_enums(default = "Paris")]
+        location: Location,
+    },
+
+    #[func_enums(rename = "clear_cache")]
+    /// Clears the cache
+    ClearCache,
+
+    #[func_enums(skip)]
+    Shutdown,
+
+    /// A variant that only happens to share the name of the default entry variant.
+    GPT {
+        prompt: String,
+    },
+
+    Ask {
+        prompt: String,
+    },
+}
+
+#[async_trait]
+impl RunCommand for Commands {
+    async fn run(
+        &self,
+        _execution_strategy: ToolCallExecutionStrategy,
+        _arguments: Option<Vec<String>>,
+        _logger: Arc<Logger>,
+        _system_message: Option<(String, usize)>,
+        _state: &(),
+    ) -> Result<ToolOutput, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        Ok(ToolOutput::none())
+    }
+}
+
+#[derive(ToolArg)]
+#[arg_description(description = "A city.")]
+pub enum Location {
+    Atlanta,
+    Boston,
+}
+
+mod weather {
+    use super::Location;
+    use async_trait::async_trait;
+    use openai_func_enums::{Logger, RunCommand, ToolCallExecutionStrategy, ToolOutput, ToolSet};
+    use std::sync::Arc;
+
+    #[derive(Debug, ToolSet)]
+    #[func_enums(namespaced)]
+    pub enum WeatherTools {
+        /// Gets the forecast for a location
+        GetForecast { location: Location },
+    }
+
+    // Shares a variant name with `WeatherTools` in the same module.
+    #[derive(Debug, ToolSet)]
+    #[func_enums(namespaced)]
+    pub enum TravelTools {
+        /// Gets the forecast for the destination of a trip
+        GetForecast { trip_id: String },
+    }
+
+    #[async_trait]
+    impl RunCommand for WeatherTools {
+        async fn run(
+            &self,
+            _execution_strategy: ToolCallExecutionStrategy,
+            _arguments: Option<Vec<String>>,
+            _logger: Arc<Logger>,
+            _system_message: Option<(String, usize)>,
+            _state: &(),
+        ) -> Result<ToolOutput, Box<dyn std::error::Error + Send + Sync + 'static>> {
+            Ok(ToolOutput::none())
+        }
+    }
+    #[async_trait]
+    impl RunCommand for TravelTools {
+        async fn run(
+            &self,
+            _execution_strategy: ToolCallExecutionStrategy,
+            _arguments: Option<Vec<String>>,
+            _logger: Arc<Logger>,
+            _system_message: Option<(String, usize)>,
+            _state: &(),
+        ) -> Result<ToolOutput, Box<dyn std::error::Error + Send + Sync + 'static>> {
+            Ok(ToolOutput::none())
+        }
+    }
+}
+
+mod orders {
+    use async_trait::async_trait;
+    use openai_func_enums::{Logger, RunCommand, ToolCallExecutionStrategy, ToolOutput, ToolSet};
+    use std::sync::Arc;
+
+    pub struct Warehouse {
+        pub name: String,
+    }
+
+    #[derive(Debug, ToolSet)]
+    #[func_enums(state = "Warehouse")]
+    pub enum OrderTools {
+        /// Looks up where an order is
+        TrackOrder { order_id: String },
+    }
+
+    #[async_trait]
+    impl RunCommand<Warehouse> for OrderTools {
+        async fn run(
+            &self,
+            _execution_strategy: ToolCallExecutionStrategy,
+            arguments: Option<Vec<String>>,
+            _logger: Arc<Logger>,
+            _system_message: Option<(String, usize)>,
+            warehouse: &Warehouse,
+        ) -> Result<ToolOutput, Box<dyn std::error::Error + Send + Sync + 'static>> {
+            let OrderTools::TrackOrder { order_id } = self;
+            Ok(ToolOutput::text(format!(
+                "{} is in {}, after {:?}",
+                order_id, warehouse.name, arguments
+            ))
+            .with_next_arguments(vec![order_id.clone()]))
+        }
+    }
+}
+
+fn tool_names(config: &RunConfig) -> Vec<String> {
+    let request = <Commands as ToolSet>::build_request("Hi", "gpt-4o", None, None, config).unwrap();
+
+    request
+        .tools
+        .unwrap_or_default()
+        .into_iter()
+        .map(|tool| tool.function.name)
+        .collect()
+}
+
+fn function_json(name: &str) -> Value {
+    let (functions, _) = CommandsGPT::all_function_jsons();
+
+    functions
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|function| function["name"] == name)
+        .unwrap_or_else(|| panic!("no function called {}", name))
+        .clone()
+}
+
+/// Answers one HTTP request on a local port with `status` and `body`. Returns the API base to
+/// send the request to, and a handle that gives back the body of the request it answered.
+fn serve_once(status: &str, content_type: &str, body: &str) -> (String, JoinHandle<Value>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let api_base = format!("http://{}/v1", listener.local_addr().unwrap());
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+
+    let handle = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut request_body = vec![0; content_length];
+        reader.read_exact(&mut request_body).unwrap();
+        stream.write_all(response.as_bytes()).unwrap();
+
+        serde_json::from_slice(&request_body).unwrap()
+    });
+
+    (api_base, handle)
+}
+
+fn call(name: &str, arguments: Value) -> FunctionCall {
+    FunctionCall {
+        name: name.to_string(),
+        arguments: arguments.to_string(),
+    }
+}
+
+#[test]
+fn entry_variant_is_not_a_tool() {
+    assert_eq!(CommandsGPT::ENTRY_VARIANT, Some("Ask"));
+
+    let names = tool_names(&RunConfig::default());
+    assert!(!names.contains(&"Ask".to_string()), "{:?}", names);
+    assert!(names.contains(&"GetCurrentWeather".to_string()));
+    assert!(names.contains(&"GPT".to_string()));
+}
+
+#[test]
+fn runtime_config_is_applied_to_the_request() {
+    let config = Config {
+        model: Some("gpt-4o-mini".to_string()),
+        max_response_tokens: Some(321),
+        ranking: Some(RankingConfig {
+            required_functions: vec!["ListOrders".to_string()],
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+    .run_config();
+
+    let request = CommandsGPT::build_request(
+        "Hi",
+        "gpt-4o",
+        None,
+        None,
+        &None,
+        Some(vec!["GetCurrentWeather".to_string()]),
+        None,
+        &config,
+    )
+    .unwrap();
+    let names: Vec<_> = request
+        .tools
+        .unwrap_or_default()
+        .into_iter()
+        .map(|tool| tool.function.name)
+        .collect();
+
+    assert_eq!(request.model, "gpt-4o-mini");
+    #[allow(deprecated)]
+    let max_tokens = request.max_tokens;
+    assert_eq!(max_tokens, Some(321));
+    assert!(
+        names.contains(&"GetCurrentWeather".to_string()),
+        "{:?}",
+        names
+    );
+    assert!(names.contains(&"ListOrders".to_string()), "{:?}", names);
+}
+
+#[test]
+fn a_merged_system_message_comes_before_the_examples() {
+    let mut few_shot_examples = FewShotExamples::new();
+    few_shot_examples.add(
+        "GetCurrentWeather",
+        FewShotExample::new(
+            "How warm is it in Atlanta?",
+            json!({ "location": "Atlanta" }),
+        ),
+    );
+    let config = RunConfig {
+        few_shot_examples,
+        ..Default::default()
+    };
+
+    let request = CommandsGPT::build_request(
+        "Hi",
+        "o1-mini",
+        None,
+        None,
+        &Some(("Be brief.".to_string(), 3)),
+        Some(vec!["GetCurrentWeather".to_string()]),
+        None,
+        &config,
+    )
+    .unwrap();
+    let messages = serde_json::to_value(&request.messages).unwrap();
+
+    assert_eq!(messages.as_array().unwrap().len(), 4);
+    assert_eq!(messages[0]["role"], "user");
+    assert_eq!(
+        messages[0]["content"],
+        "Be brief.\n\nHow warm is it in Atlanta?"
+    );
+    assert_eq!(messages[3]["content"], "Hi");
+}
+
+#[test]
+fn preferred_functions_lead_the_default_list() {
+    let config = Config {
+        ranking: Some(RankingConfig {
+            preferred_functions: vec!["ClearCache".to_string(), "ListOrders".to_string()],
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+    .run_config();
+    assert_eq!(config.preferred_functions, ["ClearCache", "ListOrders"]);
+
+    let config = RunConfig {
+        preferred_functions: vec!["clear_cache".to_string(), "ListOrders".to_string()],
+        ..config
+    };
+    let names = tool_names(&config);
+    assert_eq!(names[..2], ["clear_cache", "ListOrders"], "{:?}", names);
+    assert_eq!(names.len(), tool_names(&RunConfig::default()).len());
+}
+
+#[test]
+fn banned_functions_are_left_out_of_the_allowed_and_required_lists() {
+    let config = RunConfig {
+        banned_functions: Some(vec!["ListOrders".to_string(), "clear_cache".to_string()]),
+        ..Default::default()
+    };
+
+    let request = CommandsGPT::build_request(
+        "Hi",
+        "gpt-4o",
+        None,
+        None,
+        &None,
+        Some(vec![
+            "ListOrders".to_string(),
+            "GetCurrentWeather".to_string(),
+        ]),
+        Some(vec!["clear_cache".to_string()]),
+        &config,
+    )
+    .unwrap();
+    let names: Vec<_> = request
+        .tools
+        .unwrap_or_default()
+        .into_iter()
+        .map(|tool| tool.function.name)
+        .collect();
+
+    assert_eq!(names, ["GetCurrentWeather"]);
+}
+
+#[test]
+fn functions_are_limited_by_the_size_of_their_translations() {
+    let descriptions = DescriptionCatalog {
+        locale: "de".to_string(),
+        functions: [(
+            "ScheduleReminder".to_string(),
+            LocalizedFunction {
+                description: Some("Plant eine Erinnerung für später am Tag.".to_string()),
+                arguments: [("team".to_string(), "Das Kürzel des Teams.".to_string())].into(),
+            },
+        )]
+        .into(),
+    };
+    let (json, tokens) = CommandsGPT::function_jsons()
+        .iter()
+        .find(|(json, _)| json["name"] == "ScheduleReminder")
+        .unwrap();
+
+    let mut tool_args = get_tools_limited(
+        CommandsGPT::function_jsons_allowed_with_required,
+        vec!["ScheduleReminder".to_string()],
+        None,
+    )
+    .unwrap();
+    assert_eq!(tool_args.1, *tokens);
+    descriptions.localize_tools(&mut tool_args);
+
+    assert_ne!(tool_args.1, *tokens);
+    assert_eq!(descriptions.localized_tokens(json, *tokens), tool_args.1);
+}
+
+#[tokio::test]
+async fn a_failed_verification_fails_only_that_call() {
+    // Nothing listens on the discard port, so the verification request fails.
+    let config = RunConfig {
+        api_base: Some("http://127.0.0.1:9/v1".to_string()),
+        api_key: Some("test".to_string()),
+        retry_policy: Some(RetryPolicy {
+            max_attempts: Some(1),
+            ..Default::default()
+        }),
+        verifier: Some(ToolCallVerifier::new("gpt-4o-mini").with_function("ListOrders")),
+        ..Default::default()
+    };
+    let tool_call = ChatCompletionMessageToolCall {
+        id: "call_1".to_string(),
+        r#type: ChatCompletionToolType::Function,
+        function: call("ListOrders", json!({})),
+    };
+    let mut output = RunOutput::default();
+
+    assert!(
+        config
+            .verify_tool_call(&tool_call, false, "List my orders", &mut output)
+            .await
+    );
+    let error = output.tool_calls[0].error.as_deref().unwrap_or_default();
+    assert!(
+        error.starts_with("The call to ListOrders could not be verified"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn failed_calls_are_counted_apart() {
+    let tool_call = |name: &str| ChatCompletionMessageToolCall {
+        id: "call_1".to_string(),
+        r#type: ChatCompletionToolType::Function,
+        function: call(name, json!({})),
+    };
+    let output = RunOutput {
+        tool_calls: vec![
+            ToolCallResult::new(&tool_call("ListOrders")),
+            ToolCallResult {
+                error: Some("Bad arguments".to_string()),
+                ..ToolCallResult::new(&tool_call("ScheduleReminder"))
+            },
+        ],
+        ..Default::default()
+    };
+    let usage_stats = UsageStats::new();
+
+    usage_stats.record_output(&output);
+
+    assert_eq!(usage_stats.count("ListOrders"), 1);
+    assert_eq!(usage_stats.count("ScheduleReminder"), 0);
+    assert_eq!(usage_stats.failures("ScheduleReminder"), 1);
+    assert_eq!(usage_stats.prior("ScheduleReminder"), 0.0);
+}
+
+#[test]
+fn old_tool_results_are_left_out_of_a_follow_up_that_is_too_long() {
+    let config = RunConfig::default();
+    let mut request =
+        CommandsGPT::build_request("Hi", "gpt-4o", None, None, &None, None, None, &config).unwrap();
+    let first_follow_up = request.messages.len();
+    let tool_message = |id: &str| {
+        ChatCompletionRequestMessage::Tool(ChatCompletionRequestToolMessage {
+            content: "lots of orders ".repeat(200).into(),
+            tool_call_id: id.to_string(),
+        })
+    };
+    request.messages.extend([
+        ChatCompletionRequestMessage::Assistant(ChatCompletionRequestAssistantMessage {
+            tool_calls: Some(vec![
+                ChatCompletionMessageToolCall {
+                    id: "call_1".to_string(),
+                    r#type: ChatCompletionToolType::Function,
+                    function: call("ListOrders", json!({})),
+                },
+                ChatCompletionMessageToolCall {
+                    id: "call_2".to_string(),
+                    r#type: ChatCompletionToolType::Function,
+                    function: call("ListOrders", json!({ "status": "open" })),
+                },
+            ]),
+            ..Default::default()
+        }),
+        tool_message("call_1"),
+        tool_message("call_2"),
+    ]);
+    let request_tokens = |request: &CreateChatCompletionRequest| {
+        count_tokens(&serde_json::to_string(&request.tools).unwrap_or_default())
+            + request
+                .messages
+                .iter()
+                .map(|message| count_tokens(&serde_json::to_string(message).unwrap()))
+                .sum::<usize>()
+    };
+    let result_tokens = count_tokens(&serde_json::to_string(&tool_message("call_1")).unwrap());
+
+    // Room for one of the two results, under the limit the run was given.
+    let request_token_limit = Some(request_tokens(&request) - result_tokens / 2);
+    let mut fitted = request.clone();
+    config
+        .fit_follow_up_request(&mut fitted, first_follow_up, request_token_limit, 4096)
+        .unwrap();
+
+    assert_eq!(fitted.messages.len(), request.messages.len());
+    assert_ne!(
+        fitted.messages[first_follow_up + 1],
+        request.messages[first_follow_up + 1]
+    );
+    assert_eq!(
+        fitted.messages[first_follow_up + 2],
+        request.messages[first_follow_up + 2]
+    );
+
+    // Not even room for the prompt. The limit of the config comes first.
+    let config = RunConfig {
+        request_token_limit: Some(1),
+        ..Default::default()
+    };
+    assert!(config
+        .fit_follow_up_request(&mut request, first_follow_up, request_token_limit, 4096)
+        .is_err());
+}
+
+#[tokio::test]
+async fn a_conversation_keeps_a_merged_system_message() {
+    let (api_base, request) = serve_once(
+        "200 OK",
+        "application/json",
+        &json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "o1-mini",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": "Hello" },
+                "finish_reason": "stop"
+            }]
+        })
+        .to_string(),
+    );
+    let config = RunConfig {
+        api_base: Some(api_base),
+        api_key: Some("test".to_string()),
+        ..Default::default()
+    };
+    let mut conversation = Conversation::new();
+    conversation
+        .add_user("Hi")
+        .add_assistant("Hello")
+        .add_user("Bye");
+
+    // o1-mini takes no system messages, so the default one is merged into a user message.
+    conversation
+        .run_tools::<Commands>(
+            "o1-mini",
+            ToolCallExecutionStrategy::Async,
+            Arc::new(Logger {
+                sender: tokio::sync::mpsc::channel(16).0,
+            }),
+            &config,
+        )
+        .await
+        .unwrap();
+    let request = request.join().unwrap();
+    let contents: Vec<_> = request["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|message| message["content"].as_str().unwrap())
+        .collect();
+
+    assert_eq!(
+        contents,
+        [
+            "You are a helpful function calling bot.\n\nHi",
+            "Hello",
+            "Bye"
+        ]
+    );
+    assert_eq!(conversation.messages.len(), 4);
+}
+
+#[tokio::test]
+async fn run_hands_commands_the_state_and_arguments() {
+    let (api_base, _request) = serve_once(
+        "200 OK",
+        "application/json",
+        &json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-4o",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": { "name": "TrackOrder", "arguments": "{\"order_id\":\"A1\"}" }
+                    }]
+                },
+                "finish_reason": "tool_calls"
+            }]
+        })
+        .to_string(),
+    );
+    let config = RunConfig {
+        api_base: Some(api_base),
+        api_key: Some("test".to_string()),
+        ..Default::default()
+    };
+    let prior_result = Arc::new(tokio::sync::Mutex::new(None));
+    let command = Arc::new(tokio::sync::Mutex::new(Some(vec!["start".to_string()])));
+
+    // Boxed, since the future is too big for the stack of a test thread.
+    config
+        .scope(Box::pin(orders::CommandsGPT::run(
+            "Where is A1?",
+            "gpt-4o",
+            None,
+            None,
+            None,
+            prior_result.clone(),
+            ToolCallExecutionStrategy::Async,
+            command.clone(),
+            None,
+            None,
+            Arc::new(Logger {
+                sender: tokio::sync::mpsc::channel(16).0,
+            }),
+            Arc::new(orders::Warehouse {
+                name: "Boston".to_string(),
+            }),
+        )))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        prior_result.lock().await.as_deref(),
+        Some("A1 is in Boston, after Some([\"start\"])")
+    );
+    assert_eq!(*command.lock().await, Some(vec!["A1".to_string()]));
+}
+
+#[tokio::test]
+async fn a_text_answer_is_not_a_prior_result() {
+    let (api_base, _request) = serve_once(
+        "200 OK",
+        "application/json",
+        &json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-4o",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": "A1 shipped yesterday." },
+                "finish_reason": "stop"
+            }]
+        })
+        .to_string(),
+    );
+    let config = RunConfig {
+        api_base: Some(api_base),
+        api_key: Some("test".to_string()),
+        ..Default::default()
+    };
+    let prior_result = Arc::new(tokio::sync::Mutex::new(Some("Earlier output".to_string())));
+
+    config
+        .scope(Box::pin(orders::CommandsGPT::run(
+            "Where is A1?",
+            "gpt-4o",
+            None,
+            None,
+            None,
+            prior_result.clone(),
+            ToolCallExecutionStrategy::Async,
+            Arc::new(tokio::sync::Mutex::new(None)),
+            None,
+            None,
+            Arc::new(Logger {
+                sender: tokio::sync::mpsc::channel(16).0,
+            }),
+            Arc::new(orders::Warehouse {
+                name: "Boston".to_string(),
+            }),
+        )))
+        .await
+        .unwrap();
+
+    assert_eq!(prior_result.lock().await.as_deref(), Some("Earlier output"));
+}
+
+#[tokio::test]
+async fn a_run_without_its_state_fails_before_sending() {
+    // Nothing listens on the discard port, so only a request would fail differently.
+    let config = RunConfig {
+        api_base: Some("http://127.0.0.1:9/v1".to_string()),
+        api_key: Some("test".to_string()),
+        ..Default::default()
+    };
+
+    let error = orders::CommandsGPT::run_with_config(
+        "Where is A1?",
+        "gpt-4o",
+        None,
+        None,
+        None,
+        ToolCallExecutionStrategy::Async,
+        None,
+        None,
+        Arc::new(Logger {
+            sender: tokio::sync::mpsc::channel(16).0,
+        }),
+        &config,
+    )
+    .await
+    .unwrap_err();
+
+    assert!(error.to_string().contains("no state of type"), "{}", error);
+}
+
+#[tokio::test]
+async fn cpu_bound_registered_tools_get_a_thread_under_parallel() {
+    let mut registry = ToolRegistry::new();
+    for (name, cpu_bound) in [("Crunch", true), ("Fetch", false)] {
+        registry.register(
+            FnTool::new(
+                name,
+                "Reports its thread",
+                json!({ "type": "object" }),
+                |_: Value| async {
+                    Ok::<_, CommandError>(format!("{:?}", std::thread::current().id()))
+                },
+            )
+            .with_cpu_bound(cpu_bound),
+        );
+    }
+    let this_thread = format!("{:?}", std::thread::current().id());
+
+    let mut tasks = Vec::new();
+    let mut results = Vec::new();
+    for (id, name) in [("call_1", "Crunch"), ("call_2", "Fetch")] {
+        let tool_call = ChatCompletionMessageToolCall {
+            id: id.to_string(),
+            r#type: ChatCompletionToolType::Function,
+            function: call(name, json!({})),
+        };
+        assert!(
+            registry
+                .dispatch(
+                    &tool_call,
+                    &ToolCallExecutionStrategy::Parallel,
+                    &mut tasks,
+                    &mut results,
+                )
+                .await
+        );
+    }
+    for task in tasks {
+        results.push(task.join().await);
+    }
+
+    // The test runtime has a single thread, which only the blocking pool differs from.
+    assert_ne!(results[0].output.as_deref(), Some(this_thread.as_str()));
+    assert_eq!(results[1].output.as_deref(), Some(this_thread.as_str()));
+}
+
+#[tokio::test]
+async fn registered_tools_run_with_the_config_of_their_run() {
+    let mut registry = ToolRegistry::new();
+    for (name, cpu_bound) in [("Crunch", true), ("Fetch", false)] {
+        registry.register(
+            FnTool::new(
+                name,
+                "Reports the model of the current config",
+                json!({ "type": "object" }),
+                |_: Value| async { Ok::<_, CommandError>(RunConfig::current().model) },
+            )
+            .with_cpu_bound(cpu_bound),
+        );
+    }
+    let config = RunConfig {
+        model: Some("gpt-4o-mini".to_string()),
+        ..Default::default()
+    };
+
+    let results = config
+        .scope(Box::pin(async {
+            let mut tasks = Vec::new();
+            let mut results = Vec::new();
+            for (id, name) in [("call_1", "Crunch"), ("call_2", "Fetch")] {
+                let tool_call = ChatCompletionMessageToolCall {
+                    id: id.to_string(),
+                    r#type: ChatCompletionToolType::Function,
+                    function: call(name, json!({})),
+                };
+                registry
+                    .dispatch(
+                        &tool_call,
+                        &ToolCallExecutionStrategy::Parallel,
+                        &mut tasks,
+                        &mut results,
+                    )
+                    .await;
+            }
+            for task in tasks {
+                results.push(task.join().await);
+            }
+            results
+        }))
+        .await;
+
+    for result in results {
+        assert_eq!(
+            result.output.as_deref(),
+            Some("gpt-4o-mini"),
+            "{:?}",
+            result
+        );
+    }
+}
+
+#[tokio::test]
+async fn a_spawned_tool_that_panics_fails_its_call() {
+    let (api_base, _request) = serve_once(
+        "200 OK",
+        "application/json",
+        &json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-4o",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [
+                        {
+                            "id": "call_1",
+                            "type": "function",
+                            "function": { "name": "Explode", "arguments": "{}" }
+                        },
+                        {
+                            "id": "call_2",
+                            "type": "function",
+                            "function": { "name": "clear_cache", "arguments": "{}" }
+                        }
+                    ]
+                },
+                "finish_reason": "tool_calls"
+            }]
+        })
+        .to_string(),
+    );
+    let mut tools = ToolRegistry::new();
+    tools.register_fn(
+        "Explode",
+        "Panics",
+        json!({ "type": "object" }),
+        |_: Value| async {
+            if true {
+                panic!("the fuse was lit");
+            }
+            Ok::<_, CommandError>(())
+        },
+    );
+    let usage_stats = UsageStats::new();
+    let config = RunConfig {
+        api_base: Some(api_base),
+        api_key: Some("test".to_string()),
+        tools,
+        usage_stats: Some(usage_stats.clone()),
+        ..Default::default()
+    };
+
+    let output = config
+        .clone()
+        .scope(Box::pin(CommandsGPT::run_with_config(
+            "Light it",
+            "gpt-4o",
+            None,
+            None,
+            None,
+            ToolCallExecutionStrategy::Async,
+            None,
+            None,
+            Arc::new(Logger {
+                sender: tokio::sync::mpsc::channel(16).0,
+            }),
+            &config,
+        )))
+        .await
+        .unwrap();
+
+    // Spawned calls are joined in the order they were dispatched, and the other call still
+    // goes through.
+    let ids: Vec<&str> = output
+        .tool_calls
+        .iter()
+        .map(|result| result.id.as_str())
+        .collect();
+    assert_eq!(ids, ["call_1", "call_2"]);
+    let error = output.tool_calls[0].error.as_deref().unwrap_or_default();
+    assert!(error.contains("the fuse was lit"), "{}", error);
+    assert_eq!(output.tool_calls[1].error, None);
+    assert_eq!(usage_stats.failures("Explode"), 1);
+    assert!(output.follow_up_messages().iter().any(|message| matches!(
+        message,
+        ChatCompletionRequestMessage::Tool(message) if message.tool_call_id == "call_1"
+    )));
+}
+
+#[tokio::test]
+async fn an_anthropic_error_page_keeps_its_status() {
+    let (api_base, _request) =
+        serve_once("502 Bad Gateway", "text/html", "<html>Bad Gateway</html>");
+    let provider = AnthropicProvider {
+        api_base: api_base.trim_end_matches("/v1").to_string(),
+        api_key: Some("test".to_string()),
+        ..Default::default()
+    };
+    let config = RunConfig::default();
+    let request = CommandsGPT::build_request(
+        "Hi",
+        "claude-sonnet-4-5",
+        None,
+        None,
+        &None,
+        None,
+        None,
+        &config,
+    )
+    .unwrap();
+
+    let failure = provider
+        .try_chat_completion(request, &config)
+        .await
+        .unwrap_err();
+
+    assert_eq!(failure.status, Some(502));
+    let async_openai::error::OpenAIError::ApiError(api_error) = failure.error else {
+        panic!("not an API error: {:?}", failure.error);
+    };
+    assert_eq!(api_error.message, "<html>Bad Gateway</html>");
+    assert_eq!(api_error.code.as_deref(), Some("502"));
+}
+
+#[test]
+fn skipped_variants_are_not_tools() {
+    let names = tool_names(&RunConfig::default());
+
+    assert!(!names.contains(&"Shutdown".to_string()), "{:?}", names);
+}
+
+#[test]
+fn renamed_variants_are_sent_under_their_new_name() {
+    let names = tool_names(&RunConfig::default());
+
+    assert!(names.contains(&"clear_cache".to_string()), "{:?}", names);
+    assert!(!names.contains(&"ClearCache".to_string()), "{:?}", names);
+    assert!(CommandsGPT::parse_gpt_function_call(&call("clear_cache", json!({}))).is_ok());
+}
+
+// Enum arguments are named after their type rather than the field.
+#[test]
+fn enum_arguments_list_their_variants() {
+    let function = function_json("GetCurrentWeather");
+
+    assert_eq!(
+        function["description"].as_str().map(str::trim),
+        Some("Gets the current weather in a location")
+    );
+    assert_eq!(
+        function["parameters"]["properties"]["Location"]["enum"],
+        json!(["Atlanta", "Boston"])
+    );
+    assert_eq!(function["parameters"]["required"], json!(["Location"]));
+}
+
+#[test]
+fn a_doc_comment_of_several_lines_is_one_description() {
+    assert_eq!(
+        function_json("ListOrders")["description"],
+        "Lists the open orders, newest first"
+    );
+    assert_eq!(
+        ListOrders::DESCRIPTION,
+        "Lists the open orders, newest first"
+    );
+    assert_eq!(
+        ListOrders::DESCRIPTION_TOKENS,
+        ListOrders::FULL_DESCRIPTION_TOKENS
+    );
+}
+
+#[test]
+fn defaults_are_advertised_and_filled_in() {
+    let function = function_json("ListOrders");
+    let parameters = &function["parameters"];
+
+    assert_eq!(parameters["properties"]["limit"]["default"], json!(20));
+    let required = parameters["required"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    assert!(!required.contains(&json!("limit")), "{:?}", required);
+    assert!(!required.contains(&json!("status")), "{:?}", required);
+
+    match CommandsGPT::parse_gpt_function_call(&call("ListOrders", json!({}))).unwrap() {
+        FunctionResponse::ListOrders(arguments) => {
+            assert_eq!(arguments.limit, 20);
+            assert_eq!(arguments.status, None);
+        }
+        other => panic!("parsed as {:?}", other),
+    }
+}
+
+#[test]
+fn typed_and_serde_defaults_are_filled_in() {
+    match CommandsGPT::parse_gpt_function_call(&call("SetTemperature", json!({}))).unwrap() {
+        FunctionResponse::SetTemperature(arguments) => {
+            assert!(matches!(arguments.location, Location::Boston));
+            assert_eq!(arguments.degrees, 21.0);
+            assert_eq!(arguments.units.as_deref(), Some("celsius"));
+        }
+        other => panic!("parsed as {:?}", other),
+    }
+}
+
+#[test]
+fn defaults_that_dont_fit_fail_the_call() {
+    assert!(BookTable::check_defaults().is_err());
+    assert!(SetTemperature::check_defaults().is_ok());
+    assert!(CommandsGPT::parse_gpt_function_call(&call(
+        "BookTable",
+        json!({ "location": "Boston" })
+    ))
+    .is_err());
+}
+
+#[test]
+fn constraints_are_in_the_schema_and_checked() {
+    let function = function_json("ScheduleReminder");
+    let properties = &function["parameters"]["properties"];
+
+    assert_eq!(properties["minutes"]["minimum"], json!(1));
+    assert_eq!(properties["minutes"]["maximum"], json!(1440));
+    assert_eq!(properties["team"]["pattern"], "^[A-Z]{3}$");
+
+    assert!(CommandsGPT::parse_gpt_function_call(&call(
+        "ScheduleReminder",
+        json!({ "minutes": 30, "team": "OPS" })
+    ))
+    .is_ok());
+    assert!(CommandsGPT::parse_gpt_function_call(&call(
+        "ScheduleReminder",
+        json!({ "minutes": 0, "team": "OPS" })
+    ))
+    .is_err());
+    assert!(CommandsGPT::parse_gpt_function_call(&call(
+        "ScheduleReminder",
+        json!({ "minutes": 30, "team": "ops" })
+    ))
+    .is_err());
+}
+
+#[test]
+fn namespaced_items_are_named_after_the_enum() {
+    let (functions, tokens) = weather::WeatherToolsGPT::all_function_jsons();
+
+    assert_eq!(functions[0]["name"], "GetForecast");
+    assert!(tokens > 0);
+    assert_eq!(weather::WEATHER_TOOLS_MAX_FUNC_TOKENS, 500);
+    assert_eq!(weather::WeatherToolsGPT::ENTRY_VARIANT, None);
+    assert!(matches!(
+        weather::WeatherToolsGPT::parse_gpt_function_call(&call(
+            "GetForecast",
+            json!({ "location": "Boston" })
+        )),
+        Ok(weather::WeatherToolsFunctionResponse::GetForecast(_))
+    ));
+}
+
+#[test]
+fn namespaced_enums_share_variant_names() {
+    let weather = weather::WeatherToolsGPT::parse_gpt_function_call(&call(
+        "GetForecast",
+        json!({ "location": "Boston" }),
+    ));
+    let travel = weather::TravelToolsGPT::parse_gpt_function_call(&call(
+        "GetForecast",
+        json!({ "trip_id": "T-1" }),
+    ));
+
+    match (weather, travel) {
+        (
+            Ok(weather::WeatherToolsFunctionResponse::GetForecast(
+                weather::WeatherToolsGetForecast { .. },
+            )),
+            Ok(weather::TravelToolsFunctionResponse::GetForecast(
+                weather::TravelToolsGetForecast { trip_id },
+            )),
+        ) => assert_eq!(trip_id, "T-1"),
+        other => panic!("parsed as {:?}", other),
+    }
+}